@@ -0,0 +1,111 @@
+//! Packing every file under a directory into one archive file, and unpacking it again.
+//!
+//! An archive starts with `MAGIC`, followed by one entry for each file: the length of the file’s
+//! path relative to the archived directory as a big-endian `u16`, the path itself, the length of
+//! the file as a big-endian `u64`, and its contents. An entry with an empty path ends the archive.
+
+use std::default::Default;
+use std::io::{mod, fs, File, IoError, IoResult};
+use std::io::fs::PathExtensions;
+
+use atomic::{TempFiles, write_atomic};
+
+/// The bytes every archive starts with.
+static MAGIC: &'static [u8] = b"FBOXARC1";
+
+/// Writes every file under `root` to a new archive at `archive`, which is replaced atomically.
+/// Files are archived in sorted order of their paths, so archiving the same files twice produces
+/// the same archive.
+pub fn export_archive(root: &Path, archive: &Path) -> IoResult<()> {
+    let mut files = Vec::new();
+    for p in try!(fs::walk_dir(root)) {
+        if p.is_file() && p != *archive {
+            files.push(p);
+        }
+    }
+    files.sort();
+
+    let temp: TempFiles = Default::default();
+    let tmp = temp.path_for(archive);
+    let res = File::create(&tmp).and_then(|mut f| {
+        try!(f.write(MAGIC));
+        for p in files.iter() {
+            let rel = p.path_relative_from(root).unwrap();
+            let name = rel.as_vec();
+            let bytes = try!(File::open(p).read_to_end());
+            try!(f.write_be_u16(name.len() as u16));
+            try!(f.write(name));
+            try!(f.write_be_u64(bytes.len() as u64));
+            try!(f.write(bytes.as_slice()));
+        }
+        try!(f.write_be_u16(0));
+        f.fsync()
+    }).and_then(|()| fs::rename(&tmp, archive));
+    if res.is_err() {
+        let _ = fs::unlink(&tmp);
+    }
+    res
+}
+
+/// Restores the files in the archive at `archive` under `root`, creating directories as needed.
+/// Files already under `root` with the same paths as archived ones are replaced; other files are
+/// left alone. Each file is replaced atomically, but the archive as a whole is not restored
+/// atomically.
+pub fn import_archive(archive: &Path, root: &Path) -> IoResult<()> {
+    let mut f = try!(File::open(archive));
+    if try!(f.read_exact(MAGIC.len())).as_slice() != MAGIC {
+        return Err(corrupt("the file isn’t an archive of boxes"));
+    }
+    loop {
+        let len = try!(f.read_be_u16()) as uint;
+        if len == 0 {
+            return Ok(());
+        }
+        let rel = Path::new(try!(f.read_exact(len)));
+        // The archive could have come from anywhere, so it mustn’t be able to write outside `root`.
+        if rel.is_absolute() || rel.components().any(|c| c == b"..") {
+            return Err(corrupt("the archive holds a path outside its directory"));
+        }
+        let len = try!(f.read_be_u64()) as uint;
+        let bytes = try!(f.read_exact(len));
+        let p = root.join(rel);
+        let dir = p.dir_path();
+        if !dir.is_dir() {
+            try!(fs::mkdir_recursive(&dir, io::USER_RWX));
+        }
+        try!(write_atomic(&p, bytes.as_slice()));
+    }
+}
+
+fn corrupt(desc: &'static str) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: desc,
+        detail: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs;
+    use super::super::{Store, peek};
+
+    #[test]
+    fn archive_round_trip() {
+        let root = Path::new("target/archive_round_trip");
+        let restored = Path::new("target/archive_round_trip_restored");
+        let archive = Path::new("target/archive_round_trip.archive");
+        let _ = fs::rmdir_recursive(&root);
+        let _ = fs::rmdir_recursive(&restored);
+        let store = Store::new(&root).unwrap();
+        store.namespace("a").unwrap().open_new("x", 1i).unwrap();
+        store.namespace("a").unwrap().namespace("b").unwrap().open_new("y", 2i).unwrap();
+        store.export_archive(&archive).unwrap();
+
+        let copy = Store::new(&restored).unwrap();
+        copy.import_archive(&archive).unwrap();
+        let ns = copy.namespace("a").unwrap();
+        assert_eq!(peek::<int>(&ns.path("x").unwrap()).unwrap(), 1);
+        assert_eq!(peek::<int>(&ns.namespace("b").unwrap().path("y").unwrap()).unwrap(), 2);
+    }
+}
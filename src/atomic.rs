@@ -0,0 +1,275 @@
+//! Replacing files atomically through temporary files.
+
+use std::cell::Cell;
+use std::cmp;
+use std::default::Default;
+use std::io::{fs, File, IoResult};
+use std::io::fs::PathExtensions;
+use std::time::Duration;
+
+use direct;
+use faults;
+use faults::{Faults, ShortWrite, FsyncError, RenameError, TornWrite};
+use process;
+use progress;
+use progress::{Progress, CancelToken};
+use slow::{mod, Timings};
+use time;
+
+/// Controls the temporary files used to replace box files atomically.
+///
+/// A file is replaced by writing its new contents to a temporary file and then renaming that over
+/// it. The rename is only atomic if both files are on the same filesystem, so `dir` must be on the
+/// same filesystem as every box written with these settings.
+///
+/// The id of the process writing the file is included in the name of the temporary file, between
+/// the name of the file being replaced and `suffix`. This lets temporary files left behind by
+/// processes that crashed be told apart from ones that are still being written.
+#[deriving(Clone, PartialEq, Show)]
+pub struct TempFiles {
+    /// The directory temporary files are created in. If this is `None`, each temporary file is
+    /// created in the same directory as the file it replaces.
+    pub dir: Option<Path>,
+    /// Prepended to the name of the file being replaced to give the name of its temporary file.
+    pub prefix: String,
+    /// Appended to the name of the file being replaced to give the name of its temporary file.
+    pub suffix: String,
+    /// Whether the temporary file is removed if the write fails before it can be renamed.
+    pub remove_on_failure: bool,
+}
+
+/// How far writing a box goes to make sure its new value survives the machine losing power.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Durability {
+    /// Nothing is flushed to disk, leaving the operating system to write the new file out when it
+    /// likes. This is fastest, but after a power loss the box may be empty or hold its old value.
+    NoSync,
+    /// The new file is flushed to disk before it is renamed over the old one, so after a power
+    /// loss the box holds either its old value or its new one. This is the default.
+    Flush,
+    /// The directory is flushed to disk as well once the new file has been renamed into it, so
+    /// the new value survives a power loss as soon as the write returns.
+    Fsync,
+}
+
+impl Default for Durability {
+    fn default() -> Durability {
+        Flush
+    }
+}
+
+/// Flushes the directory holding `p` to disk, so that a file just renamed into it stays there.
+#[cfg(unix)]
+pub fn sync_dir(p: &Path) -> IoResult<()> {
+    File::open(&p.dir_path()).and_then(|mut dir| dir.fsync())
+}
+
+/// Directories can’t be opened as files here, and renames are flushed along with the file.
+#[cfg(not(unix))]
+pub fn sync_dir(_p: &Path) -> IoResult<()> {
+    Ok(())
+}
+
+impl TempFiles {
+    /// The name of the temporary file used by this process when replacing the file at `p`.
+    pub fn path_for(&self, p: &Path) -> Path {
+        self.path_for_process(p, process::id())
+    }
+
+    fn path_for_process(&self, p: &Path, pid: u32) -> Path {
+        let mut name = self.name_start(p);
+        name.push_all(pid.to_string().as_bytes());
+        name.push_all(self.suffix.as_bytes());
+        self.dir_for(p).join(name)
+    }
+
+    /// Everything in the name of a temporary file for `p` before the process id.
+    fn name_start(&self, p: &Path) -> Vec<u8> {
+        let mut name = self.prefix.as_bytes().to_vec();
+        name.push_all(p.filename().unwrap_or(b"filebox"));
+        name.push(b'.');
+        name
+    }
+
+    fn dir_for(&self, p: &Path) -> Path {
+        match self.dir {
+            Some(ref dir) => dir.clone(),
+            None => p.dir_path(),
+        }
+    }
+
+    /// Removes the temporary files for `p` that were left behind by processes that are no longer
+    /// running, returning how many were removed.
+    pub fn remove_stale(&self, p: &Path) -> IoResult<uint> {
+        let dir = self.dir_for(p);
+        if !dir.is_dir() {
+            return Ok(0);
+        }
+        let start = self.name_start(p);
+        let mut removed = 0;
+        for tmp in try!(fs::readdir(&dir)).iter() {
+            let name = match tmp.filename() {
+                Some(name) => name,
+                None => continue,
+            };
+            if name.len() <= start.len() + self.suffix.len() || !name.starts_with(start.as_slice())
+               || !name.ends_with(self.suffix.as_bytes()) {
+                continue;
+            }
+            let pid = name.slice(start.len(), name.len() - self.suffix.len());
+            let pid = match ::std::str::from_utf8(pid).and_then(|s| from_str::<u32>(s)) {
+                Some(pid) => pid,
+                None => continue,
+            };
+            if pid != process::id() && !process::is_alive(pid) {
+                try!(fs::unlink(tmp));
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl Default for TempFiles {
+    /// Temporary files are created next to the files they replace, with the process id and `.tmp`
+    /// appended to their names, and are removed if the write fails.
+    fn default() -> TempFiles {
+        TempFiles {
+            dir: None,
+            prefix: String::new(),
+            suffix: ".tmp".to_string(),
+            remove_on_failure: true,
+        }
+    }
+}
+
+/// Atomically replaces the contents of the file at `p` with `bytes`.
+pub fn write_atomic(p: &Path, bytes: &[u8]) -> IoResult<()> {
+    write_atomic_with(p, bytes, &Default::default(), None, None, None)
+}
+
+/// Like `write_atomic`, but uses the temporary file described by `temp`, reports progress to
+/// `progress`, and gives up without touching the file at `p` if `cancel` is cancelled before the
+/// write is complete. If `faults` has a failure queued, it is injected into the write.
+pub fn write_atomic_with(p: &Path, bytes: &[u8], temp: &TempFiles, progress: Option<&mut Progress>,
+                         cancel: Option<&CancelToken>, faults: Option<&Faults>) -> IoResult<()> {
+    replace(p, bytes, temp, progress, cancel, faults, false, Flush, None)
+}
+
+/// Like `write_atomic_with`, but flushing the files involved to disk only as far as `durability`
+/// asks. If `direct` is set, the temporary file is written with direct I/O, bypassing the page
+/// cache. If the write succeeds, how long writing, flushing and renaming the file took is
+/// recorded in `timings`.
+pub fn write_atomic_durable(p: &Path, bytes: &[u8], temp: &TempFiles,
+                            progress: Option<&mut Progress>, cancel: Option<&CancelToken>,
+                            faults: Option<&Faults>, direct: bool, durability: Durability,
+                            timings: Option<&mut Timings>) -> IoResult<()> {
+    replace(p, bytes, temp, progress, cancel, faults, direct, durability, timings)
+}
+
+fn replace(p: &Path, bytes: &[u8], temp: &TempFiles, progress: Option<&mut Progress>,
+           cancel: Option<&CancelToken>, faults: Option<&Faults>, direct: bool,
+           durability: Durability, timings: Option<&mut Timings>) -> IoResult<()> {
+    let fault = faults::take(faults);
+    let tmp = temp.path_for(p);
+    let start = time::precise_time_ns();
+    let written = Cell::new(start);
+    let synced = Cell::new(start);
+    let res = direct::create(&tmp, direct).and_then(|mut f| {
+        match fault {
+            Some(ShortWrite(n)) => {
+                try!(f.write(bytes.slice_to(cmp::min(n, bytes.len()))));
+                return Err(faults::injected(ShortWrite(n)));
+            }
+            Some(TornWrite(n)) => {
+                try!(f.write(bytes.slice_to(cmp::min(n, bytes.len()))));
+                try!(f.sync());
+                try!(fs::rename(&tmp, p));
+                return Err(faults::injected(TornWrite(n)));
+            }
+            Some(FsyncError) => {
+                try!(progress::write_all(f.as_writer(), bytes, progress, cancel));
+                return Err(faults::injected(FsyncError));
+            }
+            _ => {}
+        }
+        try!(progress::write_all(f.as_writer(), bytes, progress, cancel));
+        written.set(time::precise_time_ns());
+        let res = if durability == NoSync { Ok(()) } else { f.sync() };
+        synced.set(time::precise_time_ns());
+        res
+    }).and_then(|()| progress::check(cancel)).and_then(|()| match fault {
+        Some(RenameError) => Err(faults::injected(RenameError)),
+        _ => fs::rename(&tmp, p),
+    }).and_then(|()| if durability == Fsync { sync_dir(p) } else { Ok(()) });
+    if res.is_err() && temp.remove_on_failure {
+        let _ = fs::unlink(&tmp);
+    }
+    match (timings, res.is_ok()) {
+        (Some(timings), true) => {
+            let ns = |from: u64, to: u64| Duration::nanoseconds((to - from) as i64);
+            timings.write = ns(start, written.get());
+            timings.fsync = ns(written.get(), synced.get());
+            timings.rename = slow::since(synced.get());
+        }
+        _ => {}
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::default::Default;
+    use std::io::File;
+    use std::io::fs::PathExtensions;
+    use faults::{Faults, ShortWrite, FsyncError, RenameError, TornWrite};
+    use super::{TempFiles, write_atomic, write_atomic_with};
+
+    #[test]
+    fn temp_file_names() {
+        let p = Path::new("data/state.box");
+        let default: TempFiles = Default::default();
+        assert_eq!(default.path_for_process(&p, 12), Path::new("data/state.box.12.tmp"));
+        let temp = TempFiles {
+            dir: Some(Path::new("scratch")),
+            prefix: ".".to_string(),
+            suffix: ".part".to_string(),
+            remove_on_failure: false,
+        };
+        assert_eq!(temp.path_for_process(&p, 12), Path::new("scratch/.state.box.12.part"));
+    }
+
+    #[test]
+    fn remove_stale() {
+        let p = Path::new("target/remove_stale");
+        let temp: TempFiles = Default::default();
+        // No process can have an id this large.
+        let stale = temp.path_for_process(&p, 0x7ffffffe);
+        let live = temp.path_for(&p);
+        File::create(&stale).unwrap();
+        File::create(&live).unwrap();
+        assert_eq!(temp.remove_stale(&p).unwrap(), 1);
+        assert!(!stale.exists());
+        assert!(live.exists());
+    }
+
+    #[test]
+    fn injected_faults() {
+        let p = Path::new("target/injected_faults");
+        let temp: TempFiles = Default::default();
+        let faults = Faults::new();
+        write_atomic(&p, b"old").unwrap();
+        for fault in vec![ShortWrite(1), FsyncError, RenameError].into_iter() {
+            faults.inject(fault);
+            assert!(write_atomic_with(&p, b"new", &temp, None, None, Some(&faults)).is_err());
+            assert_eq!(File::open(&p).read_to_end().unwrap(), b"old".to_vec());
+            assert!(!temp.path_for(&p).exists());
+        }
+        faults.inject(TornWrite(2));
+        assert!(write_atomic_with(&p, b"new", &temp, None, None, Some(&faults)).is_err());
+        assert_eq!(File::open(&p).read_to_end().unwrap(), b"ne".to_vec());
+        assert_eq!(faults.pending(), 0);
+        write_atomic_with(&p, b"new", &temp, None, None, Some(&faults)).unwrap();
+        assert_eq!(File::open(&p).read_to_end().unwrap(), b"new".to_vec());
+    }
+}
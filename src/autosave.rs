@@ -0,0 +1,87 @@
+//! Periodic saves into slots next to a box’s file.
+
+use std::io::{fs, IoResult};
+use std::io::fs::PathExtensions;
+use std::time::Duration;
+
+/// How a box is autosaved.
+///
+/// Autosaves are written to a fixed number of slot files next to the box’s file, which are used
+/// in rotation and removed whenever the box itself is written. Slots left behind when the box is
+/// opened therefore hold changes that were never saved, usually because the program crashed.
+#[deriving(Clone, PartialEq, Show)]
+pub struct Autosave {
+    /// How many slots to rotate through. Must be at least one.
+    pub slots: uint,
+    /// How long to wait after an autosave before the next one is due.
+    pub interval: Duration,
+}
+
+/// The autosave state of a box.
+pub struct State {
+    pub settings: Autosave,
+    /// When the last autosave happened, as given by `Clock::now_ms`.
+    pub last: u64,
+    pub next_slot: uint,
+}
+
+impl State {
+    pub fn new(settings: Autosave, now: u64) -> State {
+        assert!(settings.slots > 0, "autosaving needs at least one slot");
+        State {
+            settings: settings,
+            last: now,
+            next_slot: 0,
+        }
+    }
+
+    /// Whether the interval since the last autosave has passed at the time `now`.
+    pub fn is_due(&self, now: u64) -> bool {
+        now >= self.last + self.settings.interval.num_milliseconds() as u64
+    }
+
+    /// Returns the slot to write an autosave of the box at `p` to at the time `now`, and moves on
+    /// to the next one.
+    pub fn take_slot(&mut self, p: &Path, now: u64) -> Path {
+        let slot = slot_path(p, self.next_slot);
+        self.next_slot = (self.next_slot + 1) % self.settings.slots;
+        self.last = now;
+        slot
+    }
+}
+
+/// The path of the autosave slot with the given number for the box at `p`.
+fn slot_path(p: &Path, n: uint) -> Path {
+    let mut name = p.filename().unwrap_or(b"filebox").to_vec();
+    name.push_all(format!(".autosave-{}", n).as_bytes());
+    p.with_filename(name)
+}
+
+/// Lists the autosave slots of the box at `p`, along with when they were last modified, newest
+/// first.
+pub fn slots(p: &Path) -> IoResult<Vec<(u64, Path)>> {
+    let dir = p.dir_path();
+    let mut prefix = p.filename().unwrap_or(b"filebox").to_vec();
+    prefix.push_all(b".autosave-");
+    let mut found = Vec::new();
+    for slot in try!(fs::readdir(&dir)).into_iter() {
+        let is_slot = slot.filename().map_or(false, |name| {
+            name.len() > prefix.len() && name.starts_with(prefix.as_slice())
+            && name.slice_from(prefix.len()).iter().all(|&b| b >= b'0' && b <= b'9')
+        });
+        if is_slot && slot.is_file() {
+            let modified = try!(fs::stat(&slot)).modified;
+            found.push((modified, slot));
+        }
+    }
+    found.sort_by(|&(a, _), &(b, _)| b.cmp(&a));
+    Ok(found)
+}
+
+/// Removes every autosave slot of the box at `p`.
+pub fn remove_slots(p: &Path) -> IoResult<()> {
+    for &(_, ref slot) in try!(slots(p)).iter() {
+        try!(fs::unlink(slot));
+    }
+    Ok(())
+}
@@ -0,0 +1,275 @@
+//! Backing up box files incrementally, by sending only the parts that changed.
+//!
+//! A backup starts from a `Signature` of the file as the other side last saw it, which is small
+//! enough to keep next to the box. `export_incremental` compares the file with the signature in
+//! fixed-size blocks and writes the blocks that differ to a delta file, which `apply_incremental`
+//! applies to the other side’s copy. Blocks are compared at the same offsets, so values that
+//! change in place produce small deltas, while changes that shift the rest of the file produce
+//! deltas about as large as the file.
+//!
+//! Whole stores are backed up with `backup_store`, which copies every box as it was at one moment.
+
+use std::cmp;
+use std::collections::TreeMap;
+use std::io::{mod, fs, File, IoError, IoResult};
+use std::io::fs::PathExtensions;
+use std::slice::bytes::copy_memory;
+use std::time::Duration;
+use serialize::json;
+use time;
+
+use atomic::write_atomic;
+use lock::Lock;
+use slow;
+use store::{EXTENSION, temp_file_owner};
+use super::{fnv1a, now, peek, write_value};
+
+/// The name of the file `backup_store` describes a backup in.
+pub static MANIFEST: &'static str = "manifest.json";
+
+/// The size of the blocks files are compared in.
+static BLOCK_SIZE: uint = 4096;
+
+/// What a box file looked like when it was last backed up.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub struct Signature {
+    len: u64,
+    hash: u64,
+    blocks: Vec<u64>,
+}
+
+impl Signature {
+    /// The signature of a file that doesn’t exist yet. Exporting against it produces a delta
+    /// holding the whole file.
+    pub fn empty() -> Signature {
+        Signature::of_bytes(&[])
+    }
+
+    /// The signature of the file at `p` as it is now.
+    pub fn of(p: &Path) -> IoResult<Signature> {
+        Ok(Signature::of_bytes(try!(read(p)).as_slice()))
+    }
+
+    fn of_bytes(bytes: &[u8]) -> Signature {
+        Signature {
+            len: bytes.len() as u64,
+            hash: fnv1a(bytes),
+            blocks: bytes.chunks(BLOCK_SIZE).map(fnv1a).collect(),
+        }
+    }
+}
+
+/// The changes between two versions of a file.
+#[deriving(Encodable, Decodable)]
+struct Delta {
+    /// The hash of the file the delta applies to.
+    base_hash: u64,
+    len: u64,
+    hash: u64,
+    /// The index and new contents of each block that changed.
+    blocks: Vec<(u64, Vec<u8>)>,
+}
+
+/// Writes the changes made to the file at `p` since it had the signature `since` to a delta file
+/// at `out`, returning the signature the file has now, which the next export should start from.
+pub fn export_incremental(p: &Path, since: &Signature, out: &Path) -> IoResult<Signature> {
+    let bytes = try!(read(p));
+    let now = Signature::of_bytes(bytes.as_slice());
+    let mut blocks = Vec::new();
+    for (i, (block, &hash)) in bytes.as_slice().chunks(BLOCK_SIZE).zip(now.blocks.iter())
+                                                                  .enumerate() {
+        if since.blocks.as_slice().get(i) != Some(&hash) {
+            blocks.push((i as u64, block.to_vec()));
+        }
+    }
+    try!(write_value(out, &Delta {
+        base_hash: since.hash,
+        len: now.len,
+        hash: now.hash,
+        blocks: blocks,
+    }));
+    Ok(now)
+}
+
+/// Applies the delta file at `delta`, written by `export_incremental`, to the file at `p`, which
+/// must be the version of the file the delta was exported against. A missing file counts as
+/// empty. The file is replaced atomically.
+pub fn apply_incremental(p: &Path, delta: &Path) -> IoResult<()> {
+    let delta: Delta = try!(peek(delta));
+    let mut bytes = try!(read(p));
+    if fnv1a(bytes.as_slice()) != delta.base_hash {
+        return Err(IoError {
+            kind: io::InvalidInput,
+            desc: "the delta was exported against a different version of the file",
+            detail: Some(p.display().to_string()),
+        });
+    }
+    let len = delta.len as uint;
+    if len > bytes.len() {
+        let extra = len - bytes.len();
+        bytes.grow(extra, 0);
+    } else {
+        bytes.truncate(len);
+    }
+    for &(i, ref block) in delta.blocks.iter() {
+        let start = i as uint * BLOCK_SIZE;
+        let end = cmp::min(start + block.len(), bytes.len());
+        if start > end {
+            return Err(corrupt());
+        }
+        copy_memory(bytes.slice_mut(start, end), block.slice_to(end - start));
+    }
+    if fnv1a(bytes.as_slice()) != delta.hash {
+        return Err(corrupt());
+    }
+    write_atomic(p, bytes.as_slice())
+}
+
+/// What `backup_store` copied.
+#[deriving(Clone, PartialEq, Show)]
+pub struct BackupReport {
+    /// The paths of the files copied, relative to the store.
+    pub files: Vec<Path>,
+    /// The total size of the files copied, in bytes.
+    pub bytes: u64,
+    /// How long the boxes were locked for while they were copied.
+    pub locked_for: Duration,
+}
+
+/// Copies every file of the store in `root` to the directory `dir`, which is created if it
+/// doesn’t exist and must be empty otherwise, along with a manifest giving the size and FNV-1a
+/// hash of each file, for scheduled backups of a whole store.
+///
+/// Every box is locked while the files are copied, as `Namespace::read_snapshot` locks the boxes
+/// it reads, so the copies are consistent with each other for programs that lock boxes before
+/// writing them; such programs wait for the copying to finish. Locks and the temporary files of
+/// writes that are under way aren’t copied. The manifest, named `MANIFEST`, is written last, so a
+/// backup without one was cut short.
+pub fn backup_store(root: &Path, dir: &Path) -> IoResult<BackupReport> {
+    if dir.exists() && !try!(fs::readdir(dir)).is_empty() {
+        return Err(IoError {
+            kind: io::PathAlreadyExists,
+            desc: "the backup directory isn’t empty",
+            detail: Some(dir.display().to_string()),
+        });
+    }
+    let mut files = Vec::new();
+    for p in try!(fs::walk_dir(root)) {
+        if !p.is_file() || p.extension_str() == Some("lock") || temp_file_owner(&p).is_some() {
+            continue;
+        }
+        // A backup kept inside the store isn’t part of it.
+        if dir.is_ancestor_of(&p) {
+            continue;
+        }
+        files.push(p);
+    }
+    files.sort();
+
+    let start = time::precise_time_ns();
+    // Locking in sorted order, as `read_snapshot` does, keeps the two from waiting on each other.
+    let mut locks = Vec::new();
+    for p in files.iter().filter(|p| p.extension_str() == Some(EXTENSION)) {
+        locks.push(try!(Lock::acquire(p, true)));
+    }
+    let mut report = BackupReport {
+        files: Vec::new(),
+        bytes: 0,
+        locked_for: Duration::zero(),
+    };
+    let mut entries = Vec::new();
+    for p in files.iter() {
+        let rel = p.path_relative_from(root).unwrap();
+        let to = dir.join(&rel);
+        try!(fs::mkdir_recursive(&to.dir_path(), io::USER_RWX));
+        let bytes = try!(File::open(p).read_to_end());
+        try!(File::create(&to).and_then(|mut f| {
+            try!(f.write(bytes.as_slice()));
+            f.fsync()
+        }));
+        let mut entry = TreeMap::new();
+        entry.insert("path".to_string(), json::String(rel.display().to_string()));
+        entry.insert("size".to_string(), json::U64(bytes.len() as u64));
+        entry.insert("hash".to_string(), json::U64(fnv1a(bytes.as_slice())));
+        entries.push(json::Object(entry));
+        report.bytes += bytes.len() as u64;
+        report.files.push(rel);
+    }
+    drop(locks);
+    report.locked_for = slow::since(start);
+
+    let mut manifest = TreeMap::new();
+    manifest.insert("created".to_string(), json::U64(now()));
+    manifest.insert("files".to_string(), json::List(entries));
+    try!(write_atomic(&dir.join(MANIFEST), json::Object(manifest).to_pretty_str().as_bytes()));
+    Ok(report)
+}
+
+fn read(p: &Path) -> IoResult<Vec<u8>> {
+    if p.exists() {
+        File::open(p).read_to_end()
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+fn corrupt() -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: "the delta file is corrupt",
+        detail: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs;
+    use std::io::fs::PathExtensions;
+    use super::{Signature, export_incremental, apply_incremental, MANIFEST};
+    use super::super::{Store, peek, write_value};
+
+    #[test]
+    fn incremental_backup() {
+        let path = Path::new("target/incremental_backup");
+        let copy = Path::new("target/incremental_backup_copy");
+        let delta = Path::new("target/incremental_backup_delta");
+        if copy.exists() {
+            fs::unlink(&copy).unwrap();
+        }
+        let mut val = Vec::from_elem(20000, 1u8);
+        write_value(&path, &val).unwrap();
+        let sig = export_incremental(&path, &Signature::empty(), &delta).unwrap();
+        apply_incremental(&copy, &delta).unwrap();
+        assert_eq!(peek::<Vec<u8>>(&copy).unwrap(), val);
+
+        val.as_mut_slice()[15000] = 2;
+        write_value(&path, &val).unwrap();
+        let full = fs::stat(&path).unwrap().size;
+        let sig = export_incremental(&path, &sig, &delta).unwrap();
+        assert!(fs::stat(&delta).unwrap().size < full / 2);
+        apply_incremental(&copy, &delta).unwrap();
+        assert_eq!(peek::<Vec<u8>>(&copy).unwrap(), val);
+        assert_eq!(Signature::of(&copy).unwrap(), sig);
+
+        // The same delta can’t be applied twice.
+        assert!(apply_incremental(&copy, &delta).is_err());
+    }
+
+    #[test]
+    fn back_up_store() {
+        let root = Path::new("target/back_up_store");
+        let _ = fs::rmdir_recursive(&root);
+        let store = Store::new(&root).unwrap();
+        let ns = store.namespace("app").unwrap();
+        ns.put("a", &1u32).unwrap();
+        ns.put("b", &2u32).unwrap();
+        ns.put("c", &3u32).unwrap();
+
+        let dir = root.join("backup");
+        let report = store.backup_to(&dir).unwrap();
+        assert_eq!(report.files.len(), 3);
+        assert!(dir.join(MANIFEST).exists());
+        assert_eq!(peek::<u32>(&dir.join(report.files[1].clone())).unwrap(), 2);
+        assert!(store.backup_to(&dir).is_err());
+    }
+}
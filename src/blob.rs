@@ -0,0 +1,181 @@
+//! Keeping large byte strings in files of their own next to a box.
+
+use std::io::{mod, fs, File, IoError, IoResult};
+use std::io::fs::PathExtensions;
+
+use atomic::write_atomic;
+use super::{FileBox, fnv1a};
+
+/// A reference to a byte string stored in a file of its own, for use inside the values of boxes.
+///
+/// Large byte strings, such as images, make every write of the box that holds them slow, even
+/// when only a small field next to them has changed. Storing them with `FileBox::put_blob`
+/// instead keeps them in a directory next to the box’s file, leaving only a `Blob` in the value.
+/// The files are named after their contents, so storing the same bytes twice stores them once,
+/// and a file is never changed once it has been written.
+#[deriving(Clone, PartialEq, Eq, Hash, Show, Encodable, Decodable)]
+pub struct Blob {
+    hash: u64,
+    len: u64,
+}
+
+impl Blob {
+    /// The number of bytes in the blob.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn file_name(&self) -> String {
+        format!("{:016x}-{}.blob", self.hash, self.len)
+    }
+}
+
+/// The directory the blobs of the box at `p` are stored in.
+pub fn blob_dir(p: &Path) -> Path {
+    let mut name = p.filename().unwrap_or(b"filebox").to_vec();
+    name.push_all(b".blobs");
+    p.with_filename(name)
+}
+
+impl<T> FileBox<T> {
+    /// Stores `bytes` in a file next to the box’s own, returning the reference to keep in its
+    /// value. Nothing is written if the same bytes have been stored before.
+    pub fn put_blob(&self, bytes: &[u8]) -> IoResult<Blob> {
+        let blob = Blob {
+            hash: fnv1a(bytes),
+            len: bytes.len() as u64,
+        };
+        let dir = blob_dir(&self._path);
+        if !dir.is_dir() {
+            try!(fs::mkdir(&dir, io::USER_RWX));
+        }
+        let p = dir.join(blob.file_name());
+        if !p.exists() {
+            try!(write_atomic(&p, bytes));
+        }
+        Ok(blob)
+    }
+
+    /// Reads the bytes of a blob stored with `put_blob`, failing if they have been damaged.
+    pub fn load_blob(&self, blob: &Blob) -> IoResult<Vec<u8>> {
+        load(&blob_dir(&self._path).join(blob.file_name()), blob)
+    }
+
+    /// Returns a handle that reads the bytes of `blob` the first time they are asked for, which
+    /// suits values with many blobs that are rarely all needed.
+    pub fn blob_handle(&self, blob: &Blob) -> BlobHandle {
+        BlobHandle {
+            path: blob_dir(&self._path).join(blob.file_name()),
+            blob: blob.clone(),
+            bytes: None,
+        }
+    }
+
+    /// Removes the blob files of the box that aren’t in `used`, returning how many were removed.
+    /// Blobs are never removed otherwise, since the box can’t tell which ones its value still
+    /// refers to.
+    pub fn remove_unused_blobs(&self, used: &[&Blob]) -> IoResult<uint> {
+        let dir = blob_dir(&self._path);
+        if !dir.is_dir() {
+            return Ok(0);
+        }
+        let keep: Vec<String> = used.iter().map(|blob| blob.file_name()).collect();
+        let mut removed = 0;
+        for p in try!(fs::readdir(&dir)).iter() {
+            let name = p.filename_str().unwrap_or("");
+            if name.ends_with(".blob") && !keep.iter().any(|k| k.as_slice() == name) {
+                try!(fs::unlink(p));
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// The bytes of a blob, read from its file when they are first asked for and kept from then on.
+pub struct BlobHandle {
+    path: Path,
+    blob: Blob,
+    bytes: Option<Vec<u8>>,
+}
+
+impl BlobHandle {
+    /// The blob this is a handle to.
+    pub fn blob(&self) -> &Blob {
+        &self.blob
+    }
+
+    /// Returns whether the bytes of the blob have been read yet.
+    pub fn is_loaded(&self) -> bool {
+        self.bytes.is_some()
+    }
+
+    /// Returns the bytes of the blob, reading them first if they haven’t been read yet. Fails if
+    /// they have been damaged.
+    pub fn read(&mut self) -> IoResult<&[u8]> {
+        if self.bytes.is_none() {
+            self.bytes = Some(try!(load(&self.path, &self.blob)));
+        }
+        Ok(self.bytes.as_ref().unwrap().as_slice())
+    }
+}
+
+fn load(p: &Path, blob: &Blob) -> IoResult<Vec<u8>> {
+    let bytes = try!(File::open(p).read_to_end());
+    if bytes.len() as u64 != blob.len || fnv1a(bytes.as_slice()) != blob.hash {
+        return Err(IoError {
+            kind: io::InvalidInput,
+            desc: "the blob file is corrupt",
+            detail: Some(p.display().to_string()),
+        });
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs;
+    use super::{Blob, blob_dir};
+    use super::super::{FileBox, peek};
+
+    #[deriving(Encodable, Decodable)]
+    struct Photo {
+        title: String,
+        image: Blob,
+    }
+
+    #[test]
+    fn sidecar_blobs() {
+        let path = Path::new("target/sidecar_blobs");
+        let _ = fs::rmdir_recursive(&blob_dir(&path));
+        let image = Vec::from_elem(100000, 7u8);
+        {
+            let mut x = FileBox::open_new(&path, None::<Photo>).unwrap();
+            let blob = x.put_blob(image.as_slice()).unwrap();
+            *x = Some(Photo { title: "cat".to_string(), image: blob });
+        }
+        assert!(fs::stat(&path).unwrap().size < 1000);
+        let x = FileBox::<Option<Photo>>::open(&path).unwrap();
+        let blob = x.as_ref().unwrap().image.clone();
+        assert_eq!(blob.len(), 100000);
+        assert_eq!(x.load_blob(&blob).unwrap(), image);
+        assert_eq!(x.put_blob(b"unused").unwrap().len(), 6);
+        assert_eq!(x.remove_unused_blobs(&[&blob]).unwrap(), 1);
+        assert_eq!(peek::<Option<Photo>>(&path).unwrap().unwrap().title.as_slice(), "cat");
+    }
+
+    #[test]
+    fn lazy_blobs() {
+        let path = Path::new("target/lazy_blobs");
+        let x = FileBox::open_new(&path, ()).unwrap();
+        let blob = x.put_blob(b"attachment").unwrap();
+        let mut handle = x.blob_handle(&blob);
+        assert!(!handle.is_loaded());
+        assert_eq!(handle.read().unwrap(), b"attachment");
+        assert!(handle.is_loaded());
+        fs::unlink(&blob_dir(&path).join(blob.file_name())).unwrap();
+        // The bytes were read the first time, so they are still there.
+        assert_eq!(handle.read().unwrap(), b"attachment");
+        assert!(x.blob_handle(&blob).read().is_err());
+    }
+}
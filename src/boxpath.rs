@@ -0,0 +1,169 @@
+//! Paths for boxes that follow from the types they hold.
+
+use std::collections::HashMap;
+use std::default::Default;
+use std::io::{mod, IoError, IoResult, MemWriter};
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use encoding::CompactDecoder;
+use super::FileBox;
+
+static PATH_COLLISION: &'static str = "two types have the same default box path";
+
+/// Returns whether `e` is the error `DefaultPaths::claim` fails with when another type already
+/// has the same default path.
+pub fn is_path_collision(e: &IoError) -> bool {
+    e.kind == io::PathAlreadyExists && e.desc == PATH_COLLISION
+}
+
+/// A type whose boxes are kept at a path of its own, usually implemented with `default_path!`.
+///
+/// The arguments are always `None`, and only say which type is meant.
+pub trait DefaultPath {
+    /// The name of the type, such as `AppPrefs`.
+    fn type_name(_: Option<Self>) -> &'static str;
+
+    /// The directory the type’s box is kept in.
+    fn box_dir(_: Option<Self>) -> Path;
+}
+
+/// Implements `DefaultPath` for a type, so that its box is kept in `dir`, in a file named after
+/// the type as `box_name` names it.
+///
+/// Writing the path out for each type invites copying it from another type and forgetting to
+/// change it, which leaves two types reading each other’s files.
+///
+/// ```rust,ignore
+/// #[deriving(Encodable, Decodable, Default)]
+/// struct AppPrefs {
+///     theme: String,
+/// }
+///
+/// default_path!(AppPrefs, dir = "config");
+///
+/// assert_eq!(filebox::default_path::<AppPrefs>(), Path::new("config/app_prefs.box"));
+/// let prefs: FileBox<AppPrefs> = try!(FileBox::open_default_path());
+/// ```
+#[macro_export]
+macro_rules! default_path {
+    ($t:ident, dir = $dir:expr) => {
+        impl $crate::DefaultPath for $t {
+            fn type_name(_: Option<$t>) -> &'static str {
+                stringify!($t)
+            }
+
+            fn box_dir(_: Option<$t>) -> Path {
+                Path::new($dir)
+            }
+        }
+    }
+}
+
+/// Turns the name of a type into the name of its box file, by writing it in lowercase with an
+/// underscore before each word after the first, so that `AppPrefs` becomes `app_prefs.box`.
+/// Letters in runs of capitals are kept together, so `HTTPCache` becomes `http_cache.box`.
+pub fn box_name(type_name: &str) -> String {
+    let chars: Vec<char> = type_name.chars().collect();
+    let mut name = String::with_capacity(type_name.len() + 8);
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let after_lower = chars[i - 1].is_lowercase() || chars[i - 1].is_digit();
+            let before_lower = i + 1 < chars.len() && chars[i + 1].is_lowercase();
+            if after_lower || (before_lower && chars[i - 1].is_uppercase()) {
+                name.push('_');
+            }
+        }
+        name.push(c.to_lowercase());
+    }
+    name.push_str(".box");
+    name
+}
+
+/// The path of the box holding values of type `T`.
+pub fn default_path<T: DefaultPath>() -> Path {
+    let dir = DefaultPath::box_dir(None::<T>);
+    dir.join(box_name(DefaultPath::type_name(None::<T>)))
+}
+
+/// The default paths claimed by the types of an application, to catch two types sharing a path.
+///
+/// Types with the same name in different modules, or whose names only differ in the case of
+/// their capitals, such as `APPPrefs` and `AppPrefs`, are given the same default path when they
+/// are kept in the same directory. Claiming the path of every type once, as a program starts,
+/// turns the resulting mixup into an error.
+pub struct DefaultPaths {
+    claimed: HashMap<Path, &'static str>,
+}
+
+impl DefaultPaths {
+    /// Creates a set of paths with none claimed.
+    pub fn new() -> DefaultPaths {
+        DefaultPaths {
+            claimed: HashMap::new(),
+        }
+    }
+
+    /// Claims the default path of `T`, and returns it, failing with an error for which
+    /// `is_path_collision` is true if it has already been claimed, whether by another type or
+    /// by `T` itself.
+    pub fn claim<T: DefaultPath>(&mut self) -> IoResult<Path> {
+        let p = default_path::<T>();
+        let name = DefaultPath::type_name(None::<T>);
+        match self.claimed.get(&p) {
+            Some(other) => {
+                return Err(IoError {
+                    kind: io::PathAlreadyExists,
+                    desc: PATH_COLLISION,
+                    detail: Some(format!("{} and {} are both kept at {}", other, name,
+                                         p.display())),
+                });
+            }
+            None => {}
+        }
+        self.claimed.insert(p.clone(), name);
+        Ok(p)
+    }
+}
+
+impl<'a, T> FileBox<T> where T: Decodable<CompactDecoder, IoError>
+                              + Encodable<EncoderWriter<'a, MemWriter>, IoError>
+                              + DefaultPath + Default {
+    /// Opens the box at the default path of `T`, as `open_or_new` does.
+    pub fn open_default_path() -> IoResult<FileBox<T>> {
+        FileBox::open_or_new(&default_path::<T>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DefaultPaths, box_name, default_path, is_path_collision};
+    use super::super::FileBox;
+
+    #[deriving(Encodable, Decodable, Default)]
+    struct AppPrefs {
+        theme: String,
+    }
+
+    struct APPPrefs;
+
+    default_path!(AppPrefs, dir = "target/derive_default_paths");
+    default_path!(APPPrefs, dir = "target/derive_default_paths");
+
+    #[test]
+    fn derive_default_paths() {
+        assert_eq!(box_name("AppPrefs").as_slice(), "app_prefs.box");
+        assert_eq!(box_name("HTTPCache").as_slice(), "http_cache.box");
+        assert_eq!(box_name("Utf8Text").as_slice(), "utf8_text.box");
+        assert_eq!(default_path::<AppPrefs>(),
+                   Path::new("target/derive_default_paths/app_prefs.box"));
+
+        let mut paths = DefaultPaths::new();
+        paths.claim::<AppPrefs>().unwrap();
+        assert!(is_path_collision(&paths.claim::<APPPrefs>().err().unwrap()));
+
+        let mut b: FileBox<AppPrefs> = FileBox::open_default_path().unwrap();
+        b.theme = "dark".to_string();
+        b.save().unwrap();
+    }
+}
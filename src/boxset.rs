@@ -0,0 +1,173 @@
+//! Saving many boxes of different types together.
+
+use std::default::Default;
+use std::io::{mod, IoError, IoResult, MemWriter};
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use encoding::CompactDecoder;
+use shared::SharedBox;
+use super::FileBox;
+
+/// A box in a `BoxSet`, whatever type it holds.
+trait Member {
+    /// Saves the box if its value has changed, returning whether it did.
+    fn save_changes(&self, p: &Path) -> IoResult<bool>;
+
+    /// Keeps the box from being written when it is dropped, if this is its last handle.
+    fn skip_write_on_drop(&self);
+}
+
+impl<'a, T> Member for SharedBox<T> where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    fn save_changes(&self, p: &Path) -> IoResult<bool> {
+        let mut b = match self.try_borrow_mut() {
+            Some(b) => b,
+            None => return Err(IoError {
+                kind: io::ResourceUnavailable,
+                desc: "the box is borrowed, so it can’t be saved",
+                detail: Some(p.display().to_string()),
+            }),
+        };
+        if !b._dirty {
+            return Ok(false);
+        }
+        try!(b.save());
+        Ok(true)
+    }
+
+    fn skip_write_on_drop(&self) {
+        if self.handles() == 1 {
+            match self.try_borrow_mut() {
+                Some(mut b) => b._write_on_drop = false,
+                None => {}
+            }
+        }
+    }
+}
+
+/// What happened when the boxes of a `BoxSet` were saved.
+#[deriving(Show)]
+pub struct SaveReport {
+    /// The boxes that had changed and were saved.
+    pub saved: Vec<Path>,
+    /// The boxes that couldn’t be saved, along with why.
+    pub failed: Vec<(Path, IoError)>,
+}
+
+impl SaveReport {
+    /// Returns whether every box that had changed was saved.
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Boxes of any types, opened through the set, for programs that keep many boxes for different
+/// parts of themselves and want to save them all at once, such as when shutting down.
+///
+/// Opening a box through the set hands out a `SharedBox` for it, and the set keeps a handle of its
+/// own, so `save_all` can reach every box without the program keeping track of them. A box that
+/// fails to save doesn’t stop the others, and the failures are gathered into a `SaveReport`.
+pub struct BoxSet {
+    members: Vec<(Path, Box<Member + 'static>)>,
+}
+
+impl BoxSet {
+    /// Creates a set with no boxes in it.
+    pub fn new() -> BoxSet {
+        BoxSet {
+            members: Vec::new(),
+        }
+    }
+
+    /// Opens the box at `p`, as `FileBox::open` does, and adds it to the set.
+    pub fn open<'a, T>(&mut self, p: &Path) -> IoResult<SharedBox<T>>
+            where T: Decodable<CompactDecoder, IoError>
+                   + Encodable<EncoderWriter<'a, MemWriter>, IoError> + 'static {
+        self.insert(try!(FileBox::open(p)))
+    }
+
+    /// Opens the box at `p`, as `FileBox::open_or_new` does, and adds it to the set.
+    pub fn open_or_new<'a, T>(&mut self, p: &Path) -> IoResult<SharedBox<T>>
+            where T: Decodable<CompactDecoder, IoError>
+                   + Encodable<EncoderWriter<'a, MemWriter>, IoError> + Default + 'static {
+        self.insert(try!(FileBox::open_or_new(p)))
+    }
+
+    /// Adds a box that is already open to the set, and returns a handle to it. Fails as
+    /// `SharedBox::try_clone` does if the box’s file has been replaced.
+    pub fn insert<'a, T>(&mut self, b: FileBox<T>) -> IoResult<SharedBox<T>>
+            where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> + 'static {
+        let p = b._path.clone();
+        let shared = b.into_shared();
+        let member = try!(shared.try_clone());
+        self.members.push((p, box member as Box<Member + 'static>));
+        Ok(shared)
+    }
+
+    /// The number of boxes in the set.
+    pub fn len(&self) -> uint {
+        self.members.len()
+    }
+
+    /// Saves every box in the set whose value has changed, in the order they were added.
+    pub fn save_all(&self) -> SaveReport {
+        let mut report = SaveReport {
+            saved: Vec::new(),
+            failed: Vec::new(),
+        };
+        for &(ref p, ref member) in self.members.iter() {
+            match member.save_changes(p) {
+                Ok(true) => report.saved.push(p.clone()),
+                Ok(false) => {}
+                Err(e) => report.failed.push((p.clone(), e)),
+            }
+        }
+        report
+    }
+
+    /// Saves every box in the set, as `save_all` does, and lets go of the set’s handles to them.
+    /// Boxes whose other handles have all been dropped are closed, as `FileBox::close` closes
+    /// them: one that failed to save isn’t written again when it is dropped, since its error is
+    /// in the report.
+    pub fn close_all(self) -> SaveReport {
+        let report = self.save_all();
+        for (p, member) in self.members.into_iter() {
+            if report.failed.iter().any(|&(ref failed, _)| *failed == p) {
+                member.skip_write_on_drop();
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BoxSet;
+    use super::super::{peek, write_value};
+
+    #[test]
+    fn save_boxes_together() {
+        let prefs = Path::new("target/save_boxes_together.prefs");
+        let counts = Path::new("target/save_boxes_together.counts");
+        write_value(&prefs, &"light".to_string()).unwrap();
+        write_value(&counts, &vec![1u32]).unwrap();
+        let mut set = BoxSet::new();
+        let p = set.open::<String>(&prefs).unwrap();
+        let c = set.open::<Vec<u32>>(&counts).unwrap();
+        p.with_mut(|theme| *theme = "dark".to_string());
+        let report = set.save_all();
+        assert!(report.is_ok());
+        assert_eq!(report.saved, vec![prefs.clone()]);
+        assert_eq!(peek::<String>(&prefs).unwrap().as_slice(), "dark");
+
+        c.with_mut(|counts| counts.push(2));
+        let borrowed = p.borrow_mut();
+        let report = set.close_all();
+        assert_eq!(report.saved, vec![counts.clone()]);
+        // A box in use elsewhere can’t be saved, but doesn’t stop the others.
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].val0(), prefs);
+        drop(borrowed);
+        assert_eq!(peek::<Vec<u32>>(&counts).unwrap(), vec![1, 2]);
+    }
+}
@@ -0,0 +1,270 @@
+//! Keeping only the most recently used boxes in memory.
+
+use std::cell::RefCell;
+use std::io::{IoError, IoResult, MemWriter};
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use clock::{Clock, SystemClock};
+use encoding::CompactDecoder;
+use memory::{HeapSize, MemoryTracker};
+use super::FileBox;
+
+/// A set of boxes that are loaded when they are used and unloaded again when memory runs short.
+///
+/// Boxes are added to the cache with `open`, which doesn’t read them until they are first used.
+/// Whenever the values of the loaded boxes take up more memory than the budget allows, the boxes
+/// that were used least recently are written and unloaded until they fit again, skipping boxes
+/// that were used more recently than the minimum idle time. An unloaded box is read again the
+/// next time it is used.
+///
+/// Clones of a cache share the same boxes and budget.
+#[deriving(Clone)]
+pub struct BoxCache {
+    state: Rc<RefCell<State>>,
+    tracker: MemoryTracker,
+}
+
+struct State {
+    budget: uint,
+    min_idle: Duration,
+    clock: Box<Clock + 'static>,
+    next_id: u64,
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    id: u64,
+    /// When the box was last used, in milliseconds since the Unix epoch.
+    last_used: u64,
+    slot: Box<Resident + 'static>,
+}
+
+/// A box in the cache, whatever type it holds.
+trait Resident {
+    /// Writes and unloads the box if it is loaded.
+    fn unload(&self) -> IoResult<()>;
+}
+
+type Slot<T> = RefCell<Option<FileBox<T>>>;
+
+struct WeakSlot<T> {
+    slot: Weak<Slot<T>>,
+}
+
+impl<'a, T> Resident for WeakSlot<T> where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    fn unload(&self) -> IoResult<()> {
+        match self.slot.upgrade() {
+            Some(slot) => unload(&*slot),
+            None => Ok(()),
+        }
+    }
+}
+
+fn unload<'a, T>(slot: &Slot<T>) -> IoResult<()>
+        where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    let mut slot = slot.borrow_mut();
+    let mut b = match slot.take() {
+        Some(b) => b,
+        None => return Ok(()),
+    };
+    match b.write() {
+        Ok(()) => {
+            b._write_on_drop = false;
+            Ok(())
+        }
+        Err(e) => {
+            // The value would be lost if the box were dropped, so it stays loaded.
+            *slot = Some(b);
+            Err(e)
+        }
+    }
+}
+
+impl BoxCache {
+    /// Creates a cache whose loaded boxes can use up to `budget` bytes, as measured by `HeapSize`.
+    pub fn new(budget: uint) -> BoxCache {
+        BoxCache {
+            state: Rc::new(RefCell::new(State {
+                budget: budget,
+                min_idle: Duration::zero(),
+                clock: box SystemClock as Box<Clock + 'static>,
+                next_id: 0,
+                entries: Vec::new(),
+            })),
+            tracker: MemoryTracker::new(),
+        }
+    }
+
+    /// Sets how long a box has to have gone unused before it can be unloaded. This is zero by
+    /// default, so any box but the one being used can be unloaded.
+    pub fn set_min_idle(&self, min_idle: Duration) {
+        self.state.borrow_mut().min_idle = min_idle;
+    }
+
+    /// Sets the clock used to tell how long boxes have gone unused. Caches use the system clock
+    /// unless told otherwise.
+    pub fn set_clock(&self, clock: Box<Clock + 'static>) {
+        self.state.borrow_mut().clock = clock;
+    }
+
+    /// The memory used by the values of the loaded boxes, in bytes.
+    pub fn usage(&self) -> uint {
+        self.tracker.total()
+    }
+
+    /// Adds the box at `p` to the cache, without loading it yet.
+    pub fn open<'a, T>(&self, p: &Path) -> CachedBox<T>
+            where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> + 'static {
+        let slot = Rc::new(RefCell::new(None));
+        let id = {
+            let mut state = self.state.borrow_mut();
+            let id = state.next_id;
+            state.next_id += 1;
+            state.entries.push(Entry {
+                id: id,
+                last_used: state.clock.now_ms(),
+                slot: box WeakSlot { slot: slot.downgrade() } as Box<Resident + 'static>,
+            });
+            id
+        };
+        CachedBox {
+            cache: self.clone(),
+            id: id,
+            path: p.clone(),
+            slot: slot,
+        }
+    }
+
+    /// Unloads boxes until the loaded ones fit in the budget, or until only boxes that can’t be
+    /// unloaded yet are left. This happens whenever a box is used, so it only needs to be called
+    /// to unload boxes that became idle in the meantime.
+    pub fn trim(&self) -> IoResult<()> {
+        self.trim_except(None)
+    }
+
+    fn trim_except(&self, except: Option<u64>) -> IoResult<()> {
+        let mut state = self.state.borrow_mut();
+        let now = state.clock.now_ms();
+        let min_idle = state.min_idle.num_milliseconds() as u64;
+        let budget = state.budget;
+        state.entries.sort_by(|a, b| a.last_used.cmp(&b.last_used));
+        for entry in state.entries.iter() {
+            if self.tracker.total() <= budget {
+                break;
+            }
+            if Some(entry.id) != except && now - entry.last_used >= min_idle {
+                try!(entry.slot.unload());
+            }
+        }
+        Ok(())
+    }
+
+    fn touch(&self, id: u64) {
+        let mut state = self.state.borrow_mut();
+        let now = state.clock.now_ms();
+        for entry in state.entries.iter_mut() {
+            if entry.id == id {
+                entry.last_used = now;
+            }
+        }
+    }
+}
+
+/// A box in a `BoxCache`, which is loaded whenever it is used.
+pub struct CachedBox<T> {
+    cache: BoxCache,
+    id: u64,
+    path: Path,
+    slot: Rc<Slot<T>>,
+}
+
+impl<'a, T> CachedBox<T> where T: Decodable<CompactDecoder, IoError>
+                                + Encodable<EncoderWriter<'a, MemWriter>, IoError>
+                                + HeapSize + 'static {
+    /// Hands the value of the box to `f`, loading it first if it isn’t loaded. Afterwards, other
+    /// boxes are unloaded if the cache has gone over its budget.
+    pub fn with<R>(&self, f: |&mut T| -> R) -> IoResult<R> {
+        if self.slot.borrow().is_none() {
+            let mut b = try!(FileBox::open(&self.path));
+            b.set_memory_tracker(Some(self.cache.tracker.clone()));
+            *self.slot.borrow_mut() = Some(b);
+        }
+        let res = {
+            let mut slot = self.slot.borrow_mut();
+            let b = slot.as_mut().unwrap();
+            let res = f(&mut **b);
+            b.report_memory();
+            res
+        };
+        self.cache.touch(self.id);
+        try!(self.cache.trim_except(Some(self.id)));
+        Ok(res)
+    }
+
+    /// Writes and unloads the box if it is loaded.
+    pub fn unload(&self) -> IoResult<()> {
+        unload(&*self.slot)
+    }
+}
+
+impl<T> CachedBox<T> {
+    /// The path of the box’s file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns whether the box is loaded.
+    pub fn is_loaded(&self) -> bool {
+        self.slot.borrow().is_some()
+    }
+}
+
+#[unsafe_destructor]
+impl<T> Drop for CachedBox<T> {
+    fn drop(&mut self) {
+        // The box itself is written when its slot is dropped.
+        let id = self.id;
+        self.cache.state.borrow_mut().entries.retain(|entry| entry.id != id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use std::time::Duration;
+    use super::BoxCache;
+    use super::super::{FileBox, Clock, ManualClock, peek};
+
+    #[test]
+    fn unload_idle_boxes() {
+        let a_path = Path::new("target/unload_idle_boxes_a");
+        let b_path = Path::new("target/unload_idle_boxes_b");
+        FileBox::open_new(&a_path, Vec::from_elem(50, 1u64)).unwrap();
+        FileBox::open_new(&b_path, Vec::from_elem(50, 2u64)).unwrap();
+
+        let size = mem::size_of::<Vec<u64>>() + 50 * 8;
+        let cache = BoxCache::new(size);
+        let clock = ManualClock::new(0);
+        cache.set_clock(box clock.clone() as Box<Clock + 'static>);
+        cache.set_min_idle(Duration::seconds(10));
+        let a = cache.open::<Vec<u64>>(&a_path);
+        let b = cache.open::<Vec<u64>>(&b_path);
+        assert!(!a.is_loaded() && !b.is_loaded());
+
+        a.with(|v| v.push(3)).unwrap();
+        assert!(cache.usage() > size);
+        // `a` was used too recently to be unloaded.
+        clock.advance(Duration::seconds(1));
+        b.with(|v| v.len()).unwrap();
+        assert!(a.is_loaded() && b.is_loaded());
+
+        clock.advance(Duration::seconds(20));
+        cache.trim().unwrap();
+        assert!(!a.is_loaded() && b.is_loaded());
+        assert_eq!(peek::<Vec<u64>>(&a_path).unwrap().len(), 51);
+        assert_eq!(a.with(|v| v.as_slice()[50]).unwrap(), 3);
+    }
+}
@@ -0,0 +1,68 @@
+//! The source of the current time for everything in this crate that expires or is scheduled.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time;
+
+/// Tells the time to autosaving and ownership leases.
+///
+/// Everything uses `SystemClock` unless told otherwise. Tests can use a `ManualClock` instead to
+/// make time pass without waiting for it.
+pub trait Clock {
+    /// The current time, in milliseconds since the Unix epoch.
+    fn now_ms(&self) -> u64;
+}
+
+/// The clock of the operating system.
+#[deriving(Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        let now = time::get_time();
+        now.sec as u64 * 1000 + now.nsec as u64 / 1_000_000
+    }
+}
+
+/// A clock that only moves when it is told to.
+///
+/// Clones of a manual clock share the same time, so a test can keep one clone and hand the others
+/// to the boxes and elections it is testing.
+#[deriving(Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<u64>>,
+}
+
+impl ManualClock {
+    /// Creates a clock showing the given time, in milliseconds since the Unix epoch.
+    pub fn new(now_ms: u64) -> ManualClock {
+        ManualClock {
+            now: Arc::new(Mutex::new(now_ms)),
+        }
+    }
+
+    /// Moves the clock forwards by `d`.
+    pub fn advance(&self, d: Duration) {
+        *self.now.lock() += d.num_milliseconds() as u64;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        *self.now.lock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{Clock, ManualClock};
+
+    #[test]
+    fn manual_clock() {
+        let clock = ManualClock::new(1000);
+        let other = clock.clone();
+        other.advance(Duration::seconds(2));
+        assert_eq!(clock.now_ms(), 3000);
+    }
+}
@@ -0,0 +1,103 @@
+//! Compression of the payloads stored in box files.
+
+use std::io::{mod, IoError, IoResult};
+use flate;
+
+/// The name of `NoCompression`.
+pub static NONE: &'static str = "none";
+
+/// The name of `Deflate`.
+pub static DEFLATE: &'static str = "deflate";
+
+/// A codec that box payloads are passed through before being written to disk.
+///
+/// The name of the compressor is recorded in the header of every file it writes, and reading the
+/// file back requires a compressor with the same name.
+pub trait Compressor {
+    /// The name identifying this compressor in file headers. Different codecs must have different
+    /// names.
+    fn name(&self) -> &'static str;
+
+    /// Compresses an encoded value.
+    fn compress(&self, data: &[u8]) -> IoResult<Vec<u8>>;
+
+    /// Reverses `compress`.
+    fn decompress(&self, data: &[u8]) -> IoResult<Vec<u8>>;
+}
+
+/// Stores payloads unchanged. This is what boxes use unless told otherwise.
+pub struct NoCompression;
+
+impl Compressor for NoCompression {
+    fn name(&self) -> &'static str {
+        NONE
+    }
+
+    fn compress(&self, data: &[u8]) -> IoResult<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+
+    fn decompress(&self, data: &[u8]) -> IoResult<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+}
+
+/// Compresses payloads with DEFLATE, which suits large values with a lot of repetition, such as
+/// long lists of similar records. Boxes compressed with it can be opened with `FileBox::open`,
+/// which finds the compressor from the file’s header.
+pub struct Deflate;
+
+impl Compressor for Deflate {
+    fn name(&self) -> &'static str {
+        DEFLATE
+    }
+
+    fn compress(&self, data: &[u8]) -> IoResult<Vec<u8>> {
+        match flate::deflate_bytes(data) {
+            Some(bytes) => Ok(bytes.as_slice().to_vec()),
+            None => Err(IoError {
+                kind: io::OtherIoError,
+                desc: "the payload couldn’t be compressed",
+                detail: None,
+            }),
+        }
+    }
+
+    fn decompress(&self, data: &[u8]) -> IoResult<Vec<u8>> {
+        match flate::inflate_bytes(data) {
+            Some(bytes) => Ok(bytes.as_slice().to_vec()),
+            None => Err(IoError {
+                kind: io::InvalidInput,
+                desc: "the payload isn’t valid DEFLATE data",
+                detail: None,
+            }),
+        }
+    }
+}
+
+/// Returns the compressor provided by this crate with the given name, if there is one.
+pub fn builtin(name: &str) -> Option<Box<Compressor + 'static>> {
+    match name {
+        "none" => Some(box NoCompression as Box<Compressor + 'static>),
+        "deflate" => Some(box Deflate as Box<Compressor + 'static>),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs;
+    use super::{Compressor, Deflate};
+    use super::super::{FileBox, peek};
+
+    #[test]
+    fn deflate() {
+        let path = Path::new("target/deflate");
+        let val = Vec::from_elem(10000, 42u32);
+        FileBox::open_new_compressed(&path, val.clone(), box Deflate as Box<Compressor + 'static>)
+            .unwrap();
+        assert!(fs::stat(&path).unwrap().size < 1000);
+        assert_eq!(*FileBox::<Vec<u32>>::open(&path).unwrap(), val);
+        assert_eq!(peek::<Vec<u32>>(&path).unwrap(), val);
+    }
+}
@@ -0,0 +1,125 @@
+//! Finding the conflicting copies of a box left by file syncing tools.
+
+use std::io::{fs, IoError, IoResult, MemWriter};
+use std::io::fs::PathExtensions;
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use encoding::CompactDecoder;
+use super::{FileBox, peek};
+
+/// A copy of a box that a file syncing tool made when the box was changed in two places at once.
+///
+/// Tools like Dropbox and Syncthing don’t merge files changed on two machines before they have
+/// synced: they keep one version under the box’s name and put the other next to it under a new
+/// name, such as `settings (Alice’s conflicted copy 2014-11-12).box` or
+/// `settings.sync-conflict-20141112-093000-ABCDEFG.box`. Unless it is dealt with, the changes in
+/// the copy are silently lost.
+#[deriving(Clone, PartialEq, Show)]
+pub struct ConflictCopy {
+    /// The path of the copy.
+    pub path: Path,
+    /// When the copy was last modified, in milliseconds since the Unix epoch.
+    pub modified: u64,
+}
+
+impl ConflictCopy {
+    /// Deletes the copy, discarding its changes.
+    pub fn delete(self) -> IoResult<()> {
+        fs::unlink(&self.path)
+    }
+}
+
+/// Lists the conflicting copies of the box at `p`, oldest first.
+pub fn conflict_copies(p: &Path) -> IoResult<Vec<ConflictCopy>> {
+    let dir = p.dir_path();
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut copies = Vec::new();
+    for sibling in try!(fs::readdir(&dir)).into_iter() {
+        if sibling != *p && sibling.is_file() && is_conflict_copy(p, &sibling) {
+            copies.push(ConflictCopy {
+                modified: try!(fs::stat(&sibling)).modified,
+                path: sibling,
+            });
+        }
+    }
+    copies.sort_by(|a, b| a.modified.cmp(&b.modified));
+    Ok(copies)
+}
+
+/// Returns whether `copy` is named as a conflicting copy of the box at `p`.
+fn is_conflict_copy(p: &Path, copy: &Path) -> bool {
+    let (stem, name) = match (p.filestem_str(), copy.filename_str()) {
+        (Some(stem), Some(name)) => (stem, name),
+        _ => return false,
+    };
+    if !name.starts_with(stem) {
+        return false;
+    }
+    let mut rest = name.slice_from(stem.len());
+    match p.extension_str() {
+        Some(ext) => {
+            let suffix = format!(".{}", ext);
+            if !rest.ends_with(suffix.as_slice()) {
+                return false;
+            }
+            rest = rest.slice_to(rest.len() - suffix.len());
+        }
+        None => {}
+    }
+    // Dropbox, and Syncthing.
+    (rest.starts_with(" (") && rest.ends_with(")") && rest.contains("conflicted copy"))
+        || rest.starts_with(".sync-conflict-")
+}
+
+impl<'a, T> FileBox<T> where T: Decodable<CompactDecoder, IoError>
+                              + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Opens the box at `p`, as `open` does, along with any conflicting copies of it that a file
+    /// syncing tool has left next to it, so that they can be merged or deleted.
+    pub fn open_with_conflicts(p: &Path) -> IoResult<(FileBox<T>, Vec<ConflictCopy>)> {
+        let b = try!(FileBox::open(p));
+        Ok((b, try!(conflict_copies(p))))
+    }
+
+    /// Reads the value in a conflicting copy of this box and hands it to `merge` to be merged into
+    /// the box’s value. The box is then written, and once that has succeeded, the copy is
+    /// deleted.
+    pub fn merge_conflict(&mut self, copy: ConflictCopy, merge: |&mut T, T|) -> IoResult<()> {
+        let theirs = try!(peek(&copy.path));
+        self.modify(|ours| merge(ours, theirs));
+        try!(self.save());
+        copy.delete()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs;
+    use super::super::{FileBox, write_value, peek};
+
+    #[test]
+    fn merge_conflicting_copies() {
+        let dir = Path::new("target/merge_conflicting_copies");
+        let _ = fs::rmdir_recursive(&dir);
+        fs::mkdir_recursive(&dir, ::std::io::USER_RWX).unwrap();
+        let path = dir.join("list.box");
+        FileBox::open_new(&path, vec![1u8]).unwrap();
+        let dropbox = dir.join("list (Alice's conflicted copy 2014-11-12).box");
+        write_value(&dropbox, &vec![2u8]).unwrap();
+        write_value(&dir.join("list.sync-conflict-20141112-093000-ABC.box"), &vec![3u8]).unwrap();
+        write_value(&dir.join("listing.box"), &vec![4u8]).unwrap();
+
+        let (mut b, copies) = FileBox::<Vec<u8>>::open_with_conflicts(&path).unwrap();
+        assert_eq!(copies.len(), 2);
+        for copy in copies.into_iter() {
+            b.merge_conflict(copy, |ours, theirs| ours.push_all(theirs.as_slice())).unwrap();
+        }
+        b.sort();
+        assert_eq!(*b, vec![1, 2, 3]);
+        drop(b);
+        assert_eq!(peek::<Vec<u8>>(&path).unwrap(), vec![1, 2, 3]);
+        assert!(FileBox::<Vec<u8>>::open_with_conflicts(&path).unwrap().val1().is_empty());
+    }
+}
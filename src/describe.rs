@@ -0,0 +1,218 @@
+//! Describing the types of boxes for programs that don’t have their source.
+
+use std::collections::TreeMap;
+use std::io::IoError;
+use std::mem;
+use serialize::{json, Encodable, Encoder};
+
+type EResult = Result<(), IoError>;
+
+/// Describes the type of `sample` as JSON, for documenting the files that values of the type are
+/// stored in.
+///
+/// The description is found by encoding `sample`, so any type that derives `Encodable` can be
+/// described. Primitive types are described by their names, such as `"u32"` or `"string"`, and
+/// other types by objects: structs as `{"struct": name, "fields": [{"name": .., "type": ..}]}`,
+/// tuples as `{"tuple": [..]}`, options as `{"option": ..}`, sequences as `{"seq": ..}`, maps as
+/// `{"map": [key, value]}`, and enums as `{"enum": name, "variant": ..}`.
+///
+/// Only what `sample` holds can be seen, so the types of the elements of empty sequences and
+/// maps, and of `None` options, are `null`, and an enum is described by the variant `sample`
+/// holds. Samples should be chosen to hold something in each of them.
+pub fn describe<T: Encodable<Describer, IoError>>(sample: &T) -> json::Json {
+    let mut d = Describer {
+        last: json::Null,
+        parts: Vec::new(),
+    };
+    // Describing never fails.
+    let _ = sample.encode(&mut d);
+    d.last
+}
+
+/// The encoder `describe` uses to find out the structure of a value.
+pub struct Describer {
+    /// The description of the last value encoded.
+    last: json::Json,
+    /// The descriptions of the parts of the value being encoded, such as the fields of a struct.
+    parts: Vec<json::Json>,
+}
+
+impl Describer {
+    fn primitive(&mut self, name: &str) -> EResult {
+        self.last = json::String(name.to_string());
+        Ok(())
+    }
+
+    /// Describes the value encoded by `f`.
+    fn nested(&mut self, f: |&mut Describer| -> EResult) -> Result<json::Json, IoError> {
+        try!(f(self));
+        Ok(mem::replace(&mut self.last, json::Null))
+    }
+
+    /// Describes the parts `f` encodes, such as the fields of a struct.
+    fn parts(&mut self, f: |&mut Describer| -> EResult) -> Result<json::Json, IoError> {
+        let outer = mem::replace(&mut self.parts, Vec::new());
+        try!(f(self));
+        Ok(json::List(mem::replace(&mut self.parts, outer)))
+    }
+
+    fn part(&mut self, f: |&mut Describer| -> EResult) -> EResult {
+        let part = try!(self.nested(f));
+        self.parts.push(part);
+        Ok(())
+    }
+
+    fn field(&mut self, name: &str, f: |&mut Describer| -> EResult) -> EResult {
+        let ty = try!(self.nested(f));
+        self.parts.push(object(vec![("name", json::String(name.to_string())), ("type", ty)]));
+        Ok(())
+    }
+}
+
+fn object(pairs: Vec<(&str, json::Json)>) -> json::Json {
+    let mut fields = TreeMap::new();
+    for (key, val) in pairs.into_iter() {
+        fields.insert(key.to_string(), val);
+    }
+    json::Object(fields)
+}
+
+impl Encoder<IoError> for Describer {
+    fn emit_nil(&mut self) -> EResult { self.primitive("nil") }
+    fn emit_uint(&mut self, _: uint) -> EResult { self.primitive("uint") }
+    fn emit_u64(&mut self, _: u64) -> EResult { self.primitive("u64") }
+    fn emit_u32(&mut self, _: u32) -> EResult { self.primitive("u32") }
+    fn emit_u16(&mut self, _: u16) -> EResult { self.primitive("u16") }
+    fn emit_u8(&mut self, _: u8) -> EResult { self.primitive("u8") }
+    fn emit_int(&mut self, _: int) -> EResult { self.primitive("int") }
+    fn emit_i64(&mut self, _: i64) -> EResult { self.primitive("i64") }
+    fn emit_i32(&mut self, _: i32) -> EResult { self.primitive("i32") }
+    fn emit_i16(&mut self, _: i16) -> EResult { self.primitive("i16") }
+    fn emit_i8(&mut self, _: i8) -> EResult { self.primitive("i8") }
+    fn emit_bool(&mut self, _: bool) -> EResult { self.primitive("bool") }
+    fn emit_f64(&mut self, _: f64) -> EResult { self.primitive("f64") }
+    fn emit_f32(&mut self, _: f32) -> EResult { self.primitive("f32") }
+    fn emit_char(&mut self, _: char) -> EResult { self.primitive("char") }
+    fn emit_str(&mut self, _: &str) -> EResult { self.primitive("string") }
+
+    fn emit_enum(&mut self, name: &str, f: |&mut Describer| -> EResult) -> EResult {
+        let variant = try!(self.nested(f));
+        self.last = object(vec![("enum", json::String(name.to_string())), ("variant", variant)]);
+        Ok(())
+    }
+    fn emit_enum_variant(&mut self, name: &str, _: uint, _: uint,
+                         f: |&mut Describer| -> EResult) -> EResult {
+        let args = try!(self.parts(f));
+        self.last = object(vec![("name", json::String(name.to_string())), ("args", args)]);
+        Ok(())
+    }
+    fn emit_enum_variant_arg(&mut self, _: uint, f: |&mut Describer| -> EResult) -> EResult {
+        self.part(f)
+    }
+    fn emit_enum_struct_variant(&mut self, name: &str, _: uint, _: uint,
+                                f: |&mut Describer| -> EResult) -> EResult {
+        let fields = try!(self.parts(f));
+        self.last = object(vec![("name", json::String(name.to_string())), ("fields", fields)]);
+        Ok(())
+    }
+    fn emit_enum_struct_variant_field(&mut self, name: &str, _: uint,
+                                      f: |&mut Describer| -> EResult) -> EResult {
+        self.field(name, f)
+    }
+    fn emit_struct(&mut self, name: &str, _: uint, f: |&mut Describer| -> EResult) -> EResult {
+        let fields = try!(self.parts(f));
+        self.last = object(vec![("struct", json::String(name.to_string())), ("fields", fields)]);
+        Ok(())
+    }
+    fn emit_struct_field(&mut self, name: &str, _: uint,
+                         f: |&mut Describer| -> EResult) -> EResult {
+        self.field(name, f)
+    }
+    fn emit_tuple(&mut self, _: uint, f: |&mut Describer| -> EResult) -> EResult {
+        let args = try!(self.parts(f));
+        self.last = object(vec![("tuple", args)]);
+        Ok(())
+    }
+    fn emit_tuple_arg(&mut self, _: uint, f: |&mut Describer| -> EResult) -> EResult {
+        self.part(f)
+    }
+    fn emit_tuple_struct(&mut self, name: &str, _: uint,
+                         f: |&mut Describer| -> EResult) -> EResult {
+        let args = try!(self.parts(f));
+        self.last = object(vec![("struct", json::String(name.to_string())), ("args", args)]);
+        Ok(())
+    }
+    fn emit_tuple_struct_arg(&mut self, _: uint, f: |&mut Describer| -> EResult) -> EResult {
+        self.part(f)
+    }
+    fn emit_option(&mut self, f: |&mut Describer| -> EResult) -> EResult {
+        let inner = try!(self.nested(f));
+        self.last = object(vec![("option", inner)]);
+        Ok(())
+    }
+    fn emit_option_none(&mut self) -> EResult {
+        self.last = json::Null;
+        Ok(())
+    }
+    fn emit_option_some(&mut self, f: |&mut Describer| -> EResult) -> EResult {
+        f(self)
+    }
+    fn emit_seq(&mut self, _: uint, f: |&mut Describer| -> EResult) -> EResult {
+        let elt = match try!(self.parts(f)) {
+            json::List(elts) => elts.into_iter().next().unwrap_or(json::Null),
+            _ => json::Null,
+        };
+        self.last = object(vec![("seq", elt)]);
+        Ok(())
+    }
+    fn emit_seq_elt(&mut self, idx: uint, f: |&mut Describer| -> EResult) -> EResult {
+        // Every element has the same type, so only the first is described.
+        if idx == 0 { self.part(f) } else { Ok(()) }
+    }
+    fn emit_map(&mut self, _: uint, f: |&mut Describer| -> EResult) -> EResult {
+        let entry = match try!(self.parts(f)) {
+            json::List(entry) if entry.len() == 2 => json::List(entry),
+            _ => json::List(vec![json::Null, json::Null]),
+        };
+        self.last = object(vec![("map", entry)]);
+        Ok(())
+    }
+    fn emit_map_elt_key(&mut self, idx: uint, f: |&mut Describer| -> EResult) -> EResult {
+        if idx == 0 { self.part(f) } else { Ok(()) }
+    }
+    fn emit_map_elt_val(&mut self, idx: uint, f: |&mut Describer| -> EResult) -> EResult {
+        if idx == 0 { self.part(f) } else { Ok(()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serialize::json;
+    use super::describe;
+
+    #[deriving(Encodable)]
+    struct Settings {
+        name: String,
+        retries: Option<u32>,
+        servers: Vec<(String, u16)>,
+    }
+
+    #[test]
+    fn describe_struct() {
+        let sample = Settings {
+            name: String::new(),
+            retries: Some(3),
+            servers: vec![("localhost".to_string(), 80)],
+        };
+        let expected = json::from_str(r#"{
+            "struct": "Settings",
+            "fields": [
+                {"name": "name", "type": "string"},
+                {"name": "retries", "type": {"option": "u32"}},
+                {"name": "servers", "type": {"seq": {"tuple": ["string", "u16"]}}}
+            ]
+        }"#).unwrap();
+        assert_eq!(describe(&sample), expected);
+        assert_eq!(describe(&Vec::<u8>::new()), json::from_str(r#"{"seq": null}"#).unwrap());
+    }
+}
@@ -0,0 +1,279 @@
+//! Reading and writing box files without going through the page cache.
+//!
+//! Direct I/O needs its buffers, offsets and lengths to be aligned to the block size of the disk,
+//! so files are read and written in aligned blocks here, and the padding added to the last block
+//! of a file is cut off again once it has been written. Filesystems that don’t support direct I/O
+//! are read and written normally, as is every file on platforms other than Linux and OS X.
+
+use std::io::{File, IoResult};
+#[cfg(unix)]
+use std::c_str::ToCStr;
+#[cfg(unix)]
+use std::cmp;
+#[cfg(unix)]
+use std::io::{mod, IoError};
+#[cfg(unix)]
+use std::slice::bytes::copy_memory;
+#[cfg(unix)]
+use libc;
+
+/// The alignment direct I/O needs, which is a multiple of the block size of every common disk.
+#[cfg(unix)]
+static ALIGN: uint = 4096;
+
+/// The number of bytes read or written at a time by a direct file.
+#[cfg(unix)]
+static BLOCK_SIZE: uint = 256 * ALIGN;
+
+/// A writer that can be flushed to disk, as a box’s temporary file has to be before it replaces
+/// the box’s file.
+pub trait SyncWriter: Writer {
+    /// Writes everything written so far to disk.
+    fn sync(&mut self) -> IoResult<()>;
+
+    /// The writer itself, for functions that take any writer.
+    fn as_writer(&mut self) -> &mut Writer;
+}
+
+impl SyncWriter for File {
+    fn sync(&mut self) -> IoResult<()> {
+        self.fsync()
+    }
+
+    fn as_writer(&mut self) -> &mut Writer {
+        self
+    }
+}
+
+/// Creates the file at `p` for writing, bypassing the page cache if `direct` is true.
+#[cfg(unix)]
+pub fn create(p: &Path, direct: bool) -> IoResult<Box<SyncWriter + 'static>> {
+    if !direct {
+        return Ok(box try!(File::create(p)) as Box<SyncWriter + 'static>);
+    }
+    let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC;
+    Ok(box try!(DirectFile::open(p, flags)) as Box<SyncWriter + 'static>)
+}
+
+#[cfg(not(unix))]
+pub fn create(p: &Path, _direct: bool) -> IoResult<Box<SyncWriter + 'static>> {
+    Ok(box try!(File::create(p)) as Box<SyncWriter + 'static>)
+}
+
+/// Opens the file at `p` for reading, bypassing the page cache if `direct` is true.
+#[cfg(unix)]
+pub fn open(p: &Path, direct: bool) -> IoResult<Box<Reader + 'static>> {
+    if !direct {
+        return Ok(box try!(File::open(p)) as Box<Reader + 'static>);
+    }
+    Ok(box try!(DirectFile::open(p, libc::O_RDONLY)) as Box<Reader + 'static>)
+}
+
+#[cfg(not(unix))]
+pub fn open(p: &Path, _direct: bool) -> IoResult<Box<Reader + 'static>> {
+    Ok(box try!(File::open(p)) as Box<Reader + 'static>)
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+static O_DIRECT: libc::c_int = 0o40000;
+#[cfg(all(target_os = "linux", any(target_arch = "arm", target_arch = "aarch64")))]
+static O_DIRECT: libc::c_int = 0o200000;
+#[cfg(target_os = "macos")]
+static F_NOCACHE: libc::c_int = 48;
+
+/// A file opened for direct I/O, which is either only read from or only written to.
+#[cfg(unix)]
+struct DirectFile {
+    fd: libc::c_int,
+    /// Holds one aligned block, starting at `offset`. The vector is never resized, so the block
+    /// stays where it is.
+    buf: Vec<u8>,
+    offset: uint,
+    /// How many bytes of the block hold data.
+    filled: uint,
+    /// How many bytes of the block have been handed out by `read`.
+    pos: uint,
+    /// How many bytes of data have been written to the file, not counting padding.
+    written: u64,
+}
+
+#[cfg(unix)]
+impl DirectFile {
+    fn open(p: &Path, flags: libc::c_int) -> IoResult<DirectFile> {
+        let fd = try!(open_uncached(p, flags));
+        let buf = Vec::from_elem(BLOCK_SIZE + ALIGN, 0u8);
+        let offset = (ALIGN - buf.as_ptr() as uint % ALIGN) % ALIGN;
+        Ok(DirectFile {
+            fd: fd,
+            buf: buf,
+            offset: offset,
+            filled: 0,
+            pos: 0,
+            written: 0,
+        })
+    }
+
+    fn block(&mut self) -> &mut [u8] {
+        let offset = self.offset;
+        self.buf.slice_mut(offset, offset + BLOCK_SIZE)
+    }
+
+    /// Writes the data in the block to the file, padded to a multiple of `ALIGN`.
+    fn write_block(&mut self) -> IoResult<()> {
+        let filled = self.filled;
+        let len = (filled + ALIGN - 1) / ALIGN * ALIGN;
+        for b in self.block().slice_mut(filled, len).iter_mut() {
+            *b = 0;
+        }
+        let mut done = 0;
+        while done < len {
+            let n = unsafe {
+                let start = self.block().as_ptr().offset(done as int);
+                libc::write(self.fd, start as *const libc::c_void, (len - done) as libc::size_t)
+            };
+            if n < 0 {
+                return Err(IoError::last_error());
+            }
+            done += n as uint;
+        }
+        self.written += filled as u64;
+        self.filled = 0;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Reader for DirectFile {
+    fn read(&mut self, out: &mut [u8]) -> IoResult<uint> {
+        if self.pos == self.filled {
+            let n = unsafe {
+                let start = self.block().as_mut_ptr();
+                libc::read(self.fd, start as *mut libc::c_void, BLOCK_SIZE as libc::size_t)
+            };
+            if n < 0 {
+                return Err(IoError::last_error());
+            }
+            if n == 0 {
+                return Err(io::standard_error(io::EndOfFile));
+            }
+            self.pos = 0;
+            self.filled = n as uint;
+        }
+        let (pos, filled) = (self.pos, self.filled);
+        let n = cmp::min(out.len(), filled - pos);
+        copy_memory(out, self.block().slice(pos, pos + n));
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(unix)]
+impl Writer for DirectFile {
+    fn write(&mut self, mut bytes: &[u8]) -> IoResult<()> {
+        while !bytes.is_empty() {
+            let filled = self.filled;
+            let n = cmp::min(BLOCK_SIZE - filled, bytes.len());
+            copy_memory(self.block().slice_from_mut(filled), bytes.slice_to(n));
+            self.filled += n;
+            bytes = bytes.slice_from(n);
+            if self.filled == BLOCK_SIZE {
+                try!(self.write_block());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl SyncWriter for DirectFile {
+    fn sync(&mut self) -> IoResult<()> {
+        if self.filled > 0 {
+            try!(self.write_block());
+            // The last block was padded, so the file has to be cut back to the data in it.
+            if unsafe { libc::ftruncate(self.fd, self.written as libc::off_t) } != 0 {
+                return Err(IoError::last_error());
+            }
+        }
+        if unsafe { libc::fsync(self.fd) } != 0 {
+            return Err(IoError::last_error());
+        }
+        Ok(())
+ 
+    fn as_writer(&mut self) -> &mut Writer {
+        self
+    }
+}
+
+#[cfg(unix)]
+impl Drop for DirectFile {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Opens the file at `p` with the given flags, asking for the page cache to be bypassed. If the
+/// file’s filesystem doesn’t support that, the file is opened normally instead.
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64",
+                                   target_arch = "arm", target_arch = "aarch64")))]
+fn open_uncached(p: &Path, flags: libc::c_int) -> IoResult<libc::c_int> {
+    match open_fd(p, flags | O_DIRECT) {
+        Err(ref e) if e.kind == io::InvalidInput => open_fd(p, flags),
+        res => res,
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn open_uncached(p: &Path, flags: libc::c_int) -> IoResult<libc::c_int> {
+    let fd = try!(open_fd(p, flags));
+    // Failing to turn off caching only makes the file slower for everything else.
+    unsafe {
+        libc::fcntl(fd, F_NOCACHE, 1i32);
+    }
+    Ok(fd)
+}
+
+#[cfg(all(unix, not(target_os = "macos"),
+          not(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64",
+                                           target_arch = "arm", target_arch = "aarch64")))))]
+fn open_uncached(p: &Path, flags: libc::c_int) -> IoResult<libc::c_int> {
+    open_fd(p, flags)
+}
+
+#[cfg(unix)]
+fn open_fd(p: &Path, flags: libc::c_int) -> IoResult<libc::c_int> {
+    let fd = p.with_c_str(|path| unsafe { libc::open(path, flags, 0o644 as libc::mode_t) });
+    if fd < 0 {
+        return Err(IoError::last_error());
+    }
+    Ok(fd)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs;
+    use super::{create, open};
+    use super::super::FileBox;
+
+    #[test]
+    fn direct_io() {
+        let path = Path::new("target/direct_io");
+        // Neither a whole number of blocks nor of alignments.
+        let bytes = Vec::from_fn(300_000, |i| i as u8);
+        {
+            let mut f = create(&path, true).unwrap();
+            f.write(bytes.as_slice()).unwrap();
+            f.sync().unwrap();
+        }
+        assert_eq!(fs::stat(&path).unwrap().size, bytes.len() as u64);
+        assert_eq!(open(&path, true).unwrap().read_to_end().unwrap(), bytes);
+
+        {
+            let mut x = FileBox::open_new(&path, vec![1u8, 2, 3]).unwrap();
+            x.set_direct_io(true);
+        }
+        let x: FileBox<Vec<u8>> = FileBox::open_direct(&path).unwrap();
+        assert_eq!(*x, vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,101 @@
+//! Choosing what happens when dropping a box fails to write it.
+
+use std::io::{mod, File, IoError};
+use std::mem;
+use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+use time;
+
+/// What a box does when it is dropped with changes and writing them fails.
+///
+/// Dropping a box can’t return the error, so by default it panics, as a failed write is usually
+/// something a program shouldn’t carry on from unnoticed. Programs that embed boxes in places
+/// where a panic would do more harm can choose otherwise, for every box with
+/// `set_default_drop_policy` or for one box with `FileBox::set_drop_policy`. Programs that need
+/// to handle the error themselves should close their boxes instead of dropping them.
+pub enum DropPolicy {
+    /// Panic, as boxes do by default.
+    Panic,
+    /// Carry on as if the write had succeeded.
+    Ignore,
+    /// Call the function with the path of the box and the error.
+    Callback(fn(&Path, IoError)),
+    /// Append a line describing the failure to the file at the path, and carry on. Failing to
+    /// write the line is ignored too.
+    LogToFile(Path),
+}
+
+impl Clone for DropPolicy {
+    fn clone(&self) -> DropPolicy {
+        match *self {
+            Panic => Panic,
+            Ignore => Ignore,
+            Callback(f) => Callback(f),
+            LogToFile(ref p) => LogToFile(p.clone()),
+        }
+    }
+}
+
+/// The policy boxes without one of their own follow, as a pointer to a policy that is never
+/// freed, or 0 for `Panic`.
+static DEFAULT_POLICY: AtomicUint = INIT_ATOMIC_UINT;
+
+/// Sets the policy followed by every box that hasn’t been given one of its own. The policies
+/// replaced are never freed, since other tasks may still be following them, so this suits being
+/// called once as a program starts.
+pub fn set_default_drop_policy(policy: DropPolicy) {
+    let policy: *const DropPolicy = unsafe { mem::transmute(box policy) };
+    DEFAULT_POLICY.store(policy as uint, SeqCst);
+}
+
+/// Handles the failure of the write made while dropping the box at `p`, as `policy` says, or as
+/// the default policy says if the box has none of its own.
+pub fn handle(policy: Option<&DropPolicy>, p: &Path, e: IoError) {
+    let policy = match policy {
+        Some(policy) => policy.clone(),
+        None => match DEFAULT_POLICY.load(SeqCst) {
+            0 => Panic,
+            policy => unsafe { (*(policy as *const DropPolicy)).clone() },
+        },
+    };
+    match policy {
+        Panic => panic!("could not write to file: {}", e),
+        Ignore => {}
+        Callback(f) => f(p, e),
+        LogToFile(log) => {
+            let line = format!("{} could not write {}: {}\n",
+                               time::now_utc().rfc3339(), p.display(), e);
+            let _ = File::open_mode(&log, io::Append, io::Write).and_then(|mut f| {
+                f.write_str(line.as_slice())
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{fs, File};
+    use super::{Ignore, LogToFile};
+    use super::super::{FileBox, write_value, peek};
+
+    #[test]
+    fn drop_without_panicking() {
+        let path = Path::new("target/drop_without_panicking");
+        let log = Path::new("target/drop_without_panicking.log");
+        let _ = fs::unlink(&log);
+        let mut b = FileBox::open_new(&path, 1u32).unwrap();
+        b.set_drop_policy(Some(LogToFile(log.clone())));
+        *b = 2;
+        // Another program replaces the file, so the box refuses to write over it.
+        write_value(&path, &3u32).unwrap();
+        drop(b);
+        let text = File::open(&log).read_to_string().unwrap();
+        assert!(text.as_slice().contains("could not write target/drop_without_panicking: "));
+
+        let mut b = FileBox::open(&path).unwrap();
+        b.set_drop_policy(Some(Ignore));
+        *b = 4u32;
+        write_value(&path, &5u32).unwrap();
+        drop(b);
+        assert_eq!(peek::<u32>(&path).unwrap(), 5);
+    }
+}
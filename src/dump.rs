@@ -0,0 +1,52 @@
+//! Turning box files into other formats, for debugging tools and conversion utilities.
+
+use std::io::{IoError, IoResult};
+use serialize::{Decodable, Encodable};
+
+use atomic::write_atomic;
+use encoding::CompactDecoder;
+use format::Format;
+use jsontree::{TreeEncoder, to_tree, write_tree};
+use super::peek;
+
+/// Reads the value of the box file at `p`, as `peek` does, and writes it as indented JSON, with
+/// floats written exactly, for people looking into what a box holds.
+pub fn dump_as_json<T>(p: &Path) -> IoResult<String>
+        where T: Decodable<CompactDecoder, IoError>
+               + Encodable<TreeEncoder, IoError> {
+    let val: T = try!(peek(p));
+    Ok(write_tree(&try!(to_tree(&val)), true, true))
+}
+
+/// Reads the value of the box file at `src`, as `peek` does, and writes it to `dst` in `format`,
+/// replacing the file there atomically. Unlike `FileBox::open_with_format`, this leaves `src` as
+/// it was, so it suits exporting boxes for other programs to read.
+pub fn transcode<T>(src: &Path, dst: &Path, format: &Format<T>) -> IoResult<()>
+        where T: Decodable<CompactDecoder, IoError> {
+    let val: T = try!(peek(src));
+    write_atomic(dst, try!(format.encode(&val)).as_slice())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::default::Default;
+    use std::io::File;
+    use serialize::json;
+    use super::{dump_as_json, transcode};
+    use super::super::{Json, write_value};
+
+    #[test]
+    fn dump_and_transcode_boxes() {
+        let path = Path::new("target/dump_and_transcode_boxes");
+        write_value(&path, &("a".to_string(), vec![1u32, 2])).unwrap();
+        let dumped = dump_as_json::<(String, Vec<u32>)>(&path).unwrap();
+        let tree = json::from_str(dumped.as_slice()).unwrap();
+        assert_eq!(tree.to_string().as_slice(), "[\"a\",[1,2]]");
+
+        let dst = Path::new("target/dump_and_transcode_boxes.json");
+        let format: Json = Default::default();
+        transcode::<(String, Vec<u32>)>(&path, &dst, &format).unwrap();
+        let text = File::open(&dst).read_to_end().unwrap();
+        assert_eq!(text.as_slice(), b"[\"a\",[1,2]]");
+    }
+}
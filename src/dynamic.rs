@@ -0,0 +1,203 @@
+//! Boxes holding values whose concrete type is only known at runtime, such as trait objects.
+
+use std::collections::HashMap;
+use std::io::{mod, IoError, IoResult};
+
+use super::{FileBox, peek, write_value};
+
+/// A value that can be stored along with a tag naming its concrete type.
+///
+/// This is usually implemented for a boxed trait object, like `Box<Plugin + 'static>`, by
+/// forwarding to methods of the trait that each implementation fills in.
+pub trait Tagged {
+    /// The tag identifying the concrete type of this value. Each type must have its own tag, and
+    /// the tag must stay the same for files to remain readable.
+    fn tag(&self) -> &'static str;
+
+    /// Encodes the value, for example with `bincode::encode`.
+    fn encode_payload(&self) -> IoResult<Vec<u8>>;
+}
+
+/// Turns a payload written by `Tagged::encode_payload` back into a value.
+pub type DecodeFn<B> = fn(Vec<u8>) -> IoResult<B>;
+
+/// The functions for decoding each of the concrete types a tagged value can have.
+///
+/// Box files written through a registry hold the tag of their value followed by its payload, so
+/// values of types that were never registered can still be read by code that knows their tags.
+pub struct TypeRegistry<B> {
+    decoders: HashMap<String, DecodeFn<B>>,
+}
+
+impl<B: Tagged> TypeRegistry<B> {
+    /// Creates a registry that knows no types.
+    pub fn new() -> TypeRegistry<B> {
+        TypeRegistry {
+            decoders: HashMap::new(),
+        }
+    }
+
+    /// Registers the function for decoding values with the given tag.
+    ///
+    /// Panics if a function has already been registered for the tag.
+    pub fn register(&mut self, tag: &str, decode: DecodeFn<B>) {
+        assert!(self.decoders.insert(tag.to_string(), decode).is_none(),
+                "a type tagged {} has already been registered", tag);
+    }
+
+    /// Decodes a payload with the given tag.
+    pub fn decode(&self, tag: &str, payload: Vec<u8>) -> IoResult<B> {
+        match self.decoders.get(&tag.to_string()) {
+            Some(decode) => (*decode)(payload),
+            None => Err(IoError {
+                kind: io::InvalidInput,
+                desc: "no type has been registered with this tag",
+                detail: Some(tag.to_string()),
+            }),
+        }
+    }
+
+    /// Opens the box at the given path, decoding its value with the function registered for its
+    /// tag.
+    pub fn open(&self, p: &Path) -> IoResult<DynBox<B>> {
+        let inner: FileBox<(String, Vec<u8>)> = try!(FileBox::open(p));
+        let val = {
+            let (ref tag, ref payload) = *inner;
+            try!(self.decode(tag.as_slice(), payload.clone()))
+        };
+        Ok(DynBox {
+            inner: inner,
+            val: val,
+        })
+    }
+
+    /// Creates a box at the given path holding the given value.
+    pub fn open_new(&self, p: &Path, val: B) -> IoResult<DynBox<B>> {
+        let payload = try!(val.encode_payload());
+        Ok(DynBox {
+            inner: try!(FileBox::open_new(p, (val.tag().to_string(), payload))),
+            val: val,
+        })
+    }
+
+    /// Reads the value stored at the given path without creating a box, as `peek` does.
+    pub fn peek(&self, p: &Path) -> IoResult<B> {
+        let (tag, payload): (String, Vec<u8>) = try!(peek(p));
+        self.decode(tag.as_slice(), payload)
+    }
+}
+
+/// Writes `val` to the given path along with its tag, as `write_value` does.
+pub fn write_tagged<B: Tagged>(p: &Path, val: &B) -> IoResult<()> {
+    write_value(p, &(val.tag().to_string(), try!(val.encode_payload())))
+}
+
+/// A box holding a tagged value, opened through a `TypeRegistry`. Like a `FileBox`, it writes its
+/// value back to its file when it is dropped.
+pub struct DynBox<B: Tagged> {
+    inner: FileBox<(String, Vec<u8>)>,
+    val: B,
+}
+
+impl<B: Tagged> DynBox<B> {
+    /// The underlying box, which holds the tag and payload of the value as they were last read or
+    /// written.
+    pub fn file_box(&self) -> &FileBox<(String, Vec<u8>)> {
+        &self.inner
+    }
+}
+
+impl<B: Tagged> Deref<B> for DynBox<B> {
+    fn deref(&self) -> &B {
+        &self.val
+    }
+}
+
+impl<B: Tagged> DerefMut<B> for DynBox<B> {
+    fn deref_mut(&mut self) -> &mut B {
+        &mut self.val
+    }
+}
+
+#[unsafe_destructor]
+impl<B: Tagged> Drop for DynBox<B> {
+    fn drop(&mut self) {
+        // The inner box is dropped after this, which writes the new payload to the file.
+        let payload = self.val.encode_payload().ok().expect("could not encode the value");
+        *self.inner = (self.val.tag().to_string(), payload);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::IoResult;
+    use bincode;
+    use super::{Tagged, TypeRegistry};
+
+    trait Shape {
+        fn area(&self) -> int;
+        fn tag(&self) -> &'static str;
+        fn encode(&self) -> IoResult<Vec<u8>>;
+    }
+
+    #[deriving(Encodable, Decodable)]
+    struct Square {
+        side: int,
+    }
+
+    impl Shape for Square {
+        fn area(&self) -> int { self.side * self.side }
+        fn tag(&self) -> &'static str { "square" }
+        fn encode(&self) -> IoResult<Vec<u8>> { bincode::encode(self) }
+    }
+
+    #[deriving(Encodable, Decodable)]
+    struct Rect {
+        width: int,
+        height: int,
+    }
+
+    impl Shape for Rect {
+        fn area(&self) -> int { self.width * self.height }
+        fn tag(&self) -> &'static str { "rect" }
+        fn encode(&self) -> IoResult<Vec<u8>> { bincode::encode(self) }
+    }
+
+    impl Tagged for Box<Shape + 'static> {
+        fn tag(&self) -> &'static str { (**self).tag() }
+        fn encode_payload(&self) -> IoResult<Vec<u8>> { (**self).encode() }
+    }
+
+    fn decode_square(payload: Vec<u8>) -> IoResult<Box<Shape + 'static>> {
+        let s: Square = try!(bincode::decode(payload));
+        Ok(box s as Box<Shape + 'static>)
+    }
+
+    fn decode_rect(payload: Vec<u8>) -> IoResult<Box<Shape + 'static>> {
+        let r: Rect = try!(bincode::decode(payload));
+        Ok(box r as Box<Shape + 'static>)
+    }
+
+    #[test]
+    fn trait_objects() {
+        let mut registry = TypeRegistry::new();
+        registry.register("square", decode_square);
+        registry.register("rect", decode_rect);
+
+        let path = Path::new("target/trait_objects");
+        {
+            let shape = box Square { side: 3 } as Box<Shape + 'static>;
+            let mut b = registry.open_new(&path, shape).unwrap();
+            assert_eq!(b.area(), 9);
+            *b = box Rect { width: 2, height: 5 } as Box<Shape + 'static>;
+        }
+        assert_eq!(registry.peek(&path).unwrap().area(), 10);
+        let b = registry.open(&path).unwrap();
+        assert_eq!(b.tag(), "rect");
+        assert_eq!(b.area(), 10);
+        drop(b);
+
+        let empty: TypeRegistry<Box<Shape + 'static>> = TypeRegistry::new();
+        assert!(empty.open(&path).is_err());
+    }
+}
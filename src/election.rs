@@ -0,0 +1,200 @@
+//! Electing one of several processes as the owner of a box.
+
+use std::io::{mod, fs, IoError, IoResult};
+use std::io::fs::PathExtensions;
+use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+use std::time::Duration;
+
+use clock::{Clock, SystemClock};
+#[cfg(unix)]
+use notify::BoxListener;
+use notify::notify_listeners;
+use process;
+use super::{peek, write_value};
+
+/// Distinguishes the candidates of elections in the same process.
+static NEXT_CANDIDATE: AtomicUint = INIT_ATOMIC_UINT;
+
+/// The owner of a box, as recorded in its owner file.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub struct Owner {
+    /// The id of the owning process.
+    pub pid: u32,
+    /// Tells apart candidates in the same process.
+    pub candidate: u64,
+    /// When the ownership lapses unless it is renewed, in seconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+/// One candidate in the election of an owner for a box.
+///
+/// The owner is recorded in a file next to the box’s file, which a candidate can only claim if it
+/// doesn’t exist or if the owner recorded in it has gone: either its process is no longer running,
+/// or it didn’t renew its lease in time. Claiming a free file is atomic, so only one candidate
+/// can win; taking over from an owner that has gone is not, and two candidates doing so at the
+/// same moment can both believe they have won until one of them next renews its lease.
+///
+/// The candidates interested in the box can subscribe to changes of ownership, which are sent
+/// whenever a candidate claims the box or resigns from owning it.
+pub struct Election {
+    path: Path,
+    lease: Duration,
+    candidate: u64,
+    clock: Box<Clock + 'static>,
+}
+
+impl Election {
+    /// Enters a new candidate in the election of the owner of the box at `p`. Ownership lasts for
+    /// `lease` unless it is renewed.
+    pub fn new(p: &Path, lease: Duration) -> Election {
+        Election::with_clock(p, lease, box SystemClock as Box<Clock + 'static>)
+    }
+
+    /// Like `new`, but leases are timed with the given clock. Every candidate in the election
+    /// should use the same clock.
+    pub fn with_clock(p: &Path, lease: Duration, clock: Box<Clock + 'static>) -> Election {
+        let mut name = p.filename().unwrap_or(b"filebox").to_vec();
+        name.push_all(b".owner");
+        Election {
+            path: p.with_filename(name),
+            lease: lease,
+            candidate: NEXT_CANDIDATE.fetch_add(1, SeqCst) as u64,
+            clock: clock,
+        }
+    }
+
+    /// The current owner of the box, if it has one that hasn’t gone.
+    pub fn owner(&self) -> IoResult<Option<Owner>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let owner: Owner = try!(peek(&self.path));
+        if owner.expires_at > self.now() && process::is_alive(owner.pid) {
+            Ok(Some(owner))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns whether this candidate owns the box.
+    pub fn is_owner(&self) -> IoResult<bool> {
+        Ok(try!(self.owner()).map_or(false, |owner| self.is(&owner)))
+    }
+
+    /// Tries to become the owner of the box, returning whether this candidate owns it now. If it
+    /// already did, its lease is renewed.
+    pub fn try_claim(&self) -> IoResult<bool> {
+        match try!(self.owner()) {
+            Some(ref owner) if self.is(owner) => {
+                try!(self.renew());
+                return Ok(true);
+            }
+            Some(_) => return Ok(false),
+            None => {}
+        }
+        if self.path.exists() {
+            // The owner has gone, but its file stops anyone else from claiming the box.
+            try!(fs::unlink(&self.path));
+        }
+
+        // Linking fails if the file already exists, so only one candidate can claim it.
+        let mut name = self.path.filename().unwrap().to_vec();
+        name.push_all(format!(".{}-{}", process::id(), self.candidate).as_bytes());
+        let claim = self.path.with_filename(name);
+        try!(write_value(&claim, &self.record()));
+        let won = fs::link(&claim, &self.path).is_ok();
+        try!(fs::unlink(&claim));
+        if won {
+            let _ = notify_listeners(&self.path);
+        }
+        Ok(won)
+    }
+
+    /// Extends the lease of this candidate, which must own the box.
+    pub fn renew(&self) -> IoResult<()> {
+        if !try!(self.is_owner()) {
+            return Err(not_owner());
+        }
+        write_value(&self.path, &self.record())
+    }
+
+    /// Gives up ownership of the box, which this candidate must own, so that another candidate
+    /// can claim it straight away.
+    pub fn resign(&self) -> IoResult<()> {
+        if !try!(self.is_owner()) {
+            return Err(not_owner());
+        }
+        try!(fs::unlink(&self.path));
+        let _ = notify_listeners(&self.path);
+        Ok(())
+    }
+
+    /// Starts listening for changes of ownership of the box. Candidates that lose an election can
+    /// wait on the listener and try to claim the box again when it wakes up.
+    #[cfg(unix)]
+    pub fn subscribe(&self) -> IoResult<BoxListener> {
+        BoxListener::new(&self.path)
+    }
+
+    fn is(&self, owner: &Owner) -> bool {
+        owner.pid == process::id() && owner.candidate == self.candidate
+    }
+
+    /// The current time, in seconds since the Unix epoch.
+    fn now(&self) -> u64 {
+        self.clock.now_ms() / 1000
+    }
+
+    fn record(&self) -> Owner {
+        Owner {
+            pid: process::id(),
+            candidate: self.candidate,
+            expires_at: self.now() + self.lease.num_seconds() as u64,
+        }
+    }
+}
+
+fn not_owner() -> IoError {
+    IoError {
+        kind: io::OtherIoError,
+        desc: "this candidate doesn’t own the box",
+        detail: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::Election;
+    use super::super::{Clock, SystemClock, ManualClock};
+
+    #[test]
+    fn elect_owner() {
+        let path = Path::new("target/elect_owner");
+        let a = Election::new(&path, Duration::minutes(1));
+        let b = Election::new(&path, Duration::minutes(1));
+        let _ = a.resign();
+        let _ = b.resign();
+
+        assert!(a.try_claim().unwrap());
+        assert!(!b.try_claim().unwrap());
+        assert!(a.is_owner().unwrap() && !b.is_owner().unwrap());
+        assert!(b.resign().is_err());
+        a.resign().unwrap();
+        assert!(b.try_claim().unwrap());
+        assert!(!a.try_claim().unwrap());
+        b.resign().unwrap();
+
+        // An owner whose lease has run out can be replaced.
+        let clock = ManualClock::new(SystemClock.now_ms());
+        let lease = Duration::minutes(1);
+        let c = Election::with_clock(&path, lease, box clock.clone() as Box<Clock + 'static>);
+        let d = Election::with_clock(&path, lease, box clock.clone() as Box<Clock + 'static>);
+        assert!(c.try_claim().unwrap());
+        assert!(!d.try_claim().unwrap());
+        clock.advance(Duration::minutes(2));
+        assert!(d.try_claim().unwrap());
+        assert!(!c.is_owner().unwrap());
+        d.resign().unwrap();
+    }
+}
@@ -0,0 +1,1024 @@
+//! Binary encodings that trade some generality or precision for smaller files.
+//!
+//! The encodings are variations on bincode’s, chosen with `Encoding`:
+//!
+//! * With `intern_strings`, every string is replaced by its index in a table of the distinct
+//!   strings in the value, which is stored before it. This helps values holding many copies of
+//!   the same strings, such as maps with repeated keys.
+//! * With `varint`, integers and lengths take up as few bytes as their values need, rather than
+//!   their full width. Signed integers are zigzag-encoded first, so small negative numbers stay
+//!   small.
+//! * With `f32_floats`, `f64`s are stored as `f32`s, losing the precision that doesn’t fit.
+//! * With `little_endian`, integers and floats are stored least significant byte first, rather
+//!   than bincode’s most significant byte first, as is natural on x86 and most ARM devices.
+//!
+//! The encoding of a file is recorded in its header, so it can be read without being told how it
+//! was written.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::default::Default;
+use std::int;
+use std::io::{mod, fs, File, IoError, IoResult, MemReader, MemWriter};
+use std::io::fs::PathExtensions;
+use std::slice::bytes::copy_memory;
+use std::uint;
+use serialize::{Decodable, Encodable, Decoder, Encoder};
+
+use atomic::write_atomic;
+use compress;
+use header;
+use layout::{mod, Combined, Split};
+use header::FieldOffset;
+use super::{Header, NoCompression, Compressor, open_header, now, read_encoded};
+
+type EResult = IoResult<()>;
+
+/// Options for encoding a value. The default options give bincode’s encoding.
+#[deriving(Clone, PartialEq, Show, Default)]
+pub struct Encoding {
+    /// Whether strings are stored once, in a table, and referred to by index.
+    pub intern_strings: bool,
+    /// Whether integers are stored with as few bytes as their values need.
+    pub varint: bool,
+    /// Whether `f64`s are stored as `f32`s.
+    pub f32_floats: bool,
+    /// Whether fixed-width integers and floats are stored in little-endian byte order.
+    pub little_endian: bool,
+}
+
+impl Encoding {
+    /// The name recorded in the headers of files with this encoding.
+    pub fn name(&self) -> String {
+        let mut parts = Vec::new();
+        if self.intern_strings {
+            parts.push("interned");
+        }
+        if self.varint {
+            parts.push("varint");
+        }
+        if self.f32_floats {
+            parts.push("f32");
+        }
+        if self.little_endian {
+            parts.push("le");
+        }
+        if parts.is_empty() {
+            header::BINCODE.to_string()
+        } else {
+            parts.connect("+")
+        }
+    }
+
+    /// Reverses `name`, returning `None` if `name` doesn’t name an encoding.
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        let mut encoding: Encoding = Default::default();
+        if name == header::BINCODE {
+            return Some(encoding);
+        }
+        for part in name.split('+') {
+            match part {
+                "interned" => encoding.intern_strings = true,
+                "varint" => encoding.varint = true,
+                "f32" => encoding.f32_floats = true,
+                "le" => encoding.little_endian = true,
+                _ => return None,
+            }
+        }
+        Some(encoding)
+    }
+}
+
+/// The largest number of bytes an integer can take up in the varint encoding.
+static MAX_VARINT_LEN: uint = 10;
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    (v >> 1) as i64 ^ -((v & 1) as i64)
+}
+
+/// Encodes values with an `Encoding`. See the module documentation for the formats.
+pub struct CompactEncoder {
+    writer: MemWriter,
+    encoding: Encoding,
+    indices: HashMap<String, u32>,
+    table: Vec<String>,
+    /// Where the fields encoded so far are, if they are being recorded.
+    offsets: Option<Vec<FieldOffset>>,
+    /// The names of the fields being encoded, outermost first.
+    fields: Vec<String>,
+    /// How many sequences, maps and enums are being encoded, whose fields can’t be recorded, as
+    /// they don’t occur once in every value of the type.
+    collections: uint,
+}
+
+impl CompactEncoder {
+    fn new(encoding: Encoding) -> CompactEncoder {
+        CompactEncoder {
+            writer: MemWriter::new(),
+            encoding: encoding,
+            indices: HashMap::new(),
+            table: Vec::new(),
+            offsets: None,
+            fields: Vec::new(),
+            collections: 0,
+        }
+    }
+
+    /// Encodes a sequence, map or enum with `f`.
+    fn collection(&mut self, f: |&mut CompactEncoder| -> EResult) -> EResult {
+        self.collections += 1;
+        let res = f(self);
+        self.collections -= 1;
+        res
+    }
+
+    fn write_varint(&mut self, mut v: u64) -> EResult {
+        while v >= 0x80 {
+            try!(self.writer.write_u8((v as u8) | 0x80));
+            v >>= 7;
+        }
+        self.writer.write_u8(v as u8)
+    }
+
+    /// Writes the low `size` bytes of `v`, in the encoding’s byte order.
+    fn write_unsigned(&mut self, v: u64, size: uint) -> EResult {
+        if self.encoding.little_endian {
+            self.writer.write_le_uint_n(v, size)
+        } else {
+            self.writer.write_be_uint_n(v, size)
+        }
+    }
+
+    fn write_signed(&mut self, v: i64, size: uint) -> EResult {
+        if self.encoding.little_endian {
+            self.writer.write_le_int_n(v, size)
+        } else {
+            self.writer.write_be_int_n(v, size)
+        }
+    }
+}
+
+impl Encoder<IoError> for CompactEncoder {
+    fn emit_nil(&mut self) -> EResult { Ok(()) }
+    fn emit_uint(&mut self, v: uint) -> EResult { self.emit_u64(v as u64) }
+    fn emit_int(&mut self, v: int) -> EResult { self.emit_i64(v as i64) }
+    fn emit_u8(&mut self, v: u8) -> EResult { self.writer.write_u8(v) }
+    fn emit_i8(&mut self, v: i8) -> EResult { self.writer.write_i8(v) }
+    fn emit_bool(&mut self, v: bool) -> EResult { self.writer.write_u8(if v { 1 } else { 0 }) }
+    fn emit_f32(&mut self, v: f32) -> EResult {
+        if self.encoding.little_endian {
+            self.writer.write_le_f32(v)
+        } else {
+            self.writer.write_be_f32(v)
+        }
+    }
+    fn emit_char(&mut self, v: char) -> EResult { self.writer.write_char(v) }
+
+    fn emit_u64(&mut self, v: u64) -> EResult {
+        if self.encoding.varint { self.write_varint(v) } else { self.write_unsigned(v, 8) }
+    }
+    fn emit_u32(&mut self, v: u32) -> EResult {
+        if self.encoding.varint {
+            self.write_varint(v as u64)
+        } else {
+            self.write_unsigned(v as u64, 4)
+        }
+    }
+    fn emit_u16(&mut self, v: u16) -> EResult {
+        if self.encoding.varint {
+            self.write_varint(v as u64)
+        } else {
+            self.write_unsigned(v as u64, 2)
+        }
+    }
+    fn emit_i64(&mut self, v: i64) -> EResult {
+        if self.encoding.varint {
+            self.write_varint(zigzag(v))
+        } else {
+            self.write_signed(v, 8)
+        }
+    }
+    fn emit_i32(&mut self, v: i32) -> EResult {
+        if self.encoding.varint {
+            self.write_varint(zigzag(v as i64))
+        } else {
+            self.write_signed(v as i64, 4)
+        }
+    }
+    fn emit_i16(&mut self, v: i16) -> EResult {
+        if self.encoding.varint {
+            self.write_varint(zigzag(v as i64))
+        } else {
+            self.write_signed(v as i64, 2)
+        }
+    }
+    fn emit_f64(&mut self, v: f64) -> EResult {
+        if self.encoding.f32_floats {
+            self.emit_f32(v as f32)
+        } else if self.encoding.little_endian {
+            self.writer.write_le_f64(v)
+        } else {
+            self.writer.write_be_f64(v)
+        }
+    }
+
+    fn emit_str(&mut self, v: &str) -> EResult {
+        if !self.encoding.intern_strings {
+            try!(self.emit_uint(v.len()));
+            return self.writer.write_str(v);
+        }
+        let found = self.indices.get(&v.to_string()).map(|&i| i);
+        let index = match found {
+            Some(i) => i,
+            None => {
+                let i = self.table.len() as u32;
+                self.indices.insert(v.to_string(), i);
+                self.table.push(v.to_string());
+                i
+            }
+        };
+        self.emit_u32(index)
+    }
+
+    fn emit_enum(&mut self, _: &str, f: |&mut CompactEncoder| -> EResult) -> EResult {
+        self.collection(f)
+    }
+    fn emit_enum_variant(&mut self, _: &str, v_id: uint, _: uint,
+                         f: |&mut CompactEncoder| -> EResult) -> EResult {
+        try!(self.emit_uint(v_id));
+        f(self)
+    }
+    fn emit_enum_variant_arg(&mut self, _: uint, f: |&mut CompactEncoder| -> EResult) -> EResult {
+        f(self)
+    }
+    fn emit_enum_struct_variant(&mut self, _: &str, v_id: uint, _: uint,
+                                f: |&mut CompactEncoder| -> EResult) -> EResult {
+        try!(self.emit_uint(v_id));
+        f(self)
+    }
+    fn emit_enum_struct_variant_field(&mut self, _: &str, _: uint,
+                                      f: |&mut CompactEncoder| -> EResult) -> EResult {
+        f(self)
+    }
+    fn emit_struct(&mut self, _: &str, _: uint, f: |&mut CompactEncoder| -> EResult) -> EResult {
+        f(self)
+    }
+    fn emit_struct_field(&mut self, name: &str, _: uint,
+                         f: |&mut CompactEncoder| -> EResult) -> EResult {
+        if self.offsets.is_none() || self.collections > 0 {
+            return f(self);
+        }
+        self.fields.push(name.to_string());
+        let start = self.writer.get_ref().len() as u64;
+        let res = f(self);
+        let end = self.writer.get_ref().len() as u64;
+        let field = self.fields.connect(".");
+        self.fields.pop();
+        self.offsets.as_mut().unwrap().push(FieldOffset {
+            field: field,
+            offset: start,
+            len: end - start,
+        });
+        res
+    }
+    fn emit_tuple(&mut self, _: uint, f: |&mut CompactEncoder| -> EResult) -> EResult {
+        f(self)
+    }
+    fn emit_tuple_arg(&mut self, _: uint, f: |&mut CompactEncoder| -> EResult) -> EResult {
+        f(self)
+    }
+    fn emit_tuple_struct(&mut self, _: &str, _: uint,
+                         f: |&mut CompactEncoder| -> EResult) -> EResult {
+        f(self)
+    }
+    fn emit_tuple_struct_arg(&mut self, _: uint, f: |&mut CompactEncoder| -> EResult) -> EResult {
+        f(self)
+    }
+    fn emit_option(&mut self, f: |&mut CompactEncoder| -> EResult) -> EResult {
+        f(self)
+    }
+    fn emit_option_none(&mut self) -> EResult {
+        self.writer.write_u8(0)
+    }
+    fn emit_option_some(&mut self, f: |&mut CompactEncoder| -> EResult) -> EResult {
+        try!(self.writer.write_u8(1));
+        f(self)
+    }
+    fn emit_seq(&mut self, len: uint, f: |&mut CompactEncoder| -> EResult) -> EResult {
+        try!(self.emit_uint(len));
+        self.collection(f)
+    }
+    fn emit_seq_elt(&mut self, _: uint, f: |&mut CompactEncoder| -> EResult) -> EResult {
+        f(self)
+    }
+    fn emit_map(&mut self, len: uint, f: |&mut CompactEncoder| -> EResult) -> EResult {
+        try!(self.emit_uint(len));
+        self.collection(f)
+    }
+    fn emit_map_elt_key(&mut self, _: uint, f: |&mut CompactEncoder| -> EResult) -> EResult {
+        f(self)
+    }
+    fn emit_map_elt_val(&mut self, _: uint, f: |&mut CompactEncoder| -> EResult) -> EResult {
+        f(self)
+    }
+}
+
+/// Decodes values written by `CompactEncoder`.
+pub struct CompactDecoder {
+    reader: MemReader,
+    encoding: Encoding,
+    table: Option<Vec<String>>,
+    search: Option<FieldSearch>,
+}
+
+/// The field `read_field` is looking for, and how far the decoder has got to it.
+struct FieldSearch {
+    path: Vec<String>,
+    /// The number of fields the decoder is inside of.
+    depth: uint,
+    /// The number of leading components of `path` that the fields the decoder is inside of match.
+    matched: uint,
+    /// The offset of the field in the payload, once it has been reached.
+    found: Option<u64>,
+}
+
+/// The description of the error that stops decoding once the field `read_field` is looking for has
+/// been reached.
+static FIELD_FOUND: &'static str = "the field was found";
+
+impl CompactDecoder {
+    /// Creates a decoder for `bytes`, written by `encode_compact` with the given encoding.
+    fn new(bytes: Vec<u8>, encoding: &Encoding) -> IoResult<CompactDecoder> {
+        let mut d = CompactDecoder {
+            reader: MemReader::new(bytes),
+            encoding: encoding.clone(),
+            table: None,
+            search: None,
+        };
+        if encoding.intern_strings {
+            let len = try!(d.read_uint());
+            let mut table = Vec::with_capacity(len);
+            for _ in range(0, len) {
+                table.push(try!(d.read_str()));
+            }
+            d.table = Some(table);
+        }
+        Ok(d)
+    }
+
+    fn read_varint(&mut self) -> IoResult<u64> {
+        let mut v = 0u64;
+        for i in range(0, MAX_VARINT_LEN) {
+            let b = try!(self.reader.read_u8());
+            if i == MAX_VARINT_LEN - 1 && b > 1 {
+                break;
+            }
+            v |= (b & 0x7f) as u64 << (7 * i);
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(self.error("integer is too long"))
+    }
+
+    /// Reads an unsigned integer that was written with at most `max` as its value, taking up
+    /// `size` bytes unless it is a varint.
+    fn read_unsigned(&mut self, max: u64, size: uint) -> IoResult<u64> {
+        if !self.encoding.varint {
+            return if self.encoding.little_endian {
+                self.reader.read_le_uint_n(size)
+            } else {
+                self.reader.read_be_uint_n(size)
+            };
+        }
+        let v = try!(self.read_varint());
+        if v > max {
+            return Err(self.error("integer is out of range"));
+        }
+        Ok(v)
+    }
+
+    /// Reads a signed integer that was written with a value between `-max - 1` and `max`, taking
+    /// up `size` bytes unless it is a varint.
+    fn read_signed(&mut self, max: i64, size: uint) -> IoResult<i64> {
+        if !self.encoding.varint {
+            return if self.encoding.little_endian {
+                self.reader.read_le_int_n(size)
+            } else {
+                self.reader.read_be_int_n(size)
+            };
+        }
+        let v = unzigzag(try!(self.read_varint()));
+        if v > max || v < -max - 1 {
+            return Err(self.error("integer is out of range"));
+        }
+        Ok(v)
+    }
+}
+
+impl Decoder<IoError> for CompactDecoder {
+    fn read_nil(&mut self) -> IoResult<()> { Ok(()) }
+    fn read_uint(&mut self) -> IoResult<uint> {
+        let v = try!(self.read_u64());
+        if v > uint::MAX as u64 {
+            return Err(self.error("integer is too large for a uint on this machine"));
+        }
+        Ok(v as uint)
+    }
+    fn read_int(&mut self) -> IoResult<int> {
+        let v = try!(self.read_i64());
+        if v > int::MAX as i64 || v < int::MIN as i64 {
+            return Err(self.error("integer is too large for an int on this machine"));
+        }
+        Ok(v as int)
+    }
+    fn read_u8(&mut self) -> IoResult<u8> { self.reader.read_u8() }
+    fn read_i8(&mut self) -> IoResult<i8> { self.reader.read_i8() }
+    fn read_bool(&mut self) -> IoResult<bool> { self.reader.read_u8().map(|v| v == 1) }
+    fn read_f32(&mut self) -> IoResult<f32> {
+        if self.encoding.little_endian {
+            self.reader.read_le_f32()
+        } else {
+            self.reader.read_be_f32()
+        }
+    }
+    fn read_char(&mut self) -> IoResult<char> { self.reader.read_char() }
+
+    fn read_u64(&mut self) -> IoResult<u64> {
+        self.read_unsigned(-1u64, 8)
+    }
+    fn read_u32(&mut self) -> IoResult<u32> {
+        self.read_unsigned(0xffff_ffff, 4).map(|v| v as u32)
+    }
+    fn read_u16(&mut self) -> IoResult<u16> {
+        self.read_unsigned(0xffff, 2).map(|v| v as u16)
+    }
+    fn read_i64(&mut self) -> IoResult<i64> {
+        self.read_signed(0x7fff_ffff_ffff_ffff, 8)
+    }
+    fn read_i32(&mut self) -> IoResult<i32> {
+        self.read_signed(0x7fff_ffff, 4).map(|v| v as i32)
+    }
+    fn read_i16(&mut self) -> IoResult<i16> {
+        self.read_signed(0x7fff, 2).map(|v| v as i16)
+    }
+    fn read_f64(&mut self) -> IoResult<f64> {
+        if self.encoding.f32_floats {
+            self.read_f32().map(|v| v as f64)
+        } else if self.encoding.little_endian {
+            self.reader.read_le_f64()
+        } else {
+            self.reader.read_be_f64()
+        }
+    }
+
+    fn read_str(&mut self) -> IoResult<String> {
+        if self.table.is_none() {
+            let len = try!(self.read_uint());
+            let bytes = try!(self.reader.read_exact(len));
+            return match String::from_utf8(bytes) {
+                Ok(s) => Ok(s),
+                Err(_) => Err(self.error("string isn’t valid UTF-8")),
+            };
+        }
+        let index = try!(self.read_u32()) as uint;
+        let s = self.table.as_ref().and_then(|t| t.as_slice().get(index)).map(|s| s.clone());
+        match s {
+            Some(s) => Ok(s),
+            None => Err(self.error("string index out of range")),
+        }
+    }
+
+    fn read_enum<T>(&mut self, _: &str, f: |&mut CompactDecoder| -> IoResult<T>) -> IoResult<T> {
+        f(self)
+    }
+    fn read_enum_variant<T>(&mut self, _: &[&str],
+                            f: |&mut CompactDecoder, uint| -> IoResult<T>) -> IoResult<T> {
+        let id = try!(self.read_uint());
+        f(self, id)
+    }
+    fn read_enum_variant_arg<T>(&mut self, _: uint,
+                                f: |&mut CompactDecoder| -> IoResult<T>) -> IoResult<T> {
+        f(self)
+    }
+    fn read_enum_struct_variant<T>(&mut self, _: &[&str],
+                                   f: |&mut CompactDecoder, uint| -> IoResult<T>)
+                                   -> IoResult<T> {
+        let id = try!(self.read_uint());
+        f(self, id)
+    }
+    fn read_enum_struct_variant_field<T>(&mut self, _: &str, _: uint,
+                                         f: |&mut CompactDecoder| -> IoResult<T>)
+                                         -> IoResult<T> {
+        f(self)
+    }
+    fn read_struct<T>(&mut self, _: &str, _: uint,
+                      f: |&mut CompactDecoder| -> IoResult<T>) -> IoResult<T> {
+        f(self)
+    }
+    fn read_struct_field<T>(&mut self, name: &str, _: uint,
+                            f: |&mut CompactDecoder| -> IoResult<T>) -> IoResult<T> {
+        if self.search.is_none() {
+            return f(self);
+        }
+        let pos = try!(self.reader.tell());
+        {
+            let search = self.search.as_mut().unwrap();
+            let next = search.path.as_slice().get(search.matched).map(|s| s.as_slice());
+            if search.matched == search.depth && next == Some(name) {
+                search.matched += 1;
+                if search.matched == search.path.len() {
+                    search.found = Some(pos);
+                    return Err(IoError {
+                        kind: io::OtherIoError,
+                        desc: FIELD_FOUND,
+                        detail: None,
+                    });
+                }
+            }
+            search.depth += 1;
+        }
+        let res = f(self);
+        let search = self.search.as_mut().unwrap();
+        search.depth -= 1;
+        search.matched = cmp::min(search.matched, search.depth);
+        res
+    }
+    fn read_tuple<T>(&mut self, _: uint, f: |&mut CompactDecoder| -> IoResult<T>) -> IoResult<T> {
+        f(self)
+    }
+    fn read_tuple_arg<T>(&mut self, _: uint,
+                         f: |&mut CompactDecoder| -> IoResult<T>) -> IoResult<T> {
+        f(self)
+    }
+    fn read_tuple_struct<T>(&mut self, _: &str, _: uint,
+                            f: |&mut CompactDecoder| -> IoResult<T>) -> IoResult<T> {
+        f(self)
+    }
+    fn read_tuple_struct_arg<T>(&mut self, _: uint,
+                                f: |&mut CompactDecoder| -> IoResult<T>) -> IoResult<T> {
+        f(self)
+    }
+    fn read_option<T>(&mut self, f: |&mut CompactDecoder, bool| -> IoResult<T>) -> IoResult<T> {
+        let some = try!(self.reader.read_u8()) == 1;
+        f(self, some)
+    }
+    fn read_seq<T>(&mut self, f: |&mut CompactDecoder, uint| -> IoResult<T>) -> IoResult<T> {
+        let len = try!(self.read_uint());
+        f(self, len)
+    }
+    fn read_seq_elt<T>(&mut self, _: uint,
+                       f: |&mut CompactDecoder| -> IoResult<T>) -> IoResult<T> {
+        f(self)
+    }
+    fn read_map<T>(&mut self, f: |&mut CompactDecoder, uint| -> IoResult<T>) -> IoResult<T> {
+        let len = try!(self.read_uint());
+        f(self, len)
+    }
+    fn read_map_elt_key<T>(&mut self, _: uint,
+                           f: |&mut CompactDecoder| -> IoResult<T>) -> IoResult<T> {
+        f(self)
+    }
+    fn read_map_elt_val<T>(&mut self, _: uint,
+                           f: |&mut CompactDecoder| -> IoResult<T>) -> IoResult<T> {
+        f(self)
+    }
+
+    fn error(&mut self, err: &str) -> IoError {
+        IoError {
+            kind: io::InvalidInput,
+            desc: "the box holds an invalid value",
+            detail: Some(err.to_string()),
+        }
+    }
+}
+
+/// Encodes `val` with the given encoding.
+pub fn encode_compact<T>(val: &T, encoding: &Encoding) -> IoResult<Vec<u8>>
+        where T: Encodable<CompactEncoder, IoError> {
+    encode_recording(val, encoding, false).map(|(bytes, _)| bytes)
+}
+
+/// Encodes `val` with the given encoding, along with where its fields are in the encoded bytes if
+/// `record` is true.
+fn encode_recording<T>(val: &T, encoding: &Encoding, record: bool)
+                       -> IoResult<(Vec<u8>, Vec<FieldOffset>)>
+        where T: Encodable<CompactEncoder, IoError> {
+    let mut body = CompactEncoder::new(encoding.clone());
+    if record {
+        body.offsets = Some(Vec::new());
+    }
+    try!(val.encode(&mut body));
+    let mut offsets = body.offsets.take().unwrap_or(Vec::new());
+    if !encoding.intern_strings {
+        return Ok((body.writer.unwrap(), offsets));
+    }
+    // The table is a sequence of ordinary strings.
+    let mut e = CompactEncoder::new(Encoding { intern_strings: false, .. encoding.clone() });
+    try!(e.emit_uint(body.table.len()));
+    for s in body.table.iter() {
+        try!(e.emit_str(s.as_slice()));
+    }
+    // The fields come after the table.
+    let table_len = e.writer.get_ref().len() as u64;
+    for offset in offsets.iter_mut() {
+        offset.offset += table_len;
+    }
+    try!(e.writer.write(body.writer.get_ref()));
+    Ok((e.writer.unwrap(), offsets))
+}
+
+/// Decodes a value written by `encode_compact` with the given encoding.
+pub fn decode_compact<T>(bytes: Vec<u8>, encoding: &Encoding) -> IoResult<T>
+        where T: Decodable<CompactDecoder, IoError> {
+    Decodable::decode(&mut try!(CompactDecoder::new(bytes, encoding)))
+}
+
+/// Decodes a value written by bincode, which is how every box’s value is read unless it was
+/// written with an encoding of its own. Bincode writes `uint`s and `int`s as 64-bit numbers on
+/// every machine, but reading them with bincode on a machine with narrower integers cuts off the
+/// bits that don’t fit, so this instead fails if one of them is too large for this machine.
+pub fn decode_bincode<T>(bytes: Vec<u8>) -> IoResult<T>
+        where T: Decodable<CompactDecoder, IoError> {
+    decode_compact(bytes, &Default::default())
+}
+
+/// An encoding along with the functions that encode and decode values of type `T` with it, which
+/// boxes written with an encoding of their own keep, since their other methods can’t rely on `T`
+/// implementing the traits the encoders need. See `FileBox::set_encoding`.
+pub struct Codec<T> {
+    /// The encoding values are written with.
+    pub encoding: Encoding,
+    encode: fn(&T, &Encoding) -> IoResult<Vec<u8>>,
+    decode: fn(Vec<u8>, &Encoding) -> IoResult<T>,
+}
+
+impl<T> Codec<T> where T: Encodable<CompactEncoder, IoError> + Decodable<CompactDecoder, IoError> {
+    /// A codec for values of type `T` with the given encoding.
+    pub fn new(encoding: Encoding) -> Codec<T> {
+        Codec {
+            encoding: encoding,
+            encode: encode_compact::<T>,
+            decode: decode_compact::<T>,
+        }
+    }
+}
+
+impl<T> Codec<T> {
+    /// Encodes `val` with the codec’s encoding.
+    pub fn encode(&self, val: &T) -> IoResult<Vec<u8>> {
+        (self.encode)(val, &self.encoding)
+    }
+
+    /// Decodes a payload stored with `header`, with the encoding the header names rather than the
+    /// codec’s own, so that files written before the encoding was changed can still be read.
+    pub fn decode(&self, header: &Header, payload: Vec<u8>) -> IoResult<T> {
+        (self.decode)(payload, &try!(encoding_of(header)))
+    }
+}
+
+/// Writes `val` to the given path with the given encoding, as `write_value` does otherwise.
+/// Boxes opened with `FileBox::open` can only read files written with the default encoding, but
+/// `FileBox::open_encoded` and `peek_compact` can read files with any encoding.
+pub fn write_compact<T>(p: &Path, val: &T, encoding: &Encoding) -> IoResult<()>
+        where T: Encodable<CompactEncoder, IoError> {
+    write_encoded(p, val, encoding, false)
+}
+
+/// Like `write_compact`, but records in the file’s header where each field of `val` is, so that
+/// `read_field` and `update_field` can go straight to a field instead of decoding the value up to
+/// it. This suits large values of which only a field or two change at a time, such as counters,
+/// flags and timestamps, which `update_field` can then patch in place. Fields inside sequences,
+/// maps and enums aren’t recorded, since the number of them differs from value to value.
+///
+/// Only the next `update_field` keeps the record: writing the file any other way, such as with a
+/// box, leaves it without one, and it goes back to being searched.
+pub fn write_indexed<T>(p: &Path, val: &T, encoding: &Encoding) -> IoResult<()>
+        where T: Encodable<CompactEncoder, IoError> {
+    write_encoded(p, val, encoding, true)
+}
+
+fn write_encoded<T>(p: &Path, val: &T, encoding: &Encoding, record: bool) -> IoResult<()>
+        where T: Encodable<CompactEncoder, IoError> {
+    let prev = if p.exists() {
+        try!(open_header(p)).header
+    } else {
+        Header {
+            created_at: now(),
+            .. Header::new()
+        }
+    };
+    let (payload, offsets) = try!(encode_recording(val, encoding, record));
+    let mut header = prev.next(NoCompression.name(), payload.as_slice());
+    header.encoding = encoding.name();
+    header.field_offsets = offsets;
+    layout::store(p, layout::layout_of(p), &header, payload.as_slice(), |bytes| {
+        write_atomic(p, bytes)
+    })
+}
+
+/// Reads the value stored at the given path with the encoding recorded in its header.
+pub fn peek_compact<T>(p: &Path) -> IoResult<T>
+        where T: Decodable<CompactDecoder, IoError> {
+    let (header, payload) = try!(read_encoded(p, None, None, None, false));
+    decode_compact(payload, &try!(encoding_of(&header)))
+}
+
+/// Reads one field of the value of type `T` stored at the given path, as `peek_compact` would,
+/// without decoding the rest of the value. `field` names the field by the names of the fields
+/// leading to it, separated by dots, such as `stats.hits`; fields inside sequences, maps and enum
+/// variants are found in the first element that has them.
+///
+/// Unless the file was written by `write_indexed`, the encodings don’t record where fields start,
+/// so the value is decoded up to the field and the fields before it are thrown away. Reading a
+/// field is cheapest when it comes early in its type.
+pub fn read_field<T, F>(p: &Path, field: &str) -> IoResult<F>
+        where T: Decodable<CompactDecoder, IoError>, F: Decodable<CompactDecoder, IoError> {
+    let (header, payload) = try!(read_encoded(p, None, None, None, false));
+    let mut d = try!(CompactDecoder::new(payload, &try!(encoding_of(&header))));
+    try!(seek_field::<T>(&mut d, &header, field));
+    Decodable::decode(&mut d)
+}
+
+/// Replaces one field of the value of type `T` stored at the given path with `val`, patching the
+/// bytes of the field in the box’s file rather than rewriting the file. The field is named as for
+/// `read_field`.
+///
+/// This only works for fields whose new value takes up as many bytes as the old one, like
+/// integers without the `varint` encoding, in files that aren’t compressed and don’t intern
+/// strings. Unlike other writes, patching a file isn’t atomic: a crash in the middle leaves a file
+/// whose payload doesn’t match its checksum, which fails to be read rather than returning a wrong
+/// value.
+pub fn update_field<T, F>(p: &Path, field: &str, val: &F) -> IoResult<()>
+        where T: Decodable<CompactDecoder, IoError>,
+              F: Decodable<CompactDecoder, IoError> + Encodable<CompactEncoder, IoError> {
+    let info = try!(open_header(p));
+    let encoding = try!(encoding_of(&info.header));
+    if info.header.compressor.as_slice() != compress::NONE || encoding.intern_strings {
+        return Err(cant_patch("the box’s file is compressed or interns strings"));
+    }
+    let (_, payload) = try!(read_encoded(p, None, None, None, false));
+    let mut d = try!(CompactDecoder::new(payload, &encoding));
+    let start = try!(seek_field::<T>(&mut d, &info.header, field));
+    let end = match info.header.field_offset(field) {
+        Some(offset) => start + offset.len,
+        None => {
+            let _: F = try!(Decodable::decode(&mut d));
+            try!(d.reader.tell())
+        }
+    };
+    let mut e = CompactEncoder::new(encoding);
+    try!(val.encode(&mut e));
+    let bytes = e.writer.unwrap();
+    if bytes.len() as u64 != end - start {
+        return Err(cant_patch("the new value of the field has a different size"));
+    }
+    let mut payload = d.reader.unwrap();
+    copy_memory(payload.slice_mut(start as uint, end as uint), bytes.as_slice());
+
+    let mut header = info.header.next(compress::NONE, payload.as_slice());
+    header.bytes_written = info.header.bytes_written + bytes.len() as u64;
+    // The fields are all where they were.
+    header.field_offsets = info.header.field_offsets.clone();
+    let layout = layout::layout_of(p);
+    let framed = try!(header::frame(&header, &[]));
+    let offset = match layout {
+        Split => 0,
+        Combined => {
+            let header_size = try!(fs::stat(p)).size - payload.len() as u64;
+            if !info.has_header || framed.len() as u64 != header_size {
+                // The header would change size, so the payload would have to move anyway.
+                return layout::store(p, Combined, &header, payload.as_slice(), |bytes| {
+                    write_atomic(p, bytes)
+                });
+            }
+            header_size
+        }
+    };
+    let mut f = try!(File::open_mode(p, io::Open, io::ReadWrite));
+    try!(f.seek((offset + start) as i64, io::SeekSet));
+    try!(f.write(bytes.as_slice()));
+    match layout {
+        Split => {
+            try!(f.fsync());
+            layout::write_meta(p, &header, payload.as_slice())
+        }
+        Combined => {
+            try!(f.seek(0, io::SeekSet));
+            try!(f.write(framed.as_slice()));
+            f.fsync()
+        }
+    }
+}
+
+/// Leaves `d` at the start of the field named as for `read_field`, going straight there if
+/// `header` records where it is, and returns the offset of the field in the payload.
+fn seek_field<T>(d: &mut CompactDecoder, header: &Header, field: &str) -> IoResult<u64>
+        where T: Decodable<CompactDecoder, IoError> {
+    match header.field_offset(field) {
+        Some(offset) => {
+            try!(d.reader.seek(offset.offset as i64, io::SeekSet));
+            Ok(offset.offset)
+        }
+        None => find_field::<T>(d, field),
+    }
+}
+
+/// Decodes a `T` from `d` up to the field named as for `read_field`, leaving `d` at the start of
+/// the field, and returns the offset of the field in the payload.
+fn find_field<T>(d: &mut CompactDecoder, field: &str) -> IoResult<u64>
+        where T: Decodable<CompactDecoder, IoError> {
+    d.search = Some(FieldSearch {
+        path: field.split('.').map(|s| s.to_string()).collect(),
+        depth: 0,
+        matched: 0,
+        found: None,
+    });
+    let res: IoResult<T> = Decodable::decode(d);
+    match d.search.take().and_then(|search| search.found) {
+        Some(pos) => {
+            try!(d.reader.seek(pos as i64, io::SeekSet));
+            Ok(pos)
+        }
+        None => Err(res.err().unwrap_or(IoError {
+            kind: io::InvalidInput,
+            desc: "the box’s type has no such field",
+            detail: Some(field.to_string()),
+        })),
+    }
+}
+
+fn cant_patch(detail: &str) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: "the field can’t be updated in place",
+        detail: Some(detail.to_string()),
+    }
+}
+
+/// The encoding `header` names, failing if it isn’t one this crate knows.
+pub fn encoding_of(header: &Header) -> IoResult<Encoding> {
+    match Encoding::from_name(header.encoding.as_slice()) {
+        Some(encoding) => Ok(encoding),
+        None => Err(IoError {
+            kind: io::InvalidInput,
+            desc: "the box was written with an unknown encoding",
+            detail: Some(header.encoding.clone()),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::default::Default;
+    use super::{Encoding, encode_compact, decode_compact, write_compact, peek_compact};
+    use super::{read_field, update_field, write_indexed};
+    use super::super::{FileBox, FileBoxOptions, write_value, peek, open_header};
+
+    #[test]
+    fn interned_strings() {
+        let mut map = HashMap::new();
+        for i in range(0i, 100) {
+            map.insert(i, vec!["a fairly long repeated string".to_string(), "b".to_string()]);
+        }
+        let interned = Encoding { intern_strings: true, .. Default::default() };
+        let bytes = encode_compact(&map, &interned).unwrap();
+        assert!(bytes.len() < ::bincode::encode(&map).unwrap().len() / 2);
+        assert_eq!(decode_compact::<HashMap<int, Vec<String>>>(bytes, &interned).unwrap(), map);
+
+        let path = Path::new("target/interned_strings");
+        write_compact(&path, &map, &interned).unwrap();
+        assert_eq!(open_header(&path).unwrap().header.encoding.as_slice(), "interned");
+        assert_eq!(peek_compact::<HashMap<int, Vec<String>>>(&path).unwrap(), map);
+        assert!(peek::<HashMap<int, Vec<String>>>(&path).is_err());
+
+        write_value(&path, &map).unwrap();
+        assert_eq!(peek_compact::<HashMap<int, Vec<String>>>(&path).unwrap(), map);
+    }
+
+    #[test]
+    fn numeric_options() {
+        let (ints, small) = (vec![1u64, 300, -1u64], vec![-2i32, 70000]);
+        let val = (ints.clone(), small.clone(), 1.5f64, 0.1f64);
+        let varint = Encoding { varint: true, .. Default::default() };
+        let bytes = encode_compact(&val, &varint).unwrap();
+        assert!(bytes.len() < ::bincode::encode(&val).unwrap().len());
+        assert_eq!(decode_compact(bytes, &varint).unwrap(), val);
+
+        let floats = Encoding { f32_floats: true, .. Default::default() };
+        let decoded: (Vec<u64>, Vec<i32>, f64, f64) =
+            decode_compact(encode_compact(&val, &floats).unwrap(), &floats).unwrap();
+        let (decoded_ints, decoded_small, exact, lossy) = decoded;
+        assert_eq!((decoded_ints, decoded_small, exact), (ints, small, 1.5));
+        assert!(lossy != 0.1 && (lossy - 0.1).abs() < 1e-6);
+
+        let all = Encoding {
+            intern_strings: true,
+            varint: true,
+            f32_floats: true,
+            .. Default::default()
+        };
+        assert_eq!(all.name().as_slice(), "interned+varint+f32");
+        assert_eq!(Encoding::from_name("interned+varint+f32"), Some(all));
+        assert_eq!(Encoding::from_name("bincode"), Some(Default::default()));
+        assert_eq!(Encoding::from_name("zstd"), None);
+
+        let le = Encoding { little_endian: true, .. Default::default() };
+        assert_eq!(encode_compact(&(1u16, 1i32), &le).unwrap(), vec![1, 0, 1, 0, 0, 0]);
+        assert_eq!(decode_compact(encode_compact(&val, &le).unwrap(), &le).unwrap(), val);
+        assert_eq!(Encoding::from_name("varint+le"),
+                   Some(Encoding { varint: true, little_endian: true, .. Default::default() }));
+    }
+
+    #[deriving(Encodable, Decodable)]
+    struct State {
+        log: Vec<String>,
+        stats: Stats,
+    }
+
+    #[deriving(Encodable, Decodable)]
+    struct Stats {
+        hits: u64,
+        misses: u64,
+    }
+
+    #[test]
+    fn read_one_field() {
+        let path = Path::new("target/read_one_field");
+        let state = State {
+            log: vec!["started".to_string()],
+            stats: Stats { hits: 3, misses: 4 },
+        };
+        write_value(&path, &state).unwrap();
+        assert_eq!(read_field::<State, u64>(&path, "stats.misses").unwrap(), 4);
+        assert_eq!(read_field::<State, Vec<String>>(&path, "log").unwrap(), state.log);
+        assert!(read_field::<State, u64>(&path, "stats.total").is_err());
+
+        update_field::<State, u64>(&path, "stats.hits", &10).unwrap();
+        assert_eq!(read_field::<State, u64>(&path, "stats.hits").unwrap(), 10);
+        assert_eq!(read_field::<State, u64>(&path, "stats.misses").unwrap(), 4);
+        assert_eq!(open_header(&path).unwrap().header.saves, 2);
+        assert!(update_field::<State, Vec<String>>(&path, "log", &Vec::new()).is_err());
+
+        let varint = Encoding { varint: true, intern_strings: true, .. Default::default() };
+        write_compact(&path, &state, &varint).unwrap();
+        assert_eq!(read_field::<State, u64>(&path, "stats.hits").unwrap(), 3);
+    }
+
+    #[test]
+    fn update_indexed_field() {
+        let path = Path::new("target/update_indexed_field");
+        let state = State {
+            log: vec!["started".to_string(), "stopped".to_string()],
+            stats: Stats { hits: 3, misses: 4 },
+        };
+        let interned = Encoding { intern_strings: true, .. Default::default() };
+        write_indexed(&path, &state, &interned).unwrap();
+        let header = open_header(&path).unwrap().header;
+        let fields: Vec<&str> = header.field_offsets.iter().map(|o| o.field.as_slice()).collect();
+        assert_eq!(fields, vec!["log", "stats.hits", "stats.misses", "stats"]);
+        assert_eq!(header.field_offset("stats.misses").unwrap().len, 8);
+        assert_eq!(read_field::<State, u64>(&path, "stats.misses").unwrap(), 4);
+
+        let plain: Encoding = Default::default();
+        write_indexed(&path, &state, &plain).unwrap();
+        update_field::<State, u64>(&path, "stats.misses", &5).unwrap();
+        assert_eq!(read_field::<State, u64>(&path, "stats.misses").unwrap(), 5);
+        assert_eq!(read_field::<State, Vec<String>>(&path, "log").unwrap(), state.log);
+        assert!(!open_header(&path).unwrap().header.field_offsets.is_empty());
+
+        write_compact(&path, &state, &plain).unwrap();
+        assert!(open_header(&path).unwrap().header.field_offsets.is_empty());
+    }
+
+    #[test]
+    fn box_encoding() {
+        let path = Path::new("target/box_encoding");
+        let varint = Encoding { varint: true, .. Default::default() };
+        {
+            let mut b = FileBox::open_new(&path, vec![1u64, 2, 3]).unwrap();
+            b.set_encoding(varint.clone());
+            b.save().unwrap();
+        }
+        assert_eq!(open_header(&path).unwrap().header.encoding.as_slice(), "varint");
+        assert!(FileBox::<Vec<u64>>::open(&path).is_err());
+        {
+            let mut b = FileBox::<Vec<u64>>::open_encoded(&path).unwrap();
+            assert_eq!(*b, vec![1, 2, 3]);
+            b.push(4);
+        }
+        assert_eq!(peek_compact::<Vec<u64>>(&path).unwrap(), vec![1, 2, 3, 4]);
+        assert_eq!(open_header(&path).unwrap().header.encoding.as_slice(), "varint");
+
+        let plain: Encoding = Default::default();
+        let b: FileBox<Vec<u64>> = FileBoxOptions::new().encoding(plain).open(&path).unwrap();
+        assert_eq!(*b, vec![1, 2, 3, 4]);
+        b.close().unwrap();
+        assert_eq!(peek::<Vec<u64>>(&path).unwrap(), vec![1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,141 @@
+//! Encrypting the payloads of boxes.
+
+use std::io::{mod, IoError, IoResult, MemReader, MemWriter};
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use compress::Compressor;
+use encoding::CompactDecoder;
+use super::{FileBox, fnv1a};
+
+/// A symmetric cipher, holding its key, that box payloads are encrypted with.
+///
+/// This crate doesn’t provide any ciphers itself; programs wrap one from a cryptography library.
+/// A cipher that authenticates what it decrypts, such as an AEAD, also protects boxes from being
+/// tampered with, which the encryption alone doesn’t.
+pub trait Cipher {
+    /// The name identifying the cipher in file headers, such as `aes-256-gcm`.
+    fn name(&self) -> &'static str;
+    /// Encrypts `plaintext`.
+    fn encrypt(&self, plaintext: &[u8]) -> IoResult<Vec<u8>>;
+    /// Reverses `encrypt`.
+    fn decrypt(&self, ciphertext: &[u8]) -> IoResult<Vec<u8>>;
+}
+
+static WRONG_KEY: &'static str = "the box was encrypted with a different key";
+
+/// Returns whether `e` is the error opening an encrypted box with the wrong key fails with.
+pub fn is_wrong_key(e: &IoError) -> bool {
+    e.kind == io::InvalidInput && e.desc == WRONG_KEY
+}
+
+/// Stores payloads encrypted with a `Cipher`.
+///
+/// A checksum of the payload is encrypted along with it, so that decrypting with the wrong key is
+/// reported as such instead of as a payload that fails to decode.
+pub struct Encrypted {
+    cipher: Box<Cipher + 'static>,
+}
+
+impl Encrypted {
+    /// Encrypts payloads with `cipher`.
+    pub fn new(cipher: Box<Cipher + 'static>) -> Encrypted {
+        Encrypted {
+            cipher: cipher,
+        }
+    }
+}
+
+impl Compressor for Encrypted {
+    fn name(&self) -> &'static str {
+        self.cipher.name()
+    }
+
+    fn compress(&self, data: &[u8]) -> IoResult<Vec<u8>> {
+        let mut plaintext = Vec::with_capacity(data.len() + 8);
+        try!(plaintext.write_be_u64(fnv1a(data)));
+        plaintext.push_all(data);
+        self.cipher.encrypt(plaintext.as_slice())
+    }
+
+    fn decompress(&self, data: &[u8]) -> IoResult<Vec<u8>> {
+        let wrong_key = IoError {
+            kind: io::InvalidInput,
+            desc: WRONG_KEY,
+            detail: None,
+        };
+        // Ciphers that authenticate what they decrypt refuse to decrypt with the wrong key.
+        let plaintext = try!(self.cipher.decrypt(data).map_err(|_| wrong_key.clone()));
+        if plaintext.len() < 8 {
+            return Err(wrong_key);
+        }
+        let sum = try!(MemReader::new(plaintext.slice_to(8).to_vec()).read_be_u64());
+        let payload = plaintext.slice_from(8);
+        if fnv1a(payload) != sum {
+            return Err(wrong_key);
+        }
+        Ok(payload.to_vec())
+    }
+}
+
+impl<'a, T> FileBox<T> where T: Decodable<CompactDecoder, IoError>
+                              + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Opens a box whose payload is encrypted with `cipher`, failing with an error for which
+    /// `is_wrong_key` is true if the cipher’s key isn’t the one the box was encrypted with. Boxes
+    /// that weren’t encrypted can be opened this way too, and are encrypted from then on.
+    /// Everything the box writes is encrypted, including its autosaves and journal, but its
+    /// header and blobs aren’t.
+    pub fn open_encrypted(p: &Path, cipher: Box<Cipher + 'static>) -> IoResult<FileBox<T>> {
+        FileBox::open_compressed(p, box Encrypted::new(cipher) as Box<Compressor + 'static>)
+    }
+
+    /// Like `open_new`, but the box is encrypted with `cipher`, as for `open_encrypted`.
+    pub fn open_new_encrypted(p: &Path, val: T, cipher: Box<Cipher + 'static>)
+                              -> IoResult<FileBox<T>> {
+        let c = box Encrypted::new(cipher) as Box<Compressor + 'static>;
+        FileBox::open_new_compressed(p, val, c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{File, IoResult};
+    use super::{Cipher, is_wrong_key};
+    use super::super::FileBox;
+
+    /// Stands in for a real cipher.
+    struct Xor(u8);
+
+    impl Cipher for Xor {
+        fn name(&self) -> &'static str {
+            "xor"
+        }
+
+        fn encrypt(&self, plaintext: &[u8]) -> IoResult<Vec<u8>> {
+            let Xor(key) = *self;
+            Ok(plaintext.iter().map(|&b| b ^ key).collect())
+        }
+
+        fn decrypt(&self, ciphertext: &[u8]) -> IoResult<Vec<u8>> {
+            self.encrypt(ciphertext)
+        }
+    }
+
+    #[test]
+    fn encrypt_boxes() {
+        let path = Path::new("target/encrypt_boxes");
+        let secret = "session token".to_string();
+        FileBox::open_new_encrypted(&path, secret.clone(), box Xor(7) as Box<Cipher + 'static>)
+            .unwrap();
+        let bytes = File::open(&path).read_to_end().unwrap();
+        assert!(!bytes.as_slice().windows(secret.len()).any(|w| w == secret.as_bytes()));
+        let b: FileBox<String> = FileBox::open_encrypted(&path, box Xor(7) as Box<Cipher + 'static>)
+                                     .unwrap();
+        assert_eq!(*b, secret);
+        drop(b);
+        match FileBox::<String>::open_encrypted(&path, box Xor(8) as Box<Cipher + 'static>) {
+            Err(ref e) => assert!(is_wrong_key(e)),
+            Ok(_) => panic!("opened a box with the wrong key"),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! Telling apart the ways reading and writing boxes can fail.
+
+use std::io::{mod, IoError};
+
+use header::is_corrupt;
+use lock::is_locked;
+use optimistic::is_save_conflict;
+use schema::SchemaTooNew;
+use typetag::is_type_mismatch;
+
+/// The description of errors from decoding the value of a box.
+pub static UNDECODABLE: &'static str = "the box’s file doesn’t hold a value of its type";
+
+/// The description of errors from encoding the value of a box.
+pub static UNENCODABLE: &'static str = "the box’s value couldn’t be encoded";
+
+/// What went wrong reading or writing a box, for programs that handle some failures differently
+/// from others.
+///
+/// Every function in this crate fails with an `IoError`, so that they can be combined with
+/// `try!` and with the rest of `std::io`. The error is sorted into one of these with `from_read`
+/// or `from_write`, depending on whether it came from reading a box, such as by `open`, or from
+/// writing one, such as by `open_new`, `save` or `delete`. Boxes tag the errors from decoding
+/// and encoding their values where they happen, so only those are sorted as `Decode` and
+/// `Encode`; like failures from elsewhere, such as a value decoded by hand, are sorted as `Io`.
+#[deriving(Clone, PartialEq, Show)]
+pub enum FileBoxError {
+    /// The file couldn’t be read or written, such as because it doesn’t exist or the disk is full.
+    Io(IoError),
+    /// The file is intact, but doesn’t hold a value of the box’s type.
+    Decode(IoError),
+    /// The value couldn’t be encoded in the box’s format.
+    Encode(IoError),
+    /// The file has been damaged, as `is_corrupt` tells.
+    Corrupt(IoError),
+    /// The box is locked by another process, as `is_locked` tells.
+    Locked(IoError),
+    /// Someone else saved the box first, as `is_save_conflict` tells.
+    Conflict(IoError),
+    /// The file holds a different type from the one it was opened as, as `is_type_mismatch`
+    /// tells.
+    TypeMismatch(IoError),
+    /// The file was written by a newer version of its schema than this program supports.
+    VersionMismatch(SchemaTooNew),
+}
+
+impl FileBoxError {
+    /// Sorts an error returned while reading a box.
+    pub fn from_read(e: IoError) -> FileBoxError {
+        match FileBoxError::common(e) {
+            Ok(e) => e,
+            Err(e) => if e.desc == UNDECODABLE { Decode(e) } else { Io(e) },
+        }
+    }
+
+    /// Sorts an error returned while writing a box.
+    pub fn from_write(e: IoError) -> FileBoxError {
+        match FileBoxError::common(e) {
+            Ok(e) => e,
+            Err(e) => if e.desc == UNENCODABLE { Encode(e) } else { Io(e) },
+        }
+    }
+
+    /// Sorts the errors that mean the same whether reading or writing, returning any others.
+    fn common(e: IoError) -> Result<FileBoxError, IoError> {
+        match SchemaTooNew::from_error(&e) {
+            Some(too_new) => return Ok(VersionMismatch(too_new)),
+            None => {}
+        }
+        if is_corrupt(&e) {
+            Ok(Corrupt(e))
+        } else if is_locked(&e) {
+            Ok(Locked(e))
+        } else if is_save_conflict(&e) {
+            Ok(Conflict(e))
+        } else if is_type_mismatch(&e) {
+            Ok(TypeMismatch(e))
+        } else {
+            Err(e)
+        }
+    }
+
+    /// Turns this back into the `IoError` it was made from.
+    pub fn into_io_error(self) -> IoError {
+        match self {
+            Io(e) | Decode(e) | Encode(e) | Corrupt(e) | Locked(e) | Conflict(e) => e,
+            TypeMismatch(e) => e,
+            VersionMismatch(too_new) => too_new.to_error(),
+        }
+    }
+}
+
+/// Tags `e`, an error from decoding the value of a box, so that `from_read` sorts it as `Decode`,
+/// unless it is already one of the errors sorted some other way. What `e` described is kept in its
+/// detail.
+pub fn undecodable(e: IoError) -> IoError {
+    tagged(e, UNDECODABLE)
+}
+
+/// Like `undecodable`, but for errors from encoding the value of a box, sorted as `Encode`.
+pub fn unencodable(e: IoError) -> IoError {
+    tagged(e, UNENCODABLE)
+}
+
+fn tagged(e: IoError, desc: &'static str) -> IoError {
+    if e.desc == desc {
+        return e;
+    }
+    match FileBoxError::common(e) {
+        Ok(sorted) => sorted.into_io_error(),
+        Err(e) => IoError {
+            kind: e.kind,
+            desc: desc,
+            detail: Some(match e.detail {
+                Some(ref detail) => format!("{}: {}", e.desc, detail),
+                None => e.desc.to_string(),
+            }),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{mod, File};
+    use super::{FileBoxError, Io, Decode, Encode, Corrupt, unencodable};
+    use super::super::{FileBox, Split};
+
+    #[test]
+    fn sort_errors() {
+        let missing = FileBox::<Vec<u8>>::open(&Path::new("target/sort_errors_missing"));
+        match FileBoxError::from_read(missing.err().unwrap()) {
+            Io(e) => assert_eq!(e.kind, io::FileNotFound),
+            e => panic!("unexpected error: {}", e),
+        }
+
+        let path = Path::new("target/sort_errors");
+        FileBox::open_new(&path, "a string".to_string()).unwrap();
+        match FileBoxError::from_read(FileBox::<Vec<u64>>::open(&path).err().unwrap()) {
+            Decode(_) => {}
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Errors that didn’t come from decoding or encoding a value aren’t taken for them.
+        let e = io::standard_error(io::InvalidInput);
+        assert_eq!(FileBoxError::from_read(e.clone()).into_io_error(), e);
+        match FileBoxError::from_write(e.clone()) {
+            Io(_) => {}
+            e => panic!("unexpected error: {}", e),
+        }
+        match FileBoxError::from_write(unencodable(e)) {
+            Encode(e) => assert_eq!(e.kind, io::InvalidInput),
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // A split box whose header file doesn’t describe its payload has been damaged.
+        let split = Path::new("target/sort_errors_split");
+        FileBox::open_new(&split, 1u32).unwrap().set_layout(Split);
+        File::create(&split).write(&[0, 0, 0, 2]).unwrap();
+        match FileBoxError::from_read(FileBox::<u32>::open(&split).err().unwrap()) {
+            Corrupt(_) => {}
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+}
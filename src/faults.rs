@@ -0,0 +1,68 @@
+//! Making writes fail on purpose, for testing how programs recover from failed writes and crashes.
+
+use std::io::{mod, IoError};
+use std::sync::{Arc, Mutex};
+
+/// A failure that can be injected into a write.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Fault {
+    /// Only the given number of bytes are written to the temporary file before the write fails.
+    ShortWrite(uint),
+    /// Flushing the temporary file to disk fails.
+    FsyncError,
+    /// Renaming the temporary file over the box’s file fails.
+    RenameError,
+    /// Only the given number of bytes reach the temporary file, which is renamed over the box’s
+    /// file anyway, as can happen when the system crashes on a filesystem that reorders writes.
+    /// The write then fails as though the program had crashed.
+    TornWrite(uint),
+}
+
+/// A queue of failures to inject into writes, one per write.
+///
+/// Clones of a queue share the same failures, so a test can keep one clone to inject failures
+/// into after giving the other to a box with `FileBox::set_faults`.
+#[deriving(Clone)]
+pub struct Faults {
+    queue: Arc<Mutex<Vec<Fault>>>,
+}
+
+impl Faults {
+    /// Creates an empty queue, which doesn’t make writes fail.
+    pub fn new() -> Faults {
+        Faults {
+            queue: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Makes the first write after those that already have failures queued fail with `fault`.
+    pub fn inject(&self, fault: Fault) {
+        self.queue.lock().push(fault);
+    }
+
+    /// The number of failures that haven’t been injected into a write yet.
+    pub fn pending(&self) -> uint {
+        self.queue.lock().len()
+    }
+}
+
+/// Takes the failure to inject into the next write from `faults`, if there is one.
+pub fn take(faults: Option<&Faults>) -> Option<Fault> {
+    faults.and_then(|f| {
+        let mut queue = f.queue.lock();
+        if queue.is_empty() {
+            None
+        } else {
+            Some(queue.remove(0).unwrap())
+        }
+    })
+}
+
+/// The error returned by a write that an injected `fault` made fail.
+pub fn injected(fault: Fault) -> IoError {
+    IoError {
+        kind: io::OtherIoError,
+        desc: "an injected failure made the write fail",
+        detail: Some(fault.to_string()),
+    }
+}
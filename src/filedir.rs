@@ -0,0 +1,101 @@
+//! Keeping values of one type in a directory, one file for each.
+
+use std::io::{IoError, IoResult, MemWriter};
+use std::io::fs::PathExtensions;
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use encoding::CompactDecoder;
+use store::Namespace;
+use super::{FileBox, peek, write_value};
+
+/// A directory of boxes that all hold values of type `T`, each under a string key.
+///
+/// Each value is kept in a box file of its own, named after its key as the boxes of a `Namespace`
+/// are, so values can be loaded, changed and removed independently of each other, and loading one
+/// doesn’t read any of the others.
+pub struct FileDir<T> {
+    ns: Namespace,
+}
+
+impl<T> FileDir<T> {
+    /// Opens the directory `dir`, creating it if it doesn’t exist.
+    pub fn new(dir: &Path) -> IoResult<FileDir<T>> {
+        Ok(FileDir::from_namespace(try!(Namespace::at(dir))))
+    }
+
+    /// Treats the boxes of `ns` as a directory of values of type `T`.
+    pub fn from_namespace(ns: Namespace) -> FileDir<T> {
+        FileDir {
+            ns: ns,
+        }
+    }
+
+    /// The namespace the values are kept in.
+    pub fn namespace(&self) -> &Namespace {
+        &self.ns
+    }
+
+    /// Returns whether there is a value under `key`.
+    pub fn contains(&self, key: &str) -> bool {
+        self.ns.path(key).map_or(false, |p| p.exists())
+    }
+
+    /// Removes the value under `key`, returning whether there was one.
+    pub fn remove(&self, key: &str) -> IoResult<bool> {
+        if !self.contains(key) {
+            return Ok(false);
+        }
+        try!(self.ns.remove(key));
+        Ok(true)
+    }
+
+    /// Lists the keys that have values, in order.
+    pub fn keys(&self) -> IoResult<Vec<String>> {
+        self.ns.names()
+    }
+}
+
+impl<'a, T> FileDir<T> where T: Decodable<CompactDecoder, IoError>
+                            + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Opens the box holding the value under `key`, to be read or changed in place.
+    pub fn open(&self, key: &str) -> IoResult<FileBox<T>> {
+        self.ns.open(key)
+    }
+
+    /// Reads the value under `key` without opening its box, as `peek` does.
+    pub fn get(&self, key: &str) -> IoResult<T> {
+        peek(&try!(self.ns.path(key)))
+    }
+
+    /// Stores `val` under `key`, replacing any value that is already there. The value is written
+    /// straight away, as `write_value` writes it.
+    pub fn insert(&self, key: &str, val: &T) -> IoResult<()> {
+        write_value(&try!(self.ns.path(key)), val)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs;
+    use super::FileDir;
+
+    #[test]
+    fn values_by_key() {
+        let dir = Path::new("target/values_by_key");
+        let _ = fs::rmdir_recursive(&dir);
+        let users: FileDir<(String, u32)> = FileDir::new(&dir).unwrap();
+        users.insert("bob", &("Bob".to_string(), 30)).unwrap();
+        users.insert("alice/admin", &("Alice".to_string(), 40)).unwrap();
+        assert_eq!(users.keys().unwrap(), vec!["alice/admin".to_string(), "bob".to_string()]);
+        {
+            let mut bob = users.open("bob").unwrap();
+            *bob = ("Bob".to_string(), 31);
+        }
+        assert_eq!(users.get("bob").unwrap(), ("Bob".to_string(), 31));
+        assert!(users.remove("bob").unwrap());
+        assert!(!users.remove("bob").unwrap());
+        assert!(!users.contains("bob"));
+        assert!(users.get("bob").is_err());
+    }
+}
@@ -0,0 +1,283 @@
+//! Maps whose files record each change rather than the whole map.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entries;
+use std::hash::Hash;
+use std::io::{mod, File, IoError, IoResult, MemReader, MemWriter};
+use std::io::fs::PathExtensions;
+use serialize::{Decodable, Encodable};
+use bincode::{mod, DecoderReader, EncoderWriter};
+
+use filevec::{frame_bytes, read_frame, replace_file};
+
+static INSERT: u8 = 0;
+static REMOVE: u8 = 1;
+
+/// A map kept in a file that records each insertion and removal as it is made.
+///
+/// Every change is appended to the end of the file as a record of its own, in frames like those of
+/// `FileVec`, so changing the map takes the same time however large it is. The map itself is kept
+/// in memory, and is rebuilt from the records when it is opened. Records superseded by later ones
+/// stay in the file until `compact` rewrites it with one record for each entry, or until it is
+/// compacted automatically as `set_auto_compact` asks, which suits small persistent caches that
+/// would otherwise need a database.
+pub struct FileMap<K, V> {
+    path: Path,
+    entries: HashMap<K, V>,
+    file: File,
+    stale: uint,
+    auto_compact: Option<f64>,
+    reclaimed: u64,
+}
+
+impl<'a, K, V> FileMap<K, V> where K: Decodable<DecoderReader<'a, MemReader>, IoError>
+                                    + Encodable<EncoderWriter<'a, MemWriter>, IoError>
+                                    + Hash + Eq,
+                                 V: Decodable<DecoderReader<'a, MemReader>, IoError>
+                                    + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Opens the map stored in the file at `p`, creating an empty one if the file doesn’t exist.
+    /// A record whose append was cut short by a crash is dropped, along with the change it made.
+    pub fn open(p: &Path) -> IoResult<FileMap<K, V>> {
+        let mut entries = HashMap::new();
+        let mut stale = 0;
+        let mut damaged = false;
+        if p.exists() {
+            let mut r = MemReader::new(try!(File::open(p).read_to_end()));
+            while !r.eof() {
+                let bytes = match read_frame(&mut r) {
+                    Some(bytes) => bytes,
+                    None => {
+                        damaged = true;
+                        break;
+                    }
+                };
+                let mut record = MemReader::new(bytes);
+                let op: u8 = try!(bincode::decode_from(&mut record));
+                let key: K = try!(bincode::decode_from(&mut record));
+                let replaced = if op == INSERT {
+                    let val: V = try!(bincode::decode_from(&mut record));
+                    entries.insert(key, val).is_some()
+                } else {
+                    // The removal itself is stale as well as the insertion it undoes.
+                    stale += 1;
+                    entries.remove(&key).is_some()
+                };
+                if replaced {
+                    stale += 1;
+                }
+            }
+        }
+        let mut map = FileMap {
+            path: p.clone(),
+            entries: entries,
+            file: try!(File::open_mode(p, io::Append, io::Write)),
+            stale: stale,
+            auto_compact: None,
+            reclaimed: 0,
+        };
+        if damaged {
+            // Records appended after the damaged one couldn’t be read if it were left in place.
+            try!(map.compact());
+        }
+        Ok(map)
+    }
+
+    /// Inserts `val` under `key`, recording the change in the file before making it, and returns
+    /// the value that was there before, if any.
+    pub fn insert(&mut self, key: K, val: V) -> IoResult<Option<V>> {
+        try!(self.append(try!(record(INSERT, &key, Some(&val)))));
+        let old = self.entries.insert(key, val);
+        if old.is_some() {
+            self.stale += 1;
+            try!(self.compact_if_due());
+        }
+        Ok(old)
+    }
+
+    /// Removes the entry under `key`, recording the change in the file first, and returns its
+    /// value, if there was one. Nothing is recorded if there was no entry.
+    pub fn remove(&mut self, key: &K) -> IoResult<Option<V>> {
+        if !self.entries.contains_key(key) {
+            return Ok(None);
+        }
+        try!(self.append(try!(record(REMOVE, key, None::<&V>))));
+        self.stale += 2;
+        let old = self.entries.remove(key);
+        try!(self.compact_if_due());
+        Ok(old)
+    }
+
+    /// Rewrites the file with one record for each entry, replacing it atomically, and returns
+    /// how many bytes smaller the file became.
+    pub fn compact(&mut self) -> IoResult<u64> {
+        let mut bytes = Vec::new();
+        for (key, val) in self.entries.iter() {
+            bytes.push_all(try!(record(INSERT, key, Some(val))).as_slice());
+        }
+        let (file, reclaimed) = try!(replace_file(&self.path, bytes.as_slice()));
+        self.file = file;
+        self.stale = 0;
+        self.reclaimed += reclaimed;
+        Ok(reclaimed)
+    }
+
+    /// Compacts the map if it has more stale records than `set_auto_compact` allows.
+    fn compact_if_due(&mut self) -> IoResult<()> {
+        match self.auto_compact {
+            Some(ratio) if self.stale as f64 > self.entries.len() as f64 * ratio => {
+                try!(self.compact());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, frame: Vec<u8>) -> IoResult<()> {
+        try!(self.file.write(frame.as_slice()));
+        self.file.datasync()
+    }
+}
+
+impl<K: Hash + Eq, V> FileMap<K, V> {
+    /// Returns the value under `key`, if there is one.
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key)
+    }
+
+    /// Returns whether there is an entry under `key`.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// The number of entries in the map.
+    pub fn len(&self) -> uint {
+        self.entries.len()
+    }
+
+    /// Returns whether the map has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns an iterator over the entries of the map, in no particular order.
+    pub fn iter(&self) -> Entries<K, V> {
+        self.entries.iter()
+    }
+
+    /// The number of records in the file that no longer describe an entry, which `compact` would
+    /// remove. Programs can compact the map once this grows large compared to `len`.
+    pub fn stale_records(&self) -> uint {
+        self.stale
+    }
+
+    /// The path of the map’s file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Compacts the map after any change that leaves more than `ratio` stale records in the file
+    /// for each entry, or only when `compact` is called if `ratio` is `None`, which is the
+    /// default. A ratio of 1 keeps the file at most about twice the size it would be after
+    /// compacting, for long-running programs that change the same entries over and over.
+    ///
+    /// If compacting fails, the change that was being made fails with its error, though it has
+    /// been made and recorded in the file, which is left as it was before compacting.
+    pub fn set_auto_compact(&mut self, ratio: Option<f64>) {
+        self.auto_compact = ratio;
+    }
+
+    /// The total number of bytes compacting the map has removed from its file since the map was
+    /// opened, whether it was compacted by `compact` or automatically.
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.reclaimed
+    }
+}
+
+/// A framed record of an insertion or removal.
+fn record<'a, K, V>(op: u8, key: &K, val: Option<&V>) -> IoResult<Vec<u8>>
+        where K: Encodable<EncoderWriter<'a, MemWriter>, IoError>,
+              V: Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    let mut bytes = try!(bincode::encode(&op));
+    bytes.push_all(try!(bincode::encode(key)).as_slice());
+    match val {
+        Some(val) => bytes.push_all(try!(bincode::encode(val)).as_slice()),
+        None => {}
+    }
+    frame_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::default::Default;
+    use std::io::{fs, USER_RWX};
+    use atomic::TempFiles;
+    use super::FileMap;
+
+    #[test]
+    fn record_changes() {
+        let path = Path::new("target/record_changes");
+        let _ = fs::unlink(&path);
+        {
+            let mut m = FileMap::open(&path).unwrap();
+            assert_eq!(m.insert("a".to_string(), 1u32).unwrap(), None);
+            m.insert("b".to_string(), 2).unwrap();
+            assert_eq!(m.insert("a".to_string(), 3).unwrap(), Some(1));
+            assert_eq!(m.remove(&"b".to_string()).unwrap(), Some(2));
+            assert_eq!(m.remove(&"c".to_string()).unwrap(), None);
+            assert_eq!(m.stale_records(), 3);
+        }
+        let mut m: FileMap<String, u32> = FileMap::open(&path).unwrap();
+        assert_eq!(m.len(), 1);
+        assert_eq!(m.get(&"a".to_string()), Some(&3));
+        assert_eq!(m.stale_records(), 3);
+        let before = fs::stat(&path).unwrap().size;
+        m.compact().unwrap();
+        assert!(fs::stat(&path).unwrap().size < before);
+        assert_eq!(m.stale_records(), 0);
+        drop(m);
+        let m: FileMap<String, u32> = FileMap::open(&path).unwrap();
+        assert_eq!(m.get(&"a".to_string()), Some(&3));
+    }
+
+    #[test]
+    fn compact_automatically() {
+        let path = Path::new("target/compact_automatically");
+        let _ = fs::unlink(&path);
+        let mut m = FileMap::open(&path).unwrap();
+        m.set_auto_compact(Some(2.0));
+        m.insert("a".to_string(), 0u32).unwrap();
+        m.insert("b".to_string(), 0).unwrap();
+        for i in range(1u32, 6) {
+            m.insert("a".to_string(), i).unwrap();
+        }
+        // Replacing `a` the fifth time left more than two stale records for each entry.
+        assert_eq!(m.stale_records(), 0);
+        let reclaimed = m.reclaimed_bytes();
+        assert!(reclaimed > 0);
+        m.insert("b".to_string(), 1).unwrap();
+        assert_eq!(m.stale_records(), 1);
+        assert!(m.compact().unwrap() > 0);
+        assert!(m.reclaimed_bytes() > reclaimed);
+        drop(m);
+        let m: FileMap<String, u32> = FileMap::open(&path).unwrap();
+        assert_eq!((m.get(&"a".to_string()), m.get(&"b".to_string())), (Some(&5), Some(&1)));
+    }
+
+    #[test]
+    fn failed_auto_compaction() {
+        let path = Path::new("target/failed_auto_compaction");
+        let _ = fs::unlink(&path);
+        let mut m = FileMap::open(&path).unwrap();
+        m.set_auto_compact(Some(0.5));
+        m.insert("a".to_string(), 0u32).unwrap();
+        // Nothing can be written where the new file would go.
+        let temp: TempFiles = Default::default();
+        fs::mkdir(&temp.path_for(&path), USER_RWX).unwrap();
+        assert!(m.insert("a".to_string(), 1).is_err());
+        fs::rmdir(&temp.path_for(&path)).unwrap();
+        m.insert("b".to_string(), 2).unwrap();
+        drop(m);
+        let m: FileMap<String, u32> = FileMap::open(&path).unwrap();
+        assert_eq!((m.get(&"a".to_string()), m.get(&"b".to_string())), (Some(&1), Some(&2)));
+    }
+}
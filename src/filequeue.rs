@@ -0,0 +1,251 @@
+//! Queues whose items stay on disk until their processing is committed.
+
+use std::collections::RingBuf;
+use std::io::{mod, File, IoError, IoResult, MemReader, MemWriter};
+use std::io::fs::PathExtensions;
+use serialize::{Decodable, Encodable};
+use bincode::{mod, DecoderReader, EncoderWriter};
+
+use filevec::{frame_bytes, read_frame, replace_file};
+
+static PUSH: u8 = 0;
+static COMMIT: u8 = 1;
+
+/// A first-in, first-out queue kept in a file, for simple job queues that mustn’t lose jobs.
+///
+/// Pushed items are appended to the file straight away, as the elements of a `FileVec` are.
+/// Popping an item hands it out without removing it from the file: that only happens when
+/// `commit` is called, once the program has finished with everything it has popped. Items popped
+/// but never committed, such as because the program crashed while processing them, are handed
+/// out again the next time the queue is opened, or after `rollback`, so every item is processed at
+/// least once.
+///
+/// Commits are recorded by appending to the file as well, and the records of items that have been
+/// committed stay there until the queue is emptied, or until `compact` rewrites the file, which
+/// `set_auto_compact` can have done automatically.
+pub struct FileQueue<T> {
+    path: Path,
+    items: RingBuf<T>,
+    file: File,
+    popped: uint,
+    stale: uint,
+    auto_compact: Option<f64>,
+    reclaimed: u64,
+}
+
+impl<'a, T> FileQueue<T> where T: Decodable<DecoderReader<'a, MemReader>, IoError>
+                              + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Opens the queue stored in the file at `p`, creating an empty one if the file doesn’t exist.
+    /// Every item that hasn’t been committed is in the queue, including those that were popped.
+    pub fn open(p: &Path) -> IoResult<FileQueue<T>> {
+        let mut items = RingBuf::new();
+        let mut stale = 0;
+        let mut damaged = false;
+        if p.exists() {
+            let mut r = MemReader::new(try!(File::open(p).read_to_end()));
+            while !r.eof() {
+                let bytes = match read_frame(&mut r) {
+                    Some(bytes) => bytes,
+                    None => {
+                        damaged = true;
+                        break;
+                    }
+                };
+                let mut record = MemReader::new(bytes);
+                let op: u8 = try!(bincode::decode_from(&mut record));
+                if op == PUSH {
+                    items.push_back(try!(bincode::decode_from(&mut record)));
+                } else {
+                    let count: u64 = try!(bincode::decode_from(&mut record));
+                    for _ in range(0, count) {
+                        items.pop_front();
+                    }
+                    stale += count as uint + 1;
+                }
+            }
+        }
+        let mut queue = FileQueue {
+            path: p.clone(),
+            items: items,
+            file: try!(File::open_mode(p, io::Append, io::Write)),
+            popped: 0,
+            stale: stale,
+            auto_compact: None,
+            reclaimed: 0,
+        };
+        if damaged {
+            // Records appended after the damaged one couldn’t be read if it were left in place.
+            try!(queue.compact());
+        }
+        Ok(queue)
+    }
+
+    /// Adds `val` to the back of the queue, waiting for it to reach the disk.
+    pub fn push(&mut self, val: T) -> IoResult<()> {
+        let mut bytes = try!(bincode::encode(&PUSH));
+        bytes.push_all(try!(bincode::encode(&val)).as_slice());
+        try!(self.append(bytes));
+        self.items.push_back(val);
+        Ok(())
+    }
+
+    /// Removes every item popped since the last commit from the file, so that they are never
+    /// handed out again.
+    pub fn commit(&mut self) -> IoResult<()> {
+        if self.popped == 0 {
+            return Ok(());
+        }
+        if self.items.is_empty() {
+            // Nothing in the file is needed any more, so it can start again from nothing.
+            let (file, reclaimed) = try!(replace_file(&self.path, &[]));
+            self.file = file;
+            self.stale = 0;
+            self.reclaimed += reclaimed;
+        } else {
+            let mut bytes = try!(bincode::encode(&COMMIT));
+            bytes.push_all(try!(bincode::encode(&(self.popped as u64))).as_slice());
+            try!(self.append(bytes));
+            self.stale += self.popped + 1;
+        }
+        self.popped = 0;
+        match self.auto_compact {
+            Some(ratio) if self.stale as f64 > self.items.len() as f64 * ratio => {
+                try!(self.compact());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Puts back every item popped since the last commit, at the front of the queue in the order
+    /// they were pushed, by reading them from the file again.
+    pub fn rollback(&mut self) -> IoResult<()> {
+        if self.popped > 0 {
+            let path = self.path.clone();
+            *self = try!(FileQueue::open(&path));
+        }
+        Ok(())
+    }
+
+    /// Rewrites the file with just the items in the queue, replacing it atomically. This fails if
+    /// items have been popped since the last commit, which wouldn’t be in the new file. Returns
+    /// how many bytes smaller the file became.
+    pub fn compact(&mut self) -> IoResult<u64> {
+        if self.popped > 0 {
+            return Err(IoError {
+                kind: io::InvalidInput,
+                desc: "the queue has popped items that haven’t been committed",
+                detail: Some(self.path.display().to_string()),
+            });
+        }
+        let mut bytes = Vec::new();
+        for val in self.items.iter() {
+            let mut record = try!(bincode::encode(&PUSH));
+            record.push_all(try!(bincode::encode(val)).as_slice());
+            bytes.push_all(try!(frame_bytes(record)).as_slice());
+        }
+        let (file, reclaimed) = try!(replace_file(&self.path, bytes.as_slice()));
+        self.file = file;
+        self.stale = 0;
+        self.reclaimed += reclaimed;
+        Ok(reclaimed)
+    }
+
+    fn append(&mut self, record: Vec<u8>) -> IoResult<()> {
+        try!(self.file.write(try!(frame_bytes(record)).as_slice()));
+        self.file.datasync()
+    }
+}
+
+impl<T> FileQueue<T> {
+    /// Removes the item at the front of the queue and hands it out. It stays in the file until
+    /// `commit` is called.
+    pub fn pop(&mut self) -> Option<T> {
+        let val = self.items.pop_front();
+        if val.is_some() {
+            self.popped += 1;
+        }
+        val
+    }
+
+    /// Returns the item at the front of the queue, which `pop` would hand out next.
+    pub fn peek(&self) -> Option<&T> {
+        self.items.front()
+    }
+
+    /// The number of items waiting to be popped.
+    pub fn len(&self) -> uint {
+        self.items.len()
+    }
+
+    /// Returns whether there are no items waiting to be popped.
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// The number of items popped since the last commit.
+    pub fn uncommitted(&self) -> uint {
+        self.popped
+    }
+
+    /// The number of records in the file that no longer describe an item in the queue, which
+    /// `compact` would remove.
+    pub fn stale_records(&self) -> uint {
+        self.stale
+    }
+
+    /// The path of the queue’s file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Compacts the queue after any commit that leaves more than `ratio` stale records in the
+    /// file for each item in the queue, or only when `compact` is called if `ratio` is `None`,
+    /// which is the default.
+    ///
+    /// If compacting fails, the commit fails with its error, though it has been made and recorded
+    /// in the file, which is left as it was before compacting.
+    pub fn set_auto_compact(&mut self, ratio: Option<f64>) {
+        self.auto_compact = ratio;
+    }
+
+    /// The total number of bytes compacting the queue has removed from its file since the queue
+    /// was opened, counting commits that emptied it.
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.reclaimed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs;
+    use super::FileQueue;
+
+    #[test]
+    fn redeliver_uncommitted_items() {
+        let path = Path::new("target/redeliver_uncommitted_items");
+        let _ = fs::unlink(&path);
+        {
+            let mut q = FileQueue::open(&path).unwrap();
+            for job in range(1u32, 5) {
+                q.push(job).unwrap();
+            }
+            assert_eq!(q.pop(), Some(1));
+            q.commit().unwrap();
+            assert_eq!(q.pop(), Some(2));
+            // The program stops before finishing job 2.
+        }
+        let mut q: FileQueue<u32> = FileQueue::open(&path).unwrap();
+        assert_eq!(q.peek(), Some(&2));
+        assert_eq!(q.len(), 3);
+        assert_eq!(q.pop(), Some(2));
+        assert_eq!(q.pop(), Some(3));
+        q.rollback().unwrap();
+        assert_eq!(q.len(), 3);
+
+        while q.pop().is_some() {}
+        q.commit().unwrap();
+        assert_eq!(q.stale_records(), 0);
+        assert!(FileQueue::<u32>::open(&path).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,518 @@
+//! Vectors whose files are appended to rather than rewritten.
+
+use std::io::{mod, fs, timer, File, IoError, IoResult, MemReader, MemWriter};
+use std::io::fs::PathExtensions;
+use std::cmp;
+use std::default::Default;
+use std::iter::Range;
+use std::mem;
+use std::slice;
+use std::time::Duration;
+use serialize::{Decodable, Encodable};
+use bincode::{mod, DecoderReader, EncoderWriter};
+
+use atomic::TempFiles;
+use header::CORRUPT;
+use lock::Lock;
+use super::{FileId, fnv1a};
+
+/// A vector kept in a file that grows as elements are pushed onto it.
+///
+/// A `FileBox<Vec<T>>` writes the whole vector every time it is written, which gets slower as the
+/// vector grows. A `FileVec` instead appends each pushed element to the end of its file straight
+/// away, so pushing takes the same time however long the vector is. Each element is stored as its
+/// length and checksum followed by its bincode encoding, and an element whose append was cut short
+/// by a crash is dropped when the vector is next opened.
+///
+/// Each element has a sequence number, which is its index unless elements have been removed from
+/// the front of the vector with `truncate_before`: that keeps the sequence numbers of the elements
+/// it leaves, and stores the first of them at the start of the file. Elements can otherwise only
+/// be removed by `retain`, which rewrites the whole file, as `compact` does.
+pub struct FileVec<T> {
+    path: Path,
+    items: Vec<T>,
+    /// The sequence number of the first element of `items`.
+    base: uint,
+    file: File,
+}
+
+/// Marks the record holding a vector’s first sequence number at the start of its file. No element
+/// frame can start with it, since it is too long a length for any element.
+static BASE_MARK: u64 = 0xffff_ffff_ffff_ffff;
+
+impl<'a, T> FileVec<T> where T: Decodable<DecoderReader<'a, MemReader>, IoError>
+                             + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Opens the vector stored in the file at `p`, creating an empty one if the file doesn’t
+    /// exist.
+    pub fn open(p: &Path) -> IoResult<FileVec<T>> {
+        let (base, items, damaged) = try!(read_elements(p));
+        let mut v = FileVec {
+            path: p.clone(),
+            items: items,
+            base: base,
+            file: try!(File::open_mode(p, io::Append, io::Write)),
+        };
+        if damaged {
+            // Elements appended after the damaged one couldn’t be read if it were left in place.
+            try!(v.compact());
+        }
+        Ok(v)
+    }
+
+    /// Appends `val` to the vector and to its file, waiting for it to reach the disk.
+    pub fn push(&mut self, val: T) -> IoResult<()> {
+        let frame = try!(frame(&val));
+        try!(self.file.write(frame.as_slice()));
+        try!(self.file.datasync());
+        self.items.push(val);
+        Ok(())
+    }
+
+
+    /// Follows the vector stored in the file at `p` from another process, handing out each element
+    /// with sequence number `seq` on as it is appended, or from the first element if elements up to
+    /// `seq` have been removed by `truncate_before`. See `Tail`.
+    pub fn tail_from(p: &Path, seq: uint) -> IoResult<Tail<T>> {
+        let id = try!(FileId::of(p));
+        let mut file = try!(File::open(p));
+        // A rewrite replaces the whole file at once, so the record of the first sequence number is
+        // never only partly written.
+        let bytes = try!(file.read_to_end());
+        let (base, start) = try!(read_base(p, bytes.as_slice()));
+        Ok(Tail {
+            path: p.clone(),
+            file: file,
+            id: id,
+            buf: bytes.slice_from(start).to_vec(),
+            next: base,
+            seq: seq,
+            poll: Duration::milliseconds(50),
+        })
+    }
+
+    /// Keeps only the elements for which `f` returns true, and rewrites the file to match.
+    pub fn retain(&mut self, f: |&T| -> bool) -> IoResult<()> {
+        self.items.retain(f);
+        try!(self.compact());
+        Ok(())
+    }
+
+    /// Removes the elements with sequence numbers before `seq`, or every element if there are
+    /// fewer, and rewrites the file without them, as `compact` does, to reclaim the space they took
+    /// up. Returns how many were removed. The remaining elements keep their sequence numbers, so a
+    /// `Tail` following the vector fails when it next checks the file, but one opened again from
+    /// the `seq` it had reached carries on where it left off.
+    pub fn truncate_before(&mut self, seq: uint) -> IoResult<uint> {
+        if seq <= self.base {
+            return Ok(0);
+        }
+        let n = cmp::min(seq - self.base, self.items.len());
+        if n == 0 {
+            return Ok(0);
+        }
+        let items = mem::replace(&mut self.items, Vec::new());
+        self.items = items.into_iter().skip(n).collect();
+        self.base += n;
+        try!(self.compact());
+        Ok(n)
+    }
+
+    /// Rewrites the file with just the vector’s elements, replacing it atomically, and returns
+    /// how many bytes smaller the file became. Only a damaged element left by a crash makes it
+    /// smaller, since the file holds nothing else that isn’t in the vector.
+    pub fn compact(&mut self) -> IoResult<u64> {
+        let mut bytes = Vec::new();
+        if self.base > 0 {
+            try!(bytes.write_be_u64(BASE_MARK));
+            let mut base = Vec::new();
+            try!(base.write_be_u64(self.base as u64));
+            bytes.push_all(try!(frame_bytes(base)).as_slice());
+        }
+        for val in self.items.iter() {
+            bytes.push_all(try!(frame(val)).as_slice());
+        }
+        let (file, reclaimed) = try!(replace_file(&self.path, bytes.as_slice()));
+        self.file = file;
+        Ok(reclaimed)
+    }
+}
+
+impl<'a, T> FileVec<T> where T: Decodable<DecoderReader<'a, MemReader>, IoError>
+                             + Encodable<EncoderWriter<'a, MemWriter>, IoError> + Clone {
+    /// Appends every element of `vals` to the vector, in order, with a single write to its file
+    /// and a single wait for them to reach the disk, and returns their sequence numbers. This is
+    /// much faster than pushing them one at a time for producers that emit bursts of elements.
+    ///
+    /// The vector is locked, as `FileBox::open_locked` locks a box, for as long as the batch is
+    /// being appended, and the file is read again once the lock is held, so that processes
+    /// appending batches to the same vector are given sequence numbers that don’t overlap. The
+    /// vector picks up the elements they appended along the way.
+    ///
+    /// If a crash cuts the write short, the elements that were written in full before it are
+    /// kept when the vector is next opened, and the rest are dropped.
+    pub fn append_batch(&mut self, vals: &[T]) -> IoResult<Range<uint>> {
+        let mut bytes = Vec::new();
+        for val in vals.iter() {
+            bytes.push_all(try!(frame(val)).as_slice());
+        }
+        let _lock = try!(Lock::acquire(&self.path, true));
+        let (base, items, damaged) = try!(read_elements(&self.path));
+        self.base = base;
+        self.items = items;
+        // Another process may have rewritten the file since it was opened.
+        self.file = try!(File::open_mode(&self.path, io::Append, io::Write));
+        if damaged {
+            try!(self.compact());
+        }
+        try!(self.file.write(bytes.as_slice()));
+        try!(self.file.datasync());
+        let start = self.base + self.items.len();
+        self.items.push_all(vals);
+        Ok(range(start, self.base + self.items.len()))
+    }
+}
+
+impl<T> FileVec<T> {
+    /// The number of elements in the vector.
+    pub fn len(&self) -> uint {
+        self.items.len()
+    }
+
+    /// Returns whether the vector has no elements.
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Returns an iterator over the elements of the vector.
+    pub fn iter(&self) -> slice::Items<T> {
+        self.items.iter()
+    }
+
+    /// The elements of the vector.
+    pub fn as_slice(&self) -> &[T] {
+        self.items.as_slice()
+    }
+
+    /// The sequence number of the first element, which `as_slice` puts at index 0, or of the
+    /// next element to be pushed if the vector is empty.
+    pub fn first_seq(&self) -> uint {
+        self.base
+    }
+
+    /// The path of the vector’s file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// The elements of a `FileVec` from a given sequence number on, read from its file as they are
+/// appended, so that a process can consume the vector another process pushes onto as a feed of
+/// events.
+///
+/// `try_next` returns the next element if it has been appended, and `None` otherwise. Iterating
+/// over the tail blocks instead, checking the file `poll` apart until the next element arrives,
+/// and never ends. Rewriting the vector with `retain`, `compact` or `truncate_before` replaces its
+/// file, so the tail fails once that has happened. After `truncate_before` the elements keep their
+/// sequence numbers, so a new tail opened from `seq` picks up where the old one stopped.
+pub struct Tail<T> {
+    path: Path,
+    file: File,
+    id: FileId,
+    /// What has been read from the file but not yet handed out.
+    buf: Vec<u8>,
+    /// The sequence number of the first element in `buf`.
+    next: uint,
+    seq: uint,
+    poll: Duration,
+}
+
+impl<'a, T> Tail<T> where T: Decodable<DecoderReader<'a, MemReader>, IoError> {
+    /// Returns the next element if it has been appended, without waiting for it.
+    pub fn try_next(&mut self) -> IoResult<Option<T>> {
+        loop {
+            let bytes = match try!(self.take_frame()) {
+                Some(bytes) => bytes,
+                None => {
+                    let read = try!(self.file.read_to_end());
+                    if read.is_empty() {
+                        try!(self.check_file());
+                        return Ok(None);
+                    }
+                    self.buf.push_all(read.as_slice());
+                    continue;
+                }
+            };
+            self.next += 1;
+            if self.next > self.seq {
+                self.seq = self.next;
+                return Ok(Some(try!(bincode::decode(bytes))));
+            }
+        }
+    }
+
+    /// Sets how long iterating waits between checks of the file for new elements, which is 50
+    /// milliseconds unless it is set.
+    pub fn set_poll_interval(&mut self, poll: Duration) {
+        self.poll = poll;
+    }
+
+    /// The sequence number of the element `try_next` returns next.
+    pub fn seq(&self) -> uint {
+        self.seq
+    }
+
+    /// Removes the next complete frame from `buf`, if it holds one.
+    fn take_frame(&mut self) -> IoResult<Option<Vec<u8>>> {
+        if self.buf.len() < 16 {
+            return Ok(None);
+        }
+        let mut r = MemReader::new(self.buf.slice_to(16).to_vec());
+        let len = try!(r.read_be_u64()) as uint;
+        let sum = try!(r.read_be_u64());
+        if self.buf.len() < 16 + len {
+            return Ok(None);
+        }
+        let bytes = self.buf.slice(16, 16 + len).to_vec();
+        if fnv1a(bytes.as_slice()) != sum {
+            return Err(IoError {
+                kind: io::InvalidInput,
+                desc: CORRUPT,
+                detail: Some(format!("{}: element {}", self.path.display(), self.next)),
+            });
+        }
+        self.buf = self.buf.slice_from(16 + len).to_vec();
+        Ok(Some(bytes))
+    }
+
+    /// Fails if the vector’s file has been replaced since the tail was opened.
+    fn check_file(&self) -> IoResult<()> {
+        if try!(FileId::of_existing(&self.path)) != Some(self.id.clone()) {
+            return Err(IoError {
+                kind: io::OtherIoError,
+                desc: "the vector’s file was rewritten while it was being followed",
+                detail: Some(self.path.display().to_string()),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<'a, T> Iterator<IoResult<T>> for Tail<T>
+        where T: Decodable<DecoderReader<'a, MemReader>, IoError> {
+    fn next(&mut self) -> Option<IoResult<T>> {
+        loop {
+            match self.try_next() {
+                Ok(Some(val)) => return Some(Ok(val)),
+                Ok(None) => timer::sleep(self.poll),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Reads the vector stored in the file at `p`, returning the sequence number of its first element,
+/// its elements, and whether they were cut short by a damaged element. A vector with no file is
+/// empty.
+fn read_elements<'a, T>(p: &Path) -> IoResult<(uint, Vec<T>, bool)>
+        where T: Decodable<DecoderReader<'a, MemReader>, IoError> {
+    let mut items = Vec::new();
+    if !p.exists() {
+        return Ok((0, items, false));
+    }
+    let bytes = try!(File::open(p).read_to_end());
+    let (base, start) = try!(read_base(p, bytes.as_slice()));
+    let mut r = MemReader::new(bytes.slice_from(start).to_vec());
+    while !r.eof() {
+        match read_frame(&mut r) {
+            Some(bytes) => items.push(try!(bincode::decode(bytes))),
+            None => return Ok((base, items, true)),
+        }
+    }
+    Ok((base, items, false))
+}
+
+fn frame<'a, T: Encodable<EncoderWriter<'a, MemWriter>, IoError>>(val: &T) -> IoResult<Vec<u8>> {
+    frame_bytes(try!(bincode::encode(val)))
+}
+
+/// Puts `bytes` in a frame of their own, with their length and checksum in front of them.
+pub fn frame_bytes(bytes: Vec<u8>) -> IoResult<Vec<u8>> {
+    let mut frame = Vec::with_capacity(bytes.len() + 16);
+    try!(frame.write_be_u64(bytes.len() as u64));
+    try!(frame.write_be_u64(fnv1a(bytes.as_slice())));
+    frame.push_all(bytes.as_slice());
+    Ok(frame)
+}
+
+/// Replaces the file at `p` with `bytes` atomically, returning the new file opened for appending
+/// and how many bytes smaller it is than the old one.
+///
+/// The new file is opened before it takes the old one’s place, and renaming it is the last thing
+/// that can fail, so if this fails the file at `p` is the old one, and a handle to it that the
+/// caller keeps appending to is still the right file.
+pub fn replace_file(p: &Path, bytes: &[u8]) -> IoResult<(File, u64)> {
+    let before = try!(fs::stat(p)).size;
+    let temp: TempFiles = Default::default();
+    let tmp = temp.path_for(p);
+    let res = File::create(&tmp).and_then(|mut f| {
+        try!(f.write(bytes));
+        f.fsync()
+    }).and_then(|()| File::open_mode(&tmp, io::Append, io::Write)).and_then(|file| {
+        try!(fs::rename(&tmp, p));
+        Ok(file)
+    });
+    let file = match res {
+        Ok(file) => file,
+        Err(e) => {
+            let _ = fs::unlink(&tmp);
+            return Err(e);
+        }
+    };
+    let after = bytes.len() as u64;
+    Ok((file, if before > after { before - after } else { 0 }))
+}
+
+/// Reads the record of the first sequence number from the start of the vector file at `p`, which
+/// holds `bytes`, and returns it and where the elements start, or 0 for both if there is none.
+fn read_base(p: &Path, bytes: &[u8]) -> IoResult<(uint, uint)> {
+    let mut r = MemReader::new(bytes.to_vec());
+    match r.read_be_u64() {
+        Ok(mark) if mark == BASE_MARK => {}
+        _ => return Ok((0, 0)),
+    }
+    match read_frame(&mut r) {
+        Some(base) if base.len() == 8 => {
+            let first = try!(MemReader::new(base).read_be_u64());
+            Ok((first as uint, 8 + 16 + 8))
+        }
+        _ => Err(IoError {
+            kind: io::InvalidInput,
+            desc: CORRUPT,
+            detail: Some(format!("{}: first sequence number", p.display())),
+        }),
+    }
+}
+
+/// Reads the next frame from `r`, or returns `None` if it is incomplete or damaged.
+pub fn read_frame(r: &mut MemReader) -> Option<Vec<u8>> {
+    let (len, sum) = match (r.read_be_u64(), r.read_be_u64()) {
+        (Ok(len), Ok(sum)) => (len, sum),
+        _ => return None,
+    };
+    match r.read_exact(len as uint) {
+        Ok(bytes) if fnv1a(bytes.as_slice()) == sum => Some(bytes),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{fs, File, Append, Write};
+    use super::FileVec;
+
+    #[test]
+    fn append_elements() {
+        let path = Path::new("target/append_elements");
+        let _ = fs::unlink(&path);
+        {
+            let mut v = FileVec::open(&path).unwrap();
+            for s in ["a", "b", "c"].iter() {
+                v.push(s.to_string()).unwrap();
+            }
+        }
+        let size = fs::stat(&path).unwrap().size;
+        // A crash part of the way through appending another element.
+        File::open_mode(&path, Append, Write).unwrap().write(&[0, 0, 0]).unwrap();
+
+        let mut v: FileVec<String> = FileVec::open(&path).unwrap();
+        assert_eq!(v.iter().map(|s| s.as_slice()).collect::<Vec<_>>(), vec!["a", "b", "c"]);
+        assert_eq!(fs::stat(&path).unwrap().size, size);
+        v.push("d".to_string()).unwrap();
+        v.retain(|s| s.as_slice() != "b").unwrap();
+        drop(v);
+        let v: FileVec<String> = FileVec::open(&path).unwrap();
+        assert_eq!(v.len(), 3);
+        assert_eq!(v.as_slice()[2].as_slice(), "d");
+    }
+
+    #[test]
+    fn append_batches() {
+        let path = Path::new("target/append_batches");
+        let _ = fs::unlink(&path);
+        let mut v = FileVec::open(&path).unwrap();
+        v.push(0u32).unwrap();
+        let batch: Vec<u32> = range(1, 1001).collect();
+        assert_eq!(v.append_batch(batch.as_slice()).unwrap().collect::<Vec<_>>(),
+                   range(1u, 1001).collect::<Vec<_>>());
+        drop(v);
+        let v: FileVec<u32> = FileVec::open(&path).unwrap();
+        assert_eq!(v.len(), 1001);
+        assert_eq!(v.as_slice()[1000], 1000);
+    }
+
+    #[test]
+    fn two_appenders() {
+        let path = Path::new("target/two_appenders");
+        let _ = fs::unlink(&path);
+        let mut a = FileVec::open(&path).unwrap();
+        let mut b = FileVec::open(&path).unwrap();
+        assert_eq!(a.append_batch(&[1u32, 2]).unwrap().collect::<Vec<_>>(), vec![0, 1]);
+        // The second appender sees the first one’s batch before numbering its own.
+        assert_eq!(b.append_batch(&[3u32]).unwrap().collect::<Vec<_>>(), vec![2]);
+        assert_eq!(b.as_slice(), [1, 2, 3].as_slice());
+        assert_eq!(a.append_batch(&[4u32]).unwrap().collect::<Vec<_>>(), vec![3]);
+        drop(a);
+        drop(b);
+        let v: FileVec<u32> = FileVec::open(&path).unwrap();
+        assert_eq!(v.as_slice(), [1, 2, 3, 4].as_slice());
+    }
+
+    #[test]
+    fn tail_appended_elements() {
+        let path = Path::new("target/tail_appended_elements");
+        let _ = fs::unlink(&path);
+        let mut v = FileVec::open(&path).unwrap();
+        v.append_batch(&[0u32, 1, 2]).unwrap();
+        let mut tail = FileVec::<u32>::tail_from(&path, 2).unwrap();
+        assert_eq!(tail.try_next().unwrap(), Some(2));
+        assert_eq!(tail.try_next().unwrap(), None);
+        v.push(3).unwrap();
+        assert_eq!(tail.next().unwrap().unwrap(), 3);
+        assert_eq!(tail.seq(), 4);
+
+        v.retain(|&n| n > 0).unwrap();
+        assert!(tail.try_next().is_err());
+    }
+
+    #[test]
+    fn truncate_old_elements() {
+        let path = Path::new("target/truncate_old_elements");
+        let _ = fs::unlink(&path);
+        let mut v = FileVec::open(&path).unwrap();
+        v.append_batch(range(0u32, 10).collect::<Vec<_>>().as_slice()).unwrap();
+        let size = fs::stat(&path).unwrap().size;
+        assert_eq!(v.truncate_before(4).unwrap(), 4);
+        assert!(fs::stat(&path).unwrap().size < size);
+        drop(v);
+        let mut v: FileVec<u32> = FileVec::open(&path).unwrap();
+        assert_eq!(v.as_slice()[0], 4);
+        assert_eq!(v.first_seq(), 4);
+        // Sequence numbers are kept, so truncating before 4 again removes nothing.
+        assert_eq!(v.truncate_before(4).unwrap(), 0);
+        assert_eq!(v.append_batch(&[10, 11]).unwrap().collect::<Vec<_>>(), vec![10, 11]);
+
+        let mut tail = FileVec::<u32>::tail_from(&path, 10).unwrap();
+        assert_eq!(tail.try_next().unwrap(), Some(10));
+        assert_eq!(tail.try_next().unwrap(), Some(11));
+        assert_eq!(v.truncate_before(11).unwrap(), 7);
+        assert!(tail.try_next().is_err());
+        let mut tail = FileVec::<u32>::tail_from(&path, tail.seq()).unwrap();
+        assert_eq!(tail.try_next().unwrap(), None);
+        v.push(12).unwrap();
+        assert_eq!(tail.try_next().unwrap(), Some(12));
+
+        assert_eq!(v.truncate_before(100).unwrap(), 2);
+        assert!(v.is_empty());
+        assert_eq!(v.first_seq(), 13);
+    }
+}
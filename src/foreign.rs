@@ -0,0 +1,118 @@
+//! Storing values of types that can’t be encoded themselves.
+
+use std::default::Default;
+use serialize::{Decodable, Decoder, Encodable, Encoder};
+
+/// Turns values of a type from another crate into bytes and back.
+///
+/// Boxes can only hold values whose types implement `Encodable` and `Decodable`, and those traits
+/// can’t be implemented for a type outside the crates that define the type or the traits. A codec
+/// fills the gap: it is implemented by a type of the program’s own, usually an empty struct, and
+/// values wrapped in `Foreign` are stored with the codec chosen for them.
+pub trait ForeignCodec<T> {
+    /// Turns `val` into bytes.
+    fn encode(&self, val: &T) -> Vec<u8>;
+    /// Turns bytes written by `encode` back into a value, or describes why they can’t be.
+    fn decode(&self, bytes: Vec<u8>) -> Result<T, String>;
+}
+
+/// A value stored with the codec `C`, for use inside the values of boxes.
+///
+/// The value is encoded as the sequence of bytes the codec turns it into.
+pub struct Foreign<T, C> {
+    /// The value itself.
+    pub val: T,
+    codec: C,
+}
+
+impl<T, C: ForeignCodec<T> + Default> Foreign<T, C> {
+    /// Wraps `val`, to be stored with a `C`.
+    pub fn new(val: T) -> Foreign<T, C> {
+        Foreign {
+            val: val,
+            codec: Default::default(),
+        }
+    }
+}
+
+impl<T, C> Deref<T> for Foreign<T, C> {
+    fn deref(&self) -> &T {
+        &self.val
+    }
+}
+
+impl<T, C> DerefMut<T> for Foreign<T, C> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.val
+    }
+}
+
+impl<E, S: Encoder<E>, T, C: ForeignCodec<T>> Encodable<S, E> for Foreign<T, C> {
+    fn encode(&self, s: &mut S) -> Result<(), E> {
+        self.codec.encode(&self.val).encode(s)
+    }
+}
+
+impl<E, D: Decoder<E>, T, C: ForeignCodec<T> + Default> Decodable<D, E> for Foreign<T, C> {
+    fn decode(d: &mut D) -> Result<Foreign<T, C>, E> {
+        let bytes: Vec<u8> = try!(Decodable::decode(d));
+        let codec: C = Default::default();
+        match codec.decode(bytes) {
+            Ok(val) => Ok(Foreign {
+                val: val,
+                codec: codec,
+            }),
+            Err(e) => Err(d.error(e.as_slice())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Foreign, ForeignCodec};
+    use super::super::{FileBox, peek};
+
+    /// Stands in for a type from another crate.
+    struct Point {
+        x: i8,
+        y: i8,
+    }
+
+    #[deriving(Default)]
+    struct PointCodec;
+
+    impl ForeignCodec<Point> for PointCodec {
+        fn encode(&self, p: &Point) -> Vec<u8> {
+            vec![p.x as u8, p.y as u8]
+        }
+
+        fn decode(&self, bytes: Vec<u8>) -> Result<Point, String> {
+            match bytes.as_slice() {
+                [x, y] => Ok(Point { x: x as i8, y: y as i8 }),
+                _ => Err("a point takes two bytes".to_string()),
+            }
+        }
+    }
+
+    #[deriving(Encodable, Decodable)]
+    struct Shape {
+        name: String,
+        corner: Foreign<Point, PointCodec>,
+    }
+
+    #[test]
+    fn foreign_types() {
+        let path = Path::new("target/foreign_types");
+        {
+            let shape = Shape {
+                name: "square".to_string(),
+                corner: Foreign::new(Point { x: -1, y: 2 }),
+            };
+            FileBox::open_new(&path, shape).unwrap();
+        }
+        let shape: Shape = peek(&path).unwrap();
+        assert_eq!(shape.name.as_slice(), "square");
+        assert_eq!((shape.corner.x, shape.corner.y), (-1, 2));
+        assert!(peek::<Foreign<Point, PointCodec>>(&path).is_err());
+    }
+}
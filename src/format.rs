@@ -0,0 +1,392 @@
+//! Storing the values of boxes in formats other than bincode.
+
+use std::collections::TreeMap;
+use std::default::Default;
+use std::io::{mod, IoError, IoResult, MemReader, MemWriter};
+use std::num::Float;
+use serialize::{json, Decodable, Encodable};
+use bincode::{mod, DecoderReader, EncoderWriter};
+
+use encoding::{CompactDecoder, decode_bincode};
+use jsontree::{TreeEncoder, to_tree, write_tree};
+
+/// A way of turning values of type `T` into the contents of a file and back.
+///
+/// Boxes opened with `FileBox::open_with_format` store their values in a format instead of as
+/// ordinary box files. Their files hold nothing but the encoded value, with no header, so files
+/// in a human-readable format like `Json` can be edited by hand.
+pub trait Format<T> {
+    /// Encodes `val` as the contents of a file.
+    fn encode(&self, val: &T) -> IoResult<Vec<u8>>;
+    /// Decodes the contents of a file written by `encode`.
+    fn decode(&self, bytes: Vec<u8>) -> IoResult<T>;
+}
+
+/// Bincode, as used by ordinary box files. Files in this format are read by `peek` like box files
+/// written before boxes had headers.
+#[deriving(Clone)]
+pub struct Bincode;
+
+impl<'a, T> Format<T> for Bincode where T: Encodable<EncoderWriter<'a, MemWriter>, IoError>
+                                         + Decodable<CompactDecoder, IoError> {
+    fn encode(&self, val: &T) -> IoResult<Vec<u8>> {
+        bincode::encode(val)
+    }
+
+    fn decode(&self, bytes: Vec<u8>) -> IoResult<T> {
+        decode_bincode(bytes)
+    }
+}
+
+/// Bincode laid out as another program writes it, for files written without this crate.
+///
+/// Programs that store values with bincode directly may put other bytes around them, such as a
+/// magic number of their own or the length of the value. A `Plain` format states what is there, so
+/// such files can be read, and adopted as ordinary boxes with `FileBox::adopt_with_format`, without
+/// a headerless file that happens to look like a box file being read as one.
+#[deriving(Clone, Default)]
+pub struct Plain {
+    /// Bytes the file starts with before the value, which must be present and are skipped.
+    pub prefix: Vec<u8>,
+    /// Whether the value is preceded by its length in bytes, as a big-endian `u64`.
+    pub length_prefixed: bool,
+    /// Whether bytes after the value are ignored rather than an error.
+    pub trailing: bool,
+}
+
+fn misplaced(detail: &str) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: "the file isn’t laid out as expected",
+        detail: Some(detail.to_string()),
+    }
+}
+
+impl<'a, T> Format<T> for Plain where T: Encodable<EncoderWriter<'a, MemWriter>, IoError>
+                                       + Decodable<DecoderReader<'a, MemReader>, IoError> {
+    fn encode(&self, val: &T) -> IoResult<Vec<u8>> {
+        let payload = try!(bincode::encode(val));
+        let mut bytes = self.prefix.clone();
+        if self.length_prefixed {
+            try!(bytes.write_be_u64(payload.len() as u64));
+        }
+        bytes.push_all(payload.as_slice());
+        Ok(bytes)
+    }
+
+    fn decode(&self, bytes: Vec<u8>) -> IoResult<T> {
+        if !bytes.as_slice().starts_with(self.prefix.as_slice()) {
+            return Err(misplaced("it doesn’t start with the expected prefix"));
+        }
+        let mut r = MemReader::new(bytes.slice_from(self.prefix.len()).to_vec());
+        let mut value = if self.length_prefixed {
+            let len = try!(r.read_be_u64());
+            MemReader::new(try!(r.read_exact(len as uint)))
+        } else {
+            MemReader::new(try!(r.read_to_end()))
+        };
+        let val = try!(bincode::decode_from(&mut value));
+        if !self.trailing && !(value.eof() && r.eof()) {
+            return Err(misplaced("there are bytes after the value"));
+        }
+        Ok(val)
+    }
+}
+
+/// JSON, optionally pretty-printed with one field per line.
+///
+/// The keys of objects are the names of the fields they hold, unless `case` or `renames` say
+/// otherwise, so the files can follow the conventions of the programs that read them without the
+/// fields being renamed. They apply to the keys of every object in the file, including those
+/// encoding maps.
+///
+/// By default floats are written as `serialize::json` writes them, which can lose digits, and NaN
+/// and the infinities are written as `null`, which is read back as NaN. `exact_floats` and
+/// `non_finite` change that, for files whose numbers have to survive being read back exactly.
+#[deriving(Clone)]
+pub struct Json {
+    /// Whether values are written across several indented lines, which suits files that are
+    /// edited by hand.
+    pub pretty: bool,
+    /// How field names are turned into keys.
+    pub case: KeyCase,
+    /// The keys for particular fields, as pairs of a field name and its key, which take precedence
+    /// over `case`.
+    pub renames: Vec<(String, String)>,
+    /// Whether floats are written with enough digits to be read back as exactly the same floats.
+    pub exact_floats: bool,
+    /// What is written for NaN and the infinities, which JSON has no numbers for.
+    pub non_finite: NonFinite,
+}
+
+impl Default for Json {
+    fn default() -> Json {
+        Json {
+            pretty: false,
+            case: AsWritten,
+            renames: Vec::new(),
+            exact_floats: false,
+            non_finite: NonFiniteNull,
+        }
+    }
+}
+
+/// How `Json` turns the names of fields, which are written in snake case, into keys.
+#[deriving(Clone, PartialEq, Show)]
+pub enum KeyCase {
+    /// Keys are the field names as they are: `max_retries`.
+    AsWritten,
+    /// `maxRetries`, as is usual in JavaScript.
+    CamelCase,
+    /// `MaxRetries`.
+    PascalCase,
+    /// `max-retries`.
+    KebabCase,
+}
+
+/// How `Json` writes floats that are NaN or infinite.
+#[deriving(Clone, PartialEq, Show)]
+pub enum NonFinite {
+    /// They are written as `null`, as `serialize::json` does, and read back as NaN, so the
+    /// infinities are lost.
+    NonFiniteNull,
+    /// Writing a value holding one fails.
+    NonFiniteError,
+    /// They are written as the strings `"NaN"`, `"inf"` and `"-inf"`, and read back as they were.
+    /// Other programs reading the file have to expect strings in place of these numbers.
+    NonFiniteString,
+}
+
+impl Json {
+    fn renames_keys(&self) -> bool {
+        self.case != AsWritten || !self.renames.is_empty()
+    }
+
+    /// Whether floats are written differently from how `serialize::json` writes them.
+    fn handles_floats(&self) -> bool {
+        self.exact_floats || self.non_finite != NonFiniteNull
+    }
+
+    fn key_for(&self, field: &str) -> String {
+        for &(ref name, ref key) in self.renames.iter() {
+            if name.as_slice() == field {
+                return key.clone();
+            }
+        }
+        let mut key = String::new();
+        let mut upper = self.case == PascalCase;
+        for c in field.chars() {
+            match (c, self.case) {
+                ('_', CamelCase) | ('_', PascalCase) => upper = true,
+                ('_', KebabCase) => key.push('-'),
+                _ if upper => {
+                    key.push(c.to_uppercase());
+                    upper = false;
+                }
+                _ => key.push(c),
+            }
+        }
+        key
+    }
+
+    fn field_for(&self, key: &str) -> String {
+        for &(ref name, ref renamed) in self.renames.iter() {
+            if renamed.as_slice() == key {
+                return name.clone();
+            }
+        }
+        let mut field = String::new();
+        for (i, c) in key.chars().enumerate() {
+            match self.case {
+                CamelCase | PascalCase if c.is_uppercase() => {
+                    if i > 0 {
+                        field.push('_');
+                    }
+                    field.push(c.to_lowercase());
+                }
+                KebabCase if c == '-' => field.push('_'),
+                _ => field.push(c),
+            }
+        }
+        field
+    }
+}
+
+/// Replaces every key of every object in `val` with `rename` of it.
+fn rename_keys(val: json::Json, rename: &|&str| -> String) -> json::Json {
+    match val {
+        json::Object(fields) => json::Object(fields.into_iter().map(|(key, val)| {
+            ((*rename)(key.as_slice()), rename_keys(val, rename))
+        }).collect()),
+        json::List(vals) => json::List(vals.into_iter().map(|val| rename_keys(val, rename))
+                                                        .collect()),
+        val => val,
+    }
+}
+
+/// Replaces the floats in `val` that are NaN or infinite as `policy` says.
+fn replace_non_finite(val: json::Json, policy: &NonFinite) -> IoResult<json::Json> {
+    match val {
+        json::F64(v) if !v.is_finite() => match *policy {
+            NonFiniteNull => Ok(json::Null),
+            NonFiniteError => Err(IoError {
+                kind: io::InvalidInput,
+                desc: "the value holds a float that JSON has no number for",
+                detail: Some(v.to_string()),
+            }),
+            // `json::Decoder` reads floats from strings like these.
+            NonFiniteString => Ok(json::String(v.to_string())),
+        },
+        json::Object(fields) => {
+            let mut replaced = TreeMap::new();
+            for (key, val) in fields.into_iter() {
+                replaced.insert(key, try!(replace_non_finite(val, policy)));
+            }
+            Ok(json::Object(replaced))
+        }
+        json::List(vals) => {
+            let mut replaced = Vec::with_capacity(vals.len());
+            for val in vals.into_iter() {
+                replaced.push(try!(replace_non_finite(val, policy)));
+            }
+            Ok(json::List(replaced))
+        }
+        val => Ok(val),
+    }
+}
+
+fn invalid(detail: String) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: "the box holds invalid JSON",
+        detail: Some(detail),
+    }
+}
+
+impl<'a, T> Format<T> for Json where T: Encodable<json::Encoder<'a>, IoError>
+                                      + Encodable<json::PrettyEncoder<'a>, IoError>
+                                      + Encodable<TreeEncoder, IoError>
+                                      + Decodable<json::Decoder, json::DecoderError> {
+    fn encode(&self, val: &T) -> IoResult<Vec<u8>> {
+        if self.handles_floats() {
+            let mut tree = try!(replace_non_finite(try!(to_tree(val)), &self.non_finite));
+            if self.renames_keys() {
+                tree = rename_keys(tree, &|field| self.key_for(field));
+            }
+            return Ok(write_tree(&tree, self.pretty, self.exact_floats).into_bytes());
+        }
+        if self.renames_keys() {
+            let tree = try!(json::from_str(json::encode(val).as_slice())
+                                .map_err(|e| invalid(e.to_string())));
+            let tree = rename_keys(tree, &|field| self.key_for(field));
+            let text = if self.pretty { tree.to_pretty_str() } else { tree.to_string() };
+            return Ok(text.into_bytes());
+        }
+        if !self.pretty {
+            return Ok(json::encode(val).into_bytes());
+        }
+        let mut w = MemWriter::new();
+        try!(val.encode(&mut json::PrettyEncoder::new(&mut w)));
+        Ok(w.unwrap())
+    }
+
+    fn decode(&self, bytes: Vec<u8>) -> IoResult<T> {
+        let s = try!(String::from_utf8(bytes).map_err(|_| invalid("not UTF-8".to_string())));
+        if !self.renames_keys() {
+            return json::decode(s.as_slice()).map_err(|e| invalid(e.to_string()));
+        }
+        let tree = try!(json::from_str(s.as_slice()).map_err(|e| invalid(e.to_string())));
+        let tree = rename_keys(tree, &|key| self.field_for(key));
+        Decodable::decode(&mut json::Decoder::new(tree)).map_err(|e| invalid(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::File;
+    use std::default::Default;
+    use std::f64;
+    use super::{Format, Json, CamelCase, Plain, NonFiniteError, NonFiniteString};
+    use super::super::{FileBox, peek};
+
+    #[deriving(Encodable, Decodable, PartialEq, Show)]
+    struct Config {
+        name: String,
+        retries: u32,
+    }
+
+    #[test]
+    fn json_config() {
+        let path = Path::new("target/json_config.json");
+        let config = Config { name: "server".to_string(), retries: 3 };
+        let pretty = Json { pretty: true, .. Default::default() };
+        let json = box pretty.clone() as Box<Format<Config> + 'static>;
+        FileBox::open_new_with_format(&path, config, json).unwrap();
+        let text = File::open(&path).read_to_string().unwrap();
+        assert!(text.as_slice().starts_with("{\n"));
+
+        // Someone edits the file by hand.
+        let edited = text.replace("3", "5");
+        File::create(&path).write_str(edited.as_slice()).unwrap();
+        let json = box pretty as Box<Format<Config> + 'static>;
+        let mut b: FileBox<Config> = FileBox::open_with_format(&path, json).unwrap();
+        assert_eq!(b.retries, 5);
+        b.retries = 6;
+        b.close().unwrap();
+        assert!(File::open(&path).read_to_string().unwrap().as_slice().contains("6"));
+    }
+    #[deriving(Encodable, Decodable, PartialEq, Show)]
+    struct Retry {
+        max_retries: u32,
+        backoff_ms: u32,
+    }
+
+    #[test]
+    fn rename_keys() {
+        let json = Json {
+            case: CamelCase,
+            renames: vec![("backoff_ms".to_string(), "delay".to_string())],
+            .. Default::default()
+        };
+        let retry = Retry { max_retries: 3, backoff_ms: 100 };
+        let bytes = json.encode(&retry).unwrap();
+        assert_eq!(String::from_utf8(bytes.clone()).unwrap().as_slice(),
+                   r#"{"delay":100,"maxRetries":3}"#);
+        assert_eq!(json.decode(bytes).unwrap(), retry);
+    }
+
+    #[test]
+    fn non_finite_floats() {
+        let vals = vec![1.5f64, f64::INFINITY, f64::NEG_INFINITY];
+        let strings = Json { non_finite: NonFiniteString, .. Default::default() };
+        let bytes = strings.encode(&vals).unwrap();
+        assert_eq!(String::from_utf8(bytes.clone()).unwrap().as_slice(), r#"[1.5,"inf","-inf"]"#);
+        assert_eq!(strings.decode(bytes).unwrap(), vals);
+        let nan: f64 = strings.decode(strings.encode(&f64::NAN).unwrap()).unwrap();
+        assert!(nan.is_nan());
+
+        let errors = Json { non_finite: NonFiniteError, .. Default::default() };
+        assert!(errors.encode(&vals).is_err());
+        assert!(errors.encode(&vec![1.5f64]).is_ok());
+    }
+
+    #[test]
+    fn adopt_plain_file() {
+        let path = Path::new("target/adopt_plain_file");
+        let plain = Plain {
+            prefix: b"TOOL".to_vec(),
+            length_prefixed: true,
+            .. Default::default()
+        };
+        // As written by a program using bincode itself.
+        File::create(&path).write(&[b'T', b'O', b'O', b'L', 0, 0, 0, 0, 0, 0, 0, 4,
+                                    0, 0, 0, 7]).unwrap();
+        assert!(Format::<u32>::decode(&Plain::default(), vec![0, 0, 0, 7, 0]).is_err());
+
+        let b: FileBox<u32> = FileBox::adopt_with_format(&path, &plain).unwrap();
+        assert_eq!(*b, 7);
+        drop(b);
+        assert_eq!(peek::<u32>(&path).unwrap(), 7);
+        assert!(FileBox::<u32>::adopt_with_format(&path, &plain).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+//! Keeping the previous versions of a box’s file.
+
+use std::io::{fs, File, IoResult};
+use std::io::fs::PathExtensions;
+
+use atomic::write_atomic;
+use layout;
+
+/// The path of the `n`th previous version of the box at `p`, counting from the newest, which is
+/// the first.
+pub fn generation_path(p: &Path, n: uint) -> Path {
+    let mut name = p.filename().unwrap_or(b"filebox").to_vec();
+    name.push_all(format!(".{}", n).as_bytes());
+    p.with_filename(name)
+}
+
+/// Makes the current file of the box at `p` its newest previous version, before the box is
+/// written, shifting the older versions along and dropping all but the newest `keep`.
+pub fn rotate(p: &Path, keep: uint) -> IoResult<()> {
+    if keep == 0 || !p.exists() {
+        return Ok(());
+    }
+    for n in range(1, keep).rev() {
+        try!(shift(&generation_path(p, n), &generation_path(p, n + 1)));
+        try!(shift(&layout::meta_path(&generation_path(p, n)),
+                   &layout::meta_path(&generation_path(p, n + 1))));
+    }
+    try!(preserve(p, &generation_path(p, 1)));
+    preserve(&layout::meta_path(p), &layout::meta_path(&generation_path(p, 1)))
+}
+
+fn shift(from: &Path, to: &Path) -> IoResult<()> {
+    if from.exists() {
+        try!(fs::rename(from, to));
+    }
+    Ok(())
+}
+
+/// Makes `to` hold what is in `from` now, leaving `from` where it is, since the box’s file has to
+/// stay in place until the new one is renamed over it.
+fn preserve(from: &Path, to: &Path) -> IoResult<()> {
+    if !from.exists() {
+        return Ok(());
+    }
+    if to.exists() {
+        try!(fs::unlink(to));
+    }
+    // The file is replaced rather than changed when the box is written, so a link to it keeps
+    // the old version without copying it.
+    match fs::link(from, to) {
+        Ok(()) => Ok(()),
+        Err(_) => fs::copy(from, to),
+    }
+}
+
+/// Replaces the box at `p` with its `n`th previous version, as kept by `FileBox::set_generations`,
+/// for recovering from a program that wrote garbage into the box. The previous version is left
+/// in place. Boxes open on `p` refuse to write over the restored file, as they would for any file
+/// that replaced theirs.
+pub fn restore_generation(p: &Path, n: uint) -> IoResult<()> {
+    let old = generation_path(p, n);
+    let bytes = try!(File::open(&old).read_to_end());
+    if layout::meta_path(&old).exists() {
+        let meta = try!(File::open(&layout::meta_path(&old)).read_to_end());
+        try!(write_atomic(&layout::meta_path(p), meta.as_slice()));
+    }
+    write_atomic(p, bytes.as_slice())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs::PathExtensions;
+    use super::{generation_path, restore_generation};
+    use super::super::{FileBox, peek};
+
+    #[test]
+    fn rotate_generations() {
+        let path = Path::new("target/rotate_generations");
+        {
+            let mut x = FileBox::open_new(&path, 1u32).unwrap();
+            x.set_generations(2);
+            for i in range(2, 5) {
+                x.save().unwrap();
+                *x = i;
+            }
+        }
+        assert_eq!(peek::<u32>(&path).unwrap(), 4);
+        assert_eq!(peek::<u32>(&generation_path(&path, 1)).unwrap(), 3);
+        assert_eq!(peek::<u32>(&generation_path(&path, 2)).unwrap(), 2);
+        assert!(!generation_path(&path, 3).exists());
+        restore_generation(&path, 2).unwrap();
+        assert_eq!(peek::<u32>(&path).unwrap(), 2);
+    }
+}
@@ -0,0 +1,237 @@
+//! References from one box to another, for splitting large values across many files.
+
+use std::io::{mod, IoError, IoResult, MemWriter};
+use serialize::{Decodable, Encodable, Decoder, Encoder};
+use bincode::EncoderWriter;
+
+use encoding::{CompactDecoder, decode_bincode};
+use header::Header;
+use super::{FileBox, FileId, read_payload};
+
+/// A reference to a box of type `T` stored in another file.
+///
+/// A reference is stored as the path of the file it refers to, relative to the directory of the
+/// box it is stored in, and the tag of the type the file holds. Nothing is read until the
+/// reference is resolved, and resolving it fails if the file is tagged with a different type.
+/// Files can refer to each other in cycles; `Loader` detects them when following references.
+pub struct BoxRef<T> {
+    path: Path,
+    tag: String,
+}
+
+impl<'a, T> BoxRef<T> where T: Decodable<CompactDecoder, IoError>
+                           + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Creates a reference to the box at the given path, which is relative to the directory of
+    /// the box the reference will be stored in, holding the type with the given tag.
+    pub fn new(path: &Path, tag: &str) -> BoxRef<T> {
+        BoxRef {
+            path: path.clone(),
+            tag: tag.to_string(),
+        }
+    }
+
+    /// Creates a reference, to be stored in the box at `parent`, to the box at `child`. Fails if
+    /// there is no relative path from one to the other, which can happen on Windows if they are
+    /// on different drives.
+    pub fn between(parent: &Path, child: &Path, tag: &str) -> IoResult<BoxRef<T>> {
+        match child.path_relative_from(&parent.dir_path()) {
+            Some(path) => Ok(BoxRef::new(&path, tag)),
+            None => Err(IoError {
+                kind: io::InvalidInput,
+                desc: "there is no relative path between the boxes",
+                detail: Some(format!("from {} to {}", parent.display(), child.display())),
+            }),
+        }
+    }
+
+    /// The path of the referenced box, relative to the directory of the box holding the
+    /// reference.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The tag of the type the referenced box holds.
+    pub fn tag(&self) -> &str {
+        self.tag.as_slice()
+    }
+
+    /// The path of the referenced box, when the reference is stored in the box at `parent`.
+    pub fn target(&self, parent: &Path) -> Path {
+        parent.dir_path().join(&self.path)
+    }
+
+    /// Reads the value of the referenced box, when the reference is stored in the box at
+    /// `parent`, without creating a `FileBox`.
+    pub fn resolve(&self, parent: &Path) -> IoResult<T> {
+        let (header, payload) = try!(read_payload(&self.target(parent), None, None, None));
+        try!(check_tag(&header, self.tag.as_slice()));
+        decode_bincode(payload)
+    }
+
+    /// Opens the referenced box, when the reference is stored in the box at `parent`. The box is
+    /// tagged with the type of the reference the next time it is written.
+    pub fn open(&self, parent: &Path) -> IoResult<FileBox<T>> {
+        let mut b: FileBox<T> = try!(FileBox::open(&self.target(parent)));
+        try!(check_tag(&b._header, self.tag.as_slice()));
+        if b._header.type_tag != self.tag {
+            b._header.type_tag = self.tag.clone();
+            b._dirty = true;
+        }
+        Ok(b)
+    }
+}
+
+impl<T> Clone for BoxRef<T> {
+    fn clone(&self) -> BoxRef<T> {
+        BoxRef {
+            path: self.path.clone(),
+            tag: self.tag.clone(),
+        }
+    }
+}
+
+impl<S: Encoder<E>, E, T> Encodable<S, E> for BoxRef<T> {
+    fn encode(&self, s: &mut S) -> Result<(), E> {
+        (self.path.as_vec().to_vec(), self.tag.clone()).encode(s)
+    }
+}
+
+impl<D: Decoder<E>, E, T> Decodable<D, E> for BoxRef<T> {
+    fn decode(d: &mut D) -> Result<BoxRef<T>, E> {
+        let (path, tag): (Vec<u8>, String) = try!(Decodable::decode(d));
+        Ok(BoxRef {
+            path: Path::new(path),
+            tag: tag,
+        })
+    }
+}
+
+/// Follows references between boxes, failing instead of looping forever when they form a cycle.
+///
+/// The loader keeps track of the boxes that are being loaded. References are resolved relative to
+/// the innermost one, and a reference back to any of them is an error.
+pub struct Loader {
+    stack: Vec<(Path, FileId)>,
+}
+
+impl Loader {
+    /// Creates a loader that isn’t loading any boxes.
+    pub fn new() -> Loader {
+        Loader {
+            stack: Vec::new(),
+        }
+    }
+
+    /// Reads the value of the box at the given path and passes it to `visit`, which can load the
+    /// boxes it refers to through the loader it is given. Relative paths are taken to be relative
+    /// to the box being loaded, if there is one.
+    pub fn load_path<T, R>(&mut self, p: &Path, visit: |&mut Loader, T| -> IoResult<R>)
+                           -> IoResult<R>
+            where T: Decodable<CompactDecoder, IoError> {
+        self.load_checked(p, None, visit)
+    }
+
+    /// Like `load_path`, but for the box referred to by `r`, which must be stored in the box
+    /// being loaded.
+    pub fn load<T, R>(&mut self, r: &BoxRef<T>, visit: |&mut Loader, T| -> IoResult<R>)
+                      -> IoResult<R>
+            where T: Decodable<CompactDecoder, IoError> {
+        self.load_checked(&r.path, Some(r.tag.as_slice()), visit)
+    }
+
+    fn load_checked<T, R>(&mut self, p: &Path, tag: Option<&str>,
+                          visit: |&mut Loader, T| -> IoResult<R>) -> IoResult<R>
+            where T: Decodable<CompactDecoder, IoError> {
+        let p = match self.stack.last() {
+            Some(&(ref parent, _)) => parent.dir_path().join(p),
+            None => p.clone(),
+        };
+        let id = try!(FileId::of(&p));
+        if self.stack.iter().any(|&(_, ref loading)| *loading == id) {
+            return Err(IoError {
+                kind: io::InvalidInput,
+                desc: "the boxes refer to each other in a cycle",
+                detail: Some(p.display().to_string()),
+            });
+        }
+        let (header, payload) = try!(read_payload(&p, None, None, None));
+        match tag {
+            Some(tag) => try!(check_tag(&header, tag)),
+            None => {}
+        }
+        let val = try!(decode_bincode(payload));
+        self.stack.push((p, id));
+        let res = visit(self, val);
+        self.stack.pop();
+        res
+    }
+}
+
+/// Checks that a box with the given header can hold the type with the given tag. Untagged boxes
+/// are assumed to hold the right type.
+fn check_tag(header: &Header, tag: &str) -> IoResult<()> {
+    if header.type_tag.is_empty() || header.type_tag.as_slice() == tag {
+        Ok(())
+    } else {
+        Err(IoError {
+            kind: io::InvalidInput,
+            desc: "the referenced box holds a different type",
+            detail: Some(format!("expected {}, found {}", tag, header.type_tag)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{fs, IoResult, USER_RWX};
+    use std::io::fs::PathExtensions;
+    use super::{BoxRef, Loader};
+    use super::super::{FileBox, write_value};
+
+    #[deriving(Encodable, Decodable)]
+    struct Node {
+        name: String,
+        children: Vec<BoxRef<Node>>,
+    }
+
+    fn node(name: &str, children: &[&str]) -> Node {
+        Node {
+            name: name.to_string(),
+            children: children.iter().map(|c| BoxRef::new(&Path::new(*c), "node")).collect(),
+        }
+    }
+
+    fn names(loader: &mut Loader, node: Node, out: &mut Vec<String>) -> IoResult<()> {
+        out.push(node.name);
+        for r in node.children.iter() {
+            try!(loader.load(r, |loader, child| names(loader, child, out)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn box_graph() {
+        let dir = Path::new("target/box_graph");
+        if !dir.is_dir() {
+            fs::mkdir(&dir, USER_RWX).unwrap();
+        }
+        write_value(&dir.join("a"), &node("a", &["b", "c"])).unwrap();
+        write_value(&dir.join("b"), &node("b", &["c"])).unwrap();
+        write_value(&dir.join("c"), &node("c", &[])).unwrap();
+
+        let mut found = Vec::new();
+        Loader::new().load_path(&dir.join("a"), |l, n| names(l, n, &mut found)).unwrap();
+        assert_eq!(found, vec!["a".to_string(), "b".to_string(), "c".to_string(),
+                               "c".to_string()]);
+
+        let a: FileBox<Node> = FileBox::open(&dir.join("a")).unwrap();
+        let r = BoxRef::<Node>::between(&dir.join("a"), &dir.join("b"), "node").unwrap();
+        assert_eq!(r.path(), &Path::new("b"));
+        assert_eq!(a.children[0].resolve(&dir.join("a")).unwrap().name.as_slice(), "b");
+        drop(a);
+
+        write_value(&dir.join("c"), &node("c", &["a"])).unwrap();
+        let mut found = Vec::new();
+        assert!(Loader::new().load_path(&dir.join("a"), |l, n| names(l, n, &mut found)).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+//! Writing a box as soon as a change to it is finished.
+
+use std::io::{IoError, IoResult, MemWriter};
+use std::task;
+use serialize::Encodable;
+use bincode::EncoderWriter;
+
+use droppolicy;
+use progress::is_cancelled;
+use super::FileBox;
+
+/// A change to the value of a box, which is written to the box’s file when the change is
+/// finished, as made by `FileBox::edit`.
+///
+/// The guard gives access to the value much as the box itself does. Dropping it writes the box,
+/// and panics if the write fails, as dropping a box does; `commit` writes it and returns the
+/// result instead.
+pub struct WriteGuard<'b, T: 'b> {
+    b: &'b mut FileBox<T>,
+    done: bool,
+}
+
+impl<'a, T> FileBox<T> where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Starts a change to the box’s value that is written to its file as soon as it is finished,
+    /// when the returned guard is dropped, rather than when the box is. If the box is panic-safe
+    /// (see `set_panic_safe`), a change cut short by a panic isn’t written, by the guard or by the
+    /// box.
+    pub fn edit(&mut self) -> WriteGuard<T> {
+        self._in_modify = self._panic_safe;
+        WriteGuard {
+            b: self,
+            done: false,
+        }
+    }
+}
+
+impl<'a, 'b, T> WriteGuard<'b, T> where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Finishes the change and writes the box, returning the result of the write.
+    pub fn commit(mut self) -> IoResult<()> {
+        self.done = true;
+        self.finish()
+    }
+
+    fn finish(&mut self) -> IoResult<()> {
+        let b = &mut *self.b;
+        b._in_modify = false;
+        b._last_change = Some(b._clock.now_ms());
+        b._dirty = true;
+        b.notify_observers();
+        b.write()
+    }
+}
+
+impl<'b, T> Deref<T> for WriteGuard<'b, T> {
+    fn deref(&self) -> &T {
+        self.b.val()
+    }
+}
+
+impl<'b, T> DerefMut<T> for WriteGuard<'b, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.b.val_mut()
+    }
+}
+
+#[unsafe_destructor]
+impl<'a, 'b, T> Drop for WriteGuard<'b, T>
+        where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        if self.b._panic_safe && task::failing() {
+            // The box stays marked as being changed, so it isn’t written when it is dropped either.
+            return;
+        }
+        if self.b._read_only {
+            self.b._in_modify = false;
+            match self.b._read_only_hook {
+                Some(hook) => hook(&self.b._path),
+                None => {}
+            }
+            return;
+        }
+        match self.finish() {
+            Err(ref e) if is_cancelled(e) => {}
+            Err(e) => droppolicy::handle(self.b._drop_policy.as_ref(), &self.b._path, e),
+            Ok(()) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::task;
+    use super::super::{FileBox, peek};
+
+    #[test]
+    fn write_on_guard_drop() {
+        let path = Path::new("target/write_on_guard_drop");
+        let mut b = FileBox::open_new(&path, vec![1u8]).unwrap();
+        {
+            let mut v = b.edit();
+            v.push(2);
+        }
+        assert_eq!(peek::<Vec<u8>>(&path).unwrap(), vec![1, 2]);
+        let mut v = b.edit();
+        v.push(3);
+        v.commit().unwrap();
+        assert_eq!(peek::<Vec<u8>>(&path).unwrap(), vec![1, 2, 3]);
+        drop(b);
+
+        let p = path.clone();
+        let res = task::try(proc() {
+            let mut b = FileBox::<Vec<u8>>::open(&p).unwrap();
+            b.set_panic_safe(true);
+            let mut v = b.edit();
+            v.push(4);
+            panic!("the change is cut short");
+        });
+        assert!(res.is_err());
+        assert_eq!(peek::<Vec<u8>>(&path).unwrap(), vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,100 @@
+//! Handing a box over from one process to another, such as the next instance of a server.
+
+use std::io::{mod, fs, timer, IoError, IoResult, MemWriter};
+use std::io::fs::PathExtensions;
+use std::time::Duration;
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+use time;
+
+use encoding::CompactDecoder;
+use process;
+use super::{FileBox, peek, write_value};
+
+/// How often `FileBox::take_over` looks for a handoff.
+static POLL_MS: i64 = 10;
+
+/// A box being handed over, as recorded in its handoff file.
+#[deriving(Encodable, Decodable)]
+struct Handoff {
+    /// The name of the successor the box is being handed to.
+    successor: String,
+    /// The id of the process that handed the box over.
+    pid: u32,
+}
+
+fn handoff_path(p: &Path) -> Path {
+    let mut name = p.filename().unwrap_or(b"filebox").to_vec();
+    name.push_all(b".handoff");
+    p.with_filename(name)
+}
+
+impl<'a, T> FileBox<T> where T: Decodable<CompactDecoder, IoError>
+                              + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Writes and closes the box, handing it over to the process that calls `take_over` with the
+    /// name `successor`. The box’s lock, if it was opened with one, is let go of only once the
+    /// handoff has been recorded, so the successor takes over the state exactly as it was written
+    /// here. This suits restarts where the new instance of a program adopts the old one’s state.
+    pub fn handoff(mut self, successor: &str) -> IoResult<()> {
+        self._write_on_drop = false;
+        try!(self.write());
+        try!(write_value(&handoff_path(&self._path), &Handoff {
+            successor: successor.to_string(),
+            pid: process::id(),
+        }));
+        self._lock = None;
+        Ok(())
+    }
+
+    /// Waits for the box at `p` to be handed over to `name` with `handoff`, and opens it locked,
+    /// as `open_locked` does. Fails with a `TimedOut` error if that doesn’t happen within
+    /// `timeout`, or waits forever if there is none.
+    pub fn take_over(p: &Path, name: &str, timeout: Option<Duration>) -> IoResult<FileBox<T>> {
+        let deadline = timeout.map(|t| {
+            time::precise_time_ns() + t.num_nanoseconds().unwrap_or(0) as u64
+        });
+        let path = handoff_path(p);
+        loop {
+            if path.exists() {
+                let handoff: Handoff = try!(peek(&path));
+                if handoff.successor.as_slice() == name {
+                    let b = try!(FileBox::open_locked(p));
+                    try!(fs::unlink(&path));
+                    return Ok(b);
+                }
+            }
+            match deadline {
+                Some(deadline) if time::precise_time_ns() >= deadline => return Err(IoError {
+                    kind: io::TimedOut,
+                    desc: "the box wasn’t handed over in time",
+                    detail: Some(name.to_string()),
+                }),
+                _ => timer::sleep(Duration::milliseconds(POLL_MS)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::fs::PathExtensions;
+    use std::time::Duration;
+    use super::handoff_path;
+    use super::super::FileBox;
+
+    #[test]
+    fn hand_over() {
+        let path = Path::new("target/hand_over");
+        let mut a = FileBox::open_new_locked(&path, 1u32).unwrap();
+        *a = 5;
+        a.handoff("next").unwrap();
+        match FileBox::<u32>::take_over(&path, "other", Some(Duration::milliseconds(30))) {
+            Err(ref e) => assert_eq!(e.kind, io::TimedOut),
+            Ok(_) => panic!("took over a box handed to someone else"),
+        }
+        let b = FileBox::<u32>::take_over(&path, "next", Some(Duration::seconds(1))).unwrap();
+        assert_eq!(*b, 5);
+        assert!(!handoff_path(&path).exists());
+    }
+}
@@ -0,0 +1,370 @@
+//! The header stored at the start of every box file.
+
+use std::io::{mod, IoError, IoResult, MemReader, MemWriter};
+use std::uint;
+
+use compress;
+use schema::SchemaTooNew;
+use super::fnv1a;
+
+/// The bytes every box file starts with. Files that don’t start with them are treated as a bare
+/// bincode payload, which is how boxes were stored before they had headers.
+pub static MAGIC: &'static [u8] = b"FBOX";
+
+/// The version of the format of box files written by this version of the crate. Files written
+/// before the format had a version are taken to be at version 1.
+pub static FORMAT_VERSION: u64 = 1;
+
+/// The description of errors caused by box files that have been damaged.
+pub static CORRUPT: &'static str = "the box file is corrupt";
+
+/// Returns whether `e` was caused by reading a box file that has been damaged, such as by being
+/// cut short or having its bytes changed, as opposed to a file that is intact but can’t be read.
+/// Damage is only noticed in files whose headers record a checksum, which every box written by
+/// this version of the crate has.
+pub fn is_corrupt(e: &IoError) -> bool {
+    e.kind == io::InvalidInput && e.desc == CORRUPT
+}
+
+/// The encoding of payloads written by bincode.
+pub static BINCODE: &'static str = "bincode";
+
+/// The width of `int` and `uint` on this machine, in bits.
+pub static WORD_BITS: u64 = uint::BITS as u64;
+
+/// Describes how the payload following it in a box file was stored.
+///
+/// On disk the header is a list of named fields, so that fields can be added without making older
+/// files unreadable. Fields with names this version doesn’t know about are ignored. Every number
+/// in the header is written with a fixed width, most significant byte first, so headers read the
+/// same on every machine.
+#[deriving(Clone, PartialEq, Show)]
+pub struct Header {
+    /// The name of the compressor the payload was compressed with.
+    pub compressor: String,
+    /// How many times the box has been written over its lifetime.
+    pub saves: u64,
+    /// The total size of the payloads written to the box over its lifetime, in bytes.
+    pub bytes_written: u64,
+    /// When the box was created, in seconds since the Unix epoch, or 0 if this isn’t known.
+    pub created_at: u64,
+    /// The tag of the type the payload holds, or an empty string if it wasn’t recorded.
+    pub type_tag: String,
+    /// The version of the type the payload holds, which starts at 0.
+    pub version: u64,
+    /// How the value was turned into the payload before it was compressed.
+    pub encoding: String,
+    /// The FNV-1a hash of the payload as it is stored, or `None` for files written before boxes
+    /// recorded it.
+    pub checksum: Option<u64>,
+    /// Where the fields of the value are in the payload, before it was compressed, for files
+    /// written by `write_indexed`, or nothing for other files.
+    pub field_offsets: Vec<FieldOffset>,
+    /// The width of `int` and `uint` on the machine that wrote the payload, in bits, or 0 for
+    /// files written before boxes recorded it. This is only for information: `int`s and `uint`s
+    /// are written as 64-bit numbers on every machine, and reading one that is too large for the
+    /// machine reading it fails then and there.
+    pub word_bits: u64,
+}
+
+/// Where a field of a value is in the payload it was encoded as.
+#[deriving(Clone, PartialEq, Show)]
+pub struct FieldOffset {
+    /// The name of the field, as the names of the fields leading to it separated by dots.
+    pub field: String,
+    /// The offset at which the encoded field starts.
+    pub offset: u64,
+    /// The number of bytes the encoded field takes up.
+    pub len: u64,
+}
+
+impl Header {
+    /// The header of a payload that was stored without any processing, by a box that has never
+    /// been written.
+    pub fn new() -> Header {
+        Header {
+            compressor: compress::NONE.to_string(),
+            saves: 0,
+            bytes_written: 0,
+            created_at: 0,
+            type_tag: String::new(),
+            version: 0,
+            encoding: BINCODE.to_string(),
+            checksum: None,
+            field_offsets: Vec::new(),
+            word_bits: 0,
+        }
+    }
+
+    /// The header for writing `payload`, compressed with `compressor`, to a box that previously
+    /// had this header. Where the fields of the new value are isn’t known, so it records none.
+    pub fn next(&self, compressor: &str, payload: &[u8]) -> Header {
+        Header {
+            compressor: compressor.to_string(),
+            saves: self.saves + 1,
+            bytes_written: self.bytes_written + payload.len() as u64,
+            checksum: Some(fnv1a(payload)),
+            field_offsets: Vec::new(),
+            word_bits: WORD_BITS,
+            .. self.clone()
+        }
+    }
+
+    /// Where the field named `field` is in the payload, if it was recorded.
+    pub fn field_offset(&self, field: &str) -> Option<&FieldOffset> {
+        self.field_offsets.iter().find(|offset| offset.field.as_slice() == field)
+    }
+
+    /// Checks that `payload`, as it is stored, is the one this header was written for. Payloads
+    /// without a checksum can’t be checked, so they always pass.
+    pub fn verify(&self, payload: &[u8]) -> IoResult<()> {
+        match self.checksum {
+            Some(checksum) if checksum != fnv1a(payload) => Err(IoError {
+                kind: io::InvalidInput,
+                desc: CORRUPT,
+                detail: Some("its payload doesn’t match its checksum".to_string()),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn fields(&self) -> Vec<(&'static str, Vec<u8>)> {
+        let mut fields = vec![("compressor", self.compressor.as_bytes().to_vec()),
+                              ("saves", u64_bytes(self.saves)),
+                              ("bytes_written", u64_bytes(self.bytes_written)),
+                              ("created_at", u64_bytes(self.created_at)),
+                              ("type", self.type_tag.as_bytes().to_vec()),
+                              ("version", u64_bytes(self.version)),
+                              ("encoding", self.encoding.as_bytes().to_vec()),
+                              ("format", u64_bytes(FORMAT_VERSION))];
+        match self.checksum {
+            Some(checksum) => fields.push(("checksum", u64_bytes(checksum))),
+            None => {}
+        }
+        if self.word_bits != 0 {
+            fields.push(("word_bits", u64_bytes(self.word_bits)));
+        }
+        if !self.field_offsets.is_empty() {
+            let mut w = MemWriter::new();
+            for offset in self.field_offsets.iter() {
+                w.write_be_u16(offset.field.len() as u16).unwrap();
+                w.write_str(offset.field.as_slice()).unwrap();
+                w.write_be_u64(offset.offset).unwrap();
+                w.write_be_u64(offset.len).unwrap();
+            }
+            fields.push(("field_offsets", w.unwrap()));
+        }
+        fields
+    }
+
+    fn set_field(&mut self, name: &str, value: Vec<u8>) -> IoResult<()> {
+        match name {
+            "compressor" => self.compressor = try!(utf8(value)),
+            "saves" => self.saves = try!(read_u64(value)),
+            "bytes_written" => self.bytes_written = try!(read_u64(value)),
+            "created_at" => self.created_at = try!(read_u64(value)),
+            "type" => self.type_tag = try!(utf8(value)),
+            "version" => self.version = try!(read_u64(value)),
+            "encoding" => self.encoding = try!(utf8(value)),
+            "checksum" => self.checksum = Some(try!(read_u64(value))),
+            "field_offsets" => self.field_offsets = try!(read_offsets(value)),
+            "word_bits" => self.word_bits = try!(read_u64(value)),
+            "format" => {
+                let format = try!(read_u64(value));
+                if format > FORMAT_VERSION {
+                    return Err(SchemaTooNew {
+                        schema: "format".to_string(),
+                        found: format,
+                        supported: FORMAT_VERSION,
+                    }.to_error());
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Prepends `header` to `payload`, producing the contents of a box file.
+pub fn frame(header: &Header, payload: &[u8]) -> IoResult<Vec<u8>> {
+    let mut w = MemWriter::new();
+    try!(w.write(MAGIC));
+    let fields = header.fields();
+    try!(w.write_be_u32(fields.len() as u32));
+    for &(name, ref value) in fields.iter() {
+        try!(w.write_be_u16(name.len() as u16));
+        try!(w.write_str(name));
+        try!(w.write_be_u32(value.len() as u32));
+        try!(w.write(value.as_slice()));
+    }
+    try!(w.write(payload));
+    Ok(w.unwrap())
+}
+
+/// What can be learnt about a box file from its header alone.
+#[deriving(Clone, PartialEq, Show)]
+pub struct HeaderInfo {
+    /// The header of the file. Files written before boxes had headers get the header of an
+    /// unprocessed payload.
+    pub header: Header,
+    /// Whether the file has a header at all.
+    pub has_header: bool,
+    /// The size of the payload following the header, in bytes.
+    pub payload_size: u64,
+}
+
+/// Reads the header at the start of `r`, returning it along with its length in bytes, or returns
+/// `None` if `r` doesn’t start with a header.
+pub fn read_header(r: &mut Reader) -> IoResult<Option<(Header, u64)>> {
+    match r.read_exact(MAGIC.len()) {
+        Ok(ref magic) if magic.as_slice() == MAGIC => {}
+        Ok(_) => return Ok(None),
+        Err(ref e) if e.kind == io::EndOfFile => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut header = Header::new();
+    let mut size = MAGIC.len() as u64 + 4;
+    let n = try!(r.read_be_u32().map_err(corrupt));
+    for _ in range(0, n) {
+        let len = try!(r.read_be_u16().map_err(corrupt)) as uint;
+        let name = try!(utf8(try!(r.read_exact(len).map_err(corrupt))));
+        let len = try!(r.read_be_u32().map_err(corrupt)) as uint;
+        let value = try!(r.read_exact(len).map_err(corrupt));
+        try!(header.set_field(name.as_slice(), value));
+        size += 2 + name.len() as u64 + 4 + len as u64;
+    }
+    Ok(Some((header, size)))
+}
+
+/// Splits the contents of a box file into its header and payload.
+pub fn unframe(bytes: Vec<u8>) -> IoResult<(Header, Vec<u8>)> {
+    if !bytes.as_slice().starts_with(MAGIC) {
+        return Ok((Header::new(), bytes));
+    }
+    let mut r = MemReader::new(bytes);
+    let (header, _) = try!(read_header(&mut r)).unwrap();
+    let payload = try!(r.read_to_end());
+    Ok((header, payload))
+}
+
+fn u64_bytes(n: u64) -> Vec<u8> {
+    let mut w = MemWriter::new();
+    w.write_be_u64(n).unwrap();
+    w.unwrap()
+}
+
+fn read_u64(bytes: Vec<u8>) -> IoResult<u64> {
+    if bytes.len() != 8 {
+        return Err(corrupt(io::standard_error(io::InvalidInput)));
+    }
+    MemReader::new(bytes).read_be_u64()
+}
+
+fn read_offsets(bytes: Vec<u8>) -> IoResult<Vec<FieldOffset>> {
+    let mut r = MemReader::new(bytes);
+    let mut offsets = Vec::new();
+    while !r.eof() {
+        let len = try!(r.read_be_u16().map_err(corrupt)) as uint;
+        offsets.push(FieldOffset {
+            field: try!(utf8(try!(r.read_exact(len).map_err(corrupt)))),
+            offset: try!(r.read_be_u64().map_err(corrupt)),
+            len: try!(r.read_be_u64().map_err(corrupt)),
+        });
+    }
+    Ok(offsets)
+}
+
+fn utf8(bytes: Vec<u8>) -> IoResult<String> {
+    String::from_utf8(bytes).map_err(|_| corrupt(io::standard_error(io::InvalidInput)))
+}
+
+fn corrupt(e: IoError) -> IoError {
+    let detail = match e.detail {
+        Some(detail) => format!("its header is damaged: {}", detail),
+        None => "its header is damaged".to_string(),
+    };
+    IoError {
+        kind: io::InvalidInput,
+        desc: CORRUPT,
+        detail: Some(detail),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{File, MemReader, MemWriter};
+    use schema::SchemaTooNew;
+    use super::{Header, MAGIC, FORMAT_VERSION, WORD_BITS, frame, unframe, read_header, is_corrupt};
+    use super::super::{FileBox, write_value};
+
+    #[test]
+    fn round_trip() {
+        let mut header = Header::new();
+        header.compressor = "test".to_string();
+        header.saves = 3;
+        header.type_tag = "counter".to_string();
+        header.version = 2;
+        header.checksum = Some(7);
+        let bytes = frame(&header, b"payload").unwrap();
+        let header_size = (bytes.len() - b"payload".len()) as u64;
+        let read = read_header(&mut MemReader::new(bytes.clone())).unwrap();
+        assert_eq!(read, Some((header.clone(), header_size)));
+        assert_eq!(unframe(bytes).unwrap(), (header, b"payload".to_vec()));
+    }
+
+    #[test]
+    fn newer_format() {
+        let mut w = MemWriter::new();
+        w.write(MAGIC).unwrap();
+        w.write_be_u32(1).unwrap();
+        w.write_be_u16(6).unwrap();
+        w.write_str("format").unwrap();
+        w.write_be_u32(8).unwrap();
+        w.write_be_u64(FORMAT_VERSION + 1).unwrap();
+        let e = unframe(w.unwrap()).unwrap_err();
+        assert_eq!(SchemaTooNew::from_error(&e), Some(SchemaTooNew {
+            schema: "format".to_string(),
+            found: FORMAT_VERSION + 1,
+            supported: FORMAT_VERSION,
+        }));
+    }
+
+    #[test]
+    fn headerless() {
+        assert_eq!(unframe(vec![1, 2, 3]).unwrap(), (Header::new(), vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn detect_corruption() {
+        let path = Path::new("target/detect_corruption");
+        write_value(&path, &vec![1u32, 2, 3]).unwrap();
+        let bytes = File::open(&path).read_to_end().unwrap();
+        // Cut short in the payload, and then in the header.
+        for &len in [bytes.len() - 4, MAGIC.len() + 6].iter() {
+            File::create(&path).write(bytes.slice_to(len)).unwrap();
+            match FileBox::<Vec<u32>>::open(&path) {
+                Err(ref e) => assert!(is_corrupt(e)),
+                Ok(_) => panic!("opened a damaged box"),
+            }
+        }
+    }
+
+    #[test]
+    fn wider_word_size() {
+        let path = Path::new("target/wider_word_size");
+        write_value(&path, &7u).unwrap();
+        let (header, payload) = unframe(File::open(&path).read_to_end().unwrap()).unwrap();
+        assert_eq!(header.word_bits, WORD_BITS);
+        let mut header = header;
+        header.word_bits = WORD_BITS * 2;
+        File::create(&path).write(frame(&header, payload.as_slice()).unwrap().as_slice()).unwrap();
+        assert_eq!(*FileBox::<uint>::open(&path).unwrap(), 7);
+
+        // Too large for a uint on a 32-bit machine.
+        write_value(&path, &(1u64 << 32)).unwrap();
+        match FileBox::<uint>::open(&path) {
+            Ok(b) => assert_eq!(*b as u64, 1u64 << 32),
+            Err(_) => assert_eq!(WORD_BITS, 32),
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Keeping the values a box held before each save, so that saves can be undone.
+
+use std::io::{mod, File, IoError, IoResult, MemReader, MemWriter};
+use std::io::fs::PathExtensions;
+use std::mem;
+use serialize::{Decodable, Encodable};
+use bincode::{mod, EncoderWriter};
+
+use atomic::write_atomic;
+use encoding::{CompactDecoder, decode_bincode};
+use filevec::{frame_bytes, read_frame};
+use super::FileBox;
+
+/// The path of the file the history of the box at `p` is kept in.
+pub fn history_path(p: &Path) -> Path {
+    let mut name = p.filename().unwrap_or(b"filebox").to_vec();
+    name.push_all(b".history");
+    p.with_filename(name)
+}
+
+/// The history of a box, as bincode encodings of its values.
+pub struct History {
+    path: Path,
+    limit: uint,
+    /// The values the box held before each save, oldest first, as they are in the file.
+    undo: Vec<Vec<u8>>,
+    /// The values undone since the box was last saved with a change, most recently undone last.
+    redo: Vec<Vec<u8>>,
+    /// The value the box was last saved with.
+    saved: Vec<u8>,
+}
+
+impl History {
+    /// Opens the history of the box at `p`, which was last saved with `saved`, keeping up to
+    /// `limit` values.
+    fn open(p: &Path, limit: uint, saved: Vec<u8>) -> IoResult<History> {
+        let path = history_path(p);
+        let mut undo = Vec::new();
+        if path.exists() {
+            let mut r = MemReader::new(try!(File::open(&path).read_to_end()));
+            // A value whose append was cut short is dropped, along with the save it came before.
+            while !r.eof() {
+                match read_frame(&mut r) {
+                    Some(bytes) => undo.push(bytes),
+                    None => break,
+                }
+            }
+        }
+        let mut history = History {
+            path: path,
+            limit: limit,
+            undo: undo,
+            redo: Vec::new(),
+            saved: saved,
+        };
+        try!(history.trim());
+        Ok(history)
+    }
+
+    /// Records that the box has just been saved with `current`.
+    fn saved(&mut self, current: Vec<u8>) -> IoResult<()> {
+        if current == self.saved {
+            return Ok(());
+        }
+        let previous = mem::replace(&mut self.saved, current);
+        self.redo.clear();
+        self.push(previous)
+    }
+
+    fn push(&mut self, val: Vec<u8>) -> IoResult<()> {
+        let frame = try!(frame_bytes(val.clone()));
+        self.undo.push(val);
+        if self.undo.len() > self.limit {
+            return self.trim();
+        }
+        let mut f = try!(File::open_mode(&self.path, io::Append, io::Write));
+        try!(f.write(frame.as_slice()));
+        f.datasync()
+    }
+
+    /// Drops the oldest values beyond the limit.
+    fn trim(&mut self) -> IoResult<()> {
+        if self.undo.len() <= self.limit {
+            return Ok(());
+        }
+        let excess = self.undo.len() - self.limit;
+        self.undo = self.undo.slice_from(excess).to_vec();
+        self.rewrite()
+    }
+
+    fn rewrite(&self) -> IoResult<()> {
+        let mut bytes = Vec::new();
+        for val in self.undo.iter() {
+            bytes.push_all(try!(frame_bytes(val.clone())).as_slice());
+        }
+        write_atomic(&self.path, bytes.as_slice())
+    }
+}
+
+/// Records that `b` has just been written, if its history is being kept.
+pub fn record<'a, T>(b: &mut FileBox<T>) -> IoResult<()>
+        where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    if b._history.is_none() {
+        return Ok(());
+    }
+    let current = try!(bincode::encode(b.val()));
+    b._history.as_mut().unwrap().saved(current)
+}
+
+impl<'a, T> FileBox<T> where T: Decodable<CompactDecoder, IoError>
+                              + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Keeps the value the box held before each save that changes it, up to `limit` of them, in
+    /// the file at `history_path`, so that saves can be undone with `undo`, or stops keeping them
+    /// if `limit` is `None`. The history in the file is kept when it is turned off, and picked up
+    /// again when it is next turned on. The value the box holds when this is called counts as the
+    /// one it was last saved with.
+    pub fn set_history(&mut self, limit: Option<uint>) -> IoResult<()> {
+        self._history = match limit {
+            Some(limit) => {
+                let saved = try!(bincode::encode(self.val()));
+                Some(try!(History::open(&self._path, limit, saved)))
+            }
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Puts the box back to the value it held before its last save, and writes it, returning
+    /// whether there was a save to undo. Changes that haven’t been saved are saved first, so they
+    /// are what gets undone, and can be brought back with `redo`.
+    pub fn undo(&mut self) -> IoResult<bool> {
+        if self._history.is_none() {
+            return Ok(false);
+        }
+        if self._dirty {
+            try!(self.save());
+        }
+        let val = match self._history.as_mut().unwrap().undo.pop() {
+            Some(val) => val,
+            None => return Ok(false),
+        };
+        let decoded = match decode_bincode(val.clone()) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                self._history.as_mut().unwrap().undo.push(val);
+                return Err(e);
+            }
+        };
+        let undone = mem::replace(&mut self._history.as_mut().unwrap().saved, val);
+        // The history sees the box saved with the value it expects, so it records nothing.
+        match self.replace(decoded) {
+            Ok(_) => {
+                let history = self._history.as_mut().unwrap();
+                history.redo.push(undone);
+                try!(history.rewrite());
+                Ok(true)
+            }
+            Err(e) => {
+                let history = self._history.as_mut().unwrap();
+                let val = mem::replace(&mut history.saved, undone);
+                history.undo.push(val);
+                Err(e)
+            }
+        }
+    }
+
+    /// Brings back the value most recently undone with `undo`, and writes it, returning whether
+    /// there was one. Saving a change to the box after undoing forgets what was undone.
+    pub fn redo(&mut self) -> IoResult<bool> {
+        let val = match self._history.as_mut().and_then(|history| history.redo.pop()) {
+            Some(val) => val,
+            None => return Ok(false),
+        };
+        let decoded = try!(decode_bincode(val.clone()));
+        // Recording the write as a change would forget the rest of what was undone.
+        let redo = mem::replace(&mut self._history.as_mut().unwrap().redo, Vec::new());
+        let res = self.replace(decoded);
+        let history = self._history.as_mut().unwrap();
+        history.redo = redo;
+        match res {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                history.redo.push(val);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs;
+    use super::history_path;
+    use super::super::{FileBox, peek};
+
+    #[test]
+    fn undo_and_redo_saves() {
+        let path = Path::new("target/undo_and_redo_saves");
+        let _ = fs::unlink(&history_path(&path));
+        let mut b = FileBox::open_new(&path, "a".to_string()).unwrap();
+        b.set_history(Some(2)).unwrap();
+        for s in ["ab", "abc", "abcd"].iter() {
+            *b = s.to_string();
+            b.save().unwrap();
+        }
+        // Only the two values before the last save are kept.
+        assert!(b.undo().unwrap());
+        assert_eq!(b.as_slice(), "abc");
+        assert_eq!(peek::<String>(&path).unwrap().as_slice(), "abc");
+        assert!(b.undo().unwrap());
+        assert!(!b.undo().unwrap());
+        assert_eq!(b.as_slice(), "ab");
+
+        assert!(b.redo().unwrap());
+        assert_eq!(b.as_slice(), "abc");
+        drop(b);
+        let mut b: FileBox<String> = FileBox::open(&path).unwrap();
+        b.set_history(Some(2)).unwrap();
+        assert!(b.undo().unwrap());
+        assert_eq!(b.as_slice(), "ab");
+        assert!(b.redo().unwrap());
+        assert_eq!(b.as_slice(), "abc");
+        // What was undone before the box was reopened is forgotten.
+        assert!(!b.redo().unwrap());
+    }
+}
@@ -0,0 +1,82 @@
+//! Text summaries of box files, for people looking through a data directory.
+
+use std::io::{fs, IoResult};
+use std::io::fs::PathExtensions;
+use time::{mod, Timespec};
+
+use atomic::write_atomic;
+use header::Header;
+
+/// The path of the summary written next to the box at `p` when `FileBox::set_info_file` is on.
+pub fn info_path(p: &Path) -> Path {
+    let mut name = p.filename().unwrap_or(b"filebox").to_vec();
+    name.push_all(b".info");
+    p.with_filename(name)
+}
+
+/// Describes the box file at `p`, last written with `header`, in a few lines of text: the type
+/// and version it holds, its size, checksum, encoding and compressor, and when it was created and
+/// last written.
+pub fn summary(p: &Path, header: &Header) -> IoResult<String> {
+    let stat = try!(fs::stat(p));
+    let type_tag = if header.type_tag.is_empty() { "unknown" } else { header.type_tag.as_slice() };
+    let checksum = match header.checksum {
+        Some(sum) => format!("{:016x}", sum),
+        None => "none".to_string(),
+    };
+    let created = if header.created_at == 0 {
+        "unknown".to_string()
+    } else {
+        rfc3339(header.created_at as i64 * 1000)
+    };
+    Ok(format!("type: {} (version {})\n\
+                size: {} bytes\n\
+                checksum: {}\n\
+                encoding: {}\n\
+                compressor: {}\n\
+                saves: {}\n\
+                created: {}\n\
+                written: {}\n",
+               type_tag, header.version, stat.size, checksum, header.encoding,
+               header.compressor, header.saves, created, rfc3339(stat.modified as i64)))
+}
+
+/// Writes the summary of the box file at `p` to its info file, replacing it atomically.
+pub fn write_info(p: &Path, header: &Header) -> IoResult<()> {
+    let text = try!(summary(p, header));
+    write_atomic(&info_path(p), text.as_bytes())
+}
+
+/// Removes the info file of the box at `p`, if it has one.
+pub fn remove_info(p: &Path) -> IoResult<()> {
+    let info = info_path(p);
+    if info.exists() {
+        try!(fs::unlink(&info));
+    }
+    Ok(())
+}
+
+fn rfc3339(ms: i64) -> String {
+    time::at_utc(Timespec::new(ms / 1000, 0)).rfc3339().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::File;
+    use std::io::fs::PathExtensions;
+    use super::info_path;
+    use super::super::FileBox;
+
+    #[test]
+    fn write_info_file() {
+        let path = Path::new("target/write_info_file");
+        let mut b = FileBox::open_new(&path, vec![1u8, 2, 3]).unwrap();
+        b.set_info_file(true);
+        b.save().unwrap();
+        let text = File::open(&info_path(&path)).read_to_string().unwrap();
+        assert!(text.as_slice().starts_with("type: unknown (version 0)\n"));
+        assert!(text.as_slice().contains("encoding: bincode\n"));
+        b.delete().unwrap();
+        assert!(!info_path(&path).exists());
+    }
+}
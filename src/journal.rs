@@ -0,0 +1,218 @@
+//! Saving boxes by appending to a write-ahead log.
+
+use std::cmp;
+use std::io::{mod, fs, File, IoResult, MemReader};
+use std::io::fs::PathExtensions;
+
+use atomic::write_atomic;
+use faults::{mod, Faults, ShortWrite, TornWrite, FsyncError, RenameError};
+use super::fnv1a;
+
+/// The state of a journaled box.
+pub struct Journal {
+    /// How many saves are appended to the log before it is checkpointed into the box’s file.
+    pub checkpoint_every: uint,
+    /// How many saves have been appended since the last checkpoint.
+    pub appended: uint,
+}
+
+/// The path of the log of the box at `p`.
+pub fn log_path(p: &Path) -> Path {
+    let mut name = p.filename().unwrap_or(b"filebox").to_vec();
+    name.push_all(b".wal");
+    p.with_filename(name)
+}
+
+/// Appends `bytes`, the contents of a box file, to the log of the box at `p`, and waits for them
+/// to reach the disk.
+///
+/// Each record is its length and checksum followed by the bytes themselves, so that a record cut
+/// short by a crash can be told apart from a complete one.
+///
+/// If `faults` has a failure queued, it is injected into the append. Short and torn writes leave
+/// part of the record in the log, and a failed rename, as the log is never renamed, fails before
+/// anything is written.
+pub fn append(p: &Path, bytes: &[u8], faults: Option<&Faults>) -> IoResult<()> {
+    let fault = faults::take(faults);
+    match fault {
+        Some(RenameError) => return Err(faults::injected(RenameError)),
+        _ => {}
+    }
+    let mut f = try!(File::open_mode(&log_path(p), io::Append, io::Write));
+    let mut record = Vec::with_capacity(bytes.len() + 16);
+    try!(record.write_be_u64(bytes.len() as u64));
+    try!(record.write_be_u64(fnv1a(bytes)));
+    record.push_all(bytes);
+    match fault {
+        Some(ShortWrite(n)) | Some(TornWrite(n)) => {
+            try!(f.write(record.slice_to(cmp::min(n, record.len()))));
+            return Err(faults::injected(fault.unwrap()));
+        }
+        Some(FsyncError) => {
+            try!(f.write(record.as_slice()));
+            return Err(faults::injected(FsyncError));
+        }
+        _ => {}
+    }
+    try!(f.write(record.as_slice()));
+    f.datasync()
+}
+
+/// Returns the last complete record in the log of the box at `p`, if it has one. Records after
+/// one that is incomplete or damaged are ignored, since they can only have been written after it.
+pub fn last_record(p: &Path) -> IoResult<Option<Vec<u8>>> {
+    let log = log_path(p);
+    if !log.exists() {
+        return Ok(None);
+    }
+    let mut r = MemReader::new(try!(File::open(&log).read_to_end()));
+    let mut last = None;
+    loop {
+        let (len, sum) = match (r.read_be_u64(), r.read_be_u64()) {
+            (Ok(len), Ok(sum)) => (len, sum),
+            _ => break,
+        };
+        let bytes = match r.read_exact(len as uint) {
+            Ok(bytes) => bytes,
+            Err(_) => break,
+        };
+        if fnv1a(bytes.as_slice()) != sum {
+            break;
+        }
+        last = Some(bytes);
+    }
+    Ok(last)
+}
+
+/// Brings the file of the box at `p` up to date with its log after a crash, writing the last
+/// complete record into it and removing the log. Does nothing if the box has no log.
+pub fn recover(p: &Path) -> IoResult<()> {
+    match try!(last_record(p)) {
+        Some(bytes) => try!(write_atomic(p, bytes.as_slice())),
+        None => {}
+    }
+    remove(p)
+}
+
+/// Removes the log of the box at `p`, once the box’s file holds everything in it.
+pub fn remove(p: &Path) -> IoResult<()> {
+    let log = log_path(p);
+    if log.exists() {
+        try!(fs::unlink(&log));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{File, Append, Write};
+    use std::io::fs::PathExtensions;
+    use faults::{Faults, ShortWrite};
+    use super::log_path;
+    use super::super::{FileBox, Deflate, peek, write_value};
+
+    #[test]
+    fn replay_journal() {
+        let path = Path::new("target/replay_journal");
+        let mut x = FileBox::open_new(&path, 1u32).unwrap();
+        x.set_journaled(Some(10)).unwrap();
+        *x = 2;
+        x.save().unwrap();
+        *x = 3;
+        x.save().unwrap();
+        assert!(log_path(&path).exists());
+        // The program crashes before the box is checkpointed, and the last record is torn.
+        x._write_on_drop = false;
+        drop(x);
+        File::open_mode(&log_path(&path), Append, Write).unwrap().write(&[0, 0, 1]).unwrap();
+        assert!(peek::<u32>(&path).is_err());
+
+        let mut x = FileBox::<u32>::open(&path).unwrap();
+        assert_eq!(*x, 3);
+        assert!(!log_path(&path).exists());
+        x.set_journaled(Some(1)).unwrap();
+        *x = 4;
+        x.save().unwrap();
+        assert!(!log_path(&path).exists());
+        assert_eq!(peek::<u32>(&path).unwrap(), 4);
+    }
+
+    /// Leaves the box at `p` with `val` saved only in its log.
+    fn crash_with_log(p: &Path, val: u32) {
+        let mut x = FileBox::open_new(p, 0u32).unwrap();
+        x.set_journaled(Some(10)).unwrap();
+        *x = val;
+        x.save().unwrap();
+        x._write_on_drop = false;
+    }
+
+    #[test]
+    fn recover_on_every_open() {
+        let path = Path::new("target/recover_on_every_open");
+        crash_with_log(&path, 1);
+        assert_eq!(*FileBox::<u32>::open_compressed(&path, box Deflate).unwrap(), 1);
+        crash_with_log(&path, 2);
+        assert_eq!(*FileBox::<u32>::open_direct(&path).unwrap(), 2);
+        crash_with_log(&path, 3);
+        assert_eq!(*FileBox::<u32>::open_mapped(&path).unwrap(), 3);
+        assert!(!log_path(&path).exists());
+        assert_eq!(peek::<u32>(&path).unwrap(), 3);
+    }
+
+    #[test]
+    fn journal_over_replaced_file() {
+        let path = Path::new("target/journal_over_replaced_file");
+        let mut x = FileBox::open_new(&path, 1u32).unwrap();
+        x.save().unwrap();
+        x.set_journaled(Some(10)).unwrap();
+        write_value(&path, &5u32).unwrap();
+        *x = 2;
+        // The save would only have gone to the log, but the box still won’t write over the file.
+        assert!(x.save().is_err());
+        assert!(!log_path(&path).exists());
+        x._write_on_drop = false;
+        assert_eq!(*FileBox::<u32>::open(&path).unwrap(), 5);
+    }
+    #[test]
+    fn stop_journaling() {
+        let path = Path::new("target/stop_journaling");
+        let mut x = FileBox::open_new(&path, 1u32).unwrap();
+        x.set_journaled(Some(10)).unwrap();
+        *x = 2;
+        x.save().unwrap();
+        x.set_journaled(None).unwrap();
+        assert!(!log_path(&path).exists());
+        assert_eq!(peek::<u32>(&path).unwrap(), 2);
+        *x = 3;
+        x.save().unwrap();
+        drop(x);
+        assert_eq!(*FileBox::<u32>::open(&path).unwrap(), 3);
+    }
+    #[test]
+    fn delete_journaled() {
+        let path = Path::new("target/delete_journaled");
+        let mut x = FileBox::open_new(&path, 1u32).unwrap();
+        x.set_journaled(Some(10)).unwrap();
+        *x = 2;
+        x.save().unwrap();
+        x.delete().unwrap();
+        assert!(!log_path(&path).exists());
+        assert!(FileBox::<u32>::open(&path).is_err());
+    }
+    #[test]
+    fn fault_in_append() {
+        let path = Path::new("target/fault_in_append");
+        let mut x = FileBox::open_new(&path, 1u32).unwrap();
+        x.set_journaled(Some(10)).unwrap();
+        let faults = Faults::new();
+        x._faults = Some(faults.clone());
+        faults.inject(ShortWrite(10));
+        *x = 2;
+        assert!(x.save().is_err());
+        assert_eq!(faults.pending(), 0);
+        // The torn record is ignored, so the box reopens with the value it had before.
+        x._write_on_drop = false;
+        drop(x);
+        assert_eq!(*FileBox::<u32>::open(&path).unwrap(), 1);
+    }
+}
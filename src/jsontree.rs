@@ -0,0 +1,275 @@
+//! Encoding values as JSON without losing their floats.
+//!
+//! `serialize::json` writes floats with a limited number of digits and turns NaN and the
+//! infinities into `null`, so floats don’t always survive being written and read back. The
+//! `Json` format uses the encoder and writer here instead when it is asked to handle floats
+//! exactly or to treat NaN and the infinities some other way.
+
+use std::collections::TreeMap;
+use std::f64;
+use std::io::IoError;
+use std::mem;
+use serialize::{json, Encodable, Encoder};
+
+type EResult = Result<(), IoError>;
+
+/// Turns `val` into a JSON tree, as `serialize::json` would, but with every float kept as it is.
+pub fn to_tree<T: Encodable<TreeEncoder, IoError>>(val: &T) -> Result<json::Json, IoError> {
+    let mut e = TreeEncoder {
+        last: json::Null,
+        items: Vec::new(),
+        fields: TreeMap::new(),
+        key: None,
+    };
+    try!(val.encode(&mut e));
+    Ok(e.last)
+}
+
+/// The encoder `to_tree` uses. Values are laid out as `serialize::json` lays them out, so the
+/// trees it builds are read by `json::Decoder`.
+pub struct TreeEncoder {
+    /// The last value encoded.
+    last: json::Json,
+    /// The elements of the sequence, tuple or enum variant being encoded.
+    items: Vec<json::Json>,
+    /// The fields of the struct, or entries of the map, being encoded.
+    fields: TreeMap<String, json::Json>,
+    /// The key of the map entry being encoded.
+    key: Option<String>,
+}
+
+impl TreeEncoder {
+    fn set(&mut self, val: json::Json) -> EResult {
+        self.last = val;
+        Ok(())
+    }
+
+    fn take(&mut self) -> json::Json {
+        mem::replace(&mut self.last, json::Null)
+    }
+
+    /// Encodes the elements `f` encodes as a list.
+    fn list(&mut self, f: |&mut TreeEncoder| -> EResult) -> EResult {
+        let outer = mem::replace(&mut self.items, Vec::new());
+        try!(f(self));
+        let items = mem::replace(&mut self.items, outer);
+        self.set(json::List(items))
+    }
+
+    fn item(&mut self, f: |&mut TreeEncoder| -> EResult) -> EResult {
+        try!(f(self));
+        let item = self.take();
+        self.items.push(item);
+        Ok(())
+    }
+
+    /// Encodes the fields or entries `f` encodes as an object.
+    fn object(&mut self, f: |&mut TreeEncoder| -> EResult) -> EResult {
+        let outer = mem::replace(&mut self.fields, TreeMap::new());
+        let outer_key = self.key.take();
+        try!(f(self));
+        let fields = mem::replace(&mut self.fields, outer);
+        self.key = outer_key;
+        self.set(json::Object(fields))
+    }
+
+    fn field(&mut self, name: String, f: |&mut TreeEncoder| -> EResult) -> EResult {
+        try!(f(self));
+        let val = self.take();
+        self.fields.insert(name, val);
+        Ok(())
+    }
+}
+
+impl Encoder<IoError> for TreeEncoder {
+    fn emit_nil(&mut self) -> EResult { self.set(json::Null) }
+    fn emit_uint(&mut self, v: uint) -> EResult { self.set(json::U64(v as u64)) }
+    fn emit_u64(&mut self, v: u64) -> EResult { self.set(json::U64(v)) }
+    fn emit_u32(&mut self, v: u32) -> EResult { self.set(json::U64(v as u64)) }
+    fn emit_u16(&mut self, v: u16) -> EResult { self.set(json::U64(v as u64)) }
+    fn emit_u8(&mut self, v: u8) -> EResult { self.set(json::U64(v as u64)) }
+    fn emit_int(&mut self, v: int) -> EResult { self.set(json::I64(v as i64)) }
+    fn emit_i64(&mut self, v: i64) -> EResult { self.set(json::I64(v)) }
+    fn emit_i32(&mut self, v: i32) -> EResult { self.set(json::I64(v as i64)) }
+    fn emit_i16(&mut self, v: i16) -> EResult { self.set(json::I64(v as i64)) }
+    fn emit_i8(&mut self, v: i8) -> EResult { self.set(json::I64(v as i64)) }
+    fn emit_bool(&mut self, v: bool) -> EResult { self.set(json::Boolean(v)) }
+    fn emit_f64(&mut self, v: f64) -> EResult { self.set(json::F64(v)) }
+    fn emit_f32(&mut self, v: f32) -> EResult { self.set(json::F64(v as f64)) }
+    fn emit_char(&mut self, v: char) -> EResult { self.set(json::String(v.to_string())) }
+    fn emit_str(&mut self, v: &str) -> EResult { self.set(json::String(v.to_string())) }
+
+    fn emit_enum(&mut self, _: &str, f: |&mut TreeEncoder| -> EResult) -> EResult {
+        f(self)
+    }
+    fn emit_enum_variant(&mut self, name: &str, _: uint, cnt: uint,
+                         f: |&mut TreeEncoder| -> EResult) -> EResult {
+        if cnt == 0 {
+            return self.set(json::String(name.to_string()));
+        }
+        try!(self.list(f));
+        let args = self.take();
+        let mut variant = TreeMap::new();
+        variant.insert("variant".to_string(), json::String(name.to_string()));
+        variant.insert("fields".to_string(), args);
+        self.set(json::Object(variant))
+    }
+    fn emit_enum_variant_arg(&mut self, _: uint, f: |&mut TreeEncoder| -> EResult) -> EResult {
+        self.item(f)
+    }
+    fn emit_enum_struct_variant(&mut self, name: &str, id: uint, cnt: uint,
+                                f: |&mut TreeEncoder| -> EResult) -> EResult {
+        self.emit_enum_variant(name, id, cnt, f)
+    }
+    fn emit_enum_struct_variant_field(&mut self, _: &str, idx: uint,
+                                      f: |&mut TreeEncoder| -> EResult) -> EResult {
+        self.emit_enum_variant_arg(idx, f)
+    }
+    fn emit_struct(&mut self, _: &str, _: uint, f: |&mut TreeEncoder| -> EResult) -> EResult {
+        self.object(f)
+    }
+    fn emit_struct_field(&mut self, name: &str, _: uint,
+                         f: |&mut TreeEncoder| -> EResult) -> EResult {
+        self.field(name.to_string(), f)
+    }
+    fn emit_tuple(&mut self, _: uint, f: |&mut TreeEncoder| -> EResult) -> EResult {
+        self.list(f)
+    }
+    fn emit_tuple_arg(&mut self, _: uint, f: |&mut TreeEncoder| -> EResult) -> EResult {
+        self.item(f)
+    }
+    fn emit_tuple_struct(&mut self, _: &str, _: uint,
+                         f: |&mut TreeEncoder| -> EResult) -> EResult {
+        self.list(f)
+    }
+    fn emit_tuple_struct_arg(&mut self, _: uint, f: |&mut TreeEncoder| -> EResult) -> EResult {
+        self.item(f)
+    }
+    fn emit_option(&mut self, f: |&mut TreeEncoder| -> EResult) -> EResult {
+        f(self)
+    }
+    fn emit_option_none(&mut self) -> EResult {
+        self.set(json::Null)
+    }
+    fn emit_option_some(&mut self, f: |&mut TreeEncoder| -> EResult) -> EResult {
+        f(self)
+    }
+    fn emit_seq(&mut self, _: uint, f: |&mut TreeEncoder| -> EResult) -> EResult {
+        self.list(f)
+    }
+    fn emit_seq_elt(&mut self, _: uint, f: |&mut TreeEncoder| -> EResult) -> EResult {
+        self.item(f)
+    }
+    fn emit_map(&mut self, _: uint, f: |&mut TreeEncoder| -> EResult) -> EResult {
+        self.object(f)
+    }
+    fn emit_map_elt_key(&mut self, _: uint, f: |&mut TreeEncoder| -> EResult) -> EResult {
+        try!(f(self));
+        // Keys of objects are strings, so other keys are written as strings holding them, which
+        // `json::Decoder` reads back.
+        self.key = Some(match self.take() {
+            json::String(key) => key,
+            key => key.to_string(),
+        });
+        Ok(())
+    }
+    fn emit_map_elt_val(&mut self, _: uint, f: |&mut TreeEncoder| -> EResult) -> EResult {
+        let key = self.key.take().unwrap_or(String::new());
+        self.field(key, f)
+    }
+}
+
+/// Writes `val` as JSON text, indented across several lines if `pretty` is set, as
+/// `Json::to_pretty_str` does. If `exact` is set, floats are written with as many digits as it
+/// takes for `json::from_str` to read back the same float.
+pub fn write_tree(val: &json::Json, pretty: bool, exact: bool) -> String {
+    let mut out = String::new();
+    write_value(&mut out, val, if pretty { Some(0) } else { None }, exact);
+    out
+}
+
+fn write_value(out: &mut String, val: &json::Json, indent: Option<uint>, exact: bool) {
+    let inner = indent.map(|n| n + 2);
+    match *val {
+        json::F64(v) if exact => out.push_str(exact_float(v).as_slice()),
+        json::List(ref items) if !items.is_empty() => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                new_line(out, inner);
+                write_value(out, item, inner, exact);
+            }
+            new_line(out, indent);
+            out.push(']');
+        }
+        json::Object(ref fields) if !fields.is_empty() => {
+            out.push('{');
+            for (i, (key, field)) in fields.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                new_line(out, inner);
+                out.push_str(json::String(key.clone()).to_string().as_slice());
+                out.push_str(if indent.is_some() { ": " } else { ":" });
+                write_value(out, field, inner, exact);
+            }
+            new_line(out, indent);
+            out.push('}');
+        }
+        ref val => out.push_str(val.to_string().as_slice()),
+    }
+}
+
+fn new_line(out: &mut String, indent: Option<uint>) {
+    match indent {
+        Some(n) => {
+            out.push('\n');
+            for _ in range(0, n) {
+                out.push(' ');
+            }
+        }
+        None => {}
+    }
+}
+
+/// The shortest text for `v` that `json::from_str` reads back as `v`, or `v` with 17 significant
+/// digits, the most an `f64` has, if the reader can’t read it back exactly from any.
+fn exact_float(v: f64) -> String {
+    for digits in range(0u, 17) {
+        let text = f64::to_str_exp_digits(v, digits, false);
+        match json::from_str(text.as_slice()) {
+            Ok(ref read) if read.as_f64() == Some(v) => return text,
+            _ => {}
+        }
+    }
+    f64::to_str_exp_digits(v, 16, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use serialize::json;
+    use super::{to_tree, write_tree};
+
+    #[deriving(Encodable)]
+    struct Reading {
+        name: String,
+        values: Vec<f64>,
+    }
+
+    #[test]
+    fn write_floats_exactly() {
+        let reading = Reading {
+            name: "probe".to_string(),
+            values: vec![0.1, 1.0 / 3.0, 1e300],
+        };
+        let text = write_tree(&to_tree(&reading).unwrap(), false, true);
+        let read = json::from_str(text.as_slice()).unwrap();
+        let values = read.find("values").unwrap().as_list().unwrap();
+        let values: Vec<f64> = values.iter().map(|v| v.as_f64().unwrap()).collect();
+        assert_eq!(values, reading.values);
+        assert_eq!(json::from_str(write_tree(&read, true, false).as_slice()).unwrap(),
+                   json::from_str(read.to_pretty_str().as_slice()).unwrap());
+    }
+}
@@ -0,0 +1,143 @@
+//! Where the header of a box file is kept relative to its payload.
+
+use std::io::{mod, fs, File, IoError, IoResult, MemReader, MemWriter};
+use std::io::fs::PathExtensions;
+
+use atomic::write_atomic;
+use header::{mod, Header};
+use super::fnv1a;
+
+/// How a box is laid out on disk.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Layout {
+    /// The header comes first in the box’s file, followed by the payload. This is the default.
+    Combined,
+    /// The box’s file holds nothing but the payload, and the header is kept in a separate file
+    /// next to it, named after it with `.meta` appended. The payload file then only changes when
+    /// the value does, so tools that copy or deduplicate large files can leave it alone when only
+    /// the header has changed.
+    ///
+    /// The two files can’t be replaced together atomically. A crash between writing them leaves
+    /// a payload that the header doesn’t describe, which is detected when the box is next read,
+    /// so the read fails rather than returning a wrong value.
+    Split,
+}
+
+/// The path of the file holding the header of the box at `p` when its layout is `Split`.
+pub fn meta_path(p: &Path) -> Path {
+    let mut name = p.filename().unwrap_or(b"filebox").to_vec();
+    name.push_all(b".meta");
+    p.with_filename(name)
+}
+
+/// The layout of the box at `p`, judging by whether it has a header file.
+pub fn layout_of(p: &Path) -> Layout {
+    if meta_path(p).exists() { Split } else { Combined }
+}
+
+/// Stores the file of the box at `p` with the given header and payload, in `layout`. `write` is
+/// called to replace the box’s file with the bytes given to it.
+pub fn store(p: &Path, layout: Layout, header: &Header, payload: &[u8],
+             write: |&[u8]| -> IoResult<()>) -> IoResult<()> {
+    let meta = meta_path(p);
+    match layout {
+        Combined => {
+            // Without this, the header file would be taken to describe the new file. Removing it
+            // first means a crash in between leaves the file looking headerless, not corrupt.
+            if meta.exists() {
+                try!(fs::unlink(&meta));
+            }
+            write(try!(header::frame(header, payload)).as_slice())
+        }
+        Split => {
+            try!(write(payload));
+            write_meta(p, header, payload)
+        }
+    }
+}
+
+/// Replaces the header file of the split box at `p` with one holding `header` and describing
+/// `payload`, the contents of the box’s file.
+pub fn write_meta(p: &Path, header: &Header, payload: &[u8]) -> IoResult<()> {
+    let mut hash = MemWriter::new();
+    try!(hash.write_be_u64(fnv1a(payload)));
+    write_atomic(&meta_path(p), try!(header::frame(header, hash.get_ref())).as_slice())
+}
+
+/// Reads the header of the split box at `p`, checking that it describes `payload`, the contents of
+/// the box’s file.
+pub fn read_meta(p: &Path, payload: &[u8]) -> IoResult<Header> {
+    let bytes = try!(File::open(&meta_path(p)).read_to_end());
+    if !bytes.as_slice().starts_with(header::MAGIC) {
+        return Err(mismatched(p));
+    }
+    let (header, hash) = try!(header::unframe(bytes));
+    if try!(MemReader::new(hash).read_be_u64().map_err(|_| mismatched(p))) != fnv1a(payload) {
+        return Err(mismatched(p));
+    }
+    Ok(header)
+}
+
+/// Reads the header of the split box at `p` without reading its payload, so without checking
+/// that the header describes it.
+pub fn read_meta_header(p: &Path) -> IoResult<Header> {
+    let mut f = try!(File::open(&meta_path(p)));
+    match try!(header::read_header(&mut f)) {
+        Some((header, _)) => Ok(header),
+        None => Err(mismatched(p)),
+    }
+}
+
+/// The error for a split box whose header file doesn’t describe its payload, which can only
+/// happen if one of them has been damaged or a crash came between writing them.
+fn mismatched(p: &Path) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: header::CORRUPT,
+        detail: Some(format!("the header file of {} doesn’t describe its payload", p.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::File;
+    use std::io::fs::PathExtensions;
+    use super::{Split, Combined, meta_path, layout_of};
+    use super::super::{FileBox, peek, write_value, open_header};
+
+    #[test]
+    fn split_layout() {
+        let path = Path::new("target/split_layout");
+        {
+            let mut x = FileBox::open_new(&path, vec![1u8, 2, 3]).unwrap();
+            x.set_layout(Split);
+        }
+        assert_eq!(layout_of(&path), Split);
+        // The box’s file holds the bare bincode payload.
+        let payload = File::open(&path).read_to_end().unwrap();
+        assert_eq!(payload, ::bincode::encode(&vec![1u8, 2, 3]).unwrap());
+        assert_eq!(open_header(&path).unwrap().header.saves, 1);
+        assert_eq!(peek::<Vec<u8>>(&path).unwrap(), vec![1, 2, 3]);
+
+        // Writes keep the layout of the file.
+        write_value(&path, &vec![4u8]).unwrap();
+        assert_eq!(peek::<Vec<u8>>(&path).unwrap(), vec![4]);
+        {
+            let mut x: FileBox<Vec<u8>> = FileBox::open(&path).unwrap();
+            x.push(5);
+        }
+        assert_eq!(peek::<Vec<u8>>(&path).unwrap(), vec![4, 5]);
+        assert_eq!(open_header(&path).unwrap().header.saves, 3);
+
+        // A payload the header doesn’t describe is detected.
+        File::create(&path).write(::bincode::encode(&vec![6u8]).unwrap().as_slice()).unwrap();
+        assert!(peek::<Vec<u8>>(&path).is_err());
+
+        {
+            let mut x = FileBox::open_new(&path, vec![7u8]).unwrap();
+            x.set_layout(Combined);
+        }
+        assert!(!meta_path(&path).exists());
+        assert_eq!(peek::<Vec<u8>>(&path).unwrap(), vec![7]);
+    }
+}
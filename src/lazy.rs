@@ -0,0 +1,121 @@
+//! Opening boxes without reading them until their values are needed.
+
+use std::cell::UnsafeCell;
+use std::io::{IoError, IoResult, MemWriter};
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use encoding::CompactDecoder;
+use super::FileBox;
+
+/// A box that isn’t read from its file until its value is first used.
+///
+/// `FileBox::open` reads and decodes the whole file straight away, which is wasted on programs
+/// that only sometimes look at a box. A `LazyFileBox` opens the box the first time it is
+/// dereferenced, and then behaves like the box itself, writing it when it is dropped. A box that
+/// is never used is never read or written.
+///
+/// Dereferencing panics if the box can’t be opened. `load` opens it and returns the error instead,
+/// so programs that want to handle it can call `load` before using the value.
+pub struct LazyFileBox<T> {
+    path: Path,
+    b: UnsafeCell<Option<FileBox<T>>>,
+}
+
+impl<'a, T> LazyFileBox<T> where T: Decodable<CompactDecoder, IoError>
+                                 + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Makes a lazy box for the box file at `p`, which is opened with `FileBox::open` when its
+    /// value is first used.
+    pub fn new(p: &Path) -> LazyFileBox<T> {
+        LazyFileBox {
+            path: p.clone(),
+            b: UnsafeCell::new(None),
+        }
+    }
+
+    /// Opens the box if it hasn’t been opened yet, and returns it.
+    pub fn load(&mut self) -> IoResult<&mut FileBox<T>> {
+        let slot = unsafe { &mut *self.b.get() };
+        if slot.is_none() {
+            *slot = Some(try!(FileBox::open(&self.path)));
+        }
+        Ok(slot.as_mut().unwrap())
+    }
+
+    /// Opens the box if it hasn’t been opened yet, and returns it instead of the lazy box.
+    pub fn into_inner(mut self) -> IoResult<FileBox<T>> {
+        try!(self.load());
+        Ok(unsafe { &mut *self.b.get() }.take().unwrap())
+    }
+
+    fn loaded(&self) -> &FileBox<T> {
+        // The box is only ever put in the slot while it is empty, so references to a box already
+        // in it stay valid for as long as `self` is borrowed.
+        let slot = unsafe { &mut *self.b.get() };
+        if slot.is_none() {
+            *slot = Some(FileBox::open(&self.path).ok().expect("could not read file"));
+        }
+        slot.as_ref().unwrap()
+    }
+}
+
+impl<T> LazyFileBox<T> {
+    /// Returns whether the box has been opened.
+    pub fn is_loaded(&self) -> bool {
+        unsafe { &*self.b.get() }.is_some()
+    }
+
+    /// The path of the box’s file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl<'a, T> Deref<T> for LazyFileBox<T> where T: Decodable<CompactDecoder, IoError>
+                                              + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    fn deref(&self) -> &T {
+        &**self.loaded()
+    }
+}
+
+impl<'a, T> DerefMut<T> for LazyFileBox<T>
+        where T: Decodable<CompactDecoder, IoError>
+               + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    fn deref_mut(&mut self) -> &mut T {
+        match self.load() {
+            Ok(b) => &mut **b,
+            Err(_) => panic!("could not read file"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs;
+    use super::LazyFileBox;
+    use super::super::{FileBox, peek};
+
+    #[test]
+    fn load_on_first_use() {
+        let path = Path::new("target/load_on_first_use");
+        FileBox::open_new(&path, vec![1u8]).unwrap();
+        {
+            let b: LazyFileBox<Vec<u8>> = LazyFileBox::new(&path);
+            assert!(!b.is_loaded());
+            // Never read, so never written either.
+            fs::unlink(&path).unwrap();
+        }
+        assert!(peek::<Vec<u8>>(&path).is_err());
+
+        FileBox::open_new(&path, vec![1u8]).unwrap();
+        let mut b: LazyFileBox<Vec<u8>> = LazyFileBox::new(&path);
+        assert_eq!(b.len(), 1);
+        assert!(b.is_loaded());
+        b.push(2);
+        drop(b);
+        assert_eq!(peek::<Vec<u8>>(&path).unwrap(), vec![1, 2]);
+
+        let mut b: LazyFileBox<Vec<u8>> = LazyFileBox::new(&Path::new("target/no_such_box"));
+        assert!(b.load().is_err());
+    }
+}
@@ -22,53 +22,1545 @@
 //! }
 //! ```
 
+extern crate flate;
+extern crate libc;
 extern crate serialize;
 extern crate bincode;
+extern crate time;
 
 use std::default::Default;
-use std::io::{mod, fs, File, IoError, IoResult, BufferedReader, MemWriter};
+use std::io::{mod, fs, File, IoError, IoResult, MemReader, MemWriter};
 use std::io::fs::PathExtensions;
+use std::mem;
 use std::fmt::{mod, Show, Formatter};
+use std::time::Duration;
 use serialize::{Decodable, Encodable};
 use bincode::{DecoderReader, EncoderWriter};
 
+use atomic::{write_atomic, write_atomic_with, write_atomic_durable};
+use encoding::{Codec, encoding_of, decode_bincode};
+
+pub use atomic::{TempFiles, Durability, NoSync, Flush, Fsync};
+pub use autosave::Autosave;
+pub use backup::{Signature, export_incremental, apply_incremental, BackupReport, backup_store};
+pub use blob::{Blob, BlobHandle, blob_dir};
+pub use boxpath::{DefaultPath, DefaultPaths, box_name, default_path, is_path_collision};
+pub use boxset::{BoxSet, SaveReport};
+pub use cache::{BoxCache, CachedBox};
+pub use clock::{Clock, SystemClock, ManualClock};
+pub use compress::{Compressor, NoCompression, Deflate};
+pub use conflicts::{ConflictCopy, conflict_copies};
+pub use describe::{Describer, describe};
+pub use droppolicy::{DropPolicy, Panic, Ignore, Callback, LogToFile, set_default_drop_policy};
+pub use dump::{dump_as_json, transcode};
+pub use dynamic::{Tagged, TypeRegistry, DecodeFn, DynBox, write_tagged};
+pub use election::{Election, Owner};
+pub use encoding::{Encoding, CompactEncoder, CompactDecoder, encode_compact, decode_compact};
+pub use encoding::{write_compact, write_indexed, peek_compact, read_field, update_field};
+pub use encrypt::{Cipher, Encrypted, is_wrong_key};
+pub use error::{FileBoxError, Io, Decode, Encode, Corrupt, Locked, Conflict, TypeMismatch};
+pub use error::VersionMismatch;
+#[cfg(feature = "test-utils")]
+pub use faults::{Fault, Faults, ShortWrite, FsyncError, RenameError, TornWrite};
+pub use filedir::FileDir;
+pub use filemap::FileMap;
+pub use filequeue::FileQueue;
+pub use filevec::{FileVec, Tail};
+pub use foreign::{Foreign, ForeignCodec};
+pub use format::{Format, Bincode, Plain, Json};
+pub use format::{KeyCase, AsWritten, CamelCase, PascalCase, KebabCase};
+pub use format::{NonFinite, NonFiniteNull, NonFiniteError, NonFiniteString};
+pub use generations::{generation_path, restore_generation};
+pub use graph::{BoxRef, Loader};
+pub use guard::WriteGuard;
+pub use header::{Header, HeaderInfo, FieldOffset, is_corrupt};
+pub use history::history_path;
+pub use info::info_path;
+pub use jsontree::TreeEncoder;
+pub use layout::{Layout, Combined, Split};
+pub use lazy::LazyFileBox;
+pub use lock::{is_locked, LockWait};
+pub use maintenance::{Maintenance, MaintenanceReport, MaintenanceHook, Scheduler};
+pub use memory::{HeapSize, MemoryTracker, size_of_value, is_over_budget};
+pub use metadata::{BoxMetadata, peek_metadata};
+pub use migrate::Migrate;
+pub use mirror::{Mirror, MirrorLag};
+pub use names::{escape_name, unescape_name, MAX_ESCAPED_LEN};
+#[cfg(unix)]
+pub use notify::BoxListener;
+pub use notify::notify_listeners;
+pub use observe::{Observer, BoxObserver, SharedObserver};
+pub use optimistic::{ConflictReport, is_save_conflict};
+pub use options::FileBoxOptions;
+pub use ownership::{Ownership, OwnerRecord, is_owned_elsewhere};
+pub use progress::{Progress, CancelToken, is_cancelled, DeadlineReader, is_timed_out};
+pub use quarantine::{Quarantined, quarantine, is_unreadable, raw_bytes, preserve_corrupt};
+pub use quota::QuotaExceeded;
+pub use readonly::{ReadOnlyHook, is_writable};
+pub use reconcile::{sync, SyncStrategy, NewestWins, GenerationWins, Merge};
+pub use redact::Sensitive;
+pub use registry::{Registry, Migration, MigrationReport};
+pub use replica::{ReadReplica, is_too_stale};
+pub use retention::{Retention, PruneReport};
+pub use rewrite::RewriteReport;
+pub use scan::scan;
+pub use schema::SchemaTooNew;
+pub use sharded::{ShardedDir, Router, HashRouter, ConsistentHash, RangeRouter};
+pub use shared::{SharedBox, WeakBox};
+pub use slow::{SlowHook, Timings};
+pub use snapshots::snapshot_dir;
+pub use space::InsufficientSpace;
+pub use staged::{StagedBox, Stage, MergeFn};
+pub use stats::{Stats, StatsHook};
+pub use stream::{HashReader, HashWriter, StreamReader, StreamWriter};
+pub use storage::{Storage, MemStorage, StorageBox};
+pub use store::{Store, Namespace, HealthReport, Snapshot, DiskUsage, BoxUsage};
+#[cfg(feature = "stress-utils")]
+pub use stress::{Workload, WithLock, StressReport, no_lock};
+pub use syncbox::SyncFileBox;
+pub use tiered::TieredMap;
+pub use times::{Timestamp, Elapsed, TimeRepr, DurationRepr, Seconds, Millis, Rfc3339};
+pub use trace::{TraceHook, tracer};
+pub use transaction::{DirTransaction, CommitHook, Pending};
+pub use typetag::is_type_mismatch;
+pub use verify::{Backup, VerifyReport};
+
+mod archive;
+mod atomic;
+mod autosave;
+mod backup;
+mod blob;
+mod boxpath;
+mod boxset;
+mod cache;
+mod clock;
+mod compress;
+mod conflicts;
+mod describe;
+mod direct;
+mod droppolicy;
+mod dump;
+mod dynamic;
+mod election;
+mod encoding;
+mod encrypt;
+mod error;
+#[cfg_attr(not(feature = "test-utils"), allow(dead_code))]
+mod faults;
+mod filedir;
+mod filemap;
+mod filequeue;
+mod filevec;
+mod foreign;
+mod format;
+mod generations;
+mod graph;
+mod guard;
+mod handoff;
+mod header;
+mod history;
+mod info;
+mod journal;
+mod jsontree;
+mod layout;
+mod lazy;
+mod lock;
+mod mapped;
+mod maintenance;
+mod memory;
+mod metadata;
+mod migrate;
+mod mirror;
+mod names;
+mod notify;
+mod observe;
+mod optimistic;
+mod options;
+mod ownership;
+mod process;
+mod progress;
+mod quarantine;
+mod quota;
+mod readonly;
+mod reconcile;
+mod redact;
+mod registry;
+mod replica;
+mod retention;
+mod rewrite;
+mod scan;
+mod schema;
+mod scratch;
+pub mod selftest;
+mod sharded;
+mod shared;
+mod slow;
+mod snapshots;
+mod space;
+mod staged;
+mod stats;
+mod storage;
+mod store;
+mod stream;
+#[cfg(feature = "stress-utils")]
+mod stress;
+mod syncbox;
+mod tiered;
+mod times;
+mod trace;
+mod transaction;
+mod ttl;
+mod typed;
+mod typetag;
+mod verify;
+
 /// A box that writes to a file when dropped, and reads from a file when created.
+///
+/// The file is replaced atomically when the box is written: the value is written to a temporary
+/// file next to it, which is renamed over the original once it is complete.
 pub struct FileBox<T> {
-    _file: File,
-    _val: T,
+    _path: Path,
+    // Only `None` once `into_inner` has moved the value out, as the box is being dropped.
+    _val: Option<T>,
+    _compressor: Box<Compressor + 'static>,
+    _progress: Option<Box<Progress + 'static>>,
+    _cancel: Option<CancelToken>,
+    _temp: TempFiles,
+    _pin: bool,
+    _optimistic: bool,
+    _id: Option<FileId>,
+    _header: Header,
+    _autosave: Option<autosave::State>,
+    _clock: Box<Clock + 'static>,
+    _stale_slots: bool,
+    _notify: bool,
+    _faults: Option<faults::Faults>,
+    _layout: Layout,
+    _direct: bool,
+    _mapped: bool,
+    _durability: Durability,
+    _memory: Option<memory::Tracked<T>>,
+    _idle_save: Option<Duration>,
+    _last_change: Option<u64>,
+    _dirty: bool,
+    _space_factor: Option<f64>,
+    _max_size: Option<u64>,
+    _lock: Option<lock::Lock>,
+    _owner: Option<ownership::Ownership>,
+    _journal: Option<journal::Journal>,
+    _generations: uint,
+    _panic_safe: bool,
+    _in_modify: bool,
+    _observers: Vec<Box<Observer<T> + 'static>>,
+    _box_observer: Option<SharedObserver>,
+    _format: Option<Box<Format<T> + 'static>>,
+    _codec: Option<Codec<T>>,
+    _read_only: bool,
+    _read_only_hook: Option<ReadOnlyHook>,
+    _slow: Option<(Duration, SlowHook)>,
+    _stats: Stats,
+    _stats_hook: Option<StatsHook>,
+    _write_on_drop: bool,
+    _drop_policy: Option<DropPolicy>,
+    _seen: Option<u64>,
+    _save_interval: Option<Duration>,
+    _ttl: Option<Duration>,
+    _last_tick_save: u64,
+    _info_file: bool,
+    _history: Option<history::History>,
+    _mirror: Option<Mirror>,
+    _scratch: bool,
 }
 
-impl<'a, T> FileBox<T> where T: Decodable<DecoderReader<'a, BufferedReader<File>>, IoError>
+impl<'a, T> FileBox<T> where T: Decodable<CompactDecoder, IoError>
                               + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
     /// Creates a new `FileBox` at the given path with the given value. If the file at the path is
     /// not empty, it will be overwritten.
     pub fn open_new(p: &Path, val: T) -> IoResult<FileBox<T>> {
-        Ok(FileBox {
-            _file: try!(File::open_mode(p, io::Truncate, io::Write)),
-            _val: val,
-        })
+        FileBox::open_new_compressed(p, val, box NoCompression as Box<Compressor + 'static>)
+    }
+
+    /// Like `open_new`, but the value is compressed with the given compressor whenever it is
+    /// written.
+    pub fn open_new_compressed(p: &Path, val: T, c: Box<Compressor + 'static>)
+                               -> IoResult<FileBox<T>> {
+        try!(File::open_mode(p, io::Truncate, io::Write));
+        FileBox::with_value(p, val, c)
     }
 
     /// Opens a `FileBox` from a path, reading the data stored inside. This will fail if the file
-    /// cannot be read or the file contains invalid data.
+    /// cannot be read or the file contains invalid data. The value continues to be stored with the
+    /// compressor it was read with, which must be one provided by this crate.
     pub fn open(p: &Path) -> IoResult<FileBox<T>> {
-        let f = try!(File::open_mode(p, io::Open, io::Read));
-        let val = try!(bincode::decode_from(&mut BufferedReader::new(f)));
-        let f = try!(File::open_mode(p, io::Truncate, io::Write));
-        Ok(FileBox {
-            _file: f,
-            _val: val,
-        })
+        let (header, payload) = try!(read_box(p, None, None, None, false, false));
+        let c = compress::builtin(header.compressor.as_slice()).unwrap();
+        FileBox::from_payload(p, header, payload, c)
+    }
+
+    /// Like `open`, but reports the progress of reading the file to `progress`, and gives up with
+    /// an error for which `is_cancelled` is true if `cancel` is cancelled before the file has been
+    /// read. The box keeps using `progress` and `cancel` whenever it is written.
+    pub fn open_with_progress(p: &Path, mut progress: Box<Progress + 'static>,
+                              cancel: Option<CancelToken>) -> IoResult<FileBox<T>> {
+        let (header, payload) = try!(read_box(p, None, Some(&mut *progress), cancel.as_ref(), false,
+                                              false));
+        let c = compress::builtin(header.compressor.as_slice()).unwrap();
+        let mut b = try!(FileBox::from_payload(p, header, payload, c));
+        b._progress = Some(progress);
+        b._cancel = cancel;
+        Ok(b)
+    }
+
+    /// Like `open`, but the box is read-only, so its file is never written, not even when the box
+    /// is dropped. This is the way to inspect a box without risking its file. See `set_read_only`.
+    ///
+    /// Opening the box doesn’t write anything either: saves in the box’s log are read from the
+    /// log rather than written into the file, and temporary files left by unfinished writes are
+    /// left alone.
+    pub fn open_read_only(p: &Path) -> IoResult<FileBox<T>> {
+        let (header, payload) = try!(read_box(p, None, None, None, false, true));
+        let val = try!(decode_payload(&header, payload));
+        let c = compress::builtin(header.compressor.as_slice()).unwrap();
+        FileBox::from_value(p, header, val, c, true)
+    }
+
+    /// Like `open`, but if the box’s file can’t be written, such as when it is on a read-only
+    /// filesystem, the box is opened read-only instead of failing when it is written. See
+    /// `set_read_only`.
+    pub fn open_or_read_only(p: &Path) -> IoResult<FileBox<T>> {
+        if try!(readonly::is_writable(p)) {
+            FileBox::open(p)
+        } else {
+            FileBox::open_read_only(p)
+        }
+    }
+
+    /// Like `open`, but the box is locked against other processes opening it with `open_locked`,
+    /// `open_new_locked` or `try_open` until it is dropped, waiting for any process that has it
+    /// locked to let go of it first. The lock is advisory, so it doesn’t keep out processes that
+    /// open the box some other way, and is only taken on Unix.
+    pub fn open_locked(p: &Path) -> IoResult<FileBox<T>> {
+        let lock = try!(lock::Lock::acquire(p, true));
+        let mut b = try!(FileBox::open(p));
+        b._lock = Some(lock);
+        Ok(b)
+    }
+
+    /// Like `open_new`, but the box is locked as by `open_locked`.
+    pub fn open_new_locked(p: &Path, val: T) -> IoResult<FileBox<T>> {
+        let lock = try!(lock::Lock::acquire(p, true));
+        let mut b = try!(FileBox::open_new(p, val));
+        b._lock = Some(lock);
+        Ok(b)
+    }
+
+    /// Like `open_locked`, but fails with an error for which `is_locked` is true straight away if
+    /// another process has the box locked.
+    pub fn try_open(p: &Path) -> IoResult<FileBox<T>> {
+        let lock = try!(lock::Lock::acquire(p, false));
+        let mut b = try!(FileBox::open(p));
+        b._lock = Some(lock);
+        Ok(b)
+    }
+
+    /// Like `open_locked`, but the lock is shared, so that any number of processes can have the
+    /// box open this way at once, as can a box opened with `open_locked` while it is in
+    /// `scoped_readonly`. Boxes opened with `open_locked` still keep it out otherwise.
+    pub fn open_shared(p: &Path) -> IoResult<FileBox<T>> {
+        let lock = try!(lock::Lock::acquire_shared(p, true));
+        let mut b = try!(FileBox::open(p));
+        b._lock = Some(lock);
+        Ok(b)
+    }
+
+    /// Like `open_shared`, but fails with an error for which `is_locked` is true straight away if
+    /// another process has the box locked exclusively.
+    pub fn try_open_shared(p: &Path) -> IoResult<FileBox<T>> {
+        let lock = try!(lock::Lock::acquire_shared(p, false));
+        let mut b = try!(FileBox::open(p));
+        b._lock = Some(lock);
+        Ok(b)
+    }
+
+    /// Like `open`, but the box is only written while `ownership`, which must be the ownership of
+    /// the box at `p`, is still held: once another writer has taken the box over, writing it fails
+    /// with an error for which `is_owned_elsewhere` is true. See `Ownership`.
+    pub fn open_owned(p: &Path, ownership: Ownership) -> IoResult<FileBox<T>> {
+        if ownership.path() != p {
+            return Err(IoError {
+                kind: io::InvalidInput,
+                desc: "the ownership is of a different box",
+                detail: Some(ownership.path().display().to_string()),
+            });
+        }
+        let mut b = try!(FileBox::open(p));
+        b._owner = Some(ownership);
+        Ok(b)
+    }
+
+    /// Writes the current value of the box to a new box file at `p`, as `save_as` does, and opens
+    /// it as `open` would. Copies of boxes with a format have no header for `open` to read, so
+    /// they should be made with `save_as` instead.
+    pub fn clone_to(&self, p: &Path) -> IoResult<FileBox<T>> {
+        try!(self.save_as(p));
+        FileBox::open(p)
+    }
+
+    /// Like `open`, but the file is read through a memory map, and the box keeps writing its file
+    /// through one. See `set_mapped`.
+    pub fn open_mapped(p: &Path) -> IoResult<FileBox<T>> {
+        // There is no map to read the log through, so it is recovered as `read_box` would.
+        try!(journal::recover(p));
+        let (header, stored) = try!(mapped::read(p));
+        let (header, payload) = try!(decompress(header, stored, None));
+        let c = compress::builtin(header.compressor.as_slice()).unwrap();
+        let mut b = try!(FileBox::from_payload(p, header, payload, c));
+        b._mapped = true;
+        Ok(b)
+    }
+
+    /// Like `open`, but the file is read with direct I/O, and the box keeps using direct I/O
+    /// whenever it is written. See `set_direct_io`.
+    pub fn open_direct(p: &Path) -> IoResult<FileBox<T>> {
+        let (header, payload) = try!(read_box(p, None, None, None, true, false));
+        let c = compress::builtin(header.compressor.as_slice()).unwrap();
+        let mut b = try!(FileBox::from_payload(p, header, payload, c));
+        b._direct = true;
+        Ok(b)
+    }
+
+    /// Like `open`, but for files written with the given compressor. Files that are stored
+    /// uncompressed can also be opened this way. Either way, the value is compressed with `c` from
+    /// then on.
+    pub fn open_compressed(p: &Path, c: Box<Compressor + 'static>) -> IoResult<FileBox<T>> {
+        FileBox::open_compressed_with(p, c, false)
+    }
+
+    /// Like `open_compressed`, but the box is opened read-only, as by `open_read_only`, if
+    /// `read_only` is set.
+    fn open_compressed_with(p: &Path, c: Box<Compressor + 'static>, read_only: bool)
+                            -> IoResult<FileBox<T>> {
+        let (header, payload) = try!(read_box(p, Some(&*c), None, None, false, read_only));
+        let val = try!(decode_payload(&header, payload));
+        FileBox::from_value(p, header, val, c, read_only)
+    }
+
+    /// Creates a box for the existing file at `p`, from the header and payload read from it by
+    /// `read_box`.
+    fn from_payload(p: &Path, header: Header, payload: Vec<u8>, c: Box<Compressor + 'static>)
+                    -> IoResult<FileBox<T>> {
+        let val = try!(decode_payload(&header, payload));
+        FileBox::from_value(p, header, val, c, false)
+    }
+
+    /// Like `from_payload`, but for a value that has already been decoded. Read-only boxes are
+    /// opened without writing or removing anything.
+    fn from_value(p: &Path, header: Header, val: T, c: Box<Compressor + 'static>,
+                  read_only: bool) -> IoResult<FileBox<T>> {
+        // Switching compressors changes what the box would write, even if the value stays put.
+        let dirty = header.compressor.as_slice() != c.name();
+        let mut b = try!(FileBox::with_value(p, val, c));
+        b._header = header;
+        b._dirty = dirty;
+        b._read_only = read_only;
+        b.record_load(stats::file_size(p));
+        if !read_only {
+            // Temporary files are only left behind by writes that never finished, so nothing in
+            // them is worth keeping. Failing to remove them doesn’t stop the box from working.
+            let _ = b._temp.remove_stale(p);
+        }
+        Ok(b)
+    }
+
+    /// Like `open_compressed`, but the value is decoded with `codec`, whatever encoding the file
+    /// was written with, and the box is written with `codec` from then on. The file is read with
+    /// its own built-in compressor if `c` is `None`. The box is opened read-only, as by
+    /// `open_read_only`, if `read_only` is set.
+    fn open_with_codec(p: &Path, codec: Codec<T>, c: Option<Box<Compressor + 'static>>,
+                       read_only: bool) -> IoResult<FileBox<T>> {
+        let (header, payload) = try!(read_box(p, c.as_ref().map(|c| &**c), None, None, false,
+                                              read_only));
+        let val = try!(codec.decode(&header, payload).map_err(error::undecodable));
+        let c = match c {
+            Some(c) => c,
+            None => compress::builtin(header.compressor.as_slice()).unwrap(),
+        };
+        let dirty = header.encoding != codec.encoding.name();
+        let mut b = try!(FileBox::from_value(p, header, val, c, read_only));
+        b._codec = Some(codec);
+        b._dirty = b._dirty || dirty;
+        Ok(b)
+    }
+
+    /// Opens the box at the given path if the file exists. Otherwise, the box starts out with the
+    /// value stored in `defaults`, which holds the contents of a box file (for example one
+    /// embedded in the program with `include_bytes!`), and the file at the path isn’t created
+    /// until the box is first written.
+    pub fn open_or_bundled(p: &Path, defaults: &[u8]) -> IoResult<FileBox<T>> {
+        if p.exists() {
+            return FileBox::open(p);
+        }
+        let (header, payload) = try!(unpack(defaults.to_vec(), None));
+        let c = compress::builtin(header.compressor.as_slice()).unwrap();
+        Ok(FileBox::without_file(p, try!(decode_payload(&header, payload)), c))
+    }
+
+    /// Like `open_or_bundled`, but the defaults are read from the box file at `defaults`, which is
+    /// never written to. This suits defaults installed in a read-only location.
+    pub fn open_or_copy(p: &Path, defaults: &Path) -> IoResult<FileBox<T>> {
+        if p.exists() {
+            return FileBox::open(p);
+        }
+        let (header, payload) = try!(read_payload(defaults, None, None, None));
+        let c = compress::builtin(header.compressor.as_slice()).unwrap();
+        Ok(FileBox::without_file(p, try!(decode_payload(&header, payload)), c))
+    }
+
+    /// Replaces the box file at `p` with `bytes`, the contents of a box file received from
+    /// elsewhere, such as another machine, and opens it. The bytes are only installed if they have
+    /// a header with a checksum that matches their payload, if the type tag in the header matches
+    /// the one in the file being replaced (when either has none, any tag matches), and if their
+    /// value decodes. The file is replaced atomically, so it is left alone if any of this fails.
+    pub fn adopt_bytes(p: &Path, bytes: &[u8]) -> IoResult<FileBox<T>> {
+        let (header, stored) = try!(header::unframe(bytes.to_vec()));
+        if header.checksum.is_none() {
+            return Err(IoError {
+                kind: io::InvalidInput,
+                desc: "the bytes have no checksum to verify them against",
+                detail: None,
+            });
+        }
+        if p.exists() {
+            let current = try!(open_header(p)).header.type_tag;
+            if !current.is_empty() && !header.type_tag.is_empty() && header.type_tag != current {
+                return Err(IoError {
+                    kind: io::InvalidInput,
+                    desc: "the bytes hold a different type from the box",
+                    detail: Some(format!("expected {}, found {}", current, header.type_tag)),
+                });
+            }
+        }
+        let (header, payload) = try!(decompress(header, stored.clone(), None));
+        let val = try!(decode_payload(&header, payload));
+        try!(layout::store(p, Combined, &header, stored.as_slice(), |bytes| {
+            write_atomic(p, bytes)
+        }));
+        let c = compress::builtin(header.compressor.as_slice()).unwrap();
+        let mut b = try!(FileBox::with_value(p, val, c));
+        b._header = header;
+        b._dirty = false;
+        Ok(b)
+    }
+
+    /// Opens the file at the given path, which holds a value in the given format rather than an
+    /// ordinary box file. The box keeps writing the file in that format.
+    pub fn open_with_format(p: &Path, format: Box<Format<T> + 'static>) -> IoResult<FileBox<T>> {
+        let bytes = try!(File::open(p).read_to_end());
+        let val = try!(format.decode(bytes).map_err(error::undecodable));
+        let c = box NoCompression as Box<Compressor + 'static>;
+        let mut b = try!(FileBox::with_value(p, val, c));
+        b._format = Some(format);
+        b._dirty = false;
+        Ok(b)
     }
 
-    /// Deletes a `FileBox`, deleting the file it is stored in. Returns the result of deleting the
-    /// file.
-    pub fn delete(self) -> IoResult<()> {
-        fs::unlink(self._file.path())
+    /// Replaces the file at the given path, which holds a value in the given format rather than
+    /// an ordinary box file, such as one written by another program, with an ordinary box file
+    /// holding the same value, and opens it. The file is replaced atomically, so it is left alone
+    /// if its value can’t be decoded.
+    pub fn adopt_with_format(p: &Path, format: &Format<T>) -> IoResult<FileBox<T>> {
+        let val = try!(format.decode(try!(File::open(p).read_to_end())));
+        let c = box NoCompression as Box<Compressor + 'static>;
+        let mut b = try!(FileBox::with_value(p, val, c));
+        try!(b.write());
+        Ok(b)
+    }
+
+    /// Like `open_new`, but the box is stored in the given format, as for `open_with_format`.
+    pub fn open_new_with_format(p: &Path, val: T, format: Box<Format<T> + 'static>)
+                                -> IoResult<FileBox<T>> {
+        let mut b = try!(FileBox::open_new(p, val));
+        b._format = Some(format);
+        Ok(b)
+    }
+
+    /// Opens a file at the given path that was written by something other than a box, such as a
+    /// program’s own persistence code. The contents of the file are passed to `decode`, which
+    /// turns them into the value of the box. The file keeps its foreign format until the box is
+    /// first written, when it is replaced by an ordinary box file.
+    pub fn import_legacy(p: &Path, decode: |Vec<u8>| -> IoResult<T>) -> IoResult<FileBox<T>> {
+        let bytes = try!(File::open(p).read_to_end());
+        let val = try!(decode(bytes));
+        FileBox::with_value(p, val, box NoCompression as Box<Compressor + 'static>)
+    }
+
+    /// Creates a box for the existing file at `p`, with every setting other than the compressor
+    /// left at its default.
+    fn with_value(p: &Path, val: T, c: Box<Compressor + 'static>) -> IoResult<FileBox<T>> {
+        let mut b = FileBox::without_file(p, val, c);
+        b._id = Some(try!(FileId::of(p)));
+        b._seen = try!(modified_time(p));
+        Ok(b)
+    }
+
+    /// Like `with_value`, but for a box whose file doesn’t exist yet.
+    fn without_file(p: &Path, val: T, c: Box<Compressor + 'static>) -> FileBox<T> {
+        FileBox {
+            _path: p.clone(),
+            _val: Some(val),
+            _compressor: c,
+            _progress: None,
+            _cancel: None,
+            _temp: Default::default(),
+            _pin: true,
+            _optimistic: false,
+            _id: None,
+            _header: Header {
+                created_at: now(),
+                .. Header::new()
+            },
+            _autosave: None,
+            _clock: box SystemClock as Box<Clock + 'static>,
+            _stale_slots: false,
+            _notify: false,
+            _faults: None,
+            _layout: layout::layout_of(p),
+            _direct: false,
+            _mapped: false,
+            _durability: Flush,
+            _memory: None,
+            _idle_save: None,
+            _last_change: None,
+            _dirty: true,
+            _space_factor: Some(2.0),
+            _max_size: None,
+            _lock: None,
+            _owner: None,
+            _journal: None,
+            _generations: 0,
+            _panic_safe: false,
+            _in_modify: false,
+            _observers: Vec::new(),
+            _box_observer: None,
+            _format: None,
+            _codec: None,
+            _read_only: false,
+            _read_only_hook: None,
+            _slow: None,
+            _stats: Stats::new(),
+            _stats_hook: None,
+            _write_on_drop: true,
+            _drop_policy: None,
+            _seen: None,
+            _save_interval: None,
+            _ttl: None,
+            _last_tick_save: 0,
+            _info_file: false,
+            _history: None,
+            _mirror: None,
+            _scratch: false,
+        }
+    }
+
+    /// Returns the newest autosave of the box at the given path that was written after the box
+    /// itself, if there is one. Such an autosave holds changes that were never saved, usually
+    /// because the program crashed, and can be recovered with `open_recovering`.
+    pub fn newest_autosave(p: &Path) -> IoResult<Option<Path>> {
+        let saved = if p.exists() { try!(fs::stat(p)).modified } else { 0 };
+        Ok(try!(autosave::slots(p)).into_iter()
+                                   .find(|&(modified, _)| modified >= saved)
+                                   .map(|(_, slot)| slot))
+    }
+
+    /// Opens the box at the given path with the value from its newest autosave, if there is one
+    /// that was written after the box itself, and as `open` does otherwise. The autosaves are
+    /// removed the next time the box is written.
+    pub fn open_recovering(p: &Path) -> IoResult<FileBox<T>> {
+        // Saves in the box’s log are newer than its file, so they count when finding the autosave.
+        try!(journal::recover(p));
+        let slot = match try!(FileBox::<T>::newest_autosave(p)) {
+            Some(slot) => slot,
+            None => return FileBox::open(p),
+        };
+        let (header, payload) = try!(read_payload(&slot, None, None, None));
+        let c = compress::builtin(header.compressor.as_slice()).unwrap();
+        let val = try!(decode_payload(&header, payload));
+        let mut b = if p.exists() {
+            try!(FileBox::with_value(p, val, c))
+        } else {
+            FileBox::without_file(p, val, c)
+        };
+        b._header = header;
+        b._stale_slots = true;
+        Ok(b)
+    }
+
+    /// Sets where the progress of writing the box is reported, or stops reporting it if `progress`
+    /// is `None`.
+    pub fn set_progress(&mut self, progress: Option<Box<Progress + 'static>>) {
+        self._progress = progress;
+    }
+
+    /// Sets the token that can cancel writing the box. A cancelled write leaves the file as it
+    /// was.
+    pub fn set_cancel_token(&mut self, cancel: Option<CancelToken>) {
+        self._cancel = cancel;
+    }
+
+    /// Sets how the temporary files used to replace the box’s file are named and where they are
+    /// created.
+    pub fn set_temp_files(&mut self, temp: TempFiles) {
+        self._temp = temp;
+    }
+
+    /// Sets how the box is autosaved, or stops autosaving it if `autosave` is `None`. Autosaves
+    /// only happen when `autosave` or `autosave_if_due` is called.
+    pub fn set_autosave(&mut self, autosave: Option<Autosave>) {
+        let now = self._clock.now_ms();
+        self._autosave = autosave.map(|a| autosave::State::new(a, now));
+    }
+
+    /// Sets the clock used to schedule autosaves. Boxes use the system clock unless told
+    /// otherwise.
+    pub fn set_clock(&mut self, clock: Box<Clock + 'static>) {
+        self._clock = clock;
+    }
+
+    /// Sets whether writing the box wakes the `BoxListener`s of its file. This is off by default.
+    pub fn set_notify(&mut self, notify: bool) {
+        self._notify = notify;
+    }
+
+    /// Sets whether writing the box also writes a short text summary of its file next to it, in
+    /// a file named after it with `.info` added, for people looking through the directory without
+    /// tools that can read box files. This is off by default.
+    pub fn set_info_file(&mut self, info_file: bool) {
+        self._info_file = info_file;
+    }
+
+    /// Sets a second path that every write of the box is copied to in the background, such as on
+    /// another disk, so that losing the disk the box is on doesn’t lose its value. The mirror is
+    /// copied for the first time the next time the box is written. Passing `None` stops
+    /// mirroring. See `Mirror`.
+    pub fn set_mirror(&mut self, mirror: Option<&Path>) {
+        self._mirror = mirror.map(|p| Mirror::new(p));
+    }
+
+    /// The mirror the box is copied to, if it has one, for finding out how far behind it is.
+    pub fn mirror(&self) -> Option<&Mirror> {
+        self._mirror.as_ref()
+    }
+
+    /// Sets what the box does if it is dropped with changes and writing them fails, in place of
+    /// the default policy set with `set_default_drop_policy`. Passing `None` makes the box follow
+    /// the default policy again. See `DropPolicy`.
+    pub fn set_drop_policy(&mut self, policy: Option<DropPolicy>) {
+        self._drop_policy = policy;
+    }
+
+    /// Sets the failures to inject into writes of the box, for testing how a program copes with
+    /// them.
+    #[cfg(feature = "test-utils")]
+    pub fn set_faults(&mut self, faults: Option<Faults>) {
+        self._faults = faults;
+    }
+
+    /// Sets how much free space the box makes sure there is before it is written, as a multiple of
+    /// the size of what it writes, or turns the check off if `factor` is `None`. Writes that would
+    /// leave less fail with an `InsufficientSpace` error before anything is written. The default is
+    /// two, which leaves room for the temporary file and the file it replaces to exist at once.
+    pub fn set_space_preflight(&mut self, factor: Option<f64>) {
+        self._space_factor = factor;
+    }
+
+    /// Sets the largest size, in bytes, the encoded value of the box may be written at, or removes
+    /// the limit if `limit` is `None`, which is the default. Writes of larger values fail with a
+    /// `QuotaExceeded` error before anything is written, and the box keeps its changes.
+    pub fn set_max_size(&mut self, limit: Option<u64>) {
+        self._max_size = limit;
+    }
+
+    /// The largest size the box may be written at, if it is limited. See `set_max_size`.
+    pub fn max_size(&self) -> Option<u64> {
+        self._max_size
+    }
+
+    /// Sets whether `save` appends the box to a write-ahead log next to its file instead of
+    /// replacing the file, which is cheaper for boxes that are saved often. Every
+    /// `checkpoint_every` saves, and whenever the box is closed or dropped, the file is replaced as
+    /// usual and the log removed. After a crash, `open` brings the file up to date with the log
+    /// before reading it. This is off by default, and passing `None` turns it off again, first
+    /// checkpointing any saves that are only in the log, and returning the error if that fails.
+    pub fn set_journaled(&mut self, checkpoint_every: Option<uint>) -> IoResult<()> {
+        match checkpoint_every {
+            Some(every) => {
+                let appended = self._journal.as_ref().map_or(0, |journal| journal.appended);
+                self._journal = Some(journal::Journal {
+                    checkpoint_every: every,
+                    appended: appended,
+                });
+            }
+            None => {
+                // Otherwise the next open would write the log over whatever the box saves next.
+                if self._journal.as_ref().map_or(false, |journal| journal.appended > 0) {
+                    try!(self.write());
+                }
+                self._journal = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets how many previous versions of the box’s file are kept, as `generation_path` names
+    /// them, for recovering from bad writes with `restore_generation`. Each write makes the file
+    /// being replaced the newest previous version and drops the oldest. None are kept by default.
+    pub fn set_generations(&mut self, keep: uint) {
+        self._generations = keep;
+    }
+
+    /// Sets the compressor the box is written with from its next write onwards, such as `Deflate`
+    /// for large values. Opening the box again finds the compressor from the file’s header, as
+    /// long as it is one provided by this crate.
+    pub fn set_compressor(&mut self, c: Box<Compressor + 'static>) {
+        if c.name() != self._compressor.name() {
+            self._dirty = true;
+        }
+        self._compressor = c;
+    }
+
+    /// Sets how the box is laid out on disk from its next write onwards. Boxes keep the layout of
+    /// the file they were opened from, and new boxes start out `Combined`.
+    pub fn set_layout(&mut self, layout: Layout) {
+        if layout != self._layout {
+            self._dirty = true;
+        }
+        self._layout = layout;
+    }
+
+    /// Sets how long the value has to go unchanged before `save_if_idle` writes it, or turns
+    /// idle-time saving off if `quiet` is `None`, which is the default.
+    pub fn set_idle_save(&mut self, quiet: Option<Duration>) {
+        self._idle_save = quiet;
+    }
+
+    /// Sets how often `tick` saves the box while it has changes, or turns periodic saving off if
+    /// `every` is `None`, which is the default. The first interval starts now.
+    pub fn set_save_interval(&mut self, every: Option<Duration>) {
+        self._save_interval = every;
+        self._last_tick_save = self._clock.now_ms();
+    }
+
+    /// Returns whether the value may have changed since the box was last read or written. Any
+    /// mutable access to the value counts as a change.
+    pub fn has_changes(&self) -> bool {
+        self._last_change.is_some()
+    }
+
+    /// Marks the value as changed, so that the box is written when it is dropped. Dropping a box
+    /// only writes it if its file may not hold the current value, which any mutable access to the
+    /// value counts as; this is for values changed in a way the box can’t see, such as through a
+    /// `Cell`.
+    pub fn mark_dirty(&mut self) {
+        self._last_change = Some(self._clock.now_ms());
+        self._dirty = true;
+    }
+
+    /// Sets whether the box is written with direct I/O, which bypasses the operating system’s page
+    /// cache. This is off by default. Writing a large box normally fills the cache with its
+    /// file, evicting data the rest of the system may need; writing it directly avoids that, at
+    /// the cost of the writes themselves being slower.
+    pub fn set_direct_io(&mut self, direct: bool) {
+        self._direct = direct;
+    }
+
+    /// Sets whether the box’s file is written through a memory map, flushed to disk with `msync`,
+    /// rather than with ordinary writes. This is off by default, and suits boxes of many
+    /// megabytes, which `open_mapped` reads without briefly holding two copies of the file in
+    /// memory. Boxes written with direct I/O aren’t written through a map, and writes through a
+    /// map report no progress and can’t be cancelled.
+    pub fn set_mapped(&mut self, mapped: bool) {
+        self._mapped = mapped;
+    }
+
+    /// Sets how far each write of the box, including the one made when it is dropped, goes to
+    /// make sure the new value survives the machine losing power. See `Durability`.
+    pub fn set_durability(&mut self, durability: Durability) {
+        self._durability = durability;
+    }
+
+    /// Flushes the box’s file, and the directory holding it, to disk, so that the value last
+    /// written survives the machine losing power. Boxes written with `NoSync` can call this at
+    /// the points where losing their value would matter, rather than paying for it on every
+    /// write.
+    pub fn sync_all(&self) -> IoResult<()> {
+        try!(File::open(&self._path).and_then(|mut f| f.fsync()));
+        if self._layout == Split {
+            try!(File::open(&layout::meta_path(&self._path)).and_then(|mut f| f.fsync()));
+        }
+        atomic::sync_dir(&self._path)
+    }
+
+    /// Returns whether the box is read-only. See `set_read_only`.
+    pub fn is_read_only(&self) -> bool {
+        self._read_only
+    }
+
+    /// Sets whether the box is read-only. Writing a read-only box fails with an error, and dropping
+    /// one writes nothing; if the value was changed, the hook set with `set_read_only_hook` is
+    /// called instead, so the program can warn that the changes were lost.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self._read_only = read_only;
+    }
+
+    /// Sets the function that is called when the box is dropped while read-only with changes that
+    /// weren’t written.
+    pub fn set_read_only_hook(&mut self, hook: ReadOnlyHook) {
+        self._read_only_hook = Some(hook);
+    }
+
+    /// Sets the function that is called, with how long each step took, whenever writing the box
+    /// or reloading it takes longer than `threshold`, or stops calling it if `threshold` is
+    /// `None`. This suits finding out from users’ logs that something is slowing their disks
+    /// down. See `Timings`.
+    pub fn set_slow_warning(&mut self, threshold: Option<Duration>, hook: SlowHook) {
+        self._slow = threshold.map(|threshold| (threshold, hook));
+    }
+
+    /// Calls the box’s slow warning hook if `timings` took longer than its threshold.
+    fn warn_if_slow(&self, timings: &Timings) {
+        match self._slow {
+            Some((threshold, hook)) if timings.total() > threshold => hook(&self._path, timings),
+            _ => {}
+        }
+    }
+
+    /// Sets whether the box checks that its path still leads to the same file before writing to
+    /// it. This is on by default: if the file has been replaced or removed since the box was opened
+    /// (other than by the box itself), the write fails instead of clobbering the new file.
+    pub fn pin_identity(&mut self, pin: bool) -> IoResult<()> {
+        self._pin = pin;
+        if pin {
+            self._id = try!(FileId::of_existing(&self._path));
+        }
+        Ok(())
+    }
+
+    /// Deletes a `FileBox`, deleting the file it is stored in along with its log, if it is
+    /// journaled. Returns the result of deleting the file.
+    pub fn delete(mut self) -> IoResult<()> {
+        self._write_on_drop = false;
+        // Otherwise opening the box again would bring its last save back from the log.
+        try!(journal::remove(&self._path));
+        if self._layout == Split {
+            try!(fs::unlink(&layout::meta_path(&self._path)));
+        }
+        try!(info::remove_info(&self._path));
+        fs::unlink(&self._path)
+    }
+
+    /// Returns a hash of the encoded form of the current value. Equal values always have equal
+    /// hashes, even on different machines, so this can be compared with `file_content_hash` of a
+    /// copy of the box elsewhere to tell whether the two differ.
+    pub fn content_hash(&self) -> IoResult<u64> {
+        Ok(fnv1a(try!(self.as_bytes()).as_slice()))
+    }
+
+    /// Returns the encoded form of the current value, as it is written to the box’s file before
+    /// being compressed.
+    pub fn as_bytes(&self) -> IoResult<Vec<u8>> {
+        bincode::encode(self.val())
+    }
+
+    /// Returns the bytes of the box’s file as they are on disk now, without decoding them. These
+    /// only hold the current value if the box has no unwritten changes, and only include the
+    /// header if the box’s layout is `Combined`.
+    pub fn disk_bytes(&self) -> IoResult<Vec<u8>> {
+        File::open(&self._path).read_to_end()
+    }
+
+    /// Returns the header the box was last read or written with. Its statistics describe the
+    /// box’s file as of then.
+    pub fn header(&self) -> &Header {
+        &self._header
+    }
+
+    /// Writes the box and then hands its value to `f` for reading only, leaving other programs
+    /// free to modify the file while `f` runs. If the box was opened with `open_locked`, its lock
+    /// is downgraded to a shared one meanwhile, so that other programs can open the box with
+    /// `open_shared`. Once `f` returns, the lock is made exclusive again, waiting for them to let
+    /// go of it, and the box takes its value from the file again, so it picks up whatever changes
+    /// were made in the meantime, and writes to it resume as normal.
+    pub fn scoped_readonly<R>(&mut self, f: |&T| -> R) -> IoResult<R> {
+        try!(self.write());
+        match self._lock {
+            Some(ref lock) => try!(lock.downgrade()),
+            None => {}
+        }
+        let res = f(self.val());
+        match self._lock {
+            Some(ref lock) => try!(lock.upgrade()),
+            None => {}
+        }
+        try!(self.reread());
+        Ok(res)
+    }
+
+    /// Replaces the value of the box with the one in its file, picking up changes made to the file
+    /// by other programs, such as a person editing it by hand. This fails with an error for which
+    /// `is_reload_conflict` is true if the box has changes that haven’t been written, which would
+    /// be lost.
+    pub fn reload(&mut self) -> IoResult<()> {
+        let logged = self._journal.as_ref().map_or(false, |journal| journal.appended > 0);
+        if self._dirty || logged {
+            return Err(IoError {
+                kind: io::OtherIoError,
+                desc: RELOAD_CONFLICT,
+                detail: Some(self._path.display().to_string()),
+            });
+        }
+        self.reread()
+    }
+
+    /// Reloads the box, as `reload` does, if its file has been modified or replaced since the box
+    /// last read or wrote it, and returns whether it did. Programs can call this whenever they
+    /// want to pick up outside changes, such as every so often or when a `BoxListener` wakes.
+    pub fn reload_if_changed(&mut self) -> IoResult<bool> {
+        let id = try!(FileId::of_existing(&self._path));
+        if id == self._id && try!(modified_time(&self._path)) == self._seen {
+            return Ok(false);
+        }
+        try!(self.reload());
+        Ok(true)
+    }
+
+    /// Replaces the value of the box with the one in its file, telling its `BoxObserver`.
+    fn reread(&mut self) -> IoResult<()> {
+        let mut timings = Timings::new(false);
+        let res = self.read_file(&mut timings);
+        if res.is_ok() {
+            self.warn_if_slow(&timings);
+        }
+        match (&self._box_observer, &res) {
+            (&Some(ref observer), &Ok(())) => observer.on_load(&self._path, &self._header),
+            (&Some(ref observer), &Err(ref e)) if is_corrupt(e) => {
+                observer.on_corruption(&self._path, e)
+            }
+            _ => {}
+        }
+        res
+    }
+
+    fn read_file(&mut self, timings: &mut Timings) -> IoResult<()> {
+        let start = time::precise_time_ns();
+        match self._format {
+            Some(ref format) => {
+                let bytes = try!(File::open(&self._path).read_to_end());
+                timings.read = slow::since(start);
+                let start = time::precise_time_ns();
+                self._val = Some(try!(format.decode(bytes).map_err(error::undecodable)));
+                timings.decode = slow::since(start);
+            }
+            None => {
+                let (header, payload) = {
+                    let progress = self._progress.as_mut().map(|p| &mut **p as &mut Progress);
+                    try!(read_encoded(&self._path, Some(&*self._compressor), progress,
+                                      self._cancel.as_ref(), self._direct))
+                };
+                timings.read = slow::since(start);
+                let start = time::precise_time_ns();
+                self._val = Some(try!(match self._codec {
+                    Some(ref codec) => codec.decode(&header, payload).map_err(error::undecodable),
+                    None => decode_payload(&header, payload),
+                }));
+                timings.decode = slow::since(start);
+                self._header = header;
+            }
+        }
+        self._last_change = None;
+        self._dirty = false;
+        // The file may have been replaced while the box wasn’t writing to it, but the box has
+        // seen the new one now, so it is safe to write to.
+        self._id = Some(try!(FileId::of(&self._path)));
+        self._seen = try!(modified_time(&self._path));
+        self.record_load(stats::file_size(&self._path));
+        Ok(())
+    }
+}
+
+impl<'a, T> FileBox<T> where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Writes the current value to the box’s file now, returning the error if that fails, or
+    /// appends it to the box’s log if the box is journaled. See `set_journaled`.
+    pub fn save(&mut self) -> IoResult<()> {
+        let checkpoint = match self._journal {
+            Some(ref journal) => journal.appended + 1 >= journal.checkpoint_every,
+            None => true,
+        };
+        if checkpoint || self._read_only || self._format.is_some() || self._layout == Split {
+            return self.write();
+        }
+        try!(self.check_writable());
+        let start = time::precise_time_ns();
+        let (header, bytes) = try!(self.encode_file());
+        try!(quota::check(self._max_size, bytes.len()));
+        match self._space_factor {
+            Some(factor) => try!(space::check(&self._path.dir_path(),
+                                              (bytes.len() as f64 * factor) as u64)),
+            None => {}
+        }
+        try!(journal::append(&self._path, bytes.as_slice(), self._faults.as_ref()));
+        self._journal.as_mut().unwrap().appended += 1;
+        self.record_save(bytes.len() as u64, slow::since(start));
+        self._header = header;
+        self._last_change = None;
+        self._dirty = false;
+        self.notify_observers();
+        match self._box_observer {
+            Some(ref observer) => observer.on_save(&self._path, &self._header),
+            None => {}
+        }
+        history::record(self)
+    }
+
+    /// Writes the box and closes it, returning the result of the write. Dropping a box writes it
+    /// too, but panics if the write fails, so programs that need to handle the error should close
+    /// their boxes instead.
+    pub fn close(mut self) -> IoResult<()> {
+        let res = self.write();
+        self._write_on_drop = false;
+        res
+    }
+
+    /// Replaces the value of the box with `new` and writes it to the box’s file straight away,
+    /// returning the old value. If the write fails, the box is left holding the old value.
+    pub fn replace(&mut self, new: T) -> IoResult<T> {
+        let old = mem::replace(&mut self._val, Some(new));
+        match self.write() {
+            Ok(()) => Ok(old.unwrap()),
+            Err(e) => {
+                self._val = old;
+                Err(e)
+            }
+        }
+    }
+
+    /// Writes the current value of the box to the file at `p`, replacing it atomically, without
+    /// writing the box’s own file or marking the box as saved. This suits taking a copy of the
+    /// box for export or backup. The copy has the box’s type tag, version and compressor, and
+    /// its format if it has one, but is always laid out with its header in the same file. The
+    /// copy is written with the box’s temporary files and durability, and within its size limit
+    /// and free space check, as the box’s own file would be.
+    pub fn save_as(&self, p: &Path) -> IoResult<()> {
+        let (bytes, direct) = match self._format {
+            Some(ref format) => {
+                let bytes = try!(format.encode(self.val()).map_err(error::unencodable));
+                try!(preflight(p, &self._temp, self._max_size, self._space_factor, bytes.len()));
+                (bytes, false)
+            }
+            None => {
+                let (header, payload) = try!(self.encode_payload());
+                try!(preflight(p, &self._temp, self._max_size, self._space_factor,
+                               payload.len()));
+                (try!(header::frame(&header, payload.as_slice())), self._direct)
+            }
+        };
+        write_atomic_durable(p, bytes.as_slice(), &self._temp, None, self._cancel.as_ref(),
+                             self._faults.as_ref(), direct, self._durability.clone(), None)
+    }
+
+    /// Exchanges the values of this box and `other` and writes both files straight away, this
+    /// box’s first. If either write fails, the values are exchanged back, and this box’s file is
+    /// rewritten with its old value if it had already been written.
+    pub fn swap(&mut self, other: &mut FileBox<T>) -> IoResult<()> {
+        mem::swap(&mut self._val, &mut other._val);
+        match self.write() {
+            Ok(()) => {}
+            Err(e) => {
+                mem::swap(&mut self._val, &mut other._val);
+                return Err(e);
+            }
+        }
+        match other.write() {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                mem::swap(&mut self._val, &mut other._val);
+                try!(self.write());
+                Err(e)
+            }
+        }
+    }
+
+    /// Writes the current value to the box’s file.
+    fn write(&mut self) -> IoResult<()> {
+        try!(self.prepare_write());
+        let mut timings = Timings::new(true);
+        let start = time::precise_time_ns();
+        // The value is encoded before the box’s fields are borrowed for the write.
+        let encoded = match self._format {
+            Some(_) => None,
+            None => Some(try!(self.encode_payload())),
+        };
+        let header = {
+            let path = &self._path;
+            let temp = &self._temp;
+            let mut progress = self._progress.as_mut().map(|p| &mut **p as &mut Progress);
+            let cancel = self._cancel.as_ref();
+            let faults = self._faults.as_ref();
+            let direct = self._direct;
+            let mapped = self._mapped;
+            let durability = self._durability.clone();
+            let timings = &mut timings;
+            let factor = self._space_factor;
+            let max_size = self._max_size;
+            match self._format {
+                // These files are left without a header, so that they can be edited by hand.
+                Some(ref format) => {
+                    let bytes = try!(format.encode(self._val.as_ref().unwrap())
+                                           .map_err(error::unencodable));
+                    timings.encode = slow::since(start);
+                    try!(preflight(path, temp, max_size, factor, bytes.len()));
+                    try!(write_atomic_durable(path, bytes.as_slice(), temp, progress, cancel,
+                                              faults, false, durability, Some(timings)));
+                    self._header.clone()
+                }
+                None => {
+                    let (header, payload) = encoded.unwrap();
+                    timings.encode = slow::since(start);
+                    try!(preflight(path, temp, max_size, factor, payload.len()));
+                    try!(layout::store(path, self._layout.clone(), &header, payload.as_slice(),
+                                       |bytes| {
+                        let progress = progress.take();
+                        if mapped && !direct {
+                            // The map is written and flushed in one go, so the steps can’t be
+                            // told apart.
+                            let start = time::precise_time_ns();
+                            let res = mapped::write_atomic_mapped(path, bytes, temp,
+                                                                  durability.clone());
+                            timings.write = slow::since(start);
+                            res
+                        } else {
+                            write_atomic_durable(path, bytes, temp, progress, cancel, faults,
+                                                 direct, durability.clone(), Some(&mut *timings))
+                        }
+                    }));
+                    header
+                }
+            }
+        };
+        try!(self.finish_write(header));
+        self.record_save(stats::file_size(&self._path), slow::since(start));
+        self.warn_if_slow(&timings);
+        history::record(self)
+    }
+
+    /// Encodes the value as the payload of the box’s file, with the box’s compressor and encoding,
+    /// returning the header for the new payload along with it.
+    fn encode_payload(&self) -> IoResult<(Header, Vec<u8>)> {
+        match self._codec {
+            Some(ref codec) => {
+                let encoded = try!(codec.encode(self.val()).map_err(error::unencodable));
+                let payload = try!(self._compressor.compress(encoded.as_slice()));
+                let mut header = self._header.next(self._compressor.name(), payload.as_slice());
+                header.encoding = codec.encoding.name();
+                Ok((header, payload))
+            }
+            None => encode_payload(self.val(), &*self._compressor, &self._header),
+        }
+    }
+
+    /// Like `encode_payload`, but returns the contents of the whole file.
+    fn encode_file(&self) -> IoResult<(Header, Vec<u8>)> {
+        let (header, payload) = try!(self.encode_payload());
+        let bytes = try!(header::frame(&header, payload.as_slice()));
+        Ok((header, bytes))
+    }
+
+    /// Writes the box if it has changes, idle-time saving has been set up with `set_idle_save`,
+    /// the value has gone unchanged for the time given there, and `app_idle` is true, returning
+    /// whether it did. `app_idle` is the program’s own judgement of whether now is a good time,
+    /// for example because the user hasn’t touched the keyboard for a while, so that large writes
+    /// happen when they are least likely to get in the way.
+    pub fn save_if_idle(&mut self, app_idle: bool) -> IoResult<bool> {
+        let (quiet, last_change) = match (self._idle_save, self._last_change) {
+            (Some(quiet), Some(last_change)) if app_idle => (quiet, last_change),
+            _ => return Ok(false),
+        };
+        if self._clock.now_ms() < last_change + quiet.num_milliseconds() as u64 {
+            return Ok(false);
+        }
+        try!(self.write());
+        Ok(true)
+    }
+
+    /// Writes the current value to the next autosave slot of the box. This fails if autosaving
+    /// hasn’t been set up with `set_autosave`.
+    pub fn autosave(&mut self) -> IoResult<()> {
+        let slot = match self._autosave {
+            Some(ref mut state) => state.take_slot(&self._path, self._clock.now_ms()),
+            None => return Err(IoError {
+                kind: io::InvalidInput,
+                desc: "autosaving hasn’t been set up for this box",
+                detail: None,
+            }),
+        };
+        let (_, bytes) = try!(self.encode_file());
+        try!(write_atomic_with(&slot, bytes.as_slice(), &self._temp, None, None,
+                               self._faults.as_ref()));
+        self._stale_slots = true;
+        Ok(())
+    }
+
+    /// Autosaves the box if autosaving has been set up and the interval since the last autosave
+    /// has passed, returning whether it did. Long-running programs can call this regularly, for
+    /// example from their event loop.
+    pub fn autosave_if_due(&mut self) -> IoResult<bool> {
+        let now = self._clock.now_ms();
+        if !self._autosave.as_ref().map_or(false, |state| state.is_due(now)) {
+            return Ok(false);
+        }
+        try!(self.autosave());
+        Ok(true)
+    }
+
+    /// Saves the box with `save` if periodic saving has been set up with `set_save_interval`, the
+    /// box has changes, and the interval has passed since `tick` last saved it, returning whether
+    /// it did. Long-running programs, such as daemons, can call this from their event loop or a
+    /// timer, so that a crash loses at most one interval’s worth of changes, even though `Drop`
+    /// never runs.
+    pub fn tick(&mut self) -> IoResult<bool> {
+        let every = match self._save_interval {
+            Some(every) => every,
+            None => return Ok(false),
+        };
+        let now = self._clock.now_ms();
+        if !self._dirty || now < self._last_tick_save + every.num_milliseconds() as u64 {
+            return Ok(false);
+        }
+        try!(self.save());
+        self._last_tick_save = now;
+        Ok(true)
     }
 }
 
-impl<'a, T> FileBox<T> where T: Decodable<DecoderReader<'a, BufferedReader<File>>, IoError>
+impl<T> FileBox<T> {
+    /// Closes the box without writing it, returning its value. Changes made since the box was last
+    /// written are kept in the value but never reach the file, so this is the way to abandon them.
+    pub fn into_inner(mut self) -> T {
+        self._write_on_drop = false;
+        if self._scratch {
+            let _ = fs::unlink(&self._path);
+        }
+        // The rest of the box is dropped as usual, which writes nothing now.
+        self._val.take().unwrap()
+    }
+
+    /// The value of the box, which is only missing once `into_inner` has taken it.
+    fn val(&self) -> &T {
+        self._val.as_ref().unwrap()
+    }
+
+    fn val_mut(&mut self) -> &mut T {
+        self._val.as_mut().unwrap()
+    }
+
+    /// Takes the value out of the box, leaving the default value of its type in its place. The box
+    /// counts as changed, so unless the value is put back, the default value is what gets written
+    /// the next time the box is.
+    pub fn take(&mut self) -> T where T: Default {
+        self.modify(|val| mem::replace(val, Default::default()))
+    }
+
+    /// The path of the box’s file.
+    pub fn path(&self) -> &Path {
+        &self._path
+    }
+
+    /// How much the box has read and written since it was opened.
+    pub fn stats(&self) -> &Stats {
+        &self._stats
+    }
+
+    /// Sets the function that is called with the box’s statistics each time it is read or
+    /// written, or stops calling one if `hook` is `None`.
+    pub fn set_stats_hook(&mut self, hook: Option<StatsHook>) {
+        self._stats_hook = hook;
+    }
+
+    /// Adds a read of `bytes` to the box’s statistics.
+    fn record_load(&mut self, bytes: u64) {
+        self._stats.loads += 1;
+        self._stats.bytes_read += bytes;
+        match self._stats_hook {
+            Some(hook) => hook(&self._path, &self._stats),
+            None => {}
+        }
+    }
+
+    /// Adds a save that wrote `bytes` and took `took` to the box’s statistics.
+    fn record_save(&mut self, bytes: u64, took: Duration) {
+        self._stats.saves += 1;
+        self._stats.bytes_written += bytes;
+        self._stats.last_save_duration = Some(took);
+        self._stats.last_save_at = Some(self._clock.now_ms());
+        match self._stats_hook {
+            Some(hook) => hook(&self._path, &self._stats),
+            None => {}
+        }
+    }
+
+    /// Adds an observer that is called whenever the value of the box changes.
+    pub fn on_change(&mut self, observer: Box<Observer<T> + 'static>) {
+        self._observers.push(observer);
+    }
+
+    /// Attaches `observer` to the box, in place of any attached before, or detaches it if
+    /// `observer` is `None`. See `BoxObserver`.
+    pub fn set_box_observer(&mut self, observer: Option<SharedObserver>) {
+        self._box_observer = observer;
+    }
+
+    /// Hands the value of the box to `f` to be changed, and then tells the box’s observers about
+    /// the change. See `set_panic_safe` for what happens if `f` panics.
+    pub fn modify<R>(&mut self, f: |&mut T| -> R) -> R {
+        if !self._panic_safe {
+            let res = f(&mut **self);
+            self.notify_observers();
+            return res;
+        }
+        self._in_modify = true;
+        let res = f(self.val_mut());
+        self._in_modify = false;
+        self._last_change = Some(self._clock.now_ms());
+        self._dirty = true;
+        self.notify_observers();
+        res
+    }
+
+    /// Sets whether the box guards against panics during `modify`. This is off by default, and a
+    /// box dropped by a panic in the middle of `modify` writes the half-changed value to its file.
+    /// When it is on, the value only counts as changed once `modify` returns, and a box dropped
+    /// while `modify` is running writes nothing, leaving its file as it was last written.
+    pub fn set_panic_safe(&mut self, safe: bool) {
+        self._panic_safe = safe;
+    }
+
+    fn notify_observers(&mut self) {
+        let val = self._val.as_ref().unwrap();
+        for observer in self._observers.iter_mut() {
+            observer.changed(val);
+        }
+    }
+
+    /// Registers the box with `tracker`, or stops it being tracked if `tracker` is `None`. The
+    /// memory used by the value is measured by `size`, which suits types that don’t implement
+    /// `HeapSize`.
+    pub fn set_memory_tracker_with(&mut self, tracker: Option<MemoryTracker>,
+                                   size: fn(&T) -> uint) {
+        self._memory = None;
+        self._memory = tracker.map(|t| memory::Tracked::new(t, &self._path, self.val(), size));
+    }
+
+    /// Checks that the box can be written, and makes room for its new file among its
+    /// generations.
+    fn prepare_write(&mut self) -> IoResult<()> {
+        try!(self.check_writable());
+        generations::rotate(&self._path, self._generations)
+    }
+
+    /// Checks that the box may be saved, whether to its file or to its log.
+    fn check_writable(&self) -> IoResult<()> {
+        if self._read_only {
+            return Err(readonly::error(&self._path));
+        }
+        match self._owner {
+            Some(ref owner) => try!(owner.check()),
+            None => {}
+        }
+        // Someone else’s save replaces the file, so this is checked first to report it as such.
+        try!(optimistic::check(self));
+        if self._pin && try!(FileId::of_existing(&self._path)) != self._id {
+            return Err(IoError {
+                kind: io::OtherIoError,
+                desc: "the box’s file has been replaced by a different file",
+                detail: Some(self._path.display().to_string()),
+            });
+        }
+        Ok(())
+    }
+
+    /// Brings the box up to date with its file, which has just been written with `header`.
+    fn finish_write(&mut self, header: Header) -> IoResult<()> {
+        self._header = header;
+        self._last_change = None;
+        self._dirty = false;
+        // The file holds everything in the log now, even if the box is no longer journaled.
+        try!(journal::remove(&self._path));
+        match self._journal {
+            Some(ref mut journal) => journal.appended = 0,
+            None => {}
+        }
+        // Writing replaces the file, so the box now has to look out for the new one.
+        self._id = Some(try!(FileId::of(&self._path)));
+        self._seen = try!(modified_time(&self._path));
+        if self._stale_slots {
+            // The autosaves are older than the file now, so nothing would recover them anyway.
+            let _ = autosave::remove_slots(&self._path);
+            self._stale_slots = false;
+        }
+        self.report_memory();
+        self.notify_observers();
+        match self._box_observer {
+            Some(ref observer) => observer.on_save(&self._path, &self._header),
+            None => {}
+        }
+        if self._notify {
+            // Listeners that miss a notification still see the new value the next time they read
+            // the file, so the write has succeeded either way.
+            let _ = notify::notify_listeners(&self._path);
+        }
+        if self._info_file {
+            // The summary is only a convenience, so the write has succeeded even if it fails.
+            let _ = info::write_info(&self._path, &self._header);
+        }
+        match self._mirror {
+            // The box’s own file has been written, so failing to copy it only leaves the mirror
+            // behind, which its lag reports.
+            Some(ref mirror) => { let _ = mirror.send(&self._path); }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Tells the box’s memory tracker the current size of its value. Boxes also do this whenever
+    /// they are written.
+    pub fn report_memory(&self) {
+        match self._memory {
+            Some(ref tracked) => tracked.report(self.val()),
+            None => {}
+        }
+    }
+}
+
+impl<T> FileBox<T> where T: HeapSize {
+    /// Registers the box with `tracker`, or stops it being tracked if `tracker` is `None`.
+    pub fn set_memory_tracker(&mut self, tracker: Option<MemoryTracker>) {
+        self.set_memory_tracker_with(tracker, size_of_value::<T>);
+    }
+
+    /// The memory used by the box’s value, in bytes, as estimated by `HeapSize`.
+    pub fn heap_size(&self) -> uint {
+        size_of_value(self.val())
+    }
+}
+
+impl<T> FileBox<T> where T: Show {
+    /// Shows the box’s value for diagnostics output, such as logs sent to support. Fields wrapped
+    /// in `Sensitive` are masked, as they are in all `Show` output.
+    pub fn redacted_debug(&self) -> String {
+        format!("{}", self.val())
+    }
+}
+
+impl<'a, T> FileBox<T> where T: Decodable<CompactDecoder, IoError>
                               + Encodable<EncoderWriter<'a, MemWriter>, IoError>
                               + Default {
     /// Creates a new `FileBox` at the given path with its default value.
@@ -87,36 +1579,391 @@ impl<'a, T> FileBox<T> where T: Decodable<DecoderReader<'a, BufferedReader<File>
     }
 }
 
+impl<'a, T> FileBox<T> where T: Decodable<CompactDecoder, IoError>
+                              + Encodable<EncoderWriter<'a, MemWriter>, IoError>
+                              + Encodable<CompactEncoder, IoError> {
+    /// Like `open`, but for files written with any encoding, such as by `write_compact`. The box
+    /// keeps writing its file with the encoding it was read with, unless it is changed with
+    /// `set_encoding`.
+    pub fn open_encoded(p: &Path) -> IoResult<FileBox<T>> {
+        try!(journal::recover(p));
+        let encoding = try!(encoding_of(&try!(open_header(p)).header));
+        FileBox::open_with_codec(p, Codec::new(encoding), None, false)
+    }
+
+    /// Sets the encoding the box is written with from its next write onwards, such as one with
+    /// `varint` for values made up mostly of small numbers. Boxes written with an encoding other
+    /// than the default have to be opened again with `open_encoded` or
+    /// `FileBoxOptions::encoding`, which read any encoding, rather than `open`.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        if encoding.name() != self._header.encoding {
+            self._dirty = true;
+        }
+        self._codec = Some(Codec::new(encoding));
+    }
+}
+
+/// Reads the value stored at the given path without creating a `FileBox`. Unlike `FileBox::open`,
+/// this never writes anything to the file.
+pub fn peek<T>(p: &Path) -> IoResult<T> where T: Decodable<CompactDecoder, IoError> {
+    let (_, payload) = try!(read_payload(p, None, None, None));
+    decode_bincode(payload)
+}
+
+/// Like `peek`, but for files written with the given compressor.
+pub fn peek_compressed<T>(p: &Path, c: &Compressor) -> IoResult<T>
+        where T: Decodable<CompactDecoder, IoError> {
+    let (_, payload) = try!(read_payload(p, Some(c), None, None));
+    decode_bincode(payload)
+}
+
+/// Like `peek`, but decoding the value fails once `limit` has passed, with an error for which
+/// `is_timed_out` is true, rather than taking as long as an unexpectedly large value needs.
+/// Reading the file itself isn’t limited.
+pub fn peek_within<'a, T>(p: &Path, limit: Duration) -> IoResult<T>
+        where T: Decodable<DecoderReader<'a, DeadlineReader<MemReader>>, IoError> {
+    let (_, payload) = try!(read_payload(p, None, None, None));
+    bincode::decode_from(&mut DeadlineReader::new(MemReader::new(payload), limit))
+}
+
+/// Reads and checks only the header of the box file at the given path, without reading or decoding
+/// the value stored in it. This is much faster than opening the box when all that is needed is to
+/// find out how the file was stored.
+pub fn open_header(p: &Path) -> IoResult<HeaderInfo> {
+    if layout::layout_of(p) == Split {
+        return Ok(HeaderInfo {
+            header: try!(layout::read_meta_header(p)),
+            has_header: true,
+            payload_size: try!(fs::stat(p)).size,
+        });
+    }
+    let mut f = try!(File::open_mode(p, io::Open, io::Read));
+    let size = try!(f.stat()).size;
+    Ok(match try!(header::read_header(&mut f)) {
+        Some((header, header_size)) => HeaderInfo {
+            header: header,
+            has_header: true,
+            payload_size: size - header_size,
+        },
+        None => HeaderInfo {
+            header: Header::new(),
+            has_header: false,
+            payload_size: size,
+        },
+    })
+}
+
+/// Writes a value to the given path without creating a `FileBox`. The value is written to a
+/// temporary file next to the destination, which is then renamed over it, so the file at the path
+/// always contains either the old value or the new one. The write is added to the statistics kept
+/// in the header of the file.
+pub fn write_value<'a, T>(p: &Path, val: &T) -> IoResult<()>
+        where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    write_value_with(p, val, Flush, None)
+}
+
+/// Like `write_value`, but flushing the new file to disk as far as `durability` asks, and writing
+/// it in `format` without a header if one is given, as a box with that format would.
+pub fn write_value_with<'a, T>(p: &Path, val: &T, durability: Durability,
+                               format: Option<&Format<T>>) -> IoResult<()>
+        where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    let temp = Default::default();
+    match format {
+        Some(format) => {
+            let bytes = try!(format.encode(val).map_err(error::unencodable));
+            return write_atomic_durable(p, bytes.as_slice(), &temp, None, None, None, false,
+                                        durability, None);
+        }
+        None => {}
+    }
+    let prev = if p.exists() {
+        try!(open_header(p)).header
+    } else {
+        Header {
+            created_at: now(),
+            .. Header::new()
+        }
+    };
+    let (header, payload) = try!(encode_payload(val, &NoCompression, &prev));
+    layout::store(p, layout::layout_of(p), &header, payload.as_slice(), |bytes| {
+        write_atomic_durable(p, bytes, &temp, None, None, None, false, durability.clone(), None)
+    })
+}
+
+/// Checks that a new file of `len` bytes for the box at `p` is within `max_size`, and, if `factor`
+/// is given, that there is that many times as much free space where its temporary file goes.
+/// See `FileBox::set_max_size` and `FileBox::set_space_preflight`.
+fn preflight(p: &Path, temp: &TempFiles, max_size: Option<u64>, factor: Option<f64>, len: uint)
+             -> IoResult<()> {
+    try!(quota::check(max_size, len));
+    match factor {
+        Some(factor) => space::check(&temp.path_for(p).dir_path(), (len as f64 * factor) as u64),
+        None => Ok(()),
+    }
+}
+
+/// Returns the hash of the value stored in the file at the given path, as `FileBox::content_hash`
+/// would, without decoding it.
+pub fn file_content_hash(p: &Path) -> IoResult<u64> {
+    let (_, payload) = try!(read_payload(p, None, None, None));
+    Ok(fnv1a(payload.as_slice()))
+}
+
+/// Identifies a file independently of the paths leading to it.
+#[deriving(Clone, PartialEq)]
+struct FileId {
+    device: u64,
+    inode: u64,
+}
+
+impl FileId {
+    fn of(p: &Path) -> IoResult<FileId> {
+        let stat = try!(fs::stat(p));
+        Ok(FileId {
+            device: stat.unstable.device,
+            inode: stat.unstable.inode,
+        })
+    }
+
+    /// Like `of`, but returns `None` if there is no file at `p`.
+    fn of_existing(p: &Path) -> IoResult<Option<FileId>> {
+        if p.exists() {
+            FileId::of(p).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// When the file at `p` was last modified, or `None` if there is no file at `p`.
+fn modified_time(p: &Path) -> IoResult<Option<u64>> {
+    if p.exists() {
+        Ok(Some(try!(fs::stat(p)).modified))
+    } else {
+        Ok(None)
+    }
+}
+
+static RELOAD_CONFLICT: &'static str = "the box has changes that reloading it would lose";
+
+/// Returns whether `e` is the error `FileBox::reload` fails with when the box has unwritten
+/// changes.
+pub fn is_reload_conflict(e: &IoError) -> bool {
+    e.kind == io::OtherIoError && e.desc == RELOAD_CONFLICT
+}
+
+/// The 64-bit FNV-1a hash of `bytes`. Unlike `std::hash`, this is guaranteed to be the same on
+/// every platform and in every version of Rust.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    fnv1a_update(FNV_OFFSET, bytes)
+}
+
+/// The hash FNV-1a starts from.
+static FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// Continues the FNV-1a hash `hash` of some bytes with `bytes`, which follow them.
+fn fnv1a_update(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &b in bytes.iter() {
+        hash ^= b as u64;
+        hash *= 0x100000001b3;
+    }
+    hash
+}
+
+/// Encodes `val` as the contents of a box file, compressing it with `c`. `prev` is the header the
+/// file had before; the header for the new contents is returned along with them.
+fn encode_file<'a, T>(val: &T, c: &Compressor, prev: &Header) -> IoResult<(Header, Vec<u8>)>
+        where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    let (header, payload) = try!(encode_payload(val, c, prev));
+    let bytes = try!(header::frame(&header, payload.as_slice()));
+    Ok((header, bytes))
+}
+
+/// Like `encode_file`, but returns the payload without the header in front of it.
+fn encode_payload<'a, T>(val: &T, c: &Compressor, prev: &Header) -> IoResult<(Header, Vec<u8>)>
+        where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    let encoded = try!(bincode::encode(val).map_err(error::unencodable));
+    let payload = try!(c.compress(encoded.as_slice()));
+    let mut header = prev.next(c.name(), payload.as_slice());
+    header.encoding = header::BINCODE.to_string();
+    Ok((header, payload))
+}
+
+/// Decodes the value of a box from `payload`, which was stored with `header`. Failures are tagged
+/// as errors decoding the value, for `FileBoxError::from_read`.
+fn decode_payload<T>(header: &Header, payload: Vec<u8>) -> IoResult<T>
+        where T: Decodable<CompactDecoder, IoError> {
+    check_bincode(header).and_then(|()| decode_bincode(payload)).map_err(error::undecodable)
+}
+
+static UNKNOWN_COMPRESSOR: &'static str = "the box was written with an unknown compressor";
+
+/// The current time, in seconds since the Unix epoch.
+fn now() -> u64 {
+    time::get_time().sec as u64
+}
+
+/// Reads the box file at `p`, returning its header and decompressed payload. The payload is
+/// decompressed with `c` if the header names it, and otherwise with the built-in compressor the
+/// header names. If `progress` is given, the progress of reading the file is reported to it.
+fn read_payload(p: &Path, c: Option<&Compressor>, progress: Option<&mut Progress>,
+                cancel: Option<&CancelToken>) -> IoResult<(Header, Vec<u8>)> {
+    let (header, payload) = try!(read_encoded(p, c, progress, cancel, false));
+    try!(check_bincode(&header));
+    Ok((header, payload))
+}
+
+/// Reads the file of the box at `p` for one of the `open` functions of `FileBox`, as
+/// `read_encoded` does, along with any saves in the box’s log that never reached the file. They
+/// are written into the file first, unless the box is being opened read-only, in which case the
+/// newest of them is read from the log instead, so that nothing is written.
+fn read_box(p: &Path, c: Option<&Compressor>, progress: Option<&mut Progress>,
+            cancel: Option<&CancelToken>, direct: bool, read_only: bool)
+            -> IoResult<(Header, Vec<u8>)> {
+    if read_only {
+        match try!(journal::last_record(p)) {
+            Some(bytes) => return unpack_encoded(bytes, c),
+            None => {}
+        }
+    } else {
+        // A box that was journaled may have saves in its log that never reached its file.
+        try!(journal::recover(p));
+    }
+    read_encoded(p, c, progress, cancel, direct)
+}
+
+/// Like `read_payload`, but for payloads with any encoding. The file is read with direct I/O if
+/// `direct` is true.
+fn read_encoded(p: &Path, c: Option<&Compressor>, progress: Option<&mut Progress>,
+                cancel: Option<&CancelToken>, direct: bool) -> IoResult<(Header, Vec<u8>)> {
+    let mut f = try!(direct::open(p, direct));
+    let size = try!(fs::stat(p)).size;
+    let bytes = try!(progress::read_all(&mut *f, size, progress, cancel));
+    unpack_file(p, bytes, c)
+}
+
+/// Splits `bytes`, the contents of the box file at `p`, into its header and decompressed payload,
+/// as `unpack_encoded` does. The header is read from its own file if the box’s layout is `Split`.
+fn unpack_file(p: &Path, bytes: Vec<u8>, c: Option<&Compressor>) -> IoResult<(Header, Vec<u8>)> {
+    if layout::layout_of(p) == Split {
+        let header = try!(layout::read_meta(p, bytes.as_slice()));
+        decompress(header, bytes, c)
+    } else {
+        unpack_encoded(bytes, c)
+    }
+}
+
+/// Splits the contents of a box file into its header and decompressed payload, as `read_payload`
+/// does. Fails if the payload wasn’t encoded with bincode.
+fn unpack(bytes: Vec<u8>, c: Option<&Compressor>) -> IoResult<(Header, Vec<u8>)> {
+    let (header, payload) = try!(unpack_encoded(bytes, c));
+    try!(check_bincode(&header));
+    Ok((header, payload))
+}
+
+/// Like `unpack`, but for payloads with any encoding.
+fn unpack_encoded(bytes: Vec<u8>, c: Option<&Compressor>) -> IoResult<(Header, Vec<u8>)> {
+    let (header, payload) = try!(header::unframe(bytes));
+    decompress(header, payload, c)
+}
+
+fn check_bincode(header: &Header) -> IoResult<()> {
+    if header.encoding.as_slice() != header::BINCODE {
+        return Err(IoError {
+            kind: io::InvalidInput,
+            desc: "the box was written with a different encoding",
+            detail: Some(header.encoding.clone()),
+        });
+    }
+    Ok(())
+}
+
+/// Decompresses `payload`, which was stored with `header`, with `c` if the header names it and
+/// otherwise with the built-in compressor the header names.
+fn decompress(header: Header, payload: Vec<u8>, c: Option<&Compressor>)
+              -> IoResult<(Header, Vec<u8>)> {
+    try!(header.verify(payload.as_slice()));
+    let payload = {
+        let name = header.compressor.as_slice();
+        match c {
+            Some(c) if c.name() == name => try!(c.decompress(payload.as_slice())),
+            _ => match compress::builtin(name) {
+                Some(c) => try!(c.decompress(payload.as_slice())),
+                None => return Err(IoError {
+                    kind: io::InvalidInput,
+                    desc: UNKNOWN_COMPRESSOR,
+                    detail: Some(name.to_string()),
+                }),
+            },
+        }
+    };
+    Ok((header, payload))
+}
+
 impl<T> Deref<T> for FileBox<T> {
     fn deref(&self) -> &T {
-        &self._val
+        self.val()
     }
 }
 
 impl<T> DerefMut<T> for FileBox<T> {
     fn deref_mut(&mut self) -> &mut T {
-        &mut self._val
+        self._last_change = Some(self._clock.now_ms());
+        self._dirty = true;
+        self.val_mut()
     }
 }
 
 #[unsafe_destructor]
 impl<'a, T> Drop for FileBox<T> where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> {
     fn drop(&mut self) {
-        // TODO: decide what this should do if the file can’t be written to
-        self._file.write(bincode::encode(&self._val).unwrap().as_slice())
-            .ok().expect("could not write to file");
+        if self._scratch {
+            // Nothing would ever read what was written.
+            let _ = fs::unlink(&self._path);
+            return;
+        }
+        let logged = self._journal.as_ref().map_or(false, |journal| journal.appended > 0);
+        if !self._write_on_drop || !(self._dirty || logged) {
+            return;
+        }
+        if self._in_modify {
+            // A panic cut `modify` short, so the value may be half changed.
+            return;
+        }
+        if self._read_only {
+            match self._read_only_hook {
+                Some(hook) => hook(&self._path),
+                _ => {}
+            }
+            return;
+        }
+        // Programs that want to handle the error use `close` instead.
+        match self.write() {
+            Err(ref e) if is_cancelled(e) => {}
+            Err(e) => droppolicy::handle(self._drop_policy.as_ref(), &self._path, e),
+            Ok(()) => {}
+        }
     }
 }
 
 impl<T> Show for FileBox<T> where T: Show {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        self._val.fmt(f)
+        self.val().fmt(f)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::FileBox;
+    use std::cell::RefCell;
+    use std::io::{File, IoResult};
+    use std::rc::Rc;
+    use std::task;
+    use std::io::fs::PathExtensions;
+    use std::time::Duration;
+    use super::{FileBox, Compressor, CancelToken, Autosave, Clock, ManualClock};
+    use super::{peek, peek_compressed, write_value, file_content_hash, open_header};
+    use super::{write_value_with, Format, Bincode, Fsync};
+    use super::{peek_within, is_timed_out, Observer, is_reload_conflict};
 
     #[test]
     fn write_then_read() {
@@ -167,4 +2014,465 @@ mod tests {
         let x: FileBox<Box<Vec<int>>> = FileBox::open_new(&path, box vec![1, 2, 3]).unwrap();
         assert_eq!(format!("{}", x), "[1, 2, 3]".to_string());
     }
+
+    #[test]
+    fn peek_value() {
+        let path = Path::new("target/peek_value");
+        FileBox::open_new(&path, 7i).unwrap();
+        assert_eq!(peek::<int>(&path).unwrap(), 7);
+        // Peeking must leave the file intact.
+        assert_eq!(peek::<int>(&path).unwrap(), 7);
+    }
+
+    #[test]
+    fn write_value_then_peek() {
+        let path = Path::new("target/write_value_then_peek");
+        write_value(&path, &"hello".to_string()).unwrap();
+        assert_eq!(peek::<String>(&path).unwrap(), "hello".to_string());
+        write_value(&path, &"world".to_string()).unwrap();
+        assert_eq!(peek::<String>(&path).unwrap(), "world".to_string());
+    }
+
+    #[test]
+    fn write_value_with_format() {
+        let path = Path::new("target/write_value_with_format");
+        write_value_with(&path, &7u32, Fsync, Some(&Bincode as &Format<u32>)).unwrap();
+        // The value is written on its own, without a header.
+        assert_eq!(File::open(&path).read_to_end().unwrap().len(), 4);
+        assert_eq!(peek::<u32>(&path).unwrap(), 7);
+    }
+
+    #[test]
+    fn content_hash() {
+        let path = Path::new("target/content_hash");
+        let (a, b) = {
+            let x: FileBox<int> = FileBox::open_new(&path, 5).unwrap();
+            let other = Path::new("target/content_hash_2");
+            let y: FileBox<int> = FileBox::open_new(&other, 6).unwrap();
+            (x.content_hash().unwrap(), y.content_hash().unwrap())
+        };
+        assert!(a != b);
+        assert_eq!(file_content_hash(&path).unwrap(), a);
+    }
+
+    #[test]
+    fn adopt_bytes() {
+        let path = Path::new("target/adopt_bytes");
+        let other = Path::new("target/adopt_bytes_other");
+        write_value(&path, &1u32).unwrap();
+        write_value(&other, &2u32).unwrap();
+        let mut bytes = File::open(&other).read_to_end().unwrap();
+        {
+            let x: FileBox<u32> = FileBox::adopt_bytes(&path, bytes.as_slice()).unwrap();
+            assert_eq!(*x, 2);
+        }
+        assert_eq!(peek::<u32>(&path).unwrap(), 2);
+
+        // Damaged bytes are refused, leaving the box as it was.
+        let last = bytes.len() - 1;
+        bytes.as_mut_slice()[last] ^= 1;
+        assert!(FileBox::<u32>::adopt_bytes(&path, bytes.as_slice()).is_err());
+        assert!(FileBox::<u32>::adopt_bytes(&path, ::bincode::encode(&3u32).unwrap().as_slice())
+                    .is_err());
+        assert_eq!(peek::<u32>(&path).unwrap(), 2);
+    }
+
+    #[test]
+    fn peek_within_limit() {
+        let path = Path::new("target/peek_within_limit");
+        write_value(&path, &vec![1u32, 2, 3]).unwrap();
+        assert_eq!(peek_within::<Vec<u32>>(&path, Duration::minutes(1)).unwrap(), vec![1, 2, 3]);
+        assert!(is_timed_out(&peek_within::<Vec<u32>>(&path, Duration::zero()).unwrap_err()));
+    }
+
+    struct Seen {
+        values: Rc<RefCell<Vec<int>>>,
+    }
+
+    impl Observer<int> for Seen {
+        fn changed(&mut self, val: &int) {
+            self.values.borrow_mut().push(*val);
+        }
+    }
+
+    #[test]
+    fn observe_changes() {
+        let values = Rc::new(RefCell::new(Vec::new()));
+        let mut x = FileBox::open_new(&Path::new("target/observe_changes"), 1i).unwrap();
+        x.on_change(box Seen { values: values.clone() } as Box<Observer<int> + 'static>);
+        x.modify(|v| *v += 1);
+        *x = 5;
+        assert_eq!(*values.borrow(), vec![2]);
+        x.write().unwrap();
+        assert_eq!(*values.borrow(), vec![2, 5]);
+    }
+
+    #[test]
+    fn save_and_close() {
+        let path = Path::new("target/save_and_close");
+        let mut x = FileBox::open_new(&path, 1i).unwrap();
+        x.save().unwrap();
+        assert_eq!(peek::<int>(&path).unwrap(), 1);
+        *x = 2;
+        x.close().unwrap();
+        assert_eq!(peek::<int>(&path).unwrap(), 2);
+        assert_eq!(open_header(&path).unwrap().header.saves, 2);
+    }
+
+    #[test]
+    fn raw_bytes() {
+        let path = Path::new("target/raw_bytes");
+        let mut x = FileBox::open_new(&path, 5u32).unwrap();
+        x.write().unwrap();
+        *x = 6;
+        assert_eq!(x.as_bytes().unwrap(), ::bincode::encode(&6u32).unwrap());
+        let (_, on_disk) = ::header::unframe(x.disk_bytes().unwrap()).unwrap();
+        assert_eq!(on_disk, ::bincode::encode(&5u32).unwrap());
+    }
+
+    struct Reverse;
+
+    impl Compressor for Reverse {
+        fn name(&self) -> &'static str { "reverse" }
+
+        fn compress(&self, data: &[u8]) -> IoResult<Vec<u8>> {
+            Ok(data.iter().rev().map(|&b| b).collect())
+        }
+
+        fn decompress(&self, data: &[u8]) -> IoResult<Vec<u8>> {
+            self.compress(data)
+        }
+    }
+
+    #[test]
+    fn custom_compressor() {
+        let path = Path::new("target/custom_compressor");
+        FileBox::open_new_compressed(&path, 42i, box Reverse as Box<Compressor>).unwrap();
+        assert!(peek::<int>(&path).is_err());
+        assert_eq!(peek_compressed::<int>(&path, &Reverse).unwrap(), 42);
+        {
+            let mut x: FileBox<int> =
+                FileBox::open_compressed(&path, box Reverse as Box<Compressor>).unwrap();
+            *x += 1;
+        }
+        assert_eq!(peek_compressed::<int>(&path, &Reverse).unwrap(), 43);
+    }
+
+    #[test]
+    fn headerless_file() {
+        let path = Path::new("target/headerless_file");
+        File::create(&path).write(::bincode::encode(&9i).unwrap().as_slice()).unwrap();
+        assert_eq!(peek::<int>(&path).unwrap(), 9);
+    }
+
+    #[test]
+    fn cancelled_save() {
+        let path = Path::new("target/cancelled_save");
+        write_value(&path, &1i).unwrap();
+        let token = CancelToken::new();
+        {
+            let mut x: FileBox<int> = FileBox::open(&path).unwrap();
+            x.set_cancel_token(Some(token.clone()));
+            *x = 2;
+            token.cancel();
+        }
+        assert_eq!(peek::<int>(&path).unwrap(), 1);
+    }
+
+    #[test]
+    fn header_only() {
+        let path = Path::new("target/header_only");
+        write_value(&path, &1u32).unwrap();
+        let info = open_header(&path).unwrap();
+        assert!(info.has_header);
+        assert_eq!(info.header.compressor.as_slice(), "none");
+        assert_eq!(info.payload_size, 4);
+    }
+
+    #[test]
+    fn replaced_file() {
+        let path = Path::new("target/replaced_file");
+        write_value(&path, &1i).unwrap();
+        let mut x: FileBox<int> = FileBox::open(&path).unwrap();
+        write_value(&path, &2i).unwrap();
+        assert!(x.write().is_err());
+        assert_eq!(peek::<int>(&path).unwrap(), 2);
+        x.pin_identity(false).unwrap();
+        x.write().unwrap();
+        assert_eq!(peek::<int>(&path).unwrap(), 1);
+    }
+
+    #[test]
+    fn bundled_defaults() {
+        let path = Path::new("target/bundled_defaults");
+        let _ = ::std::io::fs::unlink(&path);
+        let defaults = ::bincode::encode(&"default".to_string()).unwrap();
+        {
+            let mut x: FileBox<String> =
+                FileBox::open_or_bundled(&path, defaults.as_slice()).unwrap();
+            assert_eq!(x.as_slice(), "default");
+            assert!(!path.exists());
+            x.push_str(" changed");
+        }
+        let x: FileBox<String> = FileBox::open_or_bundled(&path, defaults.as_slice()).unwrap();
+        assert_eq!(x.as_slice(), "default changed");
+    }
+
+    #[test]
+    fn write_statistics() {
+        let path = Path::new("target/write_statistics");
+        let created_at = {
+            let x: FileBox<u32> = FileBox::open_new(&path, 1).unwrap();
+            x.header().created_at
+        };
+        drop(FileBox::<u32>::open(&path).unwrap());
+        let header = open_header(&path).unwrap().header;
+        assert_eq!(header.saves, 2);
+        assert_eq!(header.bytes_written, 8);
+        assert_eq!(header.created_at, created_at);
+    }
+
+    #[test]
+    fn recover_autosave() {
+        let path = Path::new("target/recover_autosave");
+        write_value(&path, &1i).unwrap();
+        {
+            let mut x: FileBox<int> = FileBox::open(&path).unwrap();
+            x.set_autosave(Some(Autosave { slots: 2, interval: Duration::seconds(0) }));
+            *x = 2;
+            assert!(x.autosave_if_due().unwrap());
+            // Simulate a crash, which leaves the autosave behind.
+            x.delete().unwrap();
+        }
+        let slot = FileBox::<int>::newest_autosave(&path).unwrap();
+        assert!(slot.is_some());
+        {
+            let mut x: FileBox<int> = FileBox::open_recovering(&path).unwrap();
+            assert_eq!(*x, 2);
+            *x += 1;
+        }
+        assert!(!slot.unwrap().exists());
+        assert_eq!(*FileBox::<int>::open_recovering(&path).unwrap(), 3);
+    }
+
+    #[test]
+    fn scoped_readonly() {
+        let path = Path::new("target/scoped_readonly");
+        let mut x = FileBox::open_new(&path, 1i).unwrap();
+        *x = 2;
+        let seen = x.scoped_readonly(|&val| {
+            assert_eq!(peek::<int>(&path).unwrap(), 2);
+            write_value(&path, &3i).unwrap();
+            val
+        }).unwrap();
+        assert_eq!(seen, 2);
+        assert_eq!(*x, 3);
+        *x += 1;
+        drop(x);
+        assert_eq!(peek::<int>(&path).unwrap(), 4);
+    }
+
+    #[test]
+    fn autosave_schedule() {
+        let path = Path::new("target/autosave_schedule");
+        let clock = ManualClock::new(0);
+        let mut x = FileBox::open_new(&path, 1i).unwrap();
+        x.set_clock(box clock.clone() as Box<Clock + 'static>);
+        x.set_autosave(Some(Autosave { slots: 1, interval: Duration::minutes(5) }));
+        assert!(!x.autosave_if_due().unwrap());
+        clock.advance(Duration::minutes(5));
+        assert!(x.autosave_if_due().unwrap());
+        assert!(!x.autosave_if_due().unwrap());
+    }
+
+    #[test]
+    fn save_when_idle() {
+        let path = Path::new("target/save_when_idle");
+        let clock = ManualClock::new(0);
+        let mut x = FileBox::open_new(&path, 1i).unwrap();
+        x.set_clock(box clock.clone() as Box<Clock + 'static>);
+        x.set_idle_save(Some(Duration::seconds(10)));
+        assert!(!x.save_if_idle(true).unwrap());
+        *x = 2;
+        clock.advance(Duration::seconds(5));
+        assert!(!x.save_if_idle(true).unwrap());
+        clock.advance(Duration::seconds(5));
+        assert!(!x.save_if_idle(false).unwrap());
+        assert!(x.save_if_idle(true).unwrap());
+        assert!(!x.has_changes());
+        assert_eq!(peek::<int>(&path).unwrap(), 2);
+    }
+
+    #[test]
+    fn save_on_interval() {
+        let path = Path::new("target/save_on_interval");
+        let clock = ManualClock::new(0);
+        let mut x = FileBox::open_new(&path, 1i).unwrap();
+        x.set_clock(box clock.clone() as Box<Clock + 'static>);
+        x.set_save_interval(Some(Duration::seconds(30)));
+        *x = 2;
+        clock.advance(Duration::seconds(20));
+        assert!(!x.tick().unwrap());
+        clock.advance(Duration::seconds(10));
+        assert!(x.tick().unwrap());
+        assert_eq!(peek::<int>(&path).unwrap(), 2);
+        // Nothing has changed since.
+        clock.advance(Duration::seconds(30));
+        assert!(!x.tick().unwrap());
+    }
+
+    #[test]
+    fn import_legacy() {
+        let path = Path::new("target/import_legacy");
+        File::create(&path).write_str("42").unwrap();
+        {
+            let x: FileBox<int> = FileBox::import_legacy(&path, |bytes| {
+                Ok(from_str(String::from_utf8(bytes).unwrap().as_slice()).unwrap())
+            }).unwrap();
+            assert_eq!(*x, 42);
+        }
+        assert!(open_header(&path).unwrap().has_header);
+        assert_eq!(peek::<int>(&path).unwrap(), 42);
+    }
+
+    #[test]
+    fn skip_clean_writes() {
+        let path = Path::new("target/skip_clean_writes");
+        FileBox::open_new(&path, 1i).unwrap().close().unwrap();
+        {
+            let mut x = FileBox::<int>::open(&path).unwrap();
+            x.pin_identity(false).unwrap();
+            assert_eq!(*x, 1);
+            // The box is never changed, so this survives it being dropped.
+            write_value(&path, &2i).unwrap();
+        }
+        assert_eq!(peek::<int>(&path).unwrap(), 2);
+        {
+            let mut x = FileBox::<int>::open(&path).unwrap();
+            x.pin_identity(false).unwrap();
+            write_value(&path, &3i).unwrap();
+            x.mark_dirty();
+        }
+        assert_eq!(peek::<int>(&path).unwrap(), 2);
+    }
+
+    #[test]
+    fn into_inner() {
+        let path = Path::new("target/into_inner");
+        FileBox::open_new(&path, vec![1u8]).unwrap().close().unwrap();
+        let mut x = FileBox::<Vec<u8>>::open(&path).unwrap();
+        x.push(2);
+        assert_eq!(x.into_inner(), vec![1, 2]);
+        assert_eq!(peek::<Vec<u8>>(&path).unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn panic_safe_modify() {
+        let path = Path::new("target/panic_safe_modify");
+        FileBox::open_new(&path, vec![1u8]).unwrap().close().unwrap();
+        let p = path.clone();
+        let res = task::try(proc() {
+            let mut x = FileBox::<Vec<u8>>::open(&p).unwrap();
+            x.set_panic_safe(true);
+            x.modify(|v| v.push(2));
+            x.save().unwrap();
+            x.modify(|v| {
+                v.push(3);
+                panic!("half way through");
+            });
+        });
+        assert!(res.is_err());
+        assert_eq!(peek::<Vec<u8>>(&path).unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn reload_outside_changes() {
+        let path = Path::new("target/reload_outside_changes");
+        let mut b = FileBox::open_new(&path, 1u32).unwrap();
+        b.save().unwrap();
+        assert!(!b.reload_if_changed().unwrap());
+        // Another program writes the file.
+        write_value(&path, &2u32).unwrap();
+        assert!(b.reload_if_changed().unwrap());
+        assert_eq!(*b, 2);
+
+        *b = 3;
+        write_value(&path, &4u32).unwrap();
+        assert!(is_reload_conflict(&b.reload_if_changed().unwrap_err()));
+        assert_eq!(*b, 3);
+        // Keep this box’s value over the other program’s.
+        b.pin_identity(true).unwrap();
+        b.save().unwrap();
+        drop(b);
+        assert_eq!(peek::<u32>(&path).unwrap(), 3);
+    }
+
+    #[test]
+    fn replace_and_swap() {
+        let current = Path::new("target/replace_and_swap_current");
+        let archive = Path::new("target/replace_and_swap_archive");
+        let mut b = FileBox::open_new(&current, vec![1u8]).unwrap();
+        let mut a = FileBox::open_new(&archive, vec![]).unwrap();
+        assert_eq!(b.replace(vec![2]).unwrap(), vec![1]);
+        assert_eq!(peek::<Vec<u8>>(&current).unwrap(), vec![2]);
+
+        b.swap(&mut a).unwrap();
+        assert!(b.is_empty());
+        assert_eq!(*a, vec![2]);
+        assert_eq!(peek::<Vec<u8>>(&current).unwrap(), vec![]);
+        assert_eq!(peek::<Vec<u8>>(&archive).unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn take_value() {
+        let path = Path::new("target/take_value");
+        let mut b = FileBox::open_new(&path, vec![1u8, 2]).unwrap();
+        assert_eq!(b.take(), vec![1, 2]);
+        assert!(b.is_empty() && b.has_changes());
+        b.push(3);
+        assert_eq!(b.into_inner(), vec![3]);
+        // Neither change was written.
+        assert_eq!(peek::<Vec<u8>>(&path).unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn save_copy_elsewhere() {
+        let path = Path::new("target/save_copy_elsewhere");
+        let copy = Path::new("target/save_copy_elsewhere_copy");
+        let mut b = FileBox::open_new(&path, vec![1u8]).unwrap();
+        b.push(2);
+        b.save_as(&copy).unwrap();
+        assert!(b.has_changes());
+        assert_eq!(peek::<Vec<u8>>(&path).unwrap(), vec![1]);
+        assert_eq!(peek::<Vec<u8>>(&copy).unwrap(), vec![1, 2]);
+
+        let mut c = b.clone_to(&copy).unwrap();
+        c.push(3);
+        drop(c);
+        assert_eq!(*b, vec![1, 2]);
+        assert_eq!(peek::<Vec<u8>>(&copy).unwrap(), vec![1, 2, 3]);
+        // Copies are held to the box’s size limit too.
+        b.set_max_size(Some(2));
+        assert!(b.save_as(&copy).is_err());
+        assert_eq!(peek::<Vec<u8>>(&copy).unwrap(), vec![1, 2, 3]);
+        b.set_max_size(None);
+    }
+
+    #[test]
+    fn durability_levels() {
+        let path = Path::new("target/durability_levels");
+        let mut b = FileBox::open_new(&path, 0u32).unwrap();
+        for (i, level) in vec![super::NoSync, super::Flush, super::Fsync].into_iter().enumerate() {
+            b.set_durability(level);
+            *b = i as u32;
+            b.save().unwrap();
+            assert_eq!(peek::<u32>(&path).unwrap(), i as u32);
+        }
+        b.set_durability(super::NoSync);
+        b.set_mapped(true);
+        *b = 7;
+        b.save().unwrap();
+        b.sync_all().unwrap();
+        assert_eq!(peek::<u32>(&path).unwrap(), 7);
+    }
 }
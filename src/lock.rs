@@ -0,0 +1,245 @@
+//! Keeping other processes from opening a box at the same time.
+
+use std::comm::{channel, Receiver, Empty, Disconnected};
+use std::io::{mod, IoError, IoResult, MemWriter};
+use std::task;
+use libc;
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use encoding::CompactDecoder;
+use super::FileBox;
+
+/// The description of errors caused by a box being locked by someone else.
+static LOCKED: &'static str = "the box is locked by another process";
+
+/// Returns whether `e` is the error returned by `FileBox::try_open` for a box that is already
+/// locked.
+pub fn is_locked(e: &IoError) -> bool {
+    e.kind == io::ResourceUnavailable && e.desc == LOCKED
+}
+
+/// An advisory lock on a box, held until this is dropped. Locks are exclusive unless they are
+/// taken with `acquire_shared` or downgraded.
+///
+/// The lock is taken on a file next to the box’s file rather than on the box’s file itself, since
+/// writing a box replaces its file with a new one. Locks are advisory: they only keep out other
+/// processes that take them too. They are only taken on Unix, where `flock` is used.
+#[cfg(unix)]
+pub struct Lock {
+    fd: libc::c_int,
+}
+
+#[cfg(not(unix))]
+pub struct Lock;
+
+/// The path of the lock file of the box at `p`.
+pub fn lock_path(p: &Path) -> Path {
+    let mut name = p.filename().unwrap_or(b"filebox").to_vec();
+    name.push_all(b".lock");
+    p.with_filename(name)
+}
+
+#[cfg(unix)]
+static LOCK_SH: libc::c_int = 1;
+#[cfg(unix)]
+static LOCK_EX: libc::c_int = 2;
+#[cfg(unix)]
+static LOCK_NB: libc::c_int = 4;
+
+#[cfg(unix)]
+extern {
+    fn flock(fd: libc::c_int, operation: libc::c_int) -> libc::c_int;
+}
+
+impl Lock {
+    /// Locks the box at `p`, waiting for any other process holding the lock to let go of it if
+    /// `wait` is true, and failing with an error for which `is_locked` is true otherwise.
+    pub fn acquire(p: &Path, wait: bool) -> IoResult<Lock> {
+        Lock::take(p, true, wait)
+    }
+
+    /// Like `acquire`, but the lock is shared: any number of processes can hold it shared at the
+    /// same time, but not while one holds it exclusively.
+    pub fn acquire_shared(p: &Path, wait: bool) -> IoResult<Lock> {
+        Lock::take(p, false, wait)
+    }
+
+    #[cfg(unix)]
+    fn take(p: &Path, exclusive: bool, wait: bool) -> IoResult<Lock> {
+        let flags = libc::O_RDWR | libc::O_CREAT;
+        let fd = lock_path(p).with_c_str(|path| unsafe {
+            libc::open(path, flags, 0o644 as libc::mode_t)
+        });
+        if fd < 0 {
+            return Err(IoError::last_error());
+        }
+        let lock = Lock {
+            fd: fd,
+        };
+        let op = if exclusive { LOCK_EX } else { LOCK_SH };
+        let op = if wait { op } else { op | LOCK_NB };
+        if unsafe { flock(fd, op) } != 0 {
+            if ::std::os::errno() == libc::EWOULDBLOCK as int {
+                return Err(IoError {
+                    kind: io::ResourceUnavailable,
+                    desc: LOCKED,
+                    detail: Some(p.display().to_string()),
+                });
+            }
+            return Err(IoError::last_error());
+        }
+        Ok(lock)
+    }
+
+    #[cfg(not(unix))]
+    fn take(_p: &Path, _exclusive: bool, _wait: bool) -> IoResult<Lock> {
+        Ok(Lock)
+    }
+
+    /// Turns an exclusive lock into a shared one, letting other processes take the lock shared
+    /// until it is upgraded again.
+    #[cfg(unix)]
+    pub fn downgrade(&self) -> IoResult<()> {
+        if unsafe { flock(self.fd, LOCK_SH) } != 0 {
+            return Err(IoError::last_error());
+        }
+        Ok(())
+    }
+
+    /// Turns a shared lock back into an exclusive one, waiting for the other processes holding it
+    /// shared to let go of it. The lock isn’t held at all for a moment while this waits, as
+    /// `flock` converts locks by releasing them first.
+    #[cfg(unix)]
+    pub fn upgrade(&self) -> IoResult<()> {
+        if unsafe { flock(self.fd, LOCK_EX) } != 0 {
+            return Err(IoError::last_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn downgrade(&self) -> IoResult<()> {
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn upgrade(&self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+/// A box waiting to be opened with `FileBox::open_locked_async` until another process lets go
+/// of its lock.
+///
+/// The lock is waited for by a task of its own, so the task that asked for the box can carry on
+/// with other work, such as running an event loop, and check back with `poll`. If this is dropped
+/// before the lock has been taken, the lock is let go of as soon as it is.
+pub struct LockWait<T> {
+    path: Path,
+    rx: Receiver<IoResult<Lock>>,
+}
+
+impl<'a, T> LockWait<T> where T: Decodable<CompactDecoder, IoError>
+                             + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    fn new(p: &Path) -> LockWait<T> {
+        let (tx, rx) = channel();
+        let path = p.clone();
+        task::spawn(proc() {
+            // If nobody is waiting for the lock any more, dropping it here lets go of it.
+            let _ = tx.send_opt(Lock::acquire(&path, true));
+        });
+        LockWait {
+            path: p.clone(),
+            rx: rx,
+        }
+    }
+
+    /// Opens the box if the lock has been taken, or returns `None` straight away if it hasn’t yet.
+    /// Once this has returned the box, or the error from opening it, it panics if called again.
+    pub fn poll(&mut self) -> Option<IoResult<FileBox<T>>> {
+        match self.rx.try_recv() {
+            Ok(lock) => Some(self.finish(lock)),
+            Err(Empty) => None,
+            Err(Disconnected) => panic!("the box has already been opened"),
+        }
+    }
+
+    /// Waits for the lock, blocking the current task, and opens the box.
+    pub fn wait(self) -> IoResult<FileBox<T>> {
+        let lock = self.rx.recv();
+        self.finish(lock)
+    }
+
+    fn finish(&self, lock: IoResult<Lock>) -> IoResult<FileBox<T>> {
+        let lock = try!(lock);
+        let mut b = try!(FileBox::open(&self.path));
+        b._lock = Some(lock);
+        Ok(b)
+    }
+}
+
+impl<'a, T> FileBox<T> where T: Decodable<CompactDecoder, IoError>
+                              + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Like `open_locked`, but returns straight away rather than blocking while another process
+    /// has the box locked. The box is opened once the lock has been taken; see `LockWait`.
+    pub fn open_locked_async(p: &Path) -> LockWait<T> {
+        LockWait::new(p)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // Closing the file releases the lock.
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_locked, LockWait};
+    use super::super::FileBox;
+
+    #[test]
+    fn lock_boxes() {
+        let path = Path::new("target/lock_boxes");
+        let x = FileBox::open_new_locked(&path, 1u32).unwrap();
+        match FileBox::<u32>::try_open(&path) {
+            Err(ref e) => assert!(is_locked(e)),
+            Ok(_) => panic!("opened a locked box"),
+        }
+        drop(x);
+        let mut y = FileBox::<u32>::try_open(&path).unwrap();
+        *y = 2;
+        drop(y);
+        assert_eq!(*FileBox::<u32>::open_locked(&path).unwrap(), 2);
+    }
+
+    #[test]
+    fn wait_for_lock_without_blocking() {
+        let path = Path::new("target/wait_for_lock_without_blocking");
+        let x = FileBox::open_new_locked(&path, 1u32).unwrap();
+        let mut waiting: LockWait<u32> = FileBox::open_locked_async(&path);
+        assert!(waiting.poll().is_none());
+        drop(x);
+        let y = waiting.wait().unwrap();
+        assert_eq!(*y, 1);
+        assert!(is_locked(&FileBox::<u32>::try_open(&path).err().unwrap()));
+    }
+
+    #[test]
+    fn share_lock_while_read_only() {
+        let path = Path::new("target/share_lock_while_read_only");
+        let mut x = FileBox::open_new_locked(&path, 1u32).unwrap();
+        assert!(is_locked(&FileBox::<u32>::try_open_shared(&path).err().unwrap()));
+        x.scoped_readonly(|_| {
+            let mut other = FileBox::<u32>::try_open_shared(&path).unwrap();
+            *other = 2;
+        }).unwrap();
+        assert_eq!(*x, 2);
+        assert!(is_locked(&FileBox::<u32>::try_open_shared(&path).err().unwrap()));
+    }
+}
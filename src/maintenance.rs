@@ -0,0 +1,466 @@
+//! Cleaning up after the boxes of a store in the background.
+
+use std::cmp;
+use std::comm::{channel, Sender};
+use std::io::{fs, IoError, IoResult};
+use std::io::fs::PathExtensions;
+use std::io::timer::Timer;
+use std::task;
+use std::time::Duration;
+
+use autosave;
+use clock::{Clock, SystemClock};
+use election::Election;
+use generations::{generation_path, restore_generation};
+use header;
+use journal;
+use layout;
+use lock::{mod, Lock};
+use process;
+use quarantine::preserve_corrupt;
+use store::{EXTENSION, temp_file_owner};
+use super::read_encoded;
+
+/// A function that is told what each run of maintenance did, for example to log it.
+pub type MaintenanceHook = fn(&MaintenanceReport);
+
+static HOUR_MS: u64 = 60 * 60 * 1000;
+
+/// The upkeep of a store that nothing else does while its boxes are used.
+///
+/// A run of maintenance checkpoints the logs of journaled boxes into their files, removes previous
+/// versions beyond the number to keep, and removes the files left behind by processes that
+/// stopped while using the store: temporary files, owner files and autosaves older than their
+/// boxes. Nothing is run unless a program asks for it, either by calling `run` or `run_if_due`
+/// itself or by handing the maintenance to a background task with `spawn`.
+///
+/// Only the logs of boxes whose locks can be taken are checkpointed, so journaled boxes that are
+/// opened without `FileBox::open_locked` shouldn’t be open while maintenance runs.
+///
+/// Maintenance can also scrub the store, reading a few boxes on each run and checking them
+/// against their checksums, so that damage to boxes that are rarely opened is found while there
+/// are still previous versions to repair them from. See `set_scrub`.
+pub struct Maintenance {
+    root: Path,
+    window: Option<(uint, uint)>,
+    keep_generations: Option<uint>,
+    scrub: Option<(uint, bool)>,
+    scrubbed_up_to: Option<Path>,
+    hooks: Vec<MaintenanceHook>,
+    clock: Box<Clock + Send + 'static>,
+    last_run: Option<u64>,
+}
+
+/// What a run of maintenance did.
+#[deriving(Show)]
+pub struct MaintenanceReport {
+    /// When the run started, as given by the maintenance’s clock.
+    pub started: u64,
+    /// The boxes whose logs were checkpointed into their files.
+    pub checkpointed: Vec<Path>,
+    /// The temporary files that were removed.
+    pub removed_temp_files: Vec<Path>,
+    /// The owner files that were removed.
+    pub removed_owners: Vec<Path>,
+    /// The autosaves that were removed.
+    pub removed_autosaves: Vec<Path>,
+    /// The previous versions of boxes that were removed.
+    pub removed_generations: Vec<Path>,
+    /// The boxes that were scrubbed and found to be intact.
+    pub scrubbed: Vec<Path>,
+    /// The boxes that were scrubbed and found to be damaged, along with what is wrong with them,
+    /// whether or not they were repaired.
+    pub corrupt: Vec<(Path, IoError)>,
+    /// The damaged boxes that were replaced with their newest intact previous version.
+    pub repaired: Vec<Path>,
+    /// The files that couldn’t be dealt with, along with why. A failure doesn’t stop the rest of
+    /// the run.
+    pub errors: Vec<(Path, IoError)>,
+}
+
+impl MaintenanceReport {
+    /// Returns whether the run changed nothing, either because there was nothing to do or
+    /// because everything failed. Scrubbing boxes only changes them if they are repaired.
+    pub fn is_empty(&self) -> bool {
+        self.checkpointed.is_empty() && self.removed_temp_files.is_empty()
+            && self.removed_owners.is_empty() && self.removed_autosaves.is_empty()
+            && self.removed_generations.is_empty() && self.repaired.is_empty()
+    }
+}
+
+impl Maintenance {
+    /// Creates the maintenance of the store in the directory `root`, which is run at any time and
+    /// keeps every previous version.
+    pub fn new(root: &Path) -> Maintenance {
+        Maintenance {
+            root: root.clone(),
+            window: None,
+            keep_generations: None,
+            scrub: None,
+            scrubbed_up_to: None,
+            hooks: Vec::new(),
+            clock: box SystemClock,
+            last_run: None,
+        }
+    }
+
+    /// Only runs maintenance from `run_if_due` between the hours `start` and `end` of the day in
+    /// UTC, such as from 2 to 5 at night, when the store is little used. The hours may wrap
+    /// around midnight; if `start` and `end` are the same, the window is the whole day. It is then
+    /// run once each time the window opens.
+    pub fn set_window(&mut self, start: uint, end: uint) {
+        assert!(start < 24 && end < 24, "the hours of a maintenance window must be below 24");
+        self.window = Some((start, end));
+    }
+
+    /// Removes all but the newest `keep` previous versions of each box, as kept by
+    /// `FileBox::set_generations`, when maintenance runs.
+    pub fn set_keep_generations(&mut self, keep: uint) {
+        self.keep_generations = Some(keep);
+    }
+
+    /// Scrubs up to `per_run` boxes each time maintenance runs, going through the boxes of the
+    /// store in order of their paths and starting each run after the last box the previous one
+    /// scrubbed, so that the whole store is checked over several runs without any one of them
+    /// reading much. Paired with `set_window`, this keeps the reading to when the store is little
+    /// used.
+    ///
+    /// Damaged boxes are reported in `MaintenanceReport::corrupt`. If `repair` is true, each is
+    /// also copied aside, as `preserve_corrupt` copies it, and replaced with its newest previous
+    /// version that is intact, if it has one and its lock can be taken. The changes made since
+    /// that version are lost, so repairing is best left to stores whose boxes keep generations
+    /// and can afford to go back to them.
+    pub fn set_scrub(&mut self, per_run: uint, repair: bool) {
+        self.scrub = if per_run > 0 {
+            Some((per_run, repair))
+        } else {
+            None
+        };
+    }
+
+    /// Adds a hook that is told what each run of maintenance did. Hooks run in the order they
+    /// were added.
+    pub fn add_hook(&mut self, hook: MaintenanceHook) {
+        self.hooks.push(hook);
+    }
+
+    /// Tells the time with `clock` instead of the system clock.
+    pub fn set_clock(&mut self, clock: Box<Clock + Send + 'static>) {
+        self.clock = clock;
+    }
+
+    /// Returns whether `run_if_due` would run maintenance now: always if no window has been set,
+    /// and otherwise if the window is open and it hasn’t been run since the window opened.
+    pub fn is_due(&self) -> bool {
+        let (start, end) = match self.window {
+            Some(window) => window,
+            None => return true,
+        };
+        let now = self.clock.now_ms();
+        let hour = (now / HOUR_MS % 24) as uint;
+        let len = match (end + 24 - start) % 24 {
+            0 => 24,
+            len => len,
+        };
+        let open_for = (hour + 24 - start) % 24;
+        if open_for >= len {
+            return false;
+        }
+        let opened_at = now - now % HOUR_MS - open_for as u64 * HOUR_MS;
+        self.last_run.map_or(true, |last| last < opened_at)
+    }
+
+    /// Runs maintenance if it is due, as `is_due` tells.
+    pub fn run_if_due(&mut self) -> Option<MaintenanceReport> {
+        if self.is_due() {
+            Some(self.run())
+        } else {
+            None
+        }
+    }
+
+    /// Runs maintenance now, whether or not it is due, and hands what it did to the hooks.
+    pub fn run(&mut self) -> MaintenanceReport {
+        let started = self.clock.now_ms();
+        let mut report = MaintenanceReport {
+            started: started,
+            checkpointed: Vec::new(),
+            removed_temp_files: Vec::new(),
+            removed_owners: Vec::new(),
+            removed_autosaves: Vec::new(),
+            removed_generations: Vec::new(),
+            scrubbed: Vec::new(),
+            corrupt: Vec::new(),
+            repaired: Vec::new(),
+            errors: Vec::new(),
+        };
+        match fs::walk_dir(&self.root) {
+            Ok(files) => {
+                let mut files: Vec<Path> = files.filter(|p| p.is_file()).collect();
+                files.sort();
+                // Scrubbing reads boxes as they are, so it goes first, before anything is
+                // checkpointed into them.
+                self.scrub_some(files.as_slice(), &mut report);
+                for p in files.into_iter() {
+                    match self.maintain(&p, &mut report) {
+                        Ok(()) => {}
+                        Err(e) => report.errors.push((p, e)),
+                    }
+                }
+            }
+            Err(e) => report.errors.push((self.root.clone(), e)),
+        }
+        self.last_run = Some(started);
+        for hook in self.hooks.iter() {
+            (*hook)(&report);
+        }
+        report
+    }
+
+    /// Does whatever the file at `p` needs.
+    fn maintain(&self, p: &Path, report: &mut MaintenanceReport) -> IoResult<()> {
+        match p.extension_str() {
+            Some("wal") => {
+                let box_path = p.with_extension("");
+                let _lock = match Lock::acquire(&box_path, false) {
+                    Ok(lock) => lock,
+                    Err(ref e) if lock::is_locked(e) => return Ok(()),
+                    Err(e) => return Err(e),
+                };
+                // The log may have been checkpointed by the box itself while the lock was taken.
+                if p.exists() {
+                    try!(journal::recover(&box_path));
+                    report.checkpointed.push(box_path);
+                }
+            }
+            Some("tmp") => match temp_file_owner(p) {
+                Some(pid) if pid != process::id() && !process::is_alive(pid) => {
+                    try!(fs::unlink(p));
+                    report.removed_temp_files.push(p.clone());
+                }
+                _ => {}
+            },
+            Some("owner") => {
+                let election = Election::new(&p.with_extension(""), Duration::zero());
+                if try!(election.owner()).is_none() {
+                    try!(fs::unlink(p));
+                    report.removed_owners.push(p.clone());
+                }
+            }
+            Some(EXTENSION) => {
+                let saved = try!(fs::stat(p)).modified;
+                for (modified, slot) in try!(autosave::slots(p)).into_iter() {
+                    // Newer autosaves hold changes that can still be recovered.
+                    if modified < saved {
+                        try!(fs::unlink(&slot));
+                        report.removed_autosaves.push(slot);
+                    }
+                }
+            }
+            Some(ext) => {
+                let n: Option<uint> = from_str(ext);
+                let is_generation = p.with_extension("").extension_str() == Some(EXTENSION);
+                match (n, self.keep_generations) {
+                    (Some(n), Some(keep)) if is_generation && n > keep => {
+                        try!(fs::unlink(p));
+                        let meta = layout::meta_path(p);
+                        if meta.exists() {
+                            try!(fs::unlink(&meta));
+                        }
+                        report.removed_generations.push(p.clone());
+                    }
+                    _ => {}
+                }
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Scrubs the next boxes among `files`, if scrubbing is on.
+    fn scrub_some(&mut self, files: &[Path], report: &mut MaintenanceReport) {
+        let (per_run, repair) = match self.scrub {
+            Some(scrub) => scrub,
+            None => return,
+        };
+        let boxes: Vec<&Path> = files.iter().filter(|p| {
+            p.extension_str() == Some(EXTENSION)
+        }).collect();
+        let start = match self.scrubbed_up_to {
+            Some(ref last) => boxes.iter().position(|p| *p > last).unwrap_or(0),
+            None => 0,
+        };
+        for i in range(0, cmp::min(per_run, boxes.len())) {
+            let p = boxes[(start + i) % boxes.len()];
+            match scrub(p, repair, report) {
+                Ok(()) => {}
+                Err(e) => report.errors.push((p.clone(), e)),
+            }
+            self.scrubbed_up_to = Some(p.clone());
+        }
+    }
+
+    /// Hands the maintenance to a background task, which checks whether it is due every `every`
+    /// and runs it if it is, until the returned scheduler is dropped.
+    pub fn spawn(mut self, every: Duration) -> Scheduler {
+        let (stop_tx, stop_rx) = channel::<()>();
+        task::spawn(proc() {
+            let mut timer = match Timer::new() {
+                Ok(timer) => timer,
+                Err(_) => return,
+            };
+            loop {
+                self.run_if_due();
+                let tick = timer.oneshot(every);
+                select! {
+                    _ = stop_rx.recv_opt() => break,
+                    _ = tick.recv() => {}
+                }
+            }
+        });
+        Scheduler {
+            _stop: stop_tx,
+        }
+    }
+}
+
+/// Checks the box at `p` against its checksum, and repairs it from its previous versions if it is
+/// damaged and `repair` is true.
+fn scrub(p: &Path, repair: bool, report: &mut MaintenanceReport) -> IoResult<()> {
+    let e = match read_encoded(p, None, None, None, false) {
+        Ok(_) => {
+            report.scrubbed.push(p.clone());
+            return Ok(());
+        }
+        Err(e) => e,
+    };
+    if !header::is_corrupt(&e) {
+        return Err(e);
+    }
+    report.corrupt.push((p.clone(), e));
+    if !repair {
+        return Ok(());
+    }
+    let _lock = match Lock::acquire(p, false) {
+        Ok(lock) => lock,
+        Err(ref e) if lock::is_locked(e) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let mut n = 1;
+    while generation_path(p, n).exists() {
+        if read_encoded(&generation_path(p, n), None, None, None, false).is_ok() {
+            try!(preserve_corrupt(p));
+            try!(restore_generation(p, n));
+            report.repaired.push(p.clone());
+            break;
+        }
+        n += 1;
+    }
+    Ok(())
+}
+
+/// Keeps maintenance running in the background, as started by `Maintenance::spawn`.
+///
+/// Dropping this stops the maintenance once any run in progress has finished.
+pub struct Scheduler {
+    _stop: Sender<()>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{fs, File};
+    use std::io::fs::PathExtensions;
+    use std::time::Duration;
+    use super::super::{FileBox, Store, ManualClock, generation_path, peek};
+
+    #[test]
+    fn run_maintenance() {
+        let root = Path::new("target/run_maintenance");
+        let _ = fs::rmdir_recursive(&root);
+        let store = Store::new(&root).unwrap();
+        let ns = store.namespace("app").unwrap();
+        let path = ns.path("logged").unwrap();
+        {
+            let mut b = FileBox::open_new(&path, 1u32).unwrap();
+            b.set_generations(3);
+            b.save().unwrap();
+            b.save().unwrap();
+            b.set_journaled(Some(10)).unwrap();
+            *b = 2;
+            b.save().unwrap();
+            // Leave the log behind, as a crash would.
+            b._write_on_drop = false;
+        }
+        // No process can have an id this large.
+        File::create(&ns.dir().join("a.box.2147483646.tmp")).write(b"x").unwrap();
+
+        let mut maintenance = store.maintenance();
+        maintenance.set_keep_generations(1);
+        let report = maintenance.run();
+        assert!(report.errors.is_empty());
+        assert_eq!(report.checkpointed, vec![path.clone()]);
+        assert_eq!(peek::<u32>(&path).unwrap(), 2);
+        assert_eq!(report.removed_temp_files, vec![ns.dir().join("a.box.2147483646.tmp")]);
+        assert_eq!(report.removed_generations, vec![generation_path(&path, 2)]);
+        assert!(generation_path(&path, 1).exists());
+        assert!(maintenance.run().is_empty());
+    }
+
+    #[test]
+    fn maintenance_window() {
+        let root = Path::new("target/maintenance_window");
+        let _ = fs::rmdir_recursive(&root);
+        let store = Store::new(&root).unwrap();
+        // Midnight UTC, ten days after the epoch.
+        let clock = ManualClock::new(10 * 24 * 60 * 60 * 1000);
+        let mut maintenance = store.maintenance();
+        maintenance.set_clock(box clock.clone());
+        // From 23:00 to 01:00 UTC.
+        maintenance.set_window(23, 1);
+        assert!(maintenance.run_if_due().is_some());
+        assert!(maintenance.run_if_due().is_none());
+        clock.advance(Duration::hours(1));
+        assert!(!maintenance.is_due());
+        clock.advance(Duration::hours(22));
+        assert!(maintenance.is_due());
+        assert!(maintenance.run_if_due().is_some());
+        clock.advance(Duration::minutes(90));
+        assert!(!maintenance.is_due());
+    }
+
+    #[test]
+    fn scrub_boxes() {
+        let root = Path::new("target/scrub_boxes");
+        let _ = fs::rmdir_recursive(&root);
+        let store = Store::new(&root).unwrap();
+        let ns = store.namespace("app").unwrap();
+        let (a, b, c) = (ns.path("a").unwrap(), ns.path("b").unwrap(), ns.path("c").unwrap());
+        for p in [&a, &c].iter() {
+            FileBox::open_new(*p, 1u32).unwrap();
+        }
+        {
+            let mut damaged = FileBox::open_new(&b, vec![1u8, 2, 3]).unwrap();
+            damaged.set_generations(1);
+            damaged.push(4);
+            damaged.save().unwrap();
+        }
+        let mut bytes = File::open(&b).read_to_end().unwrap();
+        let last = bytes.len() - 1;
+        bytes.as_mut_slice()[last] ^= 1;
+        File::create(&b).write(bytes.as_slice()).unwrap();
+
+        let mut maintenance = store.maintenance();
+        maintenance.set_scrub(2, false);
+        let report = maintenance.run();
+        assert_eq!(report.scrubbed, vec![a.clone()]);
+        assert_eq!(report.corrupt[0].val0(), b);
+        assert!(report.repaired.is_empty());
+        // The next run carries on from where this one stopped.
+        maintenance.set_scrub(2, true);
+        let report = maintenance.run();
+        assert_eq!(report.scrubbed, vec![c.clone(), a.clone()]);
+        let report = maintenance.run();
+        assert_eq!(report.repaired, vec![b.clone()]);
+        assert_eq!(peek::<Vec<u8>>(&b).unwrap(), vec![1, 2, 3]);
+    }
+}
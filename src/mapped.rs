@@ -0,0 +1,169 @@
+//! Reading and writing box files through memory maps.
+//!
+//! Reading a box file normally copies all of it into memory and then copies its payload out again
+//! to decode it, so a large box briefly takes up twice its size. Reading through a map lets the
+//! operating system page the file in as the header is parsed and the payload copied out, and the
+//! pages can be dropped again as soon as they have been read. Files are written by copying them
+//! into a map of a new file and flushing it with `msync` before it replaces the box’s file. Maps
+//! are only used on Unix; elsewhere, files are read and written normally.
+
+use std::io::{fs, BufReader, IoResult};
+#[cfg(unix)]
+use std::c_str::ToCStr;
+#[cfg(unix)]
+use std::io::{mod, IoError};
+#[cfg(unix)]
+use std::os::{MemoryMap, MapReadable, MapWritable, MapFd, MapNonStandardFlags};
+#[cfg(unix)]
+use std::slice::raw::{buf_as_slice, mut_buf_as_slice};
+#[cfg(unix)]
+use std::slice::bytes::copy_memory;
+#[cfg(unix)]
+use libc;
+
+use atomic::{TempFiles, Durability, NoSync, Fsync, sync_dir};
+use header::{mod, Header};
+use layout::{mod, Split};
+
+#[cfg(unix)]
+extern {
+    fn msync(addr: *mut libc::c_void, len: libc::size_t, flags: libc::c_int) -> libc::c_int;
+}
+
+#[cfg(target_os = "linux")]
+static MS_SYNC: libc::c_int = 4;
+#[cfg(all(unix, not(target_os = "linux")))]
+static MS_SYNC: libc::c_int = 0x10;
+
+/// A file descriptor, closed when this is dropped.
+#[cfg(unix)]
+struct Fd {
+    fd: libc::c_int,
+}
+
+#[cfg(unix)]
+impl Fd {
+    fn open(p: &Path, flags: libc::c_int) -> IoResult<Fd> {
+        let fd = p.with_c_str(|path| unsafe { libc::open(path, flags, 0o644 as libc::mode_t) });
+        if fd < 0 {
+            return Err(IoError::last_error());
+        }
+        Ok(Fd {
+            fd: fd,
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn map_error<E: ::std::fmt::Show>(e: E) -> IoError {
+    IoError {
+        kind: io::OtherIoError,
+        desc: "the file couldn’t be mapped into memory",
+        detail: Some(e.to_string()),
+    }
+}
+
+/// Reads the box file at `p` through a map, returning its header and its payload as stored, which
+/// may still be compressed.
+#[cfg(unix)]
+pub fn read(p: &Path) -> IoResult<(Header, Vec<u8>)> {
+    let size = try!(fs::stat(p)).size as uint;
+    if size == 0 {
+        // Empty files can’t be mapped.
+        return split(p, &[]);
+    }
+    let fd = try!(Fd::open(p, libc::O_RDONLY));
+    let map = try!(MemoryMap::new(size, &[MapReadable, MapFd(fd.fd)]).map_err(map_error));
+    unsafe { buf_as_slice(map.data() as *const u8, size, |bytes| split(p, bytes)) }
+}
+
+#[cfg(not(unix))]
+pub fn read(p: &Path) -> IoResult<(Header, Vec<u8>)> {
+    let bytes = try!(::std::io::File::open(p).read_to_end());
+    split(p, bytes.as_slice())
+}
+
+/// Splits `bytes`, the contents of the box file at `p`, into its header and payload, copying
+/// nothing but the payload.
+fn split(p: &Path, bytes: &[u8]) -> IoResult<(Header, Vec<u8>)> {
+    if layout::layout_of(p) == Split {
+        return Ok((try!(layout::read_meta(p, bytes)), bytes.to_vec()));
+    }
+    match try!(header::read_header(&mut BufReader::new(bytes))) {
+        Some((header, len)) => Ok((header, bytes.slice_from(len as uint).to_vec())),
+        None => Ok((Header::new(), bytes.to_vec())),
+    }
+}
+
+/// Replaces the file at `p` with `bytes` atomically, as `write_atomic` does, but writing the new
+/// file through a map, and flushing it to disk only as far as `durability` asks.
+pub fn write_atomic_mapped(p: &Path, bytes: &[u8], temp: &TempFiles, durability: Durability)
+                           -> IoResult<()> {
+    let tmp = temp.path_for(p);
+    let res = write(&tmp, bytes, durability != NoSync).and_then(|()| {
+        fs::rename(&tmp, p)
+    }).and_then(|()| if durability == Fsync { sync_dir(p) } else { Ok(()) });
+    if res.is_err() && temp.remove_on_failure {
+        let _ = fs::unlink(&tmp);
+    }
+    res
+}
+
+#[cfg(unix)]
+fn write(p: &Path, bytes: &[u8], sync: bool) -> IoResult<()> {
+    let fd = try!(Fd::open(p, libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC));
+    if bytes.is_empty() {
+        return Ok(());
+    }
+    if unsafe { libc::ftruncate(fd.fd, bytes.len() as libc::off_t) } != 0 {
+        return Err(IoError::last_error());
+    }
+    // Maps are private unless asked otherwise, and changes to private maps never reach the file.
+    let flags = MapNonStandardFlags(libc::MAP_SHARED);
+    let map = try!(MemoryMap::new(bytes.len(), &[MapReadable, MapWritable, MapFd(fd.fd), flags])
+                       .map_err(map_error));
+    unsafe {
+        mut_buf_as_slice(map.data(), bytes.len(), |dest| copy_memory(dest, bytes));
+        let len = bytes.len() as libc::size_t;
+        if sync && msync(map.data() as *mut libc::c_void, len, MS_SYNC) != 0 {
+            return Err(IoError::last_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write(p: &Path, bytes: &[u8], sync: bool) -> IoResult<()> {
+    let mut f = try!(::std::io::File::create(p));
+    try!(f.write(bytes));
+    if sync { f.fsync() } else { Ok(()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{FileBox, Split, peek};
+
+    #[test]
+    fn read_and_write_mapped() {
+        let path = Path::new("target/read_and_write_mapped");
+        let val: Vec<u32> = range(0, 100000).collect();
+        FileBox::open_new(&path, val.clone()).unwrap();
+        let mut b: FileBox<Vec<u32>> = FileBox::open_mapped(&path).unwrap();
+        assert!(*b == val);
+        b.push(7);
+        b.save().unwrap();
+        b.set_layout(Split);
+        drop(b);
+        assert_eq!(peek::<Vec<u32>>(&path).unwrap().len(), 100001);
+        assert_eq!(FileBox::<Vec<u32>>::open_mapped(&path).unwrap().len(), 100001);
+    }
+}
@@ -0,0 +1,323 @@
+//! Keeping track of how much memory the values of boxes use.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io::{mod, fs, IoError, IoResult, MemWriter};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use encoding::CompactDecoder;
+use super::FileBox;
+
+/// The description of errors caused by a box not fitting in the budget of its tracker.
+static OVER_BUDGET: &'static str = "the box doesn’t fit in the memory budget of its tracker";
+
+/// Returns whether `e` is the error returned by `FileBox::open_within` for a box that doesn’t fit
+/// in the budget of its tracker.
+pub fn is_over_budget(e: &IoError) -> bool {
+    e.kind == io::ResourceUnavailable && e.desc == OVER_BUDGET
+}
+
+/// Estimates the memory a value owns on the heap.
+///
+/// The estimate covers the allocations the value owns, but not the value itself, whose size is
+/// `mem::size_of` of its type. Implementations don’t need to be exact: they are used to compare
+/// boxes with each other and against a budget, so counting the bulk of the memory is enough.
+pub trait HeapSize {
+    /// The number of bytes the value owns on the heap.
+    fn heap_size(&self) -> uint;
+}
+
+macro_rules! no_heap {
+    ($($t:ty),*) => {
+        $(impl HeapSize for $t {
+            fn heap_size(&self) -> uint { 0 }
+        })*
+    }
+}
+
+no_heap!((), bool, char, u8, u16, u32, u64, uint, i8, i16, i32, i64, int, f32, f64)
+
+impl HeapSize for String {
+    fn heap_size(&self) -> uint {
+        self.capacity()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Vec<T> {
+    fn heap_size(&self) -> uint {
+        self.capacity() * mem::size_of::<T>() + self.iter().fold(0, |n, x| n + x.heap_size())
+    }
+}
+
+impl<T: HeapSize> HeapSize for Option<T> {
+    fn heap_size(&self) -> uint {
+        self.as_ref().map_or(0, |x| x.heap_size())
+    }
+}
+
+impl<T: HeapSize> HeapSize for Box<T> {
+    fn heap_size(&self) -> uint {
+        mem::size_of::<T>() + (**self).heap_size()
+    }
+}
+
+impl<K: HeapSize + Eq + Hash, V: HeapSize> HeapSize for HashMap<K, V> {
+    fn heap_size(&self) -> uint {
+        // Each bucket also holds the hash of its key.
+        let buckets = self.capacity() * (mem::size_of::<K>() + mem::size_of::<V>() + 8);
+        self.iter().fold(buckets, |n, (k, v)| n + k.heap_size() + v.heap_size())
+    }
+}
+
+impl<A: HeapSize, B: HeapSize> HeapSize for (A, B) {
+    fn heap_size(&self) -> uint {
+        let (ref a, ref b) = *self;
+        a.heap_size() + b.heap_size()
+    }
+}
+
+impl<A: HeapSize, B: HeapSize, C: HeapSize> HeapSize for (A, B, C) {
+    fn heap_size(&self) -> uint {
+        let (ref a, ref b, ref c) = *self;
+        a.heap_size() + b.heap_size() + c.heap_size()
+    }
+}
+
+/// The memory used by `val`, including the value itself.
+pub fn size_of_value<T: HeapSize>(val: &T) -> uint {
+    mem::size_of::<T>() + val.heap_size()
+}
+
+/// Adds up the memory used by the values of the boxes registered with it.
+///
+/// Boxes are registered with `FileBox::set_memory_tracker`. Each box reports the size of its value
+/// when it is registered, whenever it is written, and when `FileBox::report_memory` is called, and
+/// is forgotten when it is dropped. Changes made to a value since its box last reported aren’t
+/// counted.
+///
+/// Clones of a tracker share the same boxes, so one can be handed to every box of a program, or one
+/// tracker made for each part of a program whose memory is budgeted separately, such as each
+/// tenant of a server. A tracker made with `with_limit` has a budget that boxes opened with
+/// `FileBox::open_within` have to fit in. The memory of a box is only accounted to its tracker,
+/// not allocated from it, so it is freed when the box is dropped like that of any other value.
+#[deriving(Clone)]
+pub struct MemoryTracker {
+    entries: Arc<Mutex<Entries>>,
+}
+
+struct Entries {
+    next: u64,
+    sizes: HashMap<u64, (Path, uint)>,
+    limit: Option<uint>,
+}
+
+impl MemoryTracker {
+    /// Creates a tracker with no boxes registered.
+    pub fn new() -> MemoryTracker {
+        MemoryTracker::with_budget(None)
+    }
+
+    /// Creates a tracker with no boxes registered and a budget of `limit` bytes.
+    pub fn with_limit(limit: uint) -> MemoryTracker {
+        MemoryTracker::with_budget(Some(limit))
+    }
+
+    fn with_budget(limit: Option<uint>) -> MemoryTracker {
+        MemoryTracker {
+            entries: Arc::new(Mutex::new(Entries {
+                next: 0,
+                sizes: HashMap::new(),
+                limit: limit,
+            })),
+        }
+    }
+
+    /// The budget of the tracker, in bytes, if it has one.
+    pub fn limit(&self) -> Option<uint> {
+        self.entries.lock().limit
+    }
+
+    /// How much of the tracker’s budget is left, in bytes, if it has one. Values that have grown
+    /// since they were registered can take the total beyond the budget, leaving nothing.
+    pub fn remaining(&self) -> Option<uint> {
+        let entries = self.entries.lock();
+        let total = entries.sizes.values().fold(0, |n, &(_, size)| n + size);
+        entries.limit.map(|limit| if total < limit { limit - total } else { 0 })
+    }
+
+    /// The total memory used by the values of the registered boxes, in bytes.
+    pub fn total(&self) -> uint {
+        self.entries.lock().sizes.values().fold(0, |n, &(_, size)| n + size)
+    }
+
+    /// The paths of the registered boxes and the memory used by their values, largest first.
+    pub fn usage(&self) -> Vec<(Path, uint)> {
+        let mut usage: Vec<(Path, uint)> = self.entries.lock().sizes.values()
+                                                                   .map(|e| e.clone())
+                                                                   .collect();
+        usage.sort_by(|&(_, a), &(_, b)| b.cmp(&a));
+        usage
+    }
+
+    fn register(&self, p: &Path, size: uint) -> u64 {
+        let mut entries = self.entries.lock();
+        let id = entries.next;
+        entries.next += 1;
+        entries.sizes.insert(id, (p.clone(), size));
+        id
+    }
+
+    /// Like `register`, but fails if the box doesn’t fit in the tracker’s budget. The budget is
+    /// checked and the box registered at once, so boxes registered by several tasks at the same
+    /// time can’t overrun it between them.
+    fn try_register(&self, p: &Path, size: uint) -> IoResult<u64> {
+        let mut entries = self.entries.lock();
+        let total = entries.sizes.values().fold(0, |n, &(_, size)| n + size);
+        match entries.limit {
+            Some(limit) if total + size > limit => return Err(over_budget(p, size, limit - total)),
+            _ => {}
+        }
+        let id = entries.next;
+        entries.next += 1;
+        entries.sizes.insert(id, (p.clone(), size));
+        Ok(id)
+    }
+}
+
+fn over_budget(p: &Path, needed: uint, remaining: uint) -> IoError {
+    IoError {
+        kind: io::ResourceUnavailable,
+        desc: OVER_BUDGET,
+        detail: Some(format!("{}: {} bytes needed, {} left", p.display(), needed, remaining)),
+    }
+}
+
+/// A box’s registration with a tracker.
+pub struct Tracked<T> {
+    tracker: MemoryTracker,
+    id: u64,
+    size: fn(&T) -> uint,
+}
+
+impl<T> Tracked<T> {
+    /// Registers the box at `p` holding `val`, whose size is measured by `size`.
+    pub fn new(tracker: MemoryTracker, p: &Path, val: &T, size: fn(&T) -> uint) -> Tracked<T> {
+        let id = tracker.register(p, size(val));
+        Tracked {
+            tracker: tracker,
+            id: id,
+            size: size,
+        }
+    }
+
+    /// Like `new`, but fails if the box doesn’t fit in the tracker’s budget.
+    pub fn try_new(tracker: MemoryTracker, p: &Path, val: &T, size: fn(&T) -> uint)
+                   -> IoResult<Tracked<T>> {
+        let id = try!(tracker.try_register(p, size(val)));
+        Ok(Tracked {
+            tracker: tracker,
+            id: id,
+            size: size,
+        })
+    }
+
+    /// Records the current size of the box’s value.
+    pub fn report(&self, val: &T) {
+        let size = (self.size)(val);
+        match self.tracker.entries.lock().sizes.get_mut(&self.id) {
+            Some(entry) => {
+                let (_, ref mut current) = *entry;
+                *current = size;
+            }
+            None => {}
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<T> Drop for Tracked<T> {
+    fn drop(&mut self) {
+        self.tracker.entries.lock().sizes.remove(&self.id);
+    }
+}
+
+impl<'a, T> FileBox<T> where T: Decodable<CompactDecoder, IoError>
+                              + Encodable<EncoderWriter<'a, MemWriter>, IoError>
+                              + HeapSize {
+    /// Like `open`, but the box is registered with `tracker` as by `set_memory_tracker`, and
+    /// opening it fails with an error for which `is_over_budget` is true if its value doesn’t fit
+    /// in what is left of the tracker’s budget. Files larger than what is left are refused without
+    /// being read, since their values are almost always at least as large.
+    pub fn open_within(p: &Path, tracker: MemoryTracker) -> IoResult<FileBox<T>> {
+        match tracker.remaining() {
+            Some(remaining) => {
+                let size = try!(fs::stat(p)).size;
+                if size > remaining as u64 {
+                    return Err(over_budget(p, size as uint, remaining));
+                }
+            }
+            None => {}
+        }
+        let mut b = try!(FileBox::open(p));
+        match Tracked::try_new(tracker, p, b._val.as_ref().unwrap(), size_of_value::<T>) {
+            Ok(tracked) => b._memory = Some(tracked),
+            Err(e) => {
+                // The box was only opened to measure it, so it is freed without being written.
+                b._write_on_drop = false;
+                return Err(e);
+            }
+        }
+        Ok(b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::IoResult;
+    use std::mem;
+    use super::{HeapSize, MemoryTracker, is_over_budget};
+    use super::super::{FileBox, write_value};
+
+    #[test]
+    fn track_boxes() {
+        let strings = vec![String::with_capacity(10), String::with_capacity(10)];
+        assert_eq!(strings.heap_size(), 2 * mem::size_of::<String>() + 20);
+
+        let tracker = MemoryTracker::new();
+        let small = Path::new("target/track_boxes_small");
+        let large = Path::new("target/track_boxes_large");
+        let mut a = FileBox::open_new(&small, Vec::<u64>::with_capacity(1)).unwrap();
+        a.set_memory_tracker(Some(tracker.clone()));
+        {
+            let mut b = FileBox::open_new(&large, Vec::<u64>::with_capacity(100)).unwrap();
+            b.set_memory_tracker(Some(tracker.clone()));
+            let base = mem::size_of::<Vec<u64>>();
+            assert_eq!(tracker.total(), 2 * base + 101 * 8);
+            assert_eq!(tracker.usage(), vec![(large.clone(), base + 800),
+                                             (small.clone(), base + 8)]);
+        }
+        assert_eq!(tracker.usage().len(), 1);
+        a.reserve_exact(9);
+        a.report_memory();
+        assert_eq!(tracker.total(), mem::size_of::<Vec<u64>>() + a.capacity() * 8);
+    }
+
+    #[test]
+    fn open_within_budget() {
+        let small = Path::new("target/open_within_budget_small");
+        let large = Path::new("target/open_within_budget_large");
+        write_value(&small, &vec![0u8, ..10]).unwrap();
+        write_value(&large, &vec![0u8, ..1000]).unwrap();
+        let base = mem::size_of::<Vec<u8>>();
+        let tenant = MemoryTracker::with_limit(500);
+        let a: FileBox<Vec<u8>> = FileBox::open_within(&small, tenant.clone()).unwrap();
+        assert_eq!(tenant.remaining(), Some(500 - base - 10));
+        let res: IoResult<FileBox<Vec<u8>>> = FileBox::open_within(&large, tenant.clone());
+        assert!(is_over_budget(&res.err().unwrap()));
+        drop(a);
+        assert_eq!(tenant.remaining(), Some(500));
+    }
+}
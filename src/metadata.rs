@@ -0,0 +1,75 @@
+//! Learning about box files without knowing the types of the values they hold.
+
+use std::io::{fs, IoResult};
+
+use layout::{mod, Layout};
+use super::open_header;
+
+/// What a box file says about itself, gathered without decoding its value.
+#[deriving(Clone, PartialEq, Show)]
+pub struct BoxMetadata {
+    /// How the value was turned into the payload, such as `bincode`.
+    pub encoding: String,
+    /// The name of the compressor the payload was compressed with.
+    pub compressor: String,
+    /// The tag of the type the payload holds, or an empty string if it wasn’t recorded.
+    pub type_tag: String,
+    /// The version of the type the payload holds.
+    pub version: u64,
+    /// How many times the box has been written over its lifetime.
+    pub saves: u64,
+    /// When the box was created, in seconds since the Unix epoch, or 0 if this isn’t known.
+    pub created_at: u64,
+    /// When the file was last written, in milliseconds since the Unix epoch.
+    pub modified: u64,
+    /// The size of the payload as it is stored, in bytes.
+    pub payload_size: u64,
+    /// The FNV-1a hash of the payload as it is stored, if the file records it.
+    pub checksum: Option<u64>,
+    /// How the box is laid out on disk.
+    pub layout: Layout,
+    /// Whether the file has a header at all. Files without one, such as those written before
+    /// boxes had headers or by boxes with formats, get the metadata of an unprocessed payload.
+    pub has_header: bool,
+}
+
+/// Reads the metadata of the box file at `p` from its header and the filesystem, without reading
+/// its value, so that tools indexing or checking many boxes needn’t know what they hold. See
+/// `open_header` for the header alone.
+pub fn peek_metadata(p: &Path) -> IoResult<BoxMetadata> {
+    let info = try!(open_header(p));
+    let header = info.header;
+    Ok(BoxMetadata {
+        encoding: header.encoding,
+        compressor: header.compressor,
+        type_tag: header.type_tag,
+        version: header.version,
+        saves: header.saves,
+        created_at: header.created_at,
+        modified: try!(fs::stat(p)).modified,
+        payload_size: info.payload_size,
+        checksum: header.checksum,
+        layout: layout::layout_of(p),
+        has_header: info.has_header,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::peek_metadata;
+    use super::super::{FileBox, Combined};
+
+    #[test]
+    fn read_metadata_without_type() {
+        let path = Path::new("target/read_metadata_without_type");
+        let mut b = FileBox::open_new_tagged(&path, vec![1u8, 2, 3], "bytes").unwrap();
+        b.save().unwrap();
+        let meta = peek_metadata(&path).unwrap();
+        assert_eq!(meta.type_tag.as_slice(), "bytes");
+        assert_eq!(meta.encoding.as_slice(), "bincode");
+        assert_eq!(meta.layout, Combined);
+        assert!(meta.has_header);
+        assert!(meta.checksum.is_some());
+        assert_eq!(meta.saves, b.header().saves);
+    }
+}
@@ -0,0 +1,123 @@
+//! Upgrading the values of boxes written by older versions of a program.
+
+use std::io::{mod, IoError, IoResult, MemWriter};
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use compress;
+use encoding::CompactDecoder;
+use schema::SchemaTooNew;
+use super::{FileBox, check_bincode, read_box};
+
+/// Turns the payloads of older versions of a type into values of its current version.
+///
+/// The version of a box’s type is recorded in its header. When the type changes in a way that
+/// stops old payloads from decoding, its version is raised, and `migrate` is taught to decode
+/// payloads of the old version, usually by decoding them as a copy of the old type kept for the
+/// purpose and converting that.
+pub trait Migrate<T> {
+    /// The current version of the type.
+    fn current_version(&self) -> u64;
+    /// Decodes `payload`, which holds a value of an older `version` of the type, or returns `None`
+    /// if that version can’t be upgraded.
+    fn migrate(&self, version: u64, payload: &[u8]) -> Option<T>;
+}
+
+impl<'a, T> FileBox<T> where T: Decodable<CompactDecoder, IoError>
+                              + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Opens a box as `open` does, passing it to `m` to be upgraded if it holds an older version
+    /// of its type. Fails with a `SchemaTooNew` error if it holds a newer one. The box is written
+    /// with the current version from then on, and the upgraded value is written when the box is.
+    pub fn open_migrating(p: &Path, m: &Migrate<T>) -> IoResult<FileBox<T>> {
+        let (mut header, payload) = try!(read_box(p, None, None, None, false, false));
+        try!(check_bincode(&header));
+        let current = m.current_version();
+        if header.version > current {
+            return Err(SchemaTooNew {
+                schema: if header.type_tag.is_empty() {
+                    p.display().to_string()
+                } else {
+                    header.type_tag.clone()
+                },
+                found: header.version,
+                supported: current,
+            }.to_error());
+        }
+        let c = compress::builtin(header.compressor.as_slice()).unwrap();
+        if header.version == current {
+            return FileBox::from_payload(p, header, payload, c);
+        }
+        let val = match m.migrate(header.version, payload.as_slice()) {
+            Some(val) => val,
+            None => return Err(IoError {
+                kind: io::InvalidInput,
+                desc: "the box holds a version of its type that can’t be upgraded",
+                detail: Some(format!("version {}", header.version)),
+            }),
+        };
+        header.version = current;
+        let mut b = try!(FileBox::with_value(p, val, c));
+        b._header = header;
+        Ok(b)
+    }
+
+    /// Creates a box as `open_new` does, recording that it holds the given version of its type.
+    pub fn open_new_versioned(p: &Path, val: T, version: u64) -> IoResult<FileBox<T>> {
+        let mut b = try!(FileBox::open_new(p, val));
+        b._header.version = version;
+        Ok(b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bincode;
+    use super::Migrate;
+    use super::super::FileBox;
+
+    #[deriving(Encodable, Decodable)]
+    struct SettingsV0 {
+        volume: u8,
+    }
+
+    #[deriving(Encodable, Decodable, PartialEq, Show)]
+    struct Settings {
+        volume: u8,
+        muted: bool,
+    }
+
+    struct SettingsMigrations;
+
+    impl Migrate<Settings> for SettingsMigrations {
+        fn current_version(&self) -> u64 {
+            1
+        }
+
+        fn migrate(&self, version: u64, payload: &[u8]) -> Option<Settings> {
+            match version {
+                0 => bincode::decode::<SettingsV0>(payload.to_vec()).ok().map(|old| Settings {
+                    volume: old.volume,
+                    muted: false,
+                }),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn migrate_on_open() {
+        let path = Path::new("target/migrate_on_open");
+        FileBox::open_new(&path, SettingsV0 { volume: 7 }).unwrap();
+        {
+            let b = FileBox::open_migrating(&path, &SettingsMigrations).unwrap();
+            assert_eq!(*b, Settings { volume: 7, muted: false });
+            assert_eq!(b.header().version, 1);
+        }
+        let b = FileBox::<Settings>::open(&path).unwrap();
+        assert_eq!(b.header().version, 1);
+        assert!(!b.muted);
+        drop(b);
+        FileBox::open_new_versioned(&path, Settings { volume: 1, muted: true }, 2).unwrap();
+        assert!(FileBox::open_migrating(&path, &SettingsMigrations).is_err());
+    }
+}
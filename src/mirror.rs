@@ -0,0 +1,208 @@
+//! Copying boxes to a second location in the background.
+
+use std::comm::{channel, Sender, Receiver, Empty, Disconnected};
+use std::io::{mod, fs, File, IoError, IoResult};
+use std::io::fs::PathExtensions;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUint, SeqCst};
+use std::task;
+
+use atomic::write_atomic;
+use layout::meta_path;
+use super::now;
+
+/// How far a mirror has fallen behind the box it copies.
+#[deriving(Clone, PartialEq, Show)]
+pub struct MirrorLag {
+    /// How many writes of the box haven’t been copied to the mirror yet.
+    pub pending: uint,
+    /// When the mirror was last brought up to date, in seconds since the Unix epoch.
+    pub last_copied_at: Option<u64>,
+    /// The error the last copy failed with, if it failed. The next write of the box tries again.
+    pub last_error: Option<IoError>,
+}
+
+/// A copy of a box kept at a second path, such as on another disk or a network mount, as set up
+/// by `FileBox::set_mirror`.
+///
+/// Every time the box is written, its files are handed to a background task that copies them to
+/// the mirror, so writing the box doesn’t wait for the copy. The mirror is always a whole box file
+/// replaced atomically, so if the disk holding the box fails, the mirror can be opened like any
+/// other box, holding the value of one of the box’s recent writes. If the box is written again
+/// before a copy has started, only the newest version is copied. The mirror is only ever meant to
+/// be written by this, so programs shouldn’t write it themselves until they fail over to it.
+pub struct Mirror {
+    path: Path,
+    jobs: Sender<Job>,
+    state: Arc<State>,
+}
+
+enum Job {
+    /// Copy the bytes of a box file, and of its header file if it has one.
+    CopyJob(Vec<u8>, Option<Vec<u8>>),
+    /// Reply once every copy sent before this has been made.
+    FlushJob(Sender<()>),
+}
+
+struct State {
+    pending: AtomicUint,
+    last_copied_at: AtomicUint,
+    last_error: Mutex<Option<IoError>>,
+}
+
+impl Mirror {
+    /// Starts mirroring to the box file at `p`.
+    pub fn new(p: &Path) -> Mirror {
+        let (tx, rx) = channel();
+        let state = Arc::new(State {
+            pending: AtomicUint::new(0),
+            last_copied_at: AtomicUint::new(0),
+            last_error: Mutex::new(None),
+        });
+        let path = p.clone();
+        let task_state = state.clone();
+        task::spawn(proc() {
+            copy_jobs(&path, rx, &*task_state);
+        });
+        Mirror {
+            path: p.clone(),
+            jobs: tx,
+            state: state,
+        }
+    }
+
+    /// The path of the mirror’s box file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// How far the mirror has fallen behind.
+    pub fn lag(&self) -> MirrorLag {
+        let at = self.state.last_copied_at.load(SeqCst);
+        MirrorLag {
+            pending: self.state.pending.load(SeqCst),
+            last_copied_at: if at == 0 { None } else { Some(at as u64) },
+            last_error: self.state.last_error.lock().clone(),
+        }
+    }
+
+    /// Waits until every write of the box so far has been copied to the mirror, returning the
+    /// error the last copy failed with, if it did.
+    pub fn wait(&self) -> IoResult<()> {
+        let (tx, rx) = channel();
+        self.jobs.send(FlushJob(tx));
+        let _ = rx.recv_opt();
+        match *self.state.last_error.lock() {
+            Some(ref e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+
+    /// Hands the current files of the box at `p` to the background task to copy. If they can’t
+    /// be read, the error is reported by `lag` as well as returned.
+    pub fn send(&self, p: &Path) -> IoResult<()> {
+        match read_files(p) {
+            Ok((bytes, meta)) => {
+                self.state.pending.fetch_add(1, SeqCst);
+                self.jobs.send(CopyJob(bytes, meta));
+                Ok(())
+            }
+            Err(e) => {
+                *self.state.last_error.lock() = Some(e.clone());
+                Err(e)
+            }
+        }
+    }
+}
+
+fn read_files(p: &Path) -> IoResult<(Vec<u8>, Option<Vec<u8>>)> {
+    let bytes = try!(File::open(p).read_to_end());
+    let meta = meta_path(p);
+    if meta.exists() {
+        Ok((bytes, Some(try!(File::open(&meta).read_to_end()))))
+    } else {
+        Ok((bytes, None))
+    }
+}
+
+/// Makes the copies sent to `jobs` until every sender has gone.
+fn copy_jobs(p: &Path, jobs: Receiver<Job>, state: &State) {
+    loop {
+        let mut job = match jobs.recv_opt() {
+            Ok(job) => job,
+            Err(()) => return,
+        };
+        // Only the newest of the copies waiting is worth making.
+        let mut latest = None;
+        let mut copies = 0u;
+        let mut flushes = Vec::new();
+        loop {
+            match job {
+                CopyJob(bytes, meta) => {
+                    latest = Some((bytes, meta));
+                    copies += 1;
+                }
+                FlushJob(reply) => flushes.push(reply),
+            }
+            job = match jobs.try_recv() {
+                Ok(job) => job,
+                Err(Empty) | Err(Disconnected) => break,
+            };
+        }
+        match latest {
+            Some((bytes, meta)) => {
+                let res = copy(p, bytes.as_slice(), meta.as_ref().map(|m| m.as_slice()));
+                if res.is_ok() {
+                    state.last_copied_at.store(now() as uint, SeqCst);
+                }
+                *state.last_error.lock() = res.err();
+            }
+            None => {}
+        }
+        state.pending.fetch_sub(copies, SeqCst);
+        for reply in flushes.into_iter() {
+            let _ = reply.send_opt(());
+        }
+    }
+}
+
+fn copy(p: &Path, bytes: &[u8], meta: Option<&[u8]>) -> IoResult<()> {
+    try!(fs::mkdir_recursive(&p.dir_path(), io::USER_RWX));
+    let meta_file = meta_path(p);
+    match meta {
+        Some(meta) => {
+            try!(write_atomic(p, bytes));
+            write_atomic(&meta_file, meta)
+        }
+        None => {
+            if meta_file.exists() {
+                try!(fs::unlink(&meta_file));
+            }
+            write_atomic(p, bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs;
+    use super::super::{FileBox, peek};
+
+    #[test]
+    fn mirror_writes() {
+        let path = Path::new("target/mirror_writes");
+        let secondary = Path::new("target/mirror_writes_secondary/box");
+        let _ = fs::rmdir_recursive(&secondary.dir_path());
+        let mut b = FileBox::open_new(&path, vec![1u8]).unwrap();
+        b.set_mirror(Some(&secondary));
+        b.push(2);
+        b.save().unwrap();
+        b.push(3);
+        b.save().unwrap();
+        b.mirror().unwrap().wait().unwrap();
+        let lag = b.mirror().unwrap().lag();
+        assert_eq!(lag.pending, 0);
+        assert!(lag.last_copied_at.is_some());
+        assert_eq!(peek::<Vec<u8>>(&secondary).unwrap(), vec![1, 2, 3]);
+    }
+}
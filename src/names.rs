@@ -0,0 +1,111 @@
+//! Turning arbitrary names into file names.
+
+use std::ascii::AsciiExt;
+use std::io::{mod, IoError, IoResult};
+use std::num;
+use std::str;
+
+/// The longest file name `escape_name` produces. This leaves room below the usual limit of 255
+/// bytes for an extension and the suffixes of temporary files.
+pub static MAX_ESCAPED_LEN: uint = 200;
+
+/// Names that Windows reserves for devices, whatever their case and extension.
+static RESERVED: &'static [&'static str] = &[
+    "con", "prn", "aux", "nul",
+    "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8", "com9",
+    "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// Turns `name` into a string that can be used as a file name on any common filesystem, such as
+/// a name typed in by a user. `unescape_name` turns the result back into `name`.
+///
+/// Only lowercase ASCII letters, digits, `-` and `_` are kept as they are. Every other byte is
+/// written as `%` followed by two hexadecimal digits, so the result never contains separators or
+/// dots, and two names that differ only in case are kept apart on filesystems that ignore case.
+/// Names reserved by Windows have their first letter escaped too.
+///
+/// This fails if `name` is empty, or if the result would be longer than `MAX_ESCAPED_LEN`.
+pub fn escape_name(name: &str) -> IoResult<String> {
+    if name.is_empty() {
+        return Err(invalid("an empty name can’t be used as a file name", name));
+    }
+    let reserved = RESERVED.iter().any(|r| name.eq_ignore_ascii_case(*r));
+    let mut s = String::with_capacity(name.len());
+    for (i, &b) in name.as_bytes().iter().enumerate() {
+        if is_plain(b) && !(reserved && i == 0) {
+            s.push(b as char);
+        } else {
+            s.push_str(format!("%{:02X}", b).as_slice());
+        }
+    }
+    if s.len() > MAX_ESCAPED_LEN {
+        return Err(invalid("the name is too long to be used as a file name", name));
+    }
+    Ok(s)
+}
+
+/// Reverses `escape_name`, returning `None` if `s` couldn’t have been produced by it.
+pub fn unescape_name(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut name = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if is_plain(bytes[i]) {
+            name.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        if bytes[i] != b'%' || i + 3 > bytes.len() {
+            return None;
+        }
+        match str::from_utf8(bytes.slice(i + 1, i + 3)).and_then(|h| num::from_str_radix(h, 16)) {
+            Some(b) => name.push(b),
+            None => return None,
+        }
+        i += 3;
+    }
+    if name.is_empty() {
+        return None;
+    }
+    String::from_utf8(name).ok()
+}
+
+fn is_plain(b: u8) -> bool {
+    match b {
+        b'a'...b'z' | b'0'...b'9' | b'-' | b'_' => true,
+        _ => false,
+    }
+}
+
+fn invalid(desc: &'static str, name: &str) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: desc,
+        detail: Some(name.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_name, unescape_name};
+
+    #[test]
+    fn round_trip() {
+        for &name in ["prefs", "user-42", "../etc/passwd", "Prefs", "naïve", "CON", "nul"].iter() {
+            let escaped = escape_name(name).unwrap();
+            let escaped = escaped.as_slice();
+            assert!(!escaped.contains_char('/') && !escaped.contains_char('.'));
+            assert_eq!(unescape_name(escaped), Some(name.to_string()));
+        }
+        assert!(escape_name("Prefs").unwrap() != escape_name("prefs").unwrap());
+        assert_eq!(escape_name("con").unwrap().as_slice(), "%63on");
+        assert_eq!(unescape_name("a.b"), None);
+    }
+
+    #[test]
+    fn invalid_names() {
+        assert!(escape_name("").is_err());
+        let long = String::from_char(100, '/');
+        assert!(escape_name(long.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+//! Waking other processes when a box is written, so they don’t have to poll its file.
+//!
+//! Each listening process binds a Unix socket in a directory next to the box’s file, and writers
+//! notify them by connecting to every socket in it. Notifications aren’t available on other
+//! platforms, where `notify_listeners` does nothing.
+
+use std::io::IoResult;
+#[cfg(unix)]
+use std::io::{mod, fs};
+#[cfg(unix)]
+use std::io::fs::PathExtensions;
+#[cfg(unix)]
+use std::io::net::pipe::{UnixListener, UnixStream, UnixAcceptor};
+#[cfg(unix)]
+use std::io::{Listener, Acceptor};
+#[cfg(unix)]
+use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+#[cfg(unix)]
+use std::time::Duration;
+
+#[cfg(unix)]
+use process;
+
+/// Distinguishes the sockets of listeners in the same process.
+#[cfg(unix)]
+static NEXT_LISTENER: AtomicUint = INIT_ATOMIC_UINT;
+
+/// The directory holding the sockets of the listeners of the box at `p`.
+#[cfg(unix)]
+fn socket_dir(p: &Path) -> Path {
+    let mut name = p.filename().unwrap_or(b"filebox").to_vec();
+    name.push_all(b".notify");
+    p.with_filename(name)
+}
+
+/// Receives notifications that the box at a path has been written.
+///
+/// Notifications sent while nobody is waiting for them are kept until the next call to `wait`, so
+/// none are missed between calls, but several writes may be reported by a single wakeup.
+#[cfg(unix)]
+pub struct BoxListener {
+    socket: Path,
+    acceptor: UnixAcceptor,
+}
+
+#[cfg(unix)]
+impl BoxListener {
+    /// Starts listening for writes to the box at `p`. Only writes by boxes that have notifications
+    /// turned on (with `FileBox::set_notify`) and calls to `notify_listeners` are noticed.
+    pub fn new(p: &Path) -> IoResult<BoxListener> {
+        let dir = socket_dir(p);
+        if !dir.is_dir() {
+            try!(fs::mkdir(&dir, io::USER_RWX));
+        }
+        let n = NEXT_LISTENER.fetch_add(1, SeqCst);
+        let socket = dir.join(format!("{}-{}", process::id(), n));
+        // A socket with this name can only have been left behind by an earlier process with the
+        // same id, which is no longer listening on it.
+        let _ = fs::unlink(&socket);
+        let acceptor = try!(UnixListener::bind(&socket).listen());
+        Ok(BoxListener {
+            socket: socket,
+            acceptor: acceptor,
+        })
+    }
+
+    /// Waits until the box is written or `timeout` passes, returning whether it was written. With
+    /// no timeout, this waits forever.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> IoResult<bool> {
+        self.acceptor.set_timeout(timeout.map(|t| t.num_milliseconds() as u64));
+        match self.acceptor.accept() {
+            Ok(_) => {}
+            Err(ref e) if e.kind == io::TimedOut => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        // Reporting each of the pending notifications separately wouldn’t tell the caller
+        // anything more, so they are all taken at once.
+        self.acceptor.set_timeout(Some(0));
+        while self.acceptor.accept().is_ok() {}
+        Ok(true)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for BoxListener {
+    fn drop(&mut self) {
+        let _ = fs::unlink(&self.socket);
+    }
+}
+
+/// Wakes every `BoxListener` of the box at `p`, returning how many were woken.
+#[cfg(unix)]
+pub fn notify_listeners(p: &Path) -> IoResult<uint> {
+    let dir = socket_dir(p);
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+    let mut woken = 0;
+    for socket in try!(fs::readdir(&dir)).iter() {
+        match UnixStream::connect(socket) {
+            Ok(_) => woken += 1,
+            Err(_) if !owner_is_alive(socket) => {
+                // The listener’s process died without removing its socket.
+                let _ = fs::unlink(socket);
+            }
+            Err(_) => {}
+        }
+    }
+    Ok(woken)
+}
+
+#[cfg(not(unix))]
+pub fn notify_listeners(_p: &Path) -> IoResult<uint> {
+    Ok(0)
+}
+
+/// Returns whether the process that created the given socket might still be running.
+#[cfg(unix)]
+fn owner_is_alive(socket: &Path) -> bool {
+    let pid = socket.filename_str().and_then(|name| {
+        name.split('-').next().and_then(from_str::<u32>)
+    });
+    pid.map_or(true, process::is_alive)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::time::Duration;
+    use super::{BoxListener, notify_listeners};
+    use super::super::FileBox;
+
+    #[test]
+    fn notify_on_write() {
+        let path = Path::new("target/notify_on_write");
+        let mut listener = BoxListener::new(&path).unwrap();
+        assert!(!listener.wait(Some(Duration::milliseconds(0))).unwrap());
+        {
+            let mut x = FileBox::open_new(&path, 1i).unwrap();
+            x.set_notify(true);
+        }
+        assert!(listener.wait(Some(Duration::seconds(5))).unwrap());
+        assert!(!listener.wait(Some(Duration::milliseconds(0))).unwrap());
+        assert_eq!(notify_listeners(&path).unwrap(), 1);
+        drop(listener);
+        assert_eq!(notify_listeners(&path).unwrap(), 0);
+    }
+}
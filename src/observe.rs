@@ -0,0 +1,110 @@
+//! Telling other parts of a program when the value of a box changes, and what happens to it.
+
+use std::io::IoError;
+use std::sync::Arc;
+
+use conflicts::ConflictCopy;
+use header::Header;
+
+/// Receives the value of a box whenever it changes, for example to update a user interface.
+///
+/// Observers are added to a box with `FileBox::on_change`. They are called after each change
+/// made through `FileBox::modify` and after each write of the box. Changes made through
+/// `DerefMut` are only seen when the box is next written, since the box can’t tell when they
+/// are finished.
+pub trait Observer<T> {
+    /// Called with the new value of the box.
+    fn changed(&mut self, val: &T);
+}
+
+/// Receives the events in the life of every box it is attached to, for frameworks that manage
+/// many boxes and want to log, count or react to what happens to them in one place.
+///
+/// A framework implements the trait once, wraps the observer in a `SharedObserver`, and attaches
+/// it to each box it opens with `FileBoxOptions::observer`, or to a box that is already open with
+/// `FileBox::set_box_observer`. Every method does nothing by default, so implementations only
+/// need the events they care about. Unlike an `Observer`, it never sees the value of a box, so
+/// one observer serves boxes of every type.
+pub trait BoxObserver {
+    /// Called after the box at `p` has been read from its file, last written with `header`,
+    /// whether by opening or reloading it.
+    fn on_load(&self, _p: &Path, _header: &Header) {}
+
+    /// Called after the box at `p` has been written to its file, or to its log if it is
+    /// journaled, with `header`.
+    fn on_save(&self, _p: &Path, _header: &Header) {}
+
+    /// Called when the box at `p` is opened and copies of it left by a syncing tool are found
+    /// next to it. See `conflict_copies`.
+    fn on_conflict(&self, _p: &Path, _copies: &[ConflictCopy]) {}
+
+    /// Called when reading the box at `p` fails because its file is damaged, before the error is
+    /// returned. See `is_corrupt`.
+    fn on_corruption(&self, _p: &Path, _e: &IoError) {}
+
+    /// Called when the box at `p` is opened with the value from its autosave at `slot` in place
+    /// of the value in its file.
+    fn on_recovery(&self, _p: &Path, _slot: &Path) {}
+}
+
+/// A `BoxObserver` that can be attached to any number of boxes, in any task.
+pub type SharedObserver = Arc<Box<BoxObserver + Send + Sync + 'static>>;
+
+#[cfg(test)]
+mod tests {
+    use std::io::{fs, File, IoError};
+    use std::sync::{Arc, Mutex};
+    use super::BoxObserver;
+    use super::super::{FileBox, FileBoxOptions, ConflictCopy, Header, write_value};
+
+    struct Events {
+        seen: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl BoxObserver for Events {
+        fn on_load(&self, _: &Path, _: &Header) {
+            self.seen.lock().push("load");
+        }
+
+        fn on_save(&self, _: &Path, _: &Header) {
+            self.seen.lock().push("save");
+        }
+
+        fn on_conflict(&self, _: &Path, copies: &[ConflictCopy]) {
+            assert_eq!(copies.len(), 1);
+            self.seen.lock().push("conflict");
+        }
+
+        fn on_corruption(&self, _: &Path, _: &IoError) {
+            self.seen.lock().push("corruption");
+        }
+    }
+
+    #[test]
+    fn observe_box_events() {
+        let dir = Path::new("target/observe_box_events");
+        let _ = fs::rmdir_recursive(&dir);
+        fs::mkdir_recursive(&dir, ::std::io::USER_RWX).unwrap();
+        let path = dir.join("list.box");
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let events = box Events { seen: seen.clone() };
+        let observer = Arc::new(events as Box<BoxObserver + Send + Sync>);
+
+        let mut b = FileBox::open_new(&path, vec![1u8]).unwrap();
+        b.set_box_observer(Some(observer.clone()));
+        b.push(2);
+        b.save().unwrap();
+        drop(b);
+        write_value(&dir.join("list (Bob's conflicted copy 2014-11-12).box"), &vec![3u8]).unwrap();
+        let b: FileBox<Vec<u8>> = FileBoxOptions::new().observer(observer.clone())
+                                                       .open(&path).unwrap();
+        drop(b);
+
+        // Changes the last byte of the payload, which the checksum catches.
+        let mut bytes = File::open(&path).read_to_end().unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        File::create(&path).write(bytes.as_slice()).unwrap();
+        assert!(FileBoxOptions::<Vec<u8>>::new().observer(observer).open(&path).is_err());
+        assert_eq!(*seen.lock(), vec!["save", "load", "conflict", "corruption"]);
+    }
+}
@@ -0,0 +1,196 @@
+//! Keeping processes that share a box from writing over each other’s saves.
+
+use std::collections::TreeMap;
+use std::io::{mod, File, IoError, IoResult, MemWriter};
+use std::io::fs::PathExtensions;
+use std::str;
+use serialize::{json, Encodable};
+use bincode::EncoderWriter;
+
+use super::{FileBox, open_header, modified_time};
+
+/// The description of errors caused by another process having saved a box first.
+static CONFLICT: &'static str = "the box has been saved by someone else since it was last read";
+
+/// Returns whether `e` is the error a box with optimistic saving turned on fails to save with
+/// because someone else saved it first. See `FileBox::set_optimistic`.
+pub fn is_save_conflict(e: &IoError) -> bool {
+    e.kind == io::ResourceUnavailable && e.desc == CONFLICT
+}
+
+/// How a box’s save conflicted with someone else’s, for programs that let people decide what to
+/// keep. See `FileBox::conflict_report`.
+#[deriving(Clone, PartialEq, Show)]
+pub struct ConflictReport {
+    /// How many saves the box’s file had when the box last read or wrote it.
+    pub ours: u64,
+    /// How many saves the box’s file has now.
+    pub theirs: u64,
+    /// When the box last read or wrote its file, in milliseconds since the Unix epoch, if its file
+    /// existed then.
+    pub ours_at: Option<u64>,
+    /// When the box’s file was last written, in milliseconds since the Unix epoch.
+    pub theirs_at: u64,
+    /// The top-level fields whose values differ between the box and its file, in order, for boxes
+    /// whose format writes their values as JSON objects, or `None` for other boxes.
+    pub fields: Option<Vec<String>>,
+}
+
+/// Checks, if `b` saves optimistically, that its file hasn’t been written by anyone else since `b`
+/// last read or wrote it.
+pub fn check<T>(b: &FileBox<T>) -> IoResult<()> {
+    if !b._optimistic {
+        return Ok(());
+    }
+    if try!(saves_now(b)).map_or(false, |saves| saves != saves_seen(b)) {
+        return Err(IoError {
+            kind: io::ResourceUnavailable,
+            desc: CONFLICT,
+            detail: Some(b._path.display().to_string()),
+        });
+    }
+    Ok(())
+}
+
+/// How many saves the file of `b` has now, or `None` if it has no file, or its file doesn’t count
+/// them, as those of boxes with formats don’t.
+fn saves_now<T>(b: &FileBox<T>) -> IoResult<Option<u64>> {
+    if !b._path.exists() {
+        return Ok(None);
+    }
+    let info = try!(open_header(&b._path));
+    Ok(if info.has_header { Some(info.header.saves) } else { None })
+}
+
+/// How many saves the file of `b` had when `b` last read or wrote it.
+fn saves_seen<T>(b: &FileBox<T>) -> u64 {
+    // Saves appended to the box’s log haven’t reached its file.
+    let appended = match b._journal {
+        Some(ref journal) => journal.appended as u64,
+        None => 0,
+    };
+    b._header.saves - appended
+}
+
+/// The top-level fields that differ between two JSON objects, or `None` if either isn’t one.
+fn differing_fields(ours: &[u8], theirs: &[u8]) -> Option<Vec<String>> {
+    let parse = |bytes: &[u8]| str::from_utf8(bytes).and_then(|s| json::from_str(s).ok());
+    let (ours, theirs): (TreeMap<String, json::Json>, TreeMap<String, json::Json>) =
+        match (parse(ours), parse(theirs)) {
+            (Some(json::Object(ours)), Some(json::Object(theirs))) => (ours, theirs),
+            _ => return None,
+        };
+    let mut fields: Vec<String> = ours.iter().filter(|&(key, val)| theirs.get(key) != Some(val))
+                                      .map(|(key, _)| key.clone()).collect();
+    fields.extend(theirs.keys().filter(|key| !ours.contains_key(*key)).map(|key| key.clone()));
+    fields.sort();
+    Some(fields)
+}
+
+impl<T> FileBox<T> {
+    /// Sets whether the box checks, before each save, that nobody else has saved it since it was
+    /// last read or written, so that processes sharing a box don’t silently undo each other’s
+    /// changes. Every write counts as a save in the header of the box’s file, and if the count
+    /// there has moved on, the save fails with an error for which `is_save_conflict` is true. The
+    /// program can then `reload` the box and make its changes again, or keep its own value with
+    /// `force_save`. This is off by default.
+    ///
+    /// A box only reads its file’s header to check it, so this is cheap, but another process can
+    /// still save between the check and the write. Processes that must never lose a save should
+    /// lock the box instead.
+    pub fn set_optimistic(&mut self, optimistic: bool) {
+        self._optimistic = optimistic;
+    }
+
+    /// Describes how the box’s file has been saved by someone else since the box last read or
+    /// wrote it, such as after a save fails with an error for which `is_save_conflict` is true,
+    /// or returns `None` if it hasn’t been. The box needn’t save optimistically for this to be
+    /// used. For boxes with formats, whose files don’t count their saves, any write of the file
+    /// by someone else counts, and both save counts are zero.
+    pub fn conflict_report(&self) -> IoResult<Option<ConflictReport>> {
+        let theirs_at = match try!(modified_time(&self._path)) {
+            Some(modified) => modified,
+            None => return Ok(None),
+        };
+        let (ours, theirs) = match try!(saves_now(self)) {
+            Some(saves) => (saves_seen(self), saves),
+            None => (0, 0),
+        };
+        if ours == theirs && Some(theirs_at) == self._seen {
+            return Ok(None);
+        }
+        let fields = match self._format {
+            Some(ref format) => {
+                let theirs = try!(File::open(&self._path).read_to_end());
+                differing_fields(try!(format.encode(self.val())).as_slice(), theirs.as_slice())
+            }
+            None => None,
+        };
+        Ok(Some(ConflictReport {
+            ours: ours,
+            theirs: theirs,
+            ours_at: self._seen,
+            theirs_at: theirs_at,
+            fields: fields,
+        }))
+    }
+}
+
+impl<'a, T> FileBox<T> where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Writes the box, as `save` does, even if someone else has saved it since it was last read,
+    /// writing over their save with the box’s value. This skips the check `pin_identity` makes as
+    /// well.
+    pub fn force_save(&mut self) -> IoResult<()> {
+        let (optimistic, pin) = (self._optimistic, self._pin);
+        self._optimistic = false;
+        self._pin = false;
+        let res = self.write();
+        self._optimistic = optimistic;
+        self._pin = pin;
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_save_conflict, differing_fields};
+    use super::super::{FileBox, peek};
+
+    #[test]
+    fn refuse_conflicting_saves() {
+        let path = Path::new("target/refuse_conflicting_saves");
+        drop(FileBox::open_new(&path, 0u32).unwrap());
+        let mut a = FileBox::<u32>::open(&path).unwrap();
+        let mut b = FileBox::<u32>::open(&path).unwrap();
+        a.set_optimistic(true);
+        b.set_optimistic(true);
+        *a = 1;
+        a.save().unwrap();
+        *b = 2;
+        assert!(is_save_conflict(&b.save().err().unwrap()));
+        let report = b.conflict_report().unwrap().unwrap();
+        assert_eq!(report.theirs, report.ours + 1);
+        assert_eq!(report.fields, None);
+        b.force_save().unwrap();
+        assert_eq!(peek::<u32>(&path).unwrap(), 2);
+        assert!(is_save_conflict(&a.save().err().unwrap()));
+        a._write_on_drop = false;
+
+        // Once the box has been read again, it can be saved over what it read.
+        let mut c = FileBox::<u32>::open(&path).unwrap();
+        c.set_optimistic(true);
+        *c += 1;
+        c.save().unwrap();
+        assert_eq!(peek::<u32>(&path).unwrap(), 3);
+        drop(b);
+    }
+
+    #[test]
+    fn list_differing_fields() {
+        let ours = br#"{"name": "a", "size": 1, "tags": []}"#;
+        let theirs = br#"{"name": "a", "size": 2, "owner": "b"}"#;
+        assert_eq!(differing_fields(ours, theirs),
+                   Some(vec!["owner".to_string(), "size".to_string(), "tags".to_string()]));
+        assert_eq!(differing_fields(ours, b"[1, 2]"), None);
+    }
+}
@@ -0,0 +1,362 @@
+//! Choosing how a box is opened, one option at a time.
+
+use std::default::Default;
+use std::io::{mod, IoError, IoResult, MemWriter};
+use std::io::fs::PathExtensions;
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use atomic::{Durability, Flush};
+use compress::{Compressor, NoCompression};
+use conflicts::conflict_copies;
+use encoding::{Codec, CompactDecoder, CompactEncoder, Encoding};
+use format::Format;
+use header::is_corrupt;
+use lock::Lock;
+use observe::SharedObserver;
+use super::FileBox;
+
+/// The options a box is opened with, for when the `open` functions of `FileBox` don’t cover the
+/// combination needed.
+///
+/// Each option is set by a method that returns the options again, so they can be chained, and the
+/// box is opened at the end with `open`, as in `FileBoxOptions::new().create(true).open(&p)`. By
+/// default, the box’s file must already exist and is opened as by `FileBox::open`.
+pub struct FileBoxOptions<T> {
+    initial: Option<T>,
+    truncate: bool,
+    lock: bool,
+    wait: bool,
+    read_only: bool,
+    compressor: Option<Box<Compressor + 'static>>,
+    format: Option<Box<Format<T> + 'static>>,
+    codec: Option<Codec<T>>,
+    generations: uint,
+    mapped: bool,
+    recover: bool,
+    durability: Durability,
+    type_tag: Option<String>,
+    observer: Option<SharedObserver>,
+}
+
+impl<T> FileBoxOptions<T> {
+    /// The default options.
+    pub fn new() -> FileBoxOptions<T> {
+        FileBoxOptions {
+            initial: None,
+            truncate: false,
+            lock: false,
+            wait: true,
+            read_only: false,
+            compressor: None,
+            format: None,
+            codec: None,
+            generations: 0,
+            mapped: false,
+            recover: false,
+            durability: Flush,
+            type_tag: None,
+            observer: None,
+        }
+    }
+
+    /// Creates the box with the value `val` if its file doesn’t exist.
+    pub fn create_with(mut self, val: T) -> FileBoxOptions<T> {
+        self.initial = Some(val);
+        self
+    }
+
+    /// Whether the box starts out with the value it would be created with even if its file
+    /// exists, as with `FileBox::open_new`. Opening fails if the box isn’t created at all.
+    pub fn truncate(mut self, truncate: bool) -> FileBoxOptions<T> {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Whether the box is locked against other processes, as by `FileBox::open_locked`.
+    pub fn lock(mut self, lock: bool) -> FileBoxOptions<T> {
+        self.lock = lock;
+        self
+    }
+
+    /// Whether opening a locked box waits for the process that has it locked to let go of it, which
+    /// it does by default, or fails straight away as `FileBox::try_open` does.
+    pub fn wait(mut self, wait: bool) -> FileBoxOptions<T> {
+        self.wait = wait;
+        self
+    }
+
+    /// Whether the box is read-only. See `FileBox::set_read_only`.
+    pub fn read_only(mut self, read_only: bool) -> FileBoxOptions<T> {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Stores the box with the given compressor, as `FileBox::open_compressed` does. This can’t be
+    /// combined with `format`, `mapped` or `recover`.
+    pub fn compressor(mut self, c: Box<Compressor + 'static>) -> FileBoxOptions<T> {
+        self.compressor = Some(c);
+        self
+    }
+
+    /// Stores the box in the given format, as `FileBox::open_with_format` does. Boxes stored in a
+    /// format have no header to record how they were stored, so this can’t be combined with
+    /// `compressor`, `encoding`, `mapped` or `recover`.
+    pub fn format(mut self, format: Box<Format<T> + 'static>) -> FileBoxOptions<T> {
+        self.format = Some(format);
+        self
+    }
+
+    /// Keeps the given number of previous versions of the box’s file. See
+    /// `FileBox::set_generations`.
+    pub fn generations(mut self, keep: uint) -> FileBoxOptions<T> {
+        self.generations = keep;
+        self
+    }
+
+    /// Whether the box’s file is read and written through a memory map, as by
+    /// `FileBox::open_mapped`. This can’t be combined with `format`, `compressor`, `encoding` or
+    /// `recover`.
+    pub fn mapped(mut self, mapped: bool) -> FileBoxOptions<T> {
+        self.mapped = mapped;
+        self
+    }
+
+    /// Whether the box is opened with the value from its newest autosave if one was written after
+    /// the box itself, as by `FileBox::open_recovering`. This can’t be combined with `format`,
+    /// `compressor`, `encoding`, `mapped` or `read_only`, since recovering writes the box.
+    pub fn recover(mut self, recover: bool) -> FileBoxOptions<T> {
+        self.recover = recover;
+        self
+    }
+
+    /// How far writes of the box go to make sure its value survives the machine losing power.
+    /// See `FileBox::set_durability`.
+    pub fn durability(mut self, durability: Durability) -> FileBoxOptions<T> {
+        self.durability = durability;
+        self
+    }
+
+    /// Checks that the box holds the type tagged `tag`, and records the tag in its header, as
+    /// `FileBox::open_tagged` does.
+    pub fn type_tag(mut self, tag: &str) -> FileBoxOptions<T> {
+        self.type_tag = Some(tag.to_string());
+        self
+    }
+
+    /// Attaches `observer` to the box. Opening the box tells it that the box was loaded,
+    /// recovered or found to be damaged, and whether conflicting copies of it were found, which
+    /// takes a look through the box’s directory. See `BoxObserver`.
+    pub fn observer(mut self, observer: SharedObserver) -> FileBoxOptions<T> {
+        self.observer = Some(observer);
+        self
+    }
+}
+
+impl<T: Default> FileBoxOptions<T> {
+    /// Whether the box is created with its default value if its file doesn’t exist.
+    pub fn create(self, create: bool) -> FileBoxOptions<T> {
+        if create {
+            self.create_with(Default::default())
+        } else {
+            FileBoxOptions { initial: None, .. self }
+        }
+    }
+}
+
+impl<T> FileBoxOptions<T> where T: Decodable<CompactDecoder, IoError>
+                                 + Encodable<CompactEncoder, IoError> {
+    /// Writes the box with the given encoding, as `FileBox::set_encoding` does. The box’s file is
+    /// read with whatever encoding it was written with, as by `FileBox::open_encoded`. This can’t
+    /// be combined with `format`, `mapped` or `recover`, but can be with `compressor`.
+    pub fn encoding(mut self, encoding: Encoding) -> FileBoxOptions<T> {
+        self.codec = Some(Codec::new(encoding));
+        self
+    }
+}
+
+impl<'a, T> FileBoxOptions<T> where T: Decodable<CompactDecoder, IoError>
+                                     + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Opens the box at `p` with these options.
+    pub fn open(self, p: &Path) -> IoResult<FileBox<T>> {
+        let FileBoxOptions {
+            initial, truncate, lock, wait, read_only, compressor, format, codec, generations,
+            mapped, recover, durability, type_tag, observer
+        } = self;
+        if truncate && initial.is_none() {
+            return Err(IoError {
+                kind: io::InvalidInput,
+                desc: "a box can only be truncated if it is created",
+                detail: None,
+            });
+        }
+        // Each of these reads the file its own way, except that a box with an encoding of its own
+        // can have a compressor of its own too, so choosing more than one would leave the others
+        // ignored.
+        let ways = [("format", format.is_some()),
+                    ("compressor or encoding", compressor.is_some() || codec.is_some()),
+                    ("mapped", mapped),
+                    ("recover", recover)];
+        let chosen: Vec<&str> = ways.iter().filter(|&&(_, on)| on).map(|&(name, _)| name).collect();
+        if chosen.len() > 1 {
+            return Err(conflicting(chosen.connect(" and ").as_slice()));
+        }
+        if recover && read_only {
+            return Err(conflicting("recover and read_only"));
+        }
+        let lock = if lock { Some(try!(Lock::acquire(p, wait))) } else { None };
+        let mut b = match initial {
+            Some(val) if truncate || !p.exists() => {
+                // The format and encoding are in place before the box can first be written.
+                let mut b = match format {
+                    Some(format) => try!(FileBox::open_new_with_format(p, val, format)),
+                    None => {
+                        let c = compressor.unwrap_or_else(|| {
+                            box NoCompression as Box<Compressor + 'static>
+                        });
+                        try!(FileBox::open_new_compressed(p, val, c))
+                    }
+                };
+                b._codec = codec;
+                b
+            }
+            _ => {
+                let mut slot = None;
+                let res = match (format, compressor, codec) {
+                    (Some(format), _, _) => FileBox::open_with_format(p, format),
+                    (None, c, Some(codec)) => FileBox::open_with_codec(p, codec, c, read_only),
+                    (None, Some(c), None) => FileBox::open_compressed_with(p, c, read_only),
+                    (None, None, None) if read_only => FileBox::open_read_only(p),
+                    (None, None, None) if recover => {
+                        slot = try!(FileBox::<T>::newest_autosave(p));
+                        FileBox::open_recovering(p)
+                    }
+                    (None, None, None) if mapped => FileBox::open_mapped(p),
+                    (None, None, None) => FileBox::open(p),
+                };
+                let b = match res {
+                    Ok(b) => b,
+                    Err(e) => {
+                        match observer {
+                            Some(ref observer) if is_corrupt(&e) => observer.on_corruption(p, &e),
+                            _ => {}
+                        }
+                        return Err(e);
+                    }
+                };
+                match observer {
+                    Some(ref observer) => {
+                        observer.on_load(p, b.header());
+                        match slot {
+                            Some(ref slot) => observer.on_recovery(p, slot),
+                            None => {}
+                        }
+                        let copies = try!(conflict_copies(p));
+                        if !copies.is_empty() {
+                            observer.on_conflict(p, copies.as_slice());
+                        }
+                    }
+                    None => {}
+                }
+                b
+            }
+        };
+        b._lock = lock;
+        b._read_only = read_only;
+        b._generations = generations;
+        b._mapped = mapped;
+        b._durability = durability;
+        match type_tag {
+            Some(ref tag) => try!(b.set_type_tag(tag.as_slice())),
+            None => {}
+        }
+        b._box_observer = observer;
+        Ok(b)
+    }
+}
+
+fn conflicting(options: &str) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: "the options can’t be combined",
+        detail: Some(options.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::default::Default;
+    use std::io::{mod, fs, File};
+    use std::io::fs::PathExtensions;
+    use super::FileBoxOptions;
+    use super::super::{FileBox, Format, Json, Compressor, Deflate, Encoding, peek, open_header};
+    use super::super::journal::log_path;
+
+    #[test]
+    fn open_with_options() {
+        let path = Path::new("target/open_with_options");
+        let _ = fs::unlink(&path);
+        assert!(FileBoxOptions::<u32>::new().open(&path).is_err());
+        {
+            let mut b: FileBox<u32> = FileBoxOptions::new().create(true).lock(true)
+                                                           .open(&path).unwrap();
+            assert_eq!(*b, 0);
+            *b = 3;
+        }
+        let b: FileBox<u32> = FileBoxOptions::new().create_with(7).open(&path).unwrap();
+        assert_eq!(*b, 3);
+        drop(b);
+        FileBoxOptions::new().create_with(7u32).truncate(true).open(&path).unwrap();
+        assert_eq!(peek::<u32>(&path).unwrap(), 7);
+        assert!(FileBoxOptions::<u32>::new().truncate(true).open(&path).is_err());
+
+        let json = Path::new("target/open_with_options.json");
+        let compact: Json = Default::default();
+        let format = box compact as Box<Format<Vec<u8>> + 'static>;
+        FileBoxOptions::new().create_with(vec![1u8, 2]).format(format).open(&json).unwrap();
+        assert_eq!(File::open(&json).read_to_string().unwrap().as_slice(), "[1,2]");
+    }
+
+    #[test]
+    fn conflicting_options() {
+        let path = Path::new("target/conflicting_options");
+        FileBox::open_new(&path, 1u32).unwrap().close().unwrap();
+        let json: Json = Default::default();
+        let deflate = || box Deflate as Box<Compressor + 'static>;
+        let e = FileBoxOptions::new().format(box json as Box<Format<u32> + 'static>)
+                                     .compressor(deflate()).open(&path).err().unwrap();
+        assert_eq!(e.kind, io::InvalidInput);
+        let mapped = FileBoxOptions::<u32>::new().compressor(deflate()).mapped(true);
+        assert!(mapped.open(&path).is_err());
+        assert!(FileBoxOptions::<u32>::new().mapped(true).recover(true).open(&path).is_err());
+        assert!(FileBoxOptions::<u32>::new().recover(true).read_only(true).open(&path).is_err());
+        let varint = Encoding { varint: true, .. Default::default() };
+        let b: FileBox<u32> = FileBoxOptions::new().encoding(varint).compressor(deflate())
+                                                   .open(&path).unwrap();
+        b.close().unwrap();
+        assert_eq!(open_header(&path).unwrap().header.compressor.as_slice(), "deflate");
+    }
+    #[test]
+    fn read_only_with_compressor() {
+        let path = Path::new("target/read_only_with_compressor");
+        let mut x = FileBox::open_new(&path, 1u32).unwrap();
+        x.set_journaled(Some(10)).unwrap();
+        *x = 2;
+        x.save().unwrap();
+        x._write_on_drop = false;
+        drop(x);
+        let deflate = box Deflate as Box<Compressor + 'static>;
+        let b: FileBox<u32> = FileBoxOptions::new().read_only(true).compressor(deflate)
+                                                   .open(&path).unwrap();
+        assert_eq!(*b, 2);
+        drop(b);
+        // The save is still only in the log, since opening the box didn’t write it into the file.
+        assert!(log_path(&path).exists());
+        let varint = Encoding { varint: true, .. Default::default() };
+        let b: FileBox<u32> = FileBoxOptions::new().read_only(true).encoding(varint)
+                                                   .open(&path).unwrap();
+        assert_eq!(*b, 2);
+        drop(b);
+        assert!(log_path(&path).exists());
+    }
+}
@@ -0,0 +1,197 @@
+//! Making sure only one writer ever writes a box, even where locks can’t be relied on.
+
+use std::io::{mod, fs, IoError, IoResult};
+use std::io::fs::PathExtensions;
+
+use process;
+use super::{peek, write_value, now};
+
+/// The description of errors caused by a box being owned by another writer.
+static OWNED: &'static str = "the box is owned by another writer";
+
+/// Returns whether `e` is the error `Ownership::acquire` fails with when another writer owns the
+/// box, or the error writing an owned box fails with once another writer has taken it over.
+pub fn is_owned_elsewhere(e: &IoError) -> bool {
+    e.kind == io::ResourceUnavailable && e.desc == OWNED
+}
+
+/// Who owns a box, as recorded in its ownership file.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub struct OwnerRecord {
+    /// The id the writer chose for itself.
+    pub owner: String,
+    /// The name of the machine the writer runs on.
+    pub hostname: String,
+    /// When the writer took ownership, in seconds since the Unix epoch.
+    pub acquired_at: u64,
+}
+
+/// The right to be the only writer of a box, held until this is dropped or released.
+///
+/// `Lock` relies on `flock`, which isn’t honoured by every network file system and is lost when a
+/// container is restarted. Ownership is recorded in a file next to the box’s instead, which is
+/// claimed by linking a new file to its name, since that fails if it already exists, even over
+/// NFS. The file names an owner id chosen by the writer, rather than a process id, so that a
+/// writer restarted on another machine or in a new container can claim the box again under the
+/// same id. Ownership never lapses by itself: if a writer has gone for good, an administrator
+/// takes the box over with `force_take_ownership`.
+///
+/// A box opened with `FileBox::open_owned` checks that it is still owned before every write, so a
+/// writer whose box has been taken over can’t overwrite the new owner’s changes.
+pub struct Ownership {
+    path: Path,
+    file: Path,
+    record: OwnerRecord,
+}
+
+impl Ownership {
+    /// Takes ownership of the box at `p` for the writer with the given id, failing with an error
+    /// for which `is_owned_elsewhere` is true if another writer owns it. If the box is already
+    /// owned under this id, such as by this writer before it was restarted, it is claimed again.
+    pub fn acquire(p: &Path, owner: &str) -> IoResult<Ownership> {
+        let own = Ownership::new(p, owner);
+        match try!(Ownership::current(p)) {
+            Some(ref record) if record.owner == own.record.owner => {
+                try!(write_value(&own.file, &own.record));
+                return Ok(own);
+            }
+            Some(record) => return Err(owned_elsewhere(&record)),
+            None => {}
+        }
+
+        let mut name = own.file.filename().unwrap().to_vec();
+        name.push_all(format!(".{}-{}", process::id(), own.record.acquired_at).as_bytes());
+        let claim = own.file.with_filename(name);
+        try!(write_value(&claim, &own.record));
+        let linked = fs::link(&claim, &own.file);
+        try!(fs::unlink(&claim));
+        match linked {
+            Ok(()) => Ok(own),
+            Err(e) => match try!(Ownership::current(p)) {
+                Some(record) => Err(owned_elsewhere(&record)),
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Takes ownership of the box at `p` for the writer with the given id, whoever owns it now.
+    /// This is for recovering boxes whose owner has gone for good; if it hasn’t, its next write to
+    /// the box fails.
+    pub fn force_take_ownership(p: &Path, owner: &str) -> IoResult<Ownership> {
+        let own = Ownership::new(p, owner);
+        try!(write_value(&own.file, &own.record));
+        Ok(own)
+    }
+
+    /// Who owns the box at `p`, if anyone does.
+    pub fn current(p: &Path) -> IoResult<Option<OwnerRecord>> {
+        let file = owner_path(p);
+        if file.exists() {
+            Ok(Some(try!(peek(&file))))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Fails with an error for which `is_owned_elsewhere` is true unless the box is still owned
+    /// through this.
+    pub fn check(&self) -> IoResult<()> {
+        match try!(Ownership::current(&self.path)) {
+            Some(ref record) if *record == self.record => Ok(()),
+            Some(ref record) => Err(owned_elsewhere(record)),
+            None => Err(IoError {
+                kind: io::ResourceUnavailable,
+                desc: OWNED,
+                detail: Some("the ownership file has been removed".to_string()),
+            }),
+        }
+    }
+
+    /// The record of this ownership in the box’s ownership file.
+    pub fn record(&self) -> &OwnerRecord {
+        &self.record
+    }
+
+    /// The path of the box this is the ownership of.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Gives up ownership of the box, so that other writers can take it.
+    pub fn release(self) -> IoResult<()> {
+        try!(self.check());
+        fs::unlink(&self.file)
+    }
+
+    fn new(p: &Path, owner: &str) -> Ownership {
+        Ownership {
+            path: p.clone(),
+            file: owner_path(p),
+            record: OwnerRecord {
+                owner: owner.to_string(),
+                hostname: process::hostname(),
+                acquired_at: now(),
+            },
+        }
+    }
+}
+
+impl Drop for Ownership {
+    fn drop(&mut self) {
+        if self.check().is_ok() {
+            let _ = fs::unlink(&self.file);
+        }
+    }
+}
+
+/// The path of the ownership file of the box at `p`.
+fn owner_path(p: &Path) -> Path {
+    let mut name = p.filename().unwrap_or(b"filebox").to_vec();
+    name.push_all(b".writer");
+    p.with_filename(name)
+}
+
+fn owned_elsewhere(record: &OwnerRecord) -> IoError {
+    IoError {
+        kind: io::ResourceUnavailable,
+        desc: OWNED,
+        detail: Some(format!("owned by {} on {} since {}",
+                             record.owner, record.hostname, record.acquired_at)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::{Ownership, is_owned_elsewhere};
+    use super::super::FileBox;
+
+    #[test]
+    fn single_writer() {
+        let path = Path::new("target/single_writer");
+        FileBox::open_new(&path, vec![1u8]).unwrap();
+        let first = Ownership::acquire(&path, "first").unwrap();
+        assert!(is_owned_elsewhere(&Ownership::acquire(&path, "second").err().unwrap()));
+        let mut b: FileBox<Vec<u8>> = FileBox::open_owned(&path, first).unwrap();
+        b.push(2);
+        b.save().unwrap();
+
+        // An administrator takes the box over, and its old owner can no longer write it.
+        let second = Ownership::force_take_ownership(&path, "second").unwrap();
+        b.push(3);
+        assert!(is_owned_elsewhere(&b.save().err().unwrap()));
+        b.into_inner();
+        assert_eq!(Ownership::current(&path).unwrap().unwrap().owner, "second".to_string());
+        second.release().unwrap();
+        assert!(Ownership::current(&path).unwrap().is_none());
+    }
+    #[test]
+    fn ownership_of_another_box() {
+        let path = Path::new("target/ownership_of_another_box");
+        FileBox::open_new(&path, 1u8).unwrap();
+        let other = Path::new("target/ownership_of_another_box_2");
+        let ownership = Ownership::acquire(&other, "first").unwrap();
+        let e = FileBox::<u8>::open_owned(&path, ownership).err().unwrap();
+        assert_eq!(e.kind, io::InvalidInput);
+    }
+}
@@ -0,0 +1,57 @@
+//! Identifying the processes that files belong to.
+
+use libc;
+
+/// The id of the current process.
+pub fn id() -> u32 {
+    unsafe { libc::getpid() as u32 }
+}
+
+/// Returns whether a process with the given id is running. If this can’t be determined, the
+/// process is assumed to be running, since callers use this to decide whether it is safe to remove
+/// files that might belong to it.
+#[cfg(unix)]
+pub fn is_alive(pid: u32) -> bool {
+    if unsafe { libc::kill(pid as libc::pid_t, 0) } == 0 {
+        return true;
+    }
+    // `EPERM` means that the process exists but belongs to someone else.
+    ::std::os::errno() != libc::ESRCH as int
+}
+
+#[cfg(not(unix))]
+pub fn is_alive(_pid: u32) -> bool {
+    true
+}
+
+/// The name of the machine this process runs on, or `unknown` if it can’t be found out.
+#[cfg(unix)]
+pub fn hostname() -> String {
+    let mut buf = [0u8, ..256];
+    let name = buf.as_mut_ptr() as *mut libc::c_char;
+    if unsafe { gethostname(name, buf.len() as libc::size_t) } != 0 {
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(buf.slice_to(len)).into_string()
+}
+
+#[cfg(not(unix))]
+pub fn hostname() -> String {
+    "unknown".to_string()
+}
+
+#[cfg(unix)]
+extern {
+    fn gethostname(name: *mut libc::c_char, len: libc::size_t) -> libc::c_int;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{id, is_alive};
+
+    #[test]
+    fn current_process_is_alive() {
+        assert!(is_alive(id()));
+    }
+}
@@ -0,0 +1,248 @@
+//! Reporting progress while box files are read and written, and cancelling the reads and writes.
+
+use std::io::{mod, IoError, IoResult};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, SeqCst};
+use std::time::Duration;
+
+use clock::{Clock, SystemClock};
+
+/// The number of bytes read or written between progress reports.
+pub static CHUNK_SIZE: uint = 64 * 1024;
+
+/// Receives reports of how much of a box file has been read or written, for example to drive a
+/// progress bar.
+pub trait Progress {
+    /// Called after each chunk of the file is processed, with the number of bytes processed so far
+    /// and the total number of bytes to process.
+    fn report(&mut self, done: u64, total: u64);
+}
+
+/// A flag that can be set from any task to cancel reading or writing a box.
+///
+/// Clones of a token share the same flag. A cancelled read returns an error for which
+/// `is_cancelled` is true; a cancelled write stops before the file is replaced, leaving its old
+/// contents intact.
+#[deriving(Clone)]
+pub struct CancelToken {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    /// Creates a token that hasn’t been cancelled.
+    pub fn new() -> CancelToken {
+        CancelToken {
+            flag: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Cancels every read or write watching this token. Operations that have already finished are
+    /// not affected.
+    pub fn cancel(&self) {
+        self.flag.store(true, SeqCst);
+    }
+
+    /// Returns whether `cancel` has been called on this token or any of its clones.
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(SeqCst)
+    }
+}
+
+static CANCELLED: &'static str = "the operation was cancelled";
+
+/// Returns whether `e` is the error returned by a read or write that was cancelled through a
+/// `CancelToken`.
+pub fn is_cancelled(e: &IoError) -> bool {
+    e.kind == io::OtherIoError && e.desc == CANCELLED
+}
+
+/// Returns an error if `cancel` has been cancelled.
+pub fn check(cancel: Option<&CancelToken>) -> IoResult<()> {
+    match cancel {
+        Some(c) if c.is_cancelled() => Err(IoError {
+            kind: io::OtherIoError,
+            desc: CANCELLED,
+            detail: None,
+        }),
+        _ => Ok(()),
+    }
+}
+
+static TIMED_OUT: &'static str = "the operation didn’t finish in time";
+
+/// Returns whether `e` is the error returned by a read through a `DeadlineReader` whose time ran
+/// out.
+pub fn is_timed_out(e: &IoError) -> bool {
+    e.kind == io::TimedOut && e.desc == TIMED_OUT
+}
+
+/// How many reads a `DeadlineReader` lets through between looking at the clock.
+static READS_PER_CHECK: uint = 1024;
+
+/// A reader that fails once a time limit has passed.
+///
+/// Decoders read values a few bytes at a time, so decoding from a `DeadlineReader` stops soon
+/// after the limit passes, rather than running for as long as an unexpectedly large value takes.
+/// The clock is only looked at every so many reads, so the reader can run slightly over.
+pub struct DeadlineReader<R> {
+    inner: R,
+    clock: Box<Clock + 'static>,
+    deadline: u64,
+    reads: uint,
+}
+
+impl<R: Reader> DeadlineReader<R> {
+    /// Wraps `inner` so that reading from it fails once `limit` has passed from now.
+    pub fn new(inner: R, limit: Duration) -> DeadlineReader<R> {
+        DeadlineReader::with_clock(inner, limit, box SystemClock as Box<Clock + 'static>)
+    }
+
+    /// Like `new`, but the limit is timed with the given clock.
+    pub fn with_clock(inner: R, limit: Duration, clock: Box<Clock + 'static>)
+                      -> DeadlineReader<R> {
+        let deadline = clock.now_ms() + limit.num_milliseconds() as u64;
+        DeadlineReader {
+            inner: inner,
+            clock: clock,
+            deadline: deadline,
+            reads: 0,
+        }
+    }
+
+    fn check(&mut self) -> IoResult<()> {
+        self.reads += 1;
+        if self.reads % READS_PER_CHECK == 1 && self.clock.now_ms() >= self.deadline {
+            return Err(IoError {
+                kind: io::TimedOut,
+                desc: TIMED_OUT,
+                detail: None,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<R: Reader> Reader for DeadlineReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        try!(self.check());
+        self.inner.read(buf)
+    }
+}
+
+// bincode only decodes from buffered readers.
+impl<R: Buffer> Buffer for DeadlineReader<R> {
+    fn fill_buf<'a>(&'a mut self) -> IoResult<&'a [u8]> {
+        try!(self.check());
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: uint) {
+        self.inner.consume(amt)
+    }
+}
+
+/// Reads the rest of `r`, which is expected to hold `total` bytes.
+pub fn read_all(r: &mut Reader, total: u64, mut progress: Option<&mut Progress>,
+                cancel: Option<&CancelToken>) -> IoResult<Vec<u8>> {
+    let mut buf = Vec::with_capacity(total as uint);
+    loop {
+        try!(check(cancel));
+        match r.push(CHUNK_SIZE, &mut buf) {
+            Ok(_) => {}
+            Err(ref e) if e.kind == io::EndOfFile => break,
+            Err(e) => return Err(e),
+        }
+        match progress {
+            Some(ref mut p) => p.report(buf.len() as u64, total),
+            None => {}
+        }
+    }
+    Ok(buf)
+}
+
+/// Writes all of `bytes` to `w`.
+pub fn write_all(w: &mut Writer, bytes: &[u8], mut progress: Option<&mut Progress>,
+                 cancel: Option<&CancelToken>) -> IoResult<()> {
+    let total = bytes.len() as u64;
+    let mut done = 0;
+    for chunk in bytes.chunks(CHUNK_SIZE) {
+        try!(check(cancel));
+        try!(w.write(chunk));
+        done += chunk.len() as u64;
+        match progress {
+            Some(ref mut p) => p.report(done, total),
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{MemReader, MemWriter};
+    use std::time::Duration;
+    use clock::{Clock, ManualClock};
+    use super::{Progress, CancelToken, CHUNK_SIZE, read_all, write_all, is_cancelled};
+    use super::{DeadlineReader, READS_PER_CHECK, is_timed_out};
+
+    struct Log {
+        reports: Vec<(u64, u64)>,
+    }
+
+    impl Progress for Log {
+        fn report(&mut self, done: u64, total: u64) {
+            self.reports.push((done, total));
+        }
+    }
+
+    #[test]
+    fn reports_each_chunk() {
+        let bytes = Vec::from_elem(CHUNK_SIZE * 2 + 1, 7u8);
+        let total = bytes.len() as u64;
+        let expected = vec![(CHUNK_SIZE as u64, total), (CHUNK_SIZE as u64 * 2, total),
+                            (total, total)];
+
+        let mut log = Log { reports: Vec::new() };
+        let mut w = MemWriter::new();
+        write_all(&mut w, bytes.as_slice(), Some(&mut log as &mut Progress), None).unwrap();
+        assert_eq!(log.reports, expected);
+
+        let mut log = Log { reports: Vec::new() };
+        let mut r = MemReader::new(w.unwrap());
+        let read = read_all(&mut r, total, Some(&mut log as &mut Progress), None).unwrap();
+        assert_eq!(read, bytes);
+        assert_eq!(log.reports, expected);
+    }
+
+    #[test]
+    fn cancel() {
+        let token = CancelToken::new();
+        token.clone().cancel();
+        let mut w = MemWriter::new();
+        assert!(is_cancelled(&write_all(&mut w, b"abc", None, Some(&token)).unwrap_err()));
+        assert!(w.get_ref().is_empty());
+        let mut r = MemReader::new(b"abc".to_vec());
+        assert!(is_cancelled(&read_all(&mut r, 3, None, Some(&token)).unwrap_err()));
+    }
+
+    #[test]
+    fn deadline() {
+        let clock = ManualClock::new(0);
+        let bytes = ::bincode::encode(&Vec::from_elem(5000, 1u8)).unwrap();
+        let mut r = DeadlineReader::with_clock(MemReader::new(bytes.clone()), Duration::seconds(1),
+                                               box clock.clone() as Box<Clock + 'static>);
+        assert_eq!(r.read_exact(10).unwrap().len(), 10);
+        clock.advance(Duration::seconds(1));
+        let mut res = Ok(0);
+        for _ in range(0, READS_PER_CHECK) {
+            res = r.read_u8();
+            if res.is_err() {
+                break;
+            }
+        }
+        assert!(is_timed_out(&res.unwrap_err()));
+
+        let mut r = DeadlineReader::new(MemReader::new(bytes), Duration::minutes(1));
+        assert_eq!(::bincode::decode_from::<_, Vec<u8>>(&mut r).unwrap().len(), 5000);
+    }
+}
@@ -0,0 +1,203 @@
+//! Moving unreadable box files out of the way without losing them.
+
+use std::default::Default;
+use std::io::{mod, fs, File, IoError, IoResult, MemWriter};
+use std::io::fs::PathExtensions;
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use atomic::write_atomic;
+use compress::{Compressor, NoCompression};
+use encoding::CompactDecoder;
+use layout;
+use schema::SchemaTooNew;
+use super::{FileBox, UNKNOWN_COMPRESSOR, now};
+
+/// A box file that was moved into quarantine because it couldn’t be read.
+#[deriving(Show)]
+pub struct Quarantined {
+    /// Where the file was moved to.
+    pub path: Path,
+    /// Why it couldn’t be read.
+    pub error: IoError,
+}
+
+/// Returns whether `e`, an error from opening a box, means that the file itself is unreadable,
+/// because it is damaged or holds a different type, rather than that it couldn’t be reached or
+/// needs something the program doesn’t have, such as a newer schema or another compressor.
+pub fn is_unreadable(e: &IoError) -> bool {
+    match e.kind {
+        io::InvalidInput => {
+            SchemaTooNew::from_error(e).is_none() && e.desc != UNKNOWN_COMPRESSOR
+        }
+        io::EndOfFile => true,
+        _ => false,
+    }
+}
+
+/// Moves the box file at `p`, along with its header file if it has one, into a `quarantine`
+/// directory next to it, with a file recording `e` as the reason beside it, and returns where it
+/// was moved to. Files are named after the box and the time they were quarantined, so a box
+/// quarantined more than once keeps every bad file.
+pub fn quarantine(p: &Path, e: &IoError) -> IoResult<Path> {
+    let dir = p.dir_path().join("quarantine");
+    if !dir.exists() {
+        try!(fs::mkdir(&dir, io::USER_RWX));
+    }
+    let mut name = p.filename().unwrap_or(b"filebox").to_vec();
+    name.push_all(format!(".{}", now()).as_bytes());
+    let mut to = dir.join(name.as_slice());
+    let mut n = 1u;
+    while to.exists() {
+        let mut numbered = name.clone();
+        numbered.push_all(format!("-{}", n).as_bytes());
+        to = dir.join(numbered.as_slice());
+        n += 1;
+    }
+    let reason = format!("{}: {}\n", p.display(), e);
+    try!(write_atomic(&to.with_extension(reason_extension(&to)), reason.as_bytes()));
+    if layout::meta_path(p).exists() {
+        try!(fs::rename(&layout::meta_path(p), &layout::meta_path(&to)));
+    }
+    try!(fs::rename(p, &to));
+    Ok(to)
+}
+
+/// Reads the file at `p` as it is, header and all, for looking into box files that can’t be
+/// opened, such as in the `recover` function given to `FileBox::open_or_else`.
+pub fn raw_bytes(p: &Path) -> IoResult<Vec<u8>> {
+    File::open(p).read_to_end()
+}
+
+/// Copies the file at `p` to a file beside it with `.corrupt` added to its name, numbered if one
+/// is already there, and returns the path of the copy. Unlike `quarantine`, this leaves the file
+/// where it is, for recovery that writes over it.
+pub fn preserve_corrupt(p: &Path) -> IoResult<Path> {
+    let mut name = p.filename().unwrap_or(b"filebox").to_vec();
+    name.push_all(b".corrupt");
+    let mut to = p.with_filename(name.as_slice());
+    let mut n = 1u;
+    while to.exists() {
+        let mut numbered = name.clone();
+        numbered.push_all(format!("-{}", n).as_bytes());
+        to = p.with_filename(numbered.as_slice());
+        n += 1;
+    }
+    try!(fs::copy(p, &to));
+    Ok(to)
+}
+
+/// The extension of the reason file of the quarantined file at `to`, which keeps the extension
+/// of the file itself.
+fn reason_extension(to: &Path) -> String {
+    match to.extension_str() {
+        Some(ext) => format!("{}.reason", ext),
+        None => "reason".to_string(),
+    }
+}
+
+impl<'a, T> FileBox<T> where T: Decodable<CompactDecoder, IoError>
+                              + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Opens the box at `p` as `open` does, but if its file can’t be read because it is damaged or
+    /// holds a different type (see `is_unreadable`), calls `recover` with the error and the
+    /// file’s raw bytes, as `raw_bytes` reads them, and opens the box with the value `recover`
+    /// returns instead. That value replaces the file the next time the box is written, so
+    /// `recover` should keep the bytes it wants to hold on to, such as with `preserve_corrupt`.
+    /// Errors from `recover`, and errors other than unreadable files, are returned as they are.
+    pub fn open_or_else(p: &Path, recover: |IoError, IoResult<Vec<u8>>| -> IoResult<T>)
+                        -> IoResult<FileBox<T>> {
+        match FileBox::open(p) {
+            Ok(b) => Ok(b),
+            Err(e) => {
+                if !is_unreadable(&e) {
+                    return Err(e);
+                }
+                let val = try!(recover(e, raw_bytes(p)));
+                let c = box NoCompression as Box<Compressor + 'static>;
+                let mut b = try!(FileBox::with_value(p, val, c));
+                b._dirty = true;
+                Ok(b)
+            }
+        }
+    }
+}
+
+impl<'a, T> FileBox<T> where T: Decodable<CompactDecoder, IoError>
+                              + Encodable<EncoderWriter<'a, MemWriter>, IoError>
+                              + Default {
+    /// Like `open_or_new`, but if the box’s file can’t be read because it is damaged or holds a
+    /// different type (see `is_unreadable`), it is moved into quarantine with `quarantine` and the
+    /// box starts out with its default value instead, so one bad file doesn’t stop a program from
+    /// starting. What was quarantined, if anything, is returned along with the box. Other errors,
+    /// such as the file being too new to read, are returned as they are.
+    pub fn open_or_quarantine(p: &Path) -> IoResult<(FileBox<T>, Option<Quarantined>)> {
+        match FileBox::open_or_new(p) {
+            Ok(b) => Ok((b, None)),
+            Err(e) => {
+                if !is_unreadable(&e) {
+                    return Err(e);
+                }
+                let to = try!(quarantine(p, &e));
+                let b = try!(FileBox::new(p));
+                Ok((b, Some(Quarantined {
+                    path: to,
+                    error: e,
+                })))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{fs, File};
+    use std::io::fs::PathExtensions;
+    use super::preserve_corrupt;
+    use super::super::{FileBox, write_value};
+
+    #[test]
+    fn quarantine_bad_files() {
+        let dir = Path::new("target/quarantine_bad_files");
+        let _ = fs::rmdir_recursive(&dir);
+        fs::mkdir(&dir, ::std::io::USER_RWX).unwrap();
+        let path = dir.join("settings.box");
+        write_value(&path, &"a string".to_string()).unwrap();
+        let mut bytes = File::open(&path).read_to_end().unwrap();
+        let last = bytes.len() - 1;
+        bytes.as_mut_slice()[last] = b'!';
+        File::create(&path).write(bytes.as_slice()).unwrap();
+
+        let (b, quarantined) = FileBox::<Vec<u32>>::open_or_quarantine(&path).unwrap();
+        assert!(b.is_empty());
+        let quarantined = quarantined.unwrap();
+        assert_eq!(File::open(&quarantined.path).read_to_end().unwrap(), bytes);
+        assert!(quarantined.path.with_extension(format!("{}.reason",
+                    quarantined.path.extension_str().unwrap())).exists());
+        drop(b);
+        let (_, quarantined) = FileBox::<Vec<u32>>::open_or_quarantine(&path).unwrap();
+        assert!(quarantined.is_none());
+    }
+
+    #[test]
+    fn recover_unreadable_files() {
+        let path = Path::new("target/recover_unreadable_files");
+        let _ = fs::unlink(&Path::new("target/recover_unreadable_files.corrupt"));
+        write_value(&path, &vec![1u32, 2]).unwrap();
+        let mut bytes = File::open(&path).read_to_end().unwrap();
+        let last = bytes.len() - 1;
+        bytes.as_mut_slice()[last] = 9;
+        File::create(&path).write(bytes.as_slice()).unwrap();
+        let b = FileBox::<Vec<u32>>::open_or_else(&path, |e, raw| {
+            assert!(super::is_unreadable(&e));
+            assert_eq!(raw.unwrap(), bytes);
+            assert_eq!(preserve_corrupt(&path).unwrap(),
+                       Path::new("target/recover_unreadable_files.corrupt"));
+            Ok(vec![7])
+        }).unwrap();
+        assert_eq!(*b, vec![7]);
+        drop(b);
+        assert_eq!(FileBox::<Vec<u32>>::open(&path).unwrap().len(), 1);
+        let corrupt = Path::new("target/recover_unreadable_files.corrupt");
+        assert_eq!(File::open(&corrupt).read_to_end().unwrap(), bytes);
+    }
+}
@@ -0,0 +1,89 @@
+//! Refusing to write boxes that have grown too large.
+
+use std::io::{mod, IoError, IoResult};
+
+/// The description of errors caused by a box growing past the largest size it may be written at.
+pub static QUOTA_EXCEEDED: &'static str = "the box is larger than it is allowed to be written";
+
+/// Why a box wasn’t written: its encoded value was larger than the limit set for it, with
+/// `FileBox::set_max_size` or `Store::set_max_box_size`.
+///
+/// A value that grows without bound, such as a collection a bug keeps adding to, would otherwise
+/// be written however large it got, filling the disk. Writes past the limit fail with an `IoError`
+/// that this can be recovered from with `from_error`, before anything has been written.
+#[deriving(Clone, PartialEq, Show)]
+pub struct QuotaExceeded {
+    /// The size of the encoded value, in bytes.
+    pub size: u64,
+    /// The largest size the box may be written at, in bytes.
+    pub limit: u64,
+}
+
+impl QuotaExceeded {
+    /// The error the write fails with.
+    pub fn to_error(&self) -> IoError {
+        IoError {
+            kind: io::OtherIoError,
+            desc: QUOTA_EXCEEDED,
+            detail: Some(format!("{} bytes, limit {}", self.size, self.limit)),
+        }
+    }
+
+    /// Returns why the write failed if `e` was caused by the box being too large.
+    pub fn from_error(e: &IoError) -> Option<QuotaExceeded> {
+        if e.desc != QUOTA_EXCEEDED {
+            return None;
+        }
+        let detail = match e.detail {
+            Some(ref detail) => detail.as_slice(),
+            None => return None,
+        };
+        let mut counts = detail.split_str(" bytes, limit ");
+        let size = counts.next().and_then(from_str);
+        let limit = counts.next().and_then(from_str);
+        match (size, limit) {
+            (Some(size), Some(limit)) => Some(QuotaExceeded {
+                size: size,
+                limit: limit,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Fails with a `QuotaExceeded` error if `size` is larger than `limit`, if there is one.
+pub fn check(limit: Option<u64>, size: uint) -> IoResult<()> {
+    match limit {
+        Some(limit) if size as u64 > limit => Err(QuotaExceeded {
+            size: size as u64,
+            limit: limit,
+        }.to_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QuotaExceeded;
+    use super::super::{FileBox, Store};
+
+    #[test]
+    fn refuse_oversized_boxes() {
+        let path = Path::new("target/refuse_oversized_boxes");
+        let mut b = FileBox::open_new(&path, vec![0u8, ..10]).unwrap();
+        b.set_max_size(Some(100));
+        b.save().unwrap();
+        b.push_all(&[0, ..200]);
+        let e = b.save().err().unwrap();
+        assert_eq!(QuotaExceeded::from_error(&e).unwrap().limit, 100);
+        assert_eq!(FileBox::<Vec<u8>>::open(&path).unwrap().len(), 10);
+        b.set_max_size(None);
+        b.save().unwrap();
+
+        let mut store = Store::new(&Path::new("target/refuse_oversized_boxes_store")).unwrap();
+        store.set_max_box_size(Some(100));
+        let ns = store.namespace("app").unwrap();
+        assert!(ns.put("big", &vec![0u8, ..200]).is_err());
+        assert_eq!(ns.open_new("small", 1u32).unwrap().max_size(), Some(100));
+    }
+}
@@ -0,0 +1,106 @@
+//! Boxes whose files can’t be written, such as ones on read-only filesystems.
+
+use std::io::{mod, IoError, IoResult};
+use libc;
+
+/// Called with the path of a read-only box when it is dropped with changes that can’t be written.
+pub type ReadOnlyHook = fn(&Path);
+
+static W_OK: libc::c_int = 2;
+
+/// Returns whether the box at `p` could be written, which takes being able to create files in its
+/// directory. This is false on read-only filesystems, such as those of live CDs and immutable
+/// container images, and in directories the current user may not write to.
+pub fn is_writable(p: &Path) -> IoResult<bool> {
+    let dir = p.dir_path();
+    if dir.with_c_str(|path| unsafe { libc::access(path, W_OK) }) == 0 {
+        return Ok(true);
+    }
+    let errno = ::std::os::errno() as libc::c_int;
+    if errno == libc::EROFS || errno == libc::EACCES || errno == libc::EPERM {
+        Ok(false)
+    } else {
+        Err(IoError::from_errno(errno as uint, false))
+    }
+}
+
+/// The error writing a read-only box fails with.
+pub fn error(p: &Path) -> IoError {
+    IoError {
+        kind: io::PermissionDenied,
+        desc: "the box is read-only",
+        detail: Some(p.display().to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, INIT_ATOMIC_BOOL, SeqCst};
+    use std::io::{fs, File};
+    use std::io::fs::PathExtensions;
+    use super::is_writable;
+    use super::super::{FileBox, peek};
+    use super::super::journal::log_path;
+
+    static WARNED: AtomicBool = INIT_ATOMIC_BOOL;
+
+    fn warn(_: &Path) {
+        WARNED.store(true, SeqCst);
+    }
+
+    #[test]
+    fn read_only_boxes() {
+        let path = Path::new("target/read_only_boxes");
+        assert!(is_writable(&path).unwrap());
+        FileBox::open_new(&path, 1u32).unwrap().close().unwrap();
+        let mut b = FileBox::<u32>::open_or_read_only(&path).unwrap();
+        assert!(!b.is_read_only());
+        b.set_read_only(true);
+        b.set_read_only_hook(warn);
+        *b = 2;
+        assert!(b.save().is_err());
+        drop(b);
+        assert!(WARNED.load(SeqCst));
+        assert_eq!(peek::<u32>(&path).unwrap(), 1);
+    }
+
+    #[test]
+    fn open_read_only() {
+        let path = Path::new("target/open_read_only");
+        FileBox::open_new(&path, vec![1u8]).unwrap().close().unwrap();
+        let before = fs::stat(&path).unwrap();
+        {
+            let mut b = FileBox::<Vec<u8>>::open_read_only(&path).unwrap();
+            assert!(b.is_read_only());
+            assert_eq!(*b, vec![1]);
+            b.push(2);
+        }
+        assert_eq!(peek::<Vec<u8>>(&path).unwrap(), vec![1]);
+        assert_eq!(fs::stat(&path).unwrap().modified, before.modified);
+    }
+
+    #[test]
+    fn open_read_only_without_writing() {
+        let path = Path::new("target/open_read_only_without_writing");
+        let mut b = FileBox::open_new(&path, 1u32).unwrap();
+        b.set_journaled(Some(10)).unwrap();
+        b.save().unwrap();
+        *b = 2;
+        b.save().unwrap();
+        b._write_on_drop = false;
+        drop(b);
+        // A temporary file left by a process that is no longer running, as no process can have an
+        // id this large.
+        let temp = Path::new("target/open_read_only_without_writing.2147483646.tmp");
+        File::create(&temp).unwrap();
+
+        let b = FileBox::<u32>::open_read_only(&path).unwrap();
+        assert_eq!(*b, 2);
+        // The saves are still only in the log, and the temporary file is where it was.
+        assert!(log_path(&path).exists());
+        assert!(temp.exists());
+        assert_eq!(peek::<u32>(&path).unwrap(), 1);
+        drop(b);
+        fs::unlink(&temp).unwrap();
+    }
+}
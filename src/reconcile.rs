@@ -0,0 +1,124 @@
+//! Reconciling two copies of the same box.
+
+use std::io::{mod, fs, File, IoError, IoResult, MemWriter};
+use std::io::fs::PathExtensions;
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use atomic::write_atomic;
+use encoding::CompactDecoder;
+use super::{peek, write_value, file_content_hash, open_header};
+
+/// How `sync` decides what both copies should contain when they differ.
+pub enum SyncStrategy<'a, T> {
+    /// The copy that was modified most recently replaces the other one.
+    NewestWins,
+    /// The copy that has been saved more times, as counted in the headers of the files, replaces
+    /// the other one. Unlike `NewestWins`, this doesn’t depend on the clocks of the machines the
+    /// copies were written on agreeing. Copies saved the same number of times are settled as
+    /// `NewestWins` settles them.
+    GenerationWins,
+    /// Both copies are replaced by the result of merging their values. The first argument is the
+    /// value stored at the first path.
+    Merge(|T, T|: 'a -> T),
+}
+
+/// Makes the boxes stored at `a` and `b` hold the same value. If only one of the files exists, it
+/// is copied to the other path; if both exist and differ, `strategy` decides the outcome. Every
+/// file that is changed is replaced atomically.
+pub fn sync<'a, 'b, T>(a: &Path, b: &Path, strategy: SyncStrategy<'b, T>) -> IoResult<()>
+        where T: Decodable<CompactDecoder, IoError>
+               + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    match (a.exists(), b.exists()) {
+        (false, false) => return Err(IoError {
+            kind: io::FileNotFound,
+            desc: "neither copy of the box exists",
+            detail: None,
+        }),
+        (true, false) => return copy(a, b),
+        (false, true) => return copy(b, a),
+        (true, true) => {}
+    }
+    if try!(file_content_hash(a)) == try!(file_content_hash(b)) {
+        return Ok(());
+    }
+    match strategy {
+        NewestWins => newest_wins(a, b),
+        GenerationWins => {
+            let saves_a = try!(open_header(a)).header.saves;
+            let saves_b = try!(open_header(b)).header.saves;
+            if saves_a > saves_b {
+                copy(a, b)
+            } else if saves_b > saves_a {
+                copy(b, a)
+            } else {
+                newest_wins(a, b)
+            }
+        }
+        Merge(merge) => {
+            let merged = merge(try!(peek(a)), try!(peek(b)));
+            try!(write_value(a, &merged));
+            write_value(b, &merged)
+        }
+    }
+}
+
+/// Replaces whichever of `a` and `b` was modified less recently with the other.
+fn newest_wins(a: &Path, b: &Path) -> IoResult<()> {
+    if try!(fs::stat(a)).modified >= try!(fs::stat(b)).modified {
+        copy(a, b)
+    } else {
+        copy(b, a)
+    }
+}
+
+/// Atomically replaces the file at `to` with a copy of the file at `from`.
+fn copy(from: &Path, to: &Path) -> IoResult<()> {
+    let bytes = try!(File::open_mode(from, io::Open, io::Read).and_then(|mut f| f.read_to_end()));
+    write_atomic(to, bytes.as_slice())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sync, NewestWins, GenerationWins, Merge};
+    use super::super::{peek, write_value};
+
+    #[test]
+    fn sync_copies_missing() {
+        let a = Path::new("target/sync_copies_missing_a");
+        let b = Path::new("target/sync_copies_missing_b");
+        let _ = ::std::io::fs::unlink(&b);
+        write_value(&a, &4i).unwrap();
+        sync::<int>(&a, &b, NewestWins).unwrap();
+        assert_eq!(peek::<int>(&b).unwrap(), 4);
+    }
+
+    #[test]
+    fn sync_merge() {
+        let a = Path::new("target/sync_merge_a");
+        let b = Path::new("target/sync_merge_b");
+        write_value(&a, &vec![1i, 2]).unwrap();
+        write_value(&b, &vec![3i]).unwrap();
+        sync(&a, &b, Merge(|mut x: Vec<int>, y: Vec<int>| {
+            x.push_all(y.as_slice());
+            x
+        })).unwrap();
+        assert_eq!(peek::<Vec<int>>(&a).unwrap(), vec![1, 2, 3]);
+        assert_eq!(peek::<Vec<int>>(&b).unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn sync_generation_wins() {
+        let a = Path::new("target/sync_generation_wins_a");
+        let b = Path::new("target/sync_generation_wins_b");
+        let _ = ::std::io::fs::unlink(&a);
+        let _ = ::std::io::fs::unlink(&b);
+        for i in range(0i, 3) {
+            write_value(&a, &i).unwrap();
+        }
+        // Written last, but saved fewer times.
+        write_value(&b, &10i).unwrap();
+        sync::<int>(&a, &b, GenerationWins).unwrap();
+        assert_eq!(peek::<int>(&b).unwrap(), 2);
+    }
+}
@@ -0,0 +1,90 @@
+//! Keeping sensitive values out of diagnostics output.
+
+use std::fmt::{mod, Show, Formatter};
+use serialize::{Decodable, Decoder, Encodable, Encoder};
+
+use memory::HeapSize;
+
+/// A value that is stored as it is, but never shown.
+///
+/// Fields holding tokens, passwords or email addresses can be wrapped in this to mark them as
+/// sensitive. They are encoded exactly like the value they wrap, so wrapping a field doesn’t
+/// change the files it is stored in, but their `Show` output is masked, so deriving `Show` for the
+/// type holding them and calling `FileBox::redacted_debug` never reveals them.
+#[deriving(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Sensitive<T>(pub T);
+
+impl<T> Sensitive<T> {
+    /// Unwraps the value, which can then be shown.
+    pub fn into_inner(self) -> T {
+        let Sensitive(val) = self;
+        val
+    }
+}
+
+impl<T> Deref<T> for Sensitive<T> {
+    fn deref(&self) -> &T {
+        let Sensitive(ref val) = *self;
+        val
+    }
+}
+
+impl<T> DerefMut<T> for Sensitive<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        let Sensitive(ref mut val) = *self;
+        val
+    }
+}
+
+impl<T> Show for Sensitive<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        "<redacted>".fmt(f)
+    }
+}
+
+impl<E, S: Encoder<E>, T: Encodable<S, E>> Encodable<S, E> for Sensitive<T> {
+    fn encode(&self, s: &mut S) -> Result<(), E> {
+        (**self).encode(s)
+    }
+}
+
+impl<E, D: Decoder<E>, T: Decodable<D, E>> Decodable<D, E> for Sensitive<T> {
+    fn decode(d: &mut D) -> Result<Sensitive<T>, E> {
+        Decodable::decode(d).map(Sensitive)
+    }
+}
+
+impl<T: HeapSize> HeapSize for Sensitive<T> {
+    fn heap_size(&self) -> uint {
+        (**self).heap_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bincode;
+    use super::Sensitive;
+    use super::super::FileBox;
+
+    #[deriving(Show, Encodable, Decodable)]
+    struct Account {
+        name: String,
+        token: Sensitive<String>,
+    }
+
+    #[test]
+    fn redact_sensitive_fields() {
+        let account = Account {
+            name: "alice".to_string(),
+            token: Sensitive("hunter2".to_string()),
+        };
+        assert_eq!(bincode::encode(&account).unwrap(),
+                   bincode::encode(&("alice".to_string(), "hunter2".to_string())).unwrap());
+        let b = FileBox::open_new(&Path::new("target/redact_sensitive_fields"), account).unwrap();
+        let shown = b.redacted_debug();
+        assert!(shown.as_slice().contains("alice"));
+        assert!(shown.as_slice().contains("<redacted>"));
+        assert!(!shown.as_slice().contains("hunter2"));
+        assert_eq!(b.token.as_slice(), "hunter2");
+    }
+}
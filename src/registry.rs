@@ -0,0 +1,393 @@
+//! Declaring the types of an application’s boxes in one place.
+
+use std::collections::{HashMap, TreeMap};
+use std::default::Default;
+use std::io::{mod, IoError, IoResult, MemWriter};
+use std::io::fs::PathExtensions;
+use serialize::{json, Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use atomic::write_atomic;
+use compress;
+use describe::{Describer, describe};
+use encoding::CompactDecoder;
+use header::Header;
+use layout;
+use schema::SchemaTooNew;
+use store::Namespace;
+use super::{FileBox, check_bincode, read_box};
+
+/// Turns the payload of one version of a type into the payload of the next version.
+pub type Migration = fn(Vec<u8>) -> IoResult<Vec<u8>>;
+
+struct Schema {
+    tag: String,
+    migrations: Vec<Migration>,
+    description: Option<json::Json>,
+}
+
+/// The boxes an application keeps in a namespace, along with the types they hold.
+///
+/// Every box is registered under its name with a type tag and a chain of migrations, and is then
+/// opened through the registry by name. Boxes written through a registry record the tag and
+/// version of their type in their header; opening a box checks the tag and migrates older
+/// payloads to the current version before decoding them. Files that have no tag, like those
+/// written before the box was registered, are taken to be at the version recorded in their
+/// header, which is 0 unless it was set.
+pub struct Registry {
+    ns: Namespace,
+    schemas: HashMap<String, Schema>,
+}
+
+impl Registry {
+    /// Creates a registry for the boxes in the given namespace, with no boxes registered.
+    pub fn new(ns: Namespace) -> Registry {
+        Registry {
+            ns: ns,
+            schemas: HashMap::new(),
+        }
+    }
+
+    /// The namespace the registered boxes are stored in.
+    pub fn namespace(&self) -> &Namespace {
+        &self.ns
+    }
+
+    /// Registers the box with the given name as holding the type with the given tag. Element `i`
+    /// of `migrations` migrates payloads of version `i` to version `i + 1`, so the current
+    /// version of the type is the number of migrations.
+    ///
+    /// Panics if a box with the same name has already been registered.
+    pub fn register(&mut self, name: &str, tag: &str, migrations: Vec<Migration>) {
+        let schema = Schema {
+            tag: tag.to_string(),
+            migrations: migrations,
+            description: None,
+        };
+        assert!(self.schemas.insert(name.to_string(), schema).is_none(),
+                "a box named {} has already been registered", name);
+    }
+
+    /// Returns the current version of the type held by the box with the given name, if it has
+    /// been registered.
+    pub fn version(&self, name: &str) -> Option<u64> {
+        self.schemas.get(&name.to_string()).map(|s| s.migrations.len() as u64)
+    }
+
+    /// Records the structure of the type held by the registered box with the given name, as
+    /// `describe` finds it from `sample`, for `write_schema_doc` to document.
+    pub fn describe<T: Encodable<Describer, IoError>>(&mut self, name: &str, sample: &T)
+                                                      -> IoResult<()> {
+        try!(self.schema(name));
+        self.schemas.get_mut(&name.to_string()).unwrap().description = Some(describe(sample));
+        Ok(())
+    }
+
+    /// Writes a description of every registered box to `schema.json` in the registry’s
+    /// namespace, and returns its path. For each box, the description gives the tag and current
+    /// version of its type, along with the structure recorded by `describe`, if any, so that other
+    /// tools, and maintainers without the program’s source, can make sense of the boxes’ files.
+    pub fn write_schema_doc(&self) -> IoResult<Path> {
+        let mut boxes = TreeMap::new();
+        for (name, schema) in self.schemas.iter() {
+            let mut doc = TreeMap::new();
+            doc.insert("tag".to_string(), json::String(schema.tag.clone()));
+            doc.insert("version".to_string(), json::U64(schema.migrations.len() as u64));
+            doc.insert("type".to_string(), schema.description.clone().unwrap_or(json::Null));
+            boxes.insert(name.clone(), json::Object(doc));
+        }
+        let p = self.ns.dir().join("schema.json");
+        try!(write_atomic(&p, json::Object(boxes).to_pretty_str().as_bytes()));
+        Ok(p)
+    }
+
+    /// Opens the registered box with the given name, migrating its value to the current version
+    /// of its type. The migrated value is written back when the box is.
+    pub fn open<'a, T>(&self, name: &str) -> IoResult<FileBox<T>>
+            where T: Decodable<CompactDecoder, IoError>
+                   + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+        let schema = try!(self.schema(name));
+        let p = try!(self.ns.path(name));
+        let (mut header, mut payload) = try!(read_box(&p, None, None, None, false, false));
+        try!(check_bincode(&header));
+        try!(check_header(schema, &header));
+        let current = schema.migrations.len();
+        for migrate in schema.migrations.slice_from(header.version as uint).iter() {
+            payload = try!((*migrate)(payload));
+        }
+        // The migrated value and the new tag are only written if the box is.
+        let migrated = header.type_tag != schema.tag || header.version != current as u64;
+        header.type_tag = schema.tag.clone();
+        header.version = current as u64;
+        let c = compress::builtin(header.compressor.as_slice()).unwrap();
+        let mut b = try!(FileBox::from_payload(&p, header, payload, c));
+        b._dirty = b._dirty || migrated;
+        Ok(b)
+    }
+
+    /// Creates the registered box with the given name and value.
+    pub fn open_new<'a, T>(&self, name: &str, val: T) -> IoResult<FileBox<T>>
+            where T: Decodable<CompactDecoder, IoError>
+                   + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+        let schema = try!(self.schema(name));
+        let mut b = try!(FileBox::open_new(&try!(self.ns.path(name)), val));
+        b._header.type_tag = schema.tag.clone();
+        b._header.version = schema.migrations.len() as u64;
+        Ok(b)
+    }
+
+    /// Opens the registered box with the given name, creating it with its default value if it
+    /// doesn’t exist.
+    pub fn open_or_new<'a, T>(&self, name: &str) -> IoResult<FileBox<T>>
+            where T: Decodable<CompactDecoder, IoError>
+                   + Encodable<EncoderWriter<'a, MemWriter>, IoError>
+                   + Default {
+        if try!(self.ns.path(name)).exists() {
+            self.open(name)
+        } else {
+            self.open_new(name, Default::default())
+        }
+    }
+
+    /// Migrates the file of every registered box to the current version of its type, as opening
+    /// and then writing each box would, but without decoding them, so it suits upgrading every
+    /// box of an installation at once. A box that fails to migrate is left as it was and doesn’t
+    /// stop the others. If `dry_run` is true, nothing is written, and the report tells what would
+    /// have been migrated.
+    ///
+    /// Boxes shouldn’t be open while they are migrated, since writing them would undo it.
+    pub fn migrate_all(&self, dry_run: bool) -> MigrationReport {
+        let mut report = MigrationReport {
+            dry_run: dry_run,
+            migrated: Vec::new(),
+            current: Vec::new(),
+            missing: Vec::new(),
+            failed: Vec::new(),
+        };
+        let mut schemas: Vec<(&String, &Schema)> = self.schemas.iter().collect();
+        schemas.sort_by(|&(a, _), &(b, _)| a.cmp(b));
+        for (name, schema) in schemas.into_iter() {
+            match self.migrate_file(name.as_slice(), schema, dry_run) {
+                Ok(Some(from)) => {
+                    let to = schema.migrations.len() as u64;
+                    report.migrated.push((name.clone(), from, to));
+                }
+                Ok(None) => report.current.push(name.clone()),
+                Err(ref e) if e.kind == io::FileNotFound => report.missing.push(name.clone()),
+                Err(e) => report.failed.push((name.clone(), e)),
+            }
+        }
+        report
+    }
+
+    /// Migrates the file of the box with the given name, returning the version it was migrated
+    /// from, or `None` if it was already current.
+    fn migrate_file(&self, name: &str, schema: &Schema, dry_run: bool) -> IoResult<Option<u64>> {
+        let p = try!(self.ns.path(name));
+        // Dry runs mustn’t write anything, not even the saves in the box’s log.
+        let (mut header, mut payload) = try!(read_box(&p, None, None, None, false, dry_run));
+        try!(check_bincode(&header));
+        try!(check_header(schema, &header));
+        let current = schema.migrations.len() as u64;
+        if header.type_tag == schema.tag && header.version == current {
+            return Ok(None);
+        }
+        let from = header.version;
+        for migrate in schema.migrations.slice_from(from as uint).iter() {
+            payload = try!((*migrate)(payload));
+        }
+        if dry_run {
+            return Ok(Some(from));
+        }
+        header.type_tag = schema.tag.clone();
+        header.version = current;
+        let c = compress::builtin(header.compressor.as_slice()).unwrap();
+        let stored = try!(c.compress(payload.as_slice()));
+        let header = header.next(c.name(), stored.as_slice());
+        try!(layout::store(&p, layout::layout_of(&p), &header, stored.as_slice(), |bytes| {
+            write_atomic(&p, bytes)
+        }));
+        Ok(Some(from))
+    }
+
+    fn schema(&self, name: &str) -> IoResult<&Schema> {
+        match self.schemas.get(&name.to_string()) {
+            Some(schema) => Ok(schema),
+            None => Err(IoError {
+                kind: io::InvalidInput,
+                desc: "no box has been registered with this name",
+                detail: Some(name.to_string()),
+            }),
+        }
+    }
+}
+
+/// Checks that a box with `header` can be opened as holding the type described by `schema`.
+fn check_header(schema: &Schema, header: &Header) -> IoResult<()> {
+    if !header.type_tag.is_empty() && header.type_tag != schema.tag {
+        return Err(IoError {
+            kind: io::InvalidInput,
+            desc: "the box holds a different type from the one it was registered with",
+            detail: Some(format!("expected {}, found {}", schema.tag, header.type_tag)),
+        });
+    }
+    let current = schema.migrations.len() as u64;
+    if header.version > current {
+        return Err(SchemaTooNew {
+            schema: schema.tag.clone(),
+            found: header.version,
+            supported: current,
+        }.to_error());
+    }
+    Ok(())
+}
+
+/// What `Registry::migrate_all` did to the registered boxes, or would have done in a dry run.
+#[deriving(Show)]
+pub struct MigrationReport {
+    /// Whether this was a dry run, which wrote nothing.
+    pub dry_run: bool,
+    /// The names of the boxes that were migrated, with the versions they were migrated from and
+    /// to.
+    pub migrated: Vec<(String, u64, u64)>,
+    /// The names of the boxes that were already at the current version.
+    pub current: Vec<String>,
+    /// The names of the registered boxes that have no file.
+    pub missing: Vec<String>,
+    /// The names of the boxes that couldn’t be migrated, along with why.
+    pub failed: Vec<(String, IoError)>,
+}
+
+impl MigrationReport {
+    /// Returns whether every box that has a file is now, or would be, at the current version.
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    /// Writes the report to the file at `p` as text, one line for each box.
+    pub fn write(&self, p: &Path) -> IoResult<()> {
+        let mut text = String::new();
+        let verb = if self.dry_run { "would migrate" } else { "migrated" };
+        for &(ref name, from, to) in self.migrated.iter() {
+            text.push_str(format!("{} {} from version {} to {}\n", verb, name, from, to)
+                              .as_slice());
+        }
+        for name in self.current.iter() {
+            text.push_str(format!("{} is current\n", name).as_slice());
+        }
+        for name in self.missing.iter() {
+            text.push_str(format!("{} has no file\n", name).as_slice());
+        }
+        for &(ref name, ref e) in self.failed.iter() {
+            text.push_str(format!("{} failed: {}\n", name, e).as_slice());
+        }
+        write_atomic(p, text.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{mod, File, IoResult};
+    use serialize::json;
+    use bincode;
+    use super::Registry;
+    use super::super::{Store, FileBox, SchemaTooNew, peek, write_value};
+
+    fn add_label(old: Vec<u8>) -> IoResult<Vec<u8>> {
+        let n: int = try!(bincode::decode(old));
+        bincode::encode(&(n, "unlabelled".to_string()))
+    }
+
+    fn fail(_: Vec<u8>) -> IoResult<Vec<u8>> {
+        Err(io::standard_error(io::OtherIoError))
+    }
+
+    #[test]
+    fn migrate_on_open() {
+        let store = Store::new(&Path::new("target/migrate_on_open")).unwrap();
+        let ns = store.namespace("app").unwrap();
+        write_value(&ns.path("counter").unwrap(), &5i).unwrap();
+        write_value(&ns.path("other").unwrap(), &5i).unwrap();
+
+        let mut registry = Registry::new(ns);
+        registry.register("counter", "counter", vec![add_label]);
+        registry.register("other", "other", Vec::new());
+        assert_eq!(registry.version("counter"), Some(1));
+        {
+            let b: FileBox<(int, String)> = registry.open("counter").unwrap();
+            assert_eq!(*b, (5, "unlabelled".to_string()));
+            assert_eq!(b.header().type_tag.as_slice(), "counter");
+            assert_eq!(b.header().version, 1);
+        }
+        let b: FileBox<(int, String)> = registry.open("counter").unwrap();
+        assert_eq!(*b, (5, "unlabelled".to_string()));
+        drop(b);
+
+        // Tagged files can only be opened as the type they were written as.
+        {
+            let _: FileBox<int> = registry.open("other").unwrap();
+        }
+        let mut other = Registry::new(store.namespace("app").unwrap());
+        other.register("other", "counter", Vec::new());
+        assert!(other.open::<int>("other").is_err());
+        assert!(other.open::<int>("unregistered").is_err());
+
+        // Boxes written by a newer version of their type can’t be opened.
+        other.register("counter", "counter", Vec::new());
+        let e = other.open::<(int, String)>("counter").unwrap_err();
+        assert_eq!(SchemaTooNew::from_error(&e), Some(SchemaTooNew {
+            schema: "counter".to_string(),
+            found: 1,
+            supported: 0,
+        }));
+    }
+
+    #[test]
+    fn write_schema_doc() {
+        let store = Store::new(&Path::new("target/write_schema_doc")).unwrap();
+        let mut registry = Registry::new(store.namespace("app").unwrap());
+        registry.register("counter", "counter", vec![add_label]);
+        registry.register("names", "names", Vec::new());
+        registry.describe("counter", &(0i, String::new())).unwrap();
+        assert!(registry.describe("missing", &0i).is_err());
+        let p = registry.write_schema_doc().unwrap();
+        let doc = json::from_str(File::open(&p).read_to_string().unwrap().as_slice()).unwrap();
+        let expected = json::from_str(r#"{
+            "counter": {"tag": "counter", "version": 1, "type": {"tuple": ["int", "string"]}},
+            "names": {"tag": "names", "version": 0, "type": null}
+        }"#).unwrap();
+        assert_eq!(doc, expected);
+    }
+
+    #[test]
+    fn migrate_all() {
+        let root = Path::new("target/registry_migrate_all");
+        let _ = ::std::io::fs::rmdir_recursive(&root);
+        let store = Store::new(&root).unwrap();
+        let ns = store.namespace("app").unwrap();
+        write_value(&ns.path("a").unwrap(), &1i).unwrap();
+        write_value(&ns.path("b").unwrap(), &2i).unwrap();
+        write_value(&ns.path("bad").unwrap(), &3i).unwrap();
+
+        let mut registry = Registry::new(store.namespace("app").unwrap());
+        for name in ["a", "b", "missing"].iter() {
+            registry.register(*name, "counter", vec![add_label]);
+        }
+        registry.register("bad", "counter", vec![fail]);
+        let report = registry.migrate_all(true);
+        assert_eq!(report.migrated.len(), 2);
+        assert_eq!(report.missing, vec!["missing".to_string()]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(peek::<int>(&ns.path("a").unwrap()).unwrap(), 1);
+
+        let report = registry.migrate_all(false);
+        assert_eq!(report.migrated, vec![("a".to_string(), 0, 1), ("b".to_string(), 0, 1)]);
+        let log = root.join("migration.log");
+        report.write(&log).unwrap();
+        assert!(File::open(&log).read_to_string().unwrap().as_slice()
+                    .starts_with("migrated a from version 0 to 1\n"));
+        let b: FileBox<(int, String)> = registry.open("b").unwrap();
+        assert_eq!(*b, (2, "unlabelled".to_string()));
+        assert_eq!(registry.migrate_all(false).current, vec!["a".to_string(), "b".to_string()]);
+    }
+}
@@ -0,0 +1,213 @@
+//! Following a box written by another process, with a bound on how out of date its value gets.
+
+use std::comm::{channel, Receiver};
+use std::io::{mod, timer, IoError, IoResult, MemWriter};
+use std::task;
+use std::time::Duration;
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use clock::{Clock, SystemClock};
+use encoding::{CompactDecoder, decode_bincode};
+use super::{FileBox, FileId, modified_time, read_payload};
+
+/// The description of errors caused by a replica being unable to catch up with its box.
+static TOO_STALE: &'static str = "the replica couldn’t be brought up to date with its box";
+
+/// Returns whether `e` is the error `ReadReplica::get` fails with when it can’t tell whether its
+/// value is up to date.
+pub fn is_too_stale(e: &IoError) -> bool {
+    e.kind == io::OtherIoError && e.desc == TOO_STALE
+}
+
+/// What a file looked like when it was read, to tell when it has changed.
+type Version = (Option<FileId>, Option<u64>);
+
+/// What the background task found when it checked the box’s file.
+struct Refresh {
+    /// When the file was checked, in milliseconds since the Unix epoch.
+    checked: u64,
+    version: Version,
+    /// The payload of the file, if it had changed since the last check.
+    payload: Option<Vec<u8>>,
+}
+
+/// A read-only copy of a box that another process writes, for follower processes that serve its
+/// value, kept up to date in the background.
+///
+/// A task of the replica’s own checks the box’s file every so often, and reads it again when it
+/// has been written. The replica knows when its value was last found to match the file, and never
+/// hands out a value that was found longer ago than the `max_staleness` it was opened with:
+/// if the background task has fallen behind, such as because the disk is slow, `get` checks the
+/// file itself, and fails with an error for which `is_too_stale` is true if it can’t. Values are
+/// read from boxes stored with the built-in compressors, as `peek` reads them.
+pub struct ReadReplica<T> {
+    path: Path,
+    max_staleness: Duration,
+    val: T,
+    version: Version,
+    checked: u64,
+    rx: Receiver<Refresh>,
+}
+
+impl<T> ReadReplica<T> where T: Decodable<CompactDecoder, IoError> {
+    /// Reads the box at `p` and starts following it, checking its file in the background about
+    /// twice as often as `max_staleness`.
+    pub fn open(p: &Path, max_staleness: Duration) -> IoResult<ReadReplica<T>> {
+        let checked = SystemClock.now_ms();
+        let version = try!(version_of(p));
+        let (_, payload) = try!(read_payload(p, None, None, None));
+        let val = try!(decode_bincode(payload));
+        let (tx, rx) = channel();
+        let path = p.clone();
+        let every = ::std::cmp::max(max_staleness / 2, Duration::milliseconds(10));
+        let mut seen = version.clone();
+        task::spawn(proc() {
+            loop {
+                timer::sleep(every);
+                let checked = SystemClock.now_ms();
+                // A failed check is left for the next one, or for `get` once too long has passed.
+                let version = match version_of(&path) {
+                    Ok(version) => version,
+                    Err(_) => continue,
+                };
+                let payload = if version == seen {
+                    None
+                } else {
+                    match read_payload(&path, None, None, None) {
+                        Ok((_, payload)) => Some(payload),
+                        Err(_) => continue,
+                    }
+                };
+                seen = version.clone();
+                let refresh = Refresh {
+                    checked: checked,
+                    version: version,
+                    payload: payload,
+                };
+                // The replica has gone, so there is nobody left to check the file for.
+                if tx.send_opt(refresh).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(ReadReplica {
+            path: p.clone(),
+            max_staleness: max_staleness,
+            val: val,
+            version: version,
+            checked: checked,
+            rx: rx,
+        })
+    }
+
+    /// The value of the box, as it was no longer than `max_staleness` ago.
+    pub fn get(&mut self) -> IoResult<&T> {
+        self.catch_up();
+        if self.staleness() > self.max_staleness {
+            match self.refresh() {
+                Ok(()) => {}
+                Err(e) => {
+                    return Err(IoError {
+                        kind: io::OtherIoError,
+                        desc: TOO_STALE,
+                        detail: Some(format!("{}: {}", self.path.display(), e)),
+                    });
+                }
+            }
+        }
+        Ok(&self.val)
+    }
+
+    /// How long ago the value of the replica was last found to match the box’s file, as far as
+    /// the replica has heard from its background task.
+    pub fn staleness(&self) -> Duration {
+        let now = SystemClock.now_ms();
+        if now > self.checked {
+            Duration::milliseconds((now - self.checked) as i64)
+        } else {
+            Duration::zero()
+        }
+    }
+
+    /// The path of the box’s file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Takes in what the background task has found.
+    fn catch_up(&mut self) {
+        loop {
+            let refresh = match self.rx.try_recv() {
+                Ok(refresh) => refresh,
+                Err(_) => return,
+            };
+            // `refresh` may have checked the file before `get` last did.
+            if refresh.checked <= self.checked {
+                continue;
+            }
+            match refresh.payload {
+                // A payload that can’t be decoded counts as a failed check, as it does in `get`.
+                Some(payload) => match decode_bincode(payload) {
+                    Ok(val) => self.val = val,
+                    Err(_) => continue,
+                },
+                None if refresh.version != self.version => continue,
+                None => {}
+            }
+            self.version = refresh.version;
+            self.checked = refresh.checked;
+        }
+    }
+
+    /// Checks the box’s file straight away, reading it again if it has changed.
+    fn refresh(&mut self) -> IoResult<()> {
+        let checked = SystemClock.now_ms();
+        let version = try!(version_of(&self.path));
+        if version != self.version {
+            let (_, payload) = try!(read_payload(&self.path, None, None, None));
+            self.val = try!(decode_bincode(payload));
+            self.version = version;
+        }
+        self.checked = checked;
+        Ok(())
+    }
+}
+
+impl<'a, T> FileBox<T> where T: Decodable<CompactDecoder, IoError>
+                              + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Opens a read-only replica of the box at `p`, whose value is never older than
+    /// `max_staleness`, for programs that follow a box another process writes. See `ReadReplica`.
+    pub fn open_replica(p: &Path, max_staleness: Duration) -> IoResult<ReadReplica<T>> {
+        ReadReplica::open(p, max_staleness)
+    }
+}
+
+/// What the file at `p` looks like now.
+fn version_of(p: &Path) -> IoResult<Version> {
+    Ok((try!(FileId::of_existing(p)), try!(modified_time(p))))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{fs, timer};
+    use std::time::Duration;
+    use super::is_too_stale;
+    use super::super::{FileBox, write_value};
+
+    #[test]
+    fn follow_box_with_bounded_staleness() {
+        let path = Path::new("target/follow_box_with_bounded_staleness");
+        write_value(&path, &1u32).unwrap();
+        let mut replica = FileBox::<u32>::open_replica(&path, Duration::milliseconds(50)).unwrap();
+        assert_eq!(*replica.get().unwrap(), 1);
+        write_value(&path, &2u32).unwrap();
+        timer::sleep(Duration::milliseconds(200));
+        assert_eq!(*replica.get().unwrap(), 2);
+        assert!(replica.staleness() <= Duration::milliseconds(50));
+
+        fs::unlink(&path).unwrap();
+        timer::sleep(Duration::milliseconds(100));
+        assert!(is_too_stale(&replica.get().err().unwrap()));
+    }
+}
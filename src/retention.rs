@@ -0,0 +1,163 @@
+//! Removing boxes from a store that haven’t been used for a while.
+
+use std::cmp;
+use std::io::{fs, IoResult};
+use std::io::fs::PathExtensions;
+use std::time::Duration;
+use time;
+
+use lock::{mod, Lock};
+use store::{EXTENSION, Store};
+
+/// Which boxes `Store::prune` removes. Boxes are judged by when their files were last read or
+/// written, so on file systems mounted to skip recording reads, only writes count.
+#[deriving(Clone, PartialEq, Show)]
+pub struct Retention {
+    /// Boxes that haven’t been used for longer than this are removed.
+    pub max_idle: Option<Duration>,
+    /// While the files of the store take up more than this many bytes, the boxes that were used
+    /// least recently are removed.
+    pub max_total: Option<u64>,
+}
+
+/// What `Store::prune` removed, or would have removed.
+#[deriving(Show)]
+pub struct PruneReport {
+    /// Whether this was a dry run, in which case nothing was actually removed.
+    pub dry_run: bool,
+    /// The boxes that were removed, least recently used first, along with how many bytes their
+    /// files took up.
+    pub removed: Vec<(Path, u64)>,
+    /// The boxes that the policy would have removed, but which were locked by a program using
+    /// them.
+    pub skipped: Vec<Path>,
+}
+
+impl PruneReport {
+    /// The number of bytes freed by removing the boxes.
+    pub fn freed(&self) -> u64 {
+        self.removed.iter().fold(0, |freed, &(_, size)| freed + size)
+    }
+}
+
+impl Store {
+    /// Removes the boxes the policy says to, along with every file kept next to them, such as
+    /// their generations, autosaves, logs and blobs. This suits stores used as caches, which would
+    /// otherwise grow forever. Boxes that are locked are left alone. If `dry_run` is true, nothing
+    /// is removed, and the report lists what would have been.
+    pub fn prune(&self, policy: &Retention, dry_run: bool) -> IoResult<PruneReport> {
+        let usage = try!(self.disk_usage());
+        let mut total = usage.total();
+        let now = time::get_time();
+        let now = now.sec as u64 * 1000 + now.nsec as u64 / 1_000_000;
+        let mut boxes = Vec::new();
+        for b in usage.boxes.into_iter() {
+            // Files left behind by a box that has already gone are still counted against it.
+            if b.path.exists() {
+                let stat = try!(fs::stat(&b.path));
+                boxes.push((cmp::max(stat.accessed, stat.modified), b));
+            }
+        }
+        boxes.sort_by(|&(a, _), &(b, _)| a.cmp(&b));
+
+        let mut report = PruneReport {
+            dry_run: dry_run,
+            removed: Vec::new(),
+            skipped: Vec::new(),
+        };
+        for (used, b) in boxes.into_iter() {
+            let idle = policy.max_idle.map_or(false, |max| {
+                now > used + max.num_milliseconds() as u64
+            });
+            let over = policy.max_total.map_or(false, |max| total > max);
+            if !idle && !over {
+                continue;
+            }
+            let _lock = match Lock::acquire(&b.path, false) {
+                Ok(lock) => lock,
+                Err(ref e) if lock::is_locked(e) => {
+                    report.skipped.push(b.path);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if !dry_run {
+                try!(remove_box(&b.path));
+            }
+            total -= b.total();
+            report.removed.push((b.path.clone(), b.total()));
+        }
+        Ok(report)
+    }
+}
+
+/// Removes the box file at `p` and the files and directories kept next to it, which are named
+/// after it with an extra extension, such as `name.box.wal` or `name.box.blobs`.
+fn remove_box(p: &Path) -> IoResult<()> {
+    let name = p.filename().unwrap_or(b"");
+    for sibling in try!(fs::readdir(&p.dir_path())).into_iter() {
+        let file = sibling.filename().unwrap_or(b"");
+        if file.len() <= name.len() || !file.starts_with(name) || file[name.len()] != b'.' {
+            continue;
+        }
+        // A box whose own name starts with this box’s, such as `name.box.x.box`, keeps its files.
+        let rest = file.slice_from(name.len() + 1);
+        if rest.split(|&b| b == b'.').any(|part| part == EXTENSION.as_bytes()) {
+            continue;
+        }
+        if sibling.is_dir() {
+            try!(fs::rmdir_recursive(&sibling));
+        } else {
+            try!(fs::unlink(&sibling));
+        }
+    }
+    fs::unlink(p)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs;
+    use std::io::fs::PathExtensions;
+    use std::time::Duration;
+    use time;
+    use super::Retention;
+    use super::super::Store;
+
+    #[test]
+    fn prune_old_boxes() {
+        let root = Path::new("target/prune_old_boxes");
+        let _ = fs::rmdir_recursive(&root);
+        let store = Store::new(&root).unwrap();
+        let ns = store.namespace("cache").unwrap();
+        let now = time::get_time().sec as u64 * 1000;
+        for (i, name) in ["old", "older", "new"].iter().enumerate() {
+            let mut b = ns.open_new(*name, vec![0u8, ..100]).unwrap();
+            // Leaves an older version of the file next to it, which goes along with the box.
+            b.set_generations(1);
+            b.push(1);
+            b.save().unwrap();
+            drop(b);
+            let days = if *name == "new" { 0 } else { 10 + i as u64 };
+            let then = now - days * 24 * 60 * 60 * 1000;
+            fs::change_file_times(&ns.path(*name).unwrap(), then, then).unwrap();
+        }
+
+        let idle = Retention { max_idle: Some(Duration::days(7)), max_total: None };
+        let report = store.prune(&idle, true).unwrap();
+        assert_eq!(report.removed.len(), 2);
+        assert!(ns.path("old").unwrap().exists());
+
+        let report = store.prune(&idle, false).unwrap();
+        // The box used least recently goes first.
+        assert_eq!(report.removed[0].ref0(), &ns.path("older").unwrap());
+        assert!(report.freed() > 200);
+        assert_eq!(ns.names().unwrap(), vec!["new".to_string()]);
+        assert!(fs::readdir(ns.dir()).unwrap().iter().all(|p| {
+            p.filename_str().unwrap().starts_with("new.")
+        }));
+
+        let small = Retention { max_idle: None, max_total: Some(0) };
+        assert_eq!(store.prune(&small, false).unwrap().removed.len(), 1);
+        assert!(ns.names().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,105 @@
+//! Changing how the boxes of a store are compressed or encrypted while it is in use.
+
+use std::io::{fs, IoError, IoResult};
+use std::io::fs::PathExtensions;
+
+use atomic::write_atomic;
+use compress::Compressor;
+use journal;
+use layout;
+use lock::Lock;
+use store::{EXTENSION, Store};
+use super::read_encoded;
+
+/// What `Store::rewrite_all` rewrote.
+#[deriving(Show)]
+pub struct RewriteReport {
+    /// The boxes that were rewritten.
+    pub rewritten: Vec<Path>,
+    /// The boxes that were left as they were, along with why, such as because they were locked,
+    /// had changes waiting in their logs, or couldn’t be read with the compressors given.
+    pub skipped: Vec<(Path, IoError)>,
+}
+
+impl Store {
+    /// Rewrites every box in the store so that its payload is stored with `to`, such as to start
+    /// compressing a store, or to encrypt it with a new key. Boxes stored with a compressor that
+    /// isn’t built in are read with `from`, which must be the one they were written with.
+    ///
+    /// Each box is replaced atomically, one at a time, so the store stays usable throughout: a box
+    /// read while this runs is read either as it was or as it is now. Boxes held open for writing
+    /// find their files replaced, which their next write reports unless they were opened with
+    /// `pin_identity` turned off, so programs should reload such boxes, or lock them, in which
+    /// case they are skipped. Boxes journaled with changes that haven’t been checkpointed are
+    /// skipped too. Skipped boxes are listed in the report, and can be rewritten by running this
+    /// again later. Values kept inline, as `Store::set_inline_limit` allows, aren’t boxes of their
+    /// own, and are left as they are.
+    pub fn rewrite_all(&self, from: Option<&Compressor>, to: &Compressor)
+                       -> IoResult<RewriteReport> {
+        let mut report = RewriteReport {
+            rewritten: Vec::new(),
+            skipped: Vec::new(),
+        };
+        let mut boxes: Vec<Path> = try!(fs::walk_dir(self.root())).filter(|p| {
+            p.is_file() && p.extension_str() == Some(EXTENSION)
+        }).collect();
+        boxes.sort();
+        for p in boxes.into_iter() {
+            match rewrite(&p, from, to) {
+                Ok(()) => report.rewritten.push(p),
+                Err(e) => report.skipped.push((p, e)),
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Rewrites the box file at `p` with its payload stored with `to`.
+fn rewrite(p: &Path, from: Option<&Compressor>, to: &Compressor) -> IoResult<()> {
+    let _lock = try!(Lock::acquire(p, false));
+    if journal::log_path(p).exists() {
+        return Err(IoError {
+            kind: ::std::io::OtherIoError,
+            desc: "the box has changes in its log that haven’t been checkpointed",
+            detail: Some(p.display().to_string()),
+        });
+    }
+    let (old, payload) = try!(read_encoded(p, from, None, None, false));
+    let stored = try!(to.compress(payload.as_slice()));
+    let header = old.next(to.name(), stored.as_slice());
+    layout::store(p, layout::layout_of(p), &header, stored.as_slice(), |bytes| {
+        write_atomic(p, bytes)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs;
+    use super::super::{Store, FileBox, Deflate, peek_compressed, open_header};
+
+    #[test]
+    fn rewrite_live_store() {
+        let root = Path::new("target/rewrite_live_store");
+        let _ = fs::rmdir_recursive(&root);
+        let store = Store::new(&root).unwrap();
+        let ns = store.namespace("docs").unwrap();
+        drop(ns.open_new("a", vec![1u8, ..100]).unwrap());
+        drop(ns.open_new("b", vec![2u8, ..100]).unwrap());
+        // A box opened only for reading carries on through the rewrite.
+        let reader: FileBox<Vec<u8>> = ns.open("a").unwrap();
+        let locked = FileBox::<Vec<u8>>::open_locked(&ns.path("b").unwrap()).unwrap();
+
+        let report = store.rewrite_all(None, &Deflate).unwrap();
+        assert_eq!(report.rewritten, vec![ns.path("a").unwrap()]);
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(open_header(&ns.path("a").unwrap()).unwrap().header.compressor.as_slice(),
+                   "deflate");
+        assert_eq!(peek_compressed::<Vec<u8>>(&ns.path("a").unwrap(), &Deflate).unwrap(),
+                   *reader);
+
+        drop(locked);
+        let report = store.rewrite_all(None, &Deflate).unwrap();
+        assert_eq!(report.rewritten.len(), 2);
+        assert_eq!(*ns.open::<Vec<u8>>("b").unwrap(), vec![2u8, ..100]);
+    }
+}
@@ -0,0 +1,75 @@
+//! Reading every box in a directory with bounded memory use.
+
+use std::comm::sync_channel;
+use std::task;
+use std::io::{fs, IoError, IoResult};
+use std::io::fs::PathExtensions;
+use serialize::Decodable;
+
+use encoding::CompactDecoder;
+use super::peek;
+
+/// Reads every box in `dir` and passes each value to `visit` along with the path it was read from.
+/// Files are visited in order of their paths; files that fail to decode are passed to `visit` as
+/// errors rather than stopping the scan. Nothing is written to any of the files.
+///
+/// At most `max_decoded` values are held in memory at once. With a limit of one, each file is read
+/// only after the previous value has been visited; larger limits let the next files be read in the
+/// background while `visit` runs.
+pub fn scan<T>(dir: &Path, max_decoded: uint, visit: |&Path, IoResult<T>|) -> IoResult<()>
+        where T: Send + Decodable<CompactDecoder, IoError> {
+    assert!(max_decoded > 0, "scan needs to be able to decode at least one value");
+    let mut paths = try!(fs::readdir(dir));
+    // Temporary files and info files sit next to box files but aren't boxes themselves.
+    paths.retain(|p| {
+        p.is_file() && p.extension() != Some(b"tmp") && p.extension() != Some(b"info")
+    });
+    paths.sort();
+
+    if max_decoded == 1 {
+        for p in paths.iter() {
+            visit(p, peek(p));
+        }
+        return Ok(());
+    }
+
+    // The value being visited and the one being decoded are both outside the channel, so it only
+    // has to buffer the rest.
+    let (tx, rx) = sync_channel(max_decoded - 2);
+    task::spawn(proc() {
+        for p in paths.into_iter() {
+            let val = peek(&p);
+            if tx.send_opt((p, val)).is_err() {
+                break;
+            }
+        }
+    });
+    for (p, val) in rx.iter() {
+        visit(&p, val);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{fs, USER_RWX};
+    use std::io::fs::PathExtensions;
+    use super::scan;
+    use super::super::write_value;
+
+    #[test]
+    fn scan_directory() {
+        let dir = Path::new("target/scan_directory");
+        if !dir.is_dir() {
+            fs::mkdir(&dir, USER_RWX).unwrap();
+        }
+        for i in range(0i, 5) {
+            write_value(&dir.join(format!("{}.box", i).as_slice()), &i).unwrap();
+        }
+        for &limit in [1u, 2, 3].iter() {
+            let mut seen = Vec::new();
+            scan::<int>(&dir, limit, |_, val| seen.push(val.unwrap())).unwrap();
+            assert_eq!(seen, vec![0, 1, 2, 3, 4]);
+        }
+    }
+}
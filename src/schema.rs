@@ -0,0 +1,77 @@
+//! Telling apart files that are too new to read from files that are damaged.
+
+use std::io::{mod, IoError};
+
+/// The description of errors caused by files written by a newer schema than this program knows.
+pub static TOO_NEW: &'static str = "the box was written by a newer version than this program \
+                                    supports";
+
+/// Why a file written by a newer version of its schema can’t be read.
+///
+/// Reading such a file fails with an `IoError` that this can be recovered from with `from_error`,
+/// so a program can tell the user to upgrade instead of reporting corrupt data.
+#[deriving(Clone, PartialEq, Show)]
+pub struct SchemaTooNew {
+    /// What is too new: `format` for the format of box files themselves, or the tag of the type
+    /// the box holds.
+    pub schema: String,
+    /// The version the file was written with.
+    pub found: u64,
+    /// The newest version this program can read.
+    pub supported: u64,
+}
+
+impl SchemaTooNew {
+    /// The error reading the file fails with.
+    pub fn to_error(&self) -> IoError {
+        IoError {
+            kind: io::InvalidInput,
+            desc: TOO_NEW,
+            detail: Some(format!("{}: found version {}, supported up to {}",
+                                 self.schema, self.found, self.supported)),
+        }
+    }
+
+    /// Returns why the file couldn’t be read if `e` was caused by it being too new.
+    pub fn from_error(e: &IoError) -> Option<SchemaTooNew> {
+        if e.desc != TOO_NEW {
+            return None;
+        }
+        let detail = match e.detail {
+            Some(ref detail) => detail.as_slice(),
+            None => return None,
+        };
+        let (schema, rest) = match detail.find_str(": found version ") {
+            Some(i) => (detail.slice_to(i), detail.slice_from(i + ": found version ".len())),
+            None => return None,
+        };
+        let mut versions = rest.split_str(", supported up to ");
+        let found = versions.next().and_then(from_str);
+        let supported = versions.next().and_then(from_str);
+        match (found, supported) {
+            (Some(found), Some(supported)) => Some(SchemaTooNew {
+                schema: schema.to_string(),
+                found: found,
+                supported: supported,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::SchemaTooNew;
+
+    #[test]
+    fn round_trip_through_io_error() {
+        let too_new = SchemaTooNew {
+            schema: "settings: v2".to_string(),
+            found: 3,
+            supported: 2,
+        };
+        assert_eq!(SchemaTooNew::from_error(&too_new.to_error()), Some(too_new));
+        assert_eq!(SchemaTooNew::from_error(&io::standard_error(io::InvalidInput)), None);
+    }
+}
@@ -0,0 +1,76 @@
+//! Boxes kept in temporary files, which are removed when the boxes are dropped.
+
+use std::io::{fs, IoError, IoResult, MemWriter};
+use std::os;
+use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use encoding::CompactDecoder;
+use process;
+use super::{FileBox, FileId, modified_time, now};
+
+/// How many temporary boxes this process has created, to give each a name of its own.
+static CREATED: AtomicUint = INIT_ATOMIC_UINT;
+
+impl<'a, T> FileBox<T> where T: Decodable<CompactDecoder, IoError>
+                              + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Creates a new box holding `val` in a file of its own in the system’s temporary directory,
+    /// for scratch state and for tests that shouldn’t share files. `path` tells where the file
+    /// is. The box works like any other, but its file is removed when the box is dropped, closed
+    /// or turned into its value, unless it is kept with `persist` first.
+    pub fn temp(val: T) -> IoResult<FileBox<T>> {
+        let n = CREATED.fetch_add(1, SeqCst);
+        let name = format!("filebox-{}-{}-{}.box", process::id(), now(), n);
+        let mut b = try!(FileBox::open_new(&os::tmpdir().join(name), val));
+        b._scratch = true;
+        Ok(b)
+    }
+
+    /// Writes the box and moves its file to `p`, replacing any file there, so that it stays
+    /// after the box is dropped. This works for any box, but is only needed for boxes made with
+    /// `temp`. The box is kept at `p` from then on.
+    pub fn persist(&mut self, p: &Path) -> IoResult<()> {
+        try!(self.write());
+        if fs::rename(&self._path, p).is_err() {
+            // The temporary directory is often on another filesystem, which files can’t be renamed
+            // onto.
+            try!(fs::copy(&self._path, p));
+            try!(fs::unlink(&self._path));
+        }
+        self._path = p.clone();
+        self._id = Some(try!(FileId::of(p)));
+        self._seen = try!(modified_time(p));
+        self._scratch = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs;
+    use std::io::fs::PathExtensions;
+    use super::super::{FileBox, peek};
+
+    #[test]
+    fn temporary_boxes() {
+        let b = FileBox::temp(vec![1u32]).unwrap();
+        let other = FileBox::temp(vec![2u32]).unwrap();
+        let path = b.path().clone();
+        assert!(path.exists());
+        assert!(path != *other.path());
+        drop(b);
+        assert!(!path.exists());
+
+        let kept = Path::new("target/temporary_boxes");
+        let _ = fs::unlink(&kept);
+        let mut b = other;
+        b.push(3);
+        let path = b.path().clone();
+        b.persist(&kept).unwrap();
+        assert_eq!(*b.path(), kept);
+        drop(b);
+        assert!(!path.exists());
+        assert_eq!(peek::<Vec<u32>>(&kept).unwrap(), vec![2, 3]);
+    }
+}
@@ -0,0 +1,144 @@
+//! Checking whether the way boxes are written protects their data on a particular filesystem.
+
+use std::default::Default;
+use std::io::{fs, File, IoResult};
+use std::io::fs::PathExtensions;
+
+use atomic::{TempFiles, Durability, NoSync, Flush, Fsync, sync_dir, write_atomic};
+use atomic::write_atomic_durable;
+use faults::{Faults, ShortWrite, FsyncError, RenameError, TornWrite};
+use super::{NoCompression, Header, encode_file, peek};
+
+/// The name of the box written by `torn_write_check`.
+static BOX_NAME: &'static str = "filebox-selftest.box";
+
+/// What `torn_write_check` found out about a directory.
+#[deriving(Clone, PartialEq, Show)]
+pub struct Report {
+    /// The durability the box was written with.
+    pub durability: Durability,
+    /// Whether files in the directory can be flushed to disk.
+    pub fsync_supported: bool,
+    /// Whether the directory itself can be flushed to disk, which `Fsync` relies on.
+    pub dir_fsync_supported: bool,
+    /// Whether renaming a file over an existing one replaces it. Boxes can’t be written atomically
+    /// on filesystems where it doesn’t.
+    pub rename_replaces: bool,
+    /// Whether writes that failed before finishing left both the previous contents of the box and
+    /// the directory itself as they were.
+    pub failed_writes_are_safe: bool,
+    /// Whether reading a box whose file only holds part of what was written to it fails, rather
+    /// than returning a wrong value. Such files are left behind by crashes on filesystems that
+    /// don’t keep the order of writes and renames.
+    pub torn_writes_detected: bool,
+}
+
+impl Report {
+    /// Whether boxes written to the directory with the durability that was checked keep their
+    /// previous value whenever a write fails, and survive the machine losing power as that
+    /// durability promises. `NoSync` never flushes anything, so it is never safe. Torn writes can
+    /// only happen when the system crashes, so they are not taken into account.
+    pub fn is_safe(&self) -> bool {
+        let flushed = match self.durability {
+            NoSync => false,
+            Flush => self.fsync_supported,
+            Fsync => self.fsync_supported && self.dir_fsync_supported,
+        };
+        flushed && self.rename_replaces && self.failed_writes_are_safe
+    }
+}
+
+/// Writes a box in `dir` with `durability` while simulating failures at each step of writing it,
+/// and reports how well the box survived them. `dir` should be on the filesystem the boxes being
+/// checked live on, and `durability` should be the one they are written with. The files written
+/// are removed afterwards.
+pub fn torn_write_check(dir: &Path, durability: Durability) -> IoResult<Report> {
+    let p = dir.join(BOX_NAME);
+    let res = check(&p, durability);
+    if p.exists() {
+        try!(fs::unlink(&p));
+    }
+    res
+}
+
+fn check(p: &Path, durability: Durability) -> IoResult<Report> {
+    let temp: TempFiles = Default::default();
+    let old = (1u64, "the previous value".to_string());
+    let new = (2u64, "the value that was being written".to_string());
+    let (_, old_bytes) = try!(encode_file(&old, &NoCompression, &Header::new()));
+    let (_, new_bytes) = try!(encode_file(&new, &NoCompression, &Header::new()));
+
+    let fsync_supported = {
+        let mut f = try!(File::create(p));
+        try!(f.write(old_bytes.as_slice()));
+        f.fsync().is_ok()
+    };
+    let rename_replaces = write_atomic(p, old_bytes.as_slice()).is_ok();
+    let dir_fsync_supported = sync_dir(p).is_ok();
+
+    let faults = Faults::new();
+    let mut failed_writes_are_safe = true;
+    let failures = vec![ShortWrite(new_bytes.len() / 2), FsyncError, RenameError];
+    for fault in failures.into_iter() {
+        try!(write_atomic(p, old_bytes.as_slice()));
+        faults.inject(fault);
+        let res = write_atomic_durable(p, new_bytes.as_slice(), &temp, None, None, Some(&faults),
+                                       false, durability.clone(), None);
+        let intact = res.is_err() && try!(read(p)) == Some(old.clone())
+                     && !temp.path_for(p).exists();
+        failed_writes_are_safe = failed_writes_are_safe && intact;
+    }
+
+    let mut torn_writes_detected = true;
+    for n in range(0, new_bytes.len()) {
+        try!(write_atomic(p, old_bytes.as_slice()));
+        faults.inject(TornWrite(n));
+        let _ = write_atomic_durable(p, new_bytes.as_slice(), &temp, None, None, Some(&faults),
+                                     false, durability.clone(), None);
+        match try!(read(p)) {
+            Some(ref val) if *val != old && *val != new => torn_writes_detected = false,
+            _ => {}
+        }
+    }
+
+    Ok(Report {
+        durability: durability,
+        fsync_supported: fsync_supported,
+        dir_fsync_supported: dir_fsync_supported,
+        rename_replaces: rename_replaces,
+        failed_writes_are_safe: failed_writes_are_safe,
+        torn_writes_detected: torn_writes_detected,
+    })
+}
+
+/// Reads the test box, returning `None` if its contents are invalid.
+fn read(p: &Path) -> IoResult<Option<(u64, String)>> {
+    match peek(p) {
+        Ok(val) => Ok(Some(val)),
+        // The file is there, so it must be its contents that couldn’t be read.
+        Err(_) if p.exists() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{fs, USER_RWX};
+    use std::io::fs::PathExtensions;
+    use super::torn_write_check;
+    use super::super::{Flush, Fsync, NoSync};
+
+    #[test]
+    fn check_target_dir() {
+        let dir = Path::new("target/check_target_dir");
+        if !dir.is_dir() {
+            fs::mkdir(&dir, USER_RWX).unwrap();
+        }
+        for durability in vec![Flush, Fsync].into_iter() {
+            let report = torn_write_check(&dir, durability).unwrap();
+            assert!(report.is_safe());
+        }
+        assert!(!torn_write_check(&dir, NoSync).unwrap().is_safe());
+        assert!(fs::readdir(&dir).unwrap().is_empty());
+    }
+}
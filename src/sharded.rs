@@ -0,0 +1,290 @@
+//! Spreading the values of a directory across several directories, under the control of a
+//! router.
+
+use std::io::{fs, IoError, IoResult, MemWriter};
+use std::io::fs::PathExtensions;
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use encoding::CompactDecoder;
+use filedir::FileDir;
+use super::{FileBox, fnv1a};
+
+/// Decides which shard of a `ShardedDir` the value under each key is kept in.
+pub trait Router {
+    /// The shard the value under `key` belongs in, out of `shards`, which is never zero. This must
+    /// always give the same answer for the same key and number of shards, or values will be
+    /// looked for in the wrong place.
+    fn route(&self, key: &str, shards: uint) -> uint;
+}
+
+/// Spreads keys evenly across the shards by their hashes. Changing the number of shards moves
+/// most keys to a different one.
+#[deriving(Clone)]
+pub struct HashRouter;
+
+impl Router for HashRouter {
+    fn route(&self, key: &str, shards: uint) -> uint {
+        (fnv1a(key.as_bytes()) % shards as u64) as uint
+    }
+}
+
+/// Spreads keys across the shards by consistent hashing, so that adding a shard only moves the
+/// keys that belong in it, about one in every `shards` of them, and removing the last one only
+/// moves the keys that were in it.
+///
+/// Each shard owns a number of points on a ring of hashes, and each key belongs to the shard
+/// owning the first point after the key’s hash. More points per shard spread the keys more evenly,
+/// but make routing slower.
+#[deriving(Clone)]
+pub struct ConsistentHash {
+    points: uint,
+}
+
+impl ConsistentHash {
+    /// A router giving each shard `points` points on the ring, which must be at least one. A
+    /// hundred or so spreads keys well.
+    pub fn new(points: uint) -> ConsistentHash {
+        assert!(points > 0, "each shard needs at least one point on the ring");
+        ConsistentHash {
+            points: points,
+        }
+    }
+}
+
+impl Router for ConsistentHash {
+    fn route(&self, key: &str, shards: uint) -> uint {
+        let hash = fnv1a(key.as_bytes());
+        // The first point after the key’s hash, or, if there is none, the first point on the ring.
+        let mut after: Option<(u64, uint)> = None;
+        let mut first: Option<(u64, uint)> = None;
+        for shard in range(0, shards) {
+            for i in range(0, self.points) {
+                let point = fnv1a(format!("{}/{}", shard, i).as_bytes());
+                if point >= hash && after.map_or(true, |(p, _)| point < p) {
+                    after = Some((point, shard));
+                }
+                if first.map_or(true, |(p, _)| point < p) {
+                    first = Some((point, shard));
+                }
+            }
+        }
+        after.or(first).unwrap().val1()
+    }
+}
+
+/// Keeps keys in ranges: keys before the first bound go in the first shard, keys from the first
+/// bound up to the second in the second, and so on. Keys past the last shard go in the last one.
+/// This keeps keys that sort together in the same shard, such as those starting with the same
+/// prefix.
+#[deriving(Clone)]
+pub struct RangeRouter {
+    bounds: Vec<String>,
+}
+
+impl RangeRouter {
+    /// A router splitting keys at `bounds`, which are sorted.
+    pub fn new(mut bounds: Vec<String>) -> RangeRouter {
+        bounds.sort();
+        RangeRouter {
+            bounds: bounds,
+        }
+    }
+}
+
+impl Router for RangeRouter {
+    fn route(&self, key: &str, shards: uint) -> uint {
+        let shard = self.bounds.iter().take_while(|bound| bound.as_slice() <= key).count();
+        ::std::cmp::min(shard, shards - 1)
+    }
+}
+
+/// The prefix of the names of shard directories.
+static SHARD_PREFIX: &'static str = "shard-";
+
+/// A directory of boxes that all hold values of type `T`, each under a string key, spread across
+/// several directories, the shards, rather than all kept in one.
+///
+/// Each shard is a `FileDir` of its own, in a directory named `shard-` followed by its number, and
+/// the `Router` the directory was opened with decides which shard each key is kept in. Sharding
+/// keeps directories small when there are very many values, and lets shards be placed on
+/// different disks by making their directories links. The number of shards and the router aren’t
+/// recorded, so the directory must always be opened with the same ones, or be moved to new ones
+/// with `rebalance`.
+pub struct ShardedDir<T> {
+    dir: Path,
+    shards: Vec<FileDir<T>>,
+    router: Box<Router + 'static>,
+}
+
+impl<T> ShardedDir<T> {
+    /// Opens the directory `dir`, with keys spread across `shards` shards by their hashes,
+    /// creating the directory and its shards if they don’t exist.
+    pub fn new(dir: &Path, shards: uint) -> IoResult<ShardedDir<T>> {
+        ShardedDir::with_router(dir, shards, box HashRouter as Box<Router + 'static>)
+    }
+
+    /// Like `new`, but keys are spread across the shards by `router`.
+    pub fn with_router(dir: &Path, shards: uint, router: Box<Router + 'static>)
+                       -> IoResult<ShardedDir<T>> {
+        assert!(shards > 0, "a sharded directory needs at least one shard");
+        Ok(ShardedDir {
+            dir: dir.clone(),
+            shards: try!(open_shards(dir, shards)),
+            router: router,
+        })
+    }
+
+    /// The shard the value under `key` is kept in.
+    pub fn shard_of(&self, key: &str) -> uint {
+        self.router.route(key, self.shards.len())
+    }
+
+    /// The shard with the given number, which must be less than the number of shards.
+    pub fn shard(&self, shard: uint) -> &FileDir<T> {
+        &self.shards[shard]
+    }
+
+    /// The number of shards.
+    pub fn shards(&self) -> uint {
+        self.shards.len()
+    }
+
+    /// Returns whether there is a value under `key`.
+    pub fn contains(&self, key: &str) -> bool {
+        self.dir_of(key).contains(key)
+    }
+
+    /// Removes the value under `key`, returning whether there was one.
+    pub fn remove(&self, key: &str) -> IoResult<bool> {
+        self.dir_of(key).remove(key)
+    }
+
+    /// Lists the keys that have values, in every shard, in order.
+    pub fn keys(&self) -> IoResult<Vec<String>> {
+        let mut keys = Vec::new();
+        for shard in self.shards.iter() {
+            keys.extend(try!(shard.keys()).into_iter());
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Spreads the values across `shards` shards with `router` from now on, moving every value
+    /// that belongs in a different shard than the one it is in, and returns how many were moved.
+    /// Shards that are no longer needed are removed once they are empty.
+    ///
+    /// Values are moved one at a time, by renaming their files, so boxes that are open while this
+    /// runs find their files gone the next time they are written. If moving is interrupted, values
+    /// that haven’t been moved yet can’t be found until this is run again with the same shards and
+    /// router, which carries on where it left off.
+    pub fn rebalance(&mut self, shards: uint, router: Box<Router + 'static>) -> IoResult<uint> {
+        assert!(shards > 0, "a sharded directory needs at least one shard");
+        let new = try!(open_shards(&self.dir, shards));
+        let mut moved = 0;
+        // Every shard on disk is looked through, including any left by an earlier rebalance.
+        for (from, dir) in try!(shard_dirs(&self.dir)).into_iter() {
+            let old = try!(FileDir::<T>::new(&dir));
+            for key in try!(old.keys()).into_iter() {
+                let to = router.route(key.as_slice(), shards);
+                if to != from {
+                    let src = try!(old.namespace().path(key.as_slice()));
+                    try!(fs::rename(&src, &try!(new[to].namespace().path(key.as_slice()))));
+                    moved += 1;
+                }
+            }
+            if from >= shards {
+                // Files other than boxes, such as locks, keep the directory around.
+                let _ = fs::rmdir(&dir);
+            }
+        }
+        self.shards = new;
+        self.router = router;
+        Ok(moved)
+    }
+
+    fn dir_of(&self, key: &str) -> &FileDir<T> {
+        &self.shards[self.shard_of(key)]
+    }
+}
+
+impl<'a, T> ShardedDir<T> where T: Decodable<CompactDecoder, IoError>
+                               + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Opens the box holding the value under `key`, to be read or changed in place.
+    pub fn open(&self, key: &str) -> IoResult<FileBox<T>> {
+        self.dir_of(key).open(key)
+    }
+
+    /// Reads the value under `key` without opening its box, as `peek` does.
+    pub fn get(&self, key: &str) -> IoResult<T> {
+        self.dir_of(key).get(key)
+    }
+
+    /// Stores `val` under `key`, replacing any value that is already there, as `FileDir::insert`
+    /// does.
+    pub fn insert(&self, key: &str, val: &T) -> IoResult<()> {
+        self.dir_of(key).insert(key, val)
+    }
+}
+
+/// Opens the first `shards` shards of the sharded directory `dir`.
+fn open_shards<T>(dir: &Path, shards: uint) -> IoResult<Vec<FileDir<T>>> {
+    range(0, shards).map(|shard| {
+        FileDir::new(&dir.join(format!("{}{}", SHARD_PREFIX, shard)))
+    }).collect()
+}
+
+/// The shards of the sharded directory `dir` that exist on disk, along with their numbers.
+fn shard_dirs(dir: &Path) -> IoResult<Vec<(uint, Path)>> {
+    let mut shards: Vec<(uint, Path)> = try!(fs::readdir(dir)).into_iter().filter_map(|p| {
+        let shard = p.filename_str().and_then(|name| {
+            if name.starts_with(SHARD_PREFIX) {
+                from_str(name.slice_from(SHARD_PREFIX.len()))
+            } else {
+                None
+            }
+        });
+        match shard {
+            Some(shard) if p.is_dir() => Some((shard, p)),
+            _ => None,
+        }
+    }).collect();
+    shards.sort_by(|a, b| a.val0().cmp(&b.val0()));
+    Ok(shards)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs;
+    use std::io::fs::PathExtensions;
+    use super::{ShardedDir, Router, ConsistentHash, RangeRouter};
+
+    #[test]
+    fn route_and_rebalance_shards() {
+        let dir = Path::new("target/route_and_rebalance_shards");
+        let _ = fs::rmdir_recursive(&dir);
+        let mut users: ShardedDir<u32> = ShardedDir::new(&dir, 2).unwrap();
+        for i in range(0u32, 20) {
+            users.insert(format!("user{}", i).as_slice(), &i).unwrap();
+        }
+        assert_eq!(users.keys().unwrap().len(), 20);
+        let ring = box ConsistentHash::new(50) as Box<Router + 'static>;
+        assert!(users.rebalance(3, ring).unwrap() > 0);
+        assert_eq!(users.get("user7").unwrap(), 7);
+        let shard = users.shard_of("user7");
+        assert!(users.shard(shard).contains("user7"));
+
+        // With consistent hashing, adding a shard only moves keys into the new one.
+        let ring = box ConsistentHash::new(50) as Box<Router + 'static>;
+        users.rebalance(4, ring).unwrap();
+        let user7 = users.shard_of("user7");
+        assert!(user7 == shard || user7 == 3);
+
+        let ranges = RangeRouter::new(vec!["user5".to_string()]);
+        assert_eq!(ranges.route("user10", 2), 0);
+        assert_eq!(ranges.route("user7", 2), 1);
+        users.rebalance(1, box ranges as Box<Router + 'static>).unwrap();
+        assert_eq!(users.shard(0).keys().unwrap().len(), 20);
+        assert!(!dir.join("shard-3").exists());
+    }
+}
@@ -0,0 +1,207 @@
+//! Several handles to one box.
+
+use std::cell::{Ref, RefCell, RefMut};
+use std::io::{mod, IoError, IoResult, MemWriter};
+use std::rc::{mod, Rc, Weak};
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use encoding::CompactDecoder;
+use super::{FileBox, FileId};
+
+/// A box that can be reached through several handles.
+///
+/// Every handle made with `try_clone` shares the same value and file, so a change made through one
+/// is seen through the others straight away. The box is written once, when the last handle is
+/// dropped, rather than once per handle. That write is the one dropping the box itself makes, so
+/// if it fails, the box’s drop policy decides what happens, as set with `set_drop_policy` through
+/// `borrow_mut`.
+///
+/// Any number of parts of the program can read the box at once, through `with` or `borrow`, but
+/// only one can change it at a time, through `with_mut` or `borrow_mut`, as with a `RefCell`.
+///
+/// The handles are counted with an `Rc`, so they all belong to the task that made the box. Boxes
+/// can’t be sent to other tasks in any case, since the compressors, clocks and observers they hold
+/// needn’t be `Send`, so counting them atomically and locking them would only cost time.
+pub struct SharedBox<T> {
+    inner: Rc<RefCell<FileBox<T>>>,
+}
+
+impl<T> FileBox<T> {
+    /// Turns the box into a `SharedBox`, so that more handles to it can be made.
+    pub fn into_shared(self) -> SharedBox<T> {
+        SharedBox {
+            inner: Rc::new(RefCell::new(self)),
+        }
+    }
+}
+
+impl<T> SharedBox<T> {
+    /// Makes another handle to the box. Fails if the box’s file has been replaced by a different
+    /// file since the box last read or wrote it, as writing the box would then, so that no handle
+    /// is made to a box that can’t be written.
+    pub fn try_clone(&self) -> IoResult<SharedBox<T>> {
+        {
+            let b = self.inner.borrow();
+            if b._pin && try!(FileId::of_existing(&b._path)) != b._id {
+                return Err(IoError {
+                    kind: io::OtherIoError,
+                    desc: "the box’s file has been replaced by a different file",
+                    detail: Some(b._path.display().to_string()),
+                });
+            }
+        }
+        Ok(SharedBox {
+            inner: self.inner.clone(),
+        })
+    }
+
+    /// Hands the value of the box to `f`.
+    pub fn with<R>(&self, f: |&T| -> R) -> R {
+        f(&**self.inner.borrow())
+    }
+
+    /// Hands the value of the box to `f` to be changed.
+    pub fn with_mut<R>(&self, f: |&mut T| -> R) -> R {
+        f(&mut **self.inner.borrow_mut())
+    }
+
+    /// Borrows the box for reading, until the returned guard is dropped. Panics if the box is
+    /// borrowed for changing.
+    pub fn borrow(&self) -> Ref<FileBox<T>> {
+        self.inner.borrow()
+    }
+
+    /// Borrows the box for changing, until the returned guard is dropped. Panics if the box is
+    /// borrowed at all.
+    pub fn borrow_mut(&self) -> RefMut<FileBox<T>> {
+        self.inner.borrow_mut()
+    }
+
+    /// Like `borrow_mut`, but returns `None` instead of panicking if the box is already borrowed.
+    pub fn try_borrow_mut(&self) -> Option<RefMut<FileBox<T>>> {
+        self.inner.try_borrow_mut()
+    }
+
+    /// The number of handles to the box, including this one. Weak handles aren’t counted.
+    pub fn handles(&self) -> uint {
+        rc::strong_count(&self.inner)
+    }
+
+    /// Makes a weak handle to the box, which doesn’t keep the box’s value in memory.
+    pub fn downgrade(&self) -> WeakBox<T> {
+        WeakBox {
+            path: self.inner.borrow()._path.clone(),
+            inner: RefCell::new(self.inner.downgrade()),
+        }
+    }
+}
+
+impl<'a, T> SharedBox<T> where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Writes the box now, rather than waiting for the last handle to be dropped.
+    pub fn save(&self) -> IoResult<()> {
+        self.inner.borrow_mut().write()
+    }
+}
+
+/// A handle to a box that doesn’t keep it in memory.
+///
+/// While other handles to the box exist, upgrading a weak handle gives another handle to the same
+/// value. Once they have all been dropped, the box has been written and its value freed, and
+/// upgrading opens it again from its file.
+pub struct WeakBox<T> {
+    path: Path,
+    inner: RefCell<Weak<RefCell<FileBox<T>>>>,
+}
+
+impl<'a, T> WeakBox<T> where T: Decodable<CompactDecoder, IoError>
+                           + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Returns a handle to the box, opening it again if no other handles to it are left.
+    pub fn upgrade(&self) -> IoResult<SharedBox<T>> {
+        match self.inner.borrow().upgrade() {
+            Some(inner) => return Ok(SharedBox { inner: inner }),
+            None => {}
+        }
+        let shared = try!(FileBox::open(&self.path)).into_shared();
+        *self.inner.borrow_mut() = shared.inner.downgrade();
+        Ok(shared)
+    }
+}
+
+impl<T> WeakBox<T> {
+    /// The path of the box’s file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns whether the box’s value is in memory, held by other handles.
+    pub fn is_resident(&self) -> bool {
+        self.inner.borrow().upgrade().is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{fs, File};
+    use super::super::{FileBox, LogToFile, peek, write_value};
+
+    #[test]
+    fn share_handles() {
+        let path = Path::new("target/share_handles");
+        let a = FileBox::open_new(&path, vec![1u8]).unwrap().into_shared();
+        let b = a.try_clone().unwrap();
+        assert_eq!(a.handles(), 2);
+        b.with_mut(|v| v.push(2));
+        assert_eq!(a.with(|v| v.clone()), vec![1, 2]);
+        drop(a);
+        // Nothing is written until the last handle goes.
+        assert!(peek::<Vec<u8>>(&path).is_err());
+        drop(b);
+        assert_eq!(peek::<Vec<u8>>(&path).unwrap(), vec![1, 2]);
+
+        let a = FileBox::open(&path).unwrap().into_shared();
+        write_value(&path, &vec![3u8]).unwrap();
+        assert!(a.try_clone().is_err());
+        a.with(|v: &Vec<u8>| assert_eq!(*v, vec![1, 2]));
+        // The box can’t be written over the new file either, so dropping the last handle fails
+        // to write it, which the drop policy hears about.
+        assert!(a.save().is_err());
+        let log = Path::new("target/share_handles.log");
+        let _ = fs::unlink(&log);
+        a.borrow_mut().set_drop_policy(Some(LogToFile(log.clone())));
+        a.with_mut(|v| v.push(4));
+        drop(a);
+        assert!(File::open(&log).read_to_string().unwrap().as_slice().contains("share_handles"));
+        assert_eq!(peek::<Vec<u8>>(&path).unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn borrow_handles() {
+        let path = Path::new("target/borrow_handles");
+        let a = FileBox::open_new(&path, vec![1u8]).unwrap().into_shared();
+        let b = a.try_clone().unwrap();
+        {
+            let (x, y) = (a.borrow(), b.borrow());
+            assert_eq!(x.len(), y.len());
+            assert!(b.try_borrow_mut().is_none());
+        }
+        b.borrow_mut().push(2);
+        assert_eq!(**a.borrow(), vec![1, 2]);
+    }
+
+    #[test]
+    fn weak_handles() {
+        let path = Path::new("target/weak_handles");
+        let a = FileBox::open_new(&path, 1u32).unwrap().into_shared();
+        let weak = a.downgrade();
+        assert!(weak.is_resident());
+        weak.upgrade().unwrap().with_mut(|v| *v = 2);
+        assert_eq!(a.with(|v| *v), 2);
+        drop(a);
+        assert!(!weak.is_resident());
+        assert_eq!(peek::<u32>(&path).unwrap(), 2);
+        let b = weak.upgrade().unwrap();
+        assert_eq!(b.with(|v| *v), 2);
+        assert!(weak.is_resident());
+    }
+}
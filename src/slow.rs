@@ -0,0 +1,85 @@
+//! Warning when saving or loading a box takes longer than it should.
+
+use std::time::Duration;
+use time;
+
+/// Called with the path of a box, and how long each step took, when saving or loading it took
+/// longer than the threshold set with `FileBox::set_slow_warning`.
+pub type SlowHook = fn(&Path, &Timings);
+
+/// How long each step of saving or loading a box took, so that a slow save can be put down to
+/// the disk, to something scanning every file written, such as antivirus software, or to a
+/// network drive. Steps that weren’t taken are left at zero.
+#[deriving(Clone, PartialEq, Show)]
+pub struct Timings {
+    /// Whether these are the timings of a save, rather than a load.
+    pub save: bool,
+    /// Reading the file and checking and decompressing its payload, when loading.
+    pub read: Duration,
+    /// Decoding the value, when loading.
+    pub decode: Duration,
+    /// Encoding and compressing the value, when saving.
+    pub encode: Duration,
+    /// Writing the new file.
+    pub write: Duration,
+    /// Flushing the new file to disk.
+    pub fsync: Duration,
+    /// Renaming the new file over the old one, and flushing the directory if the box’s
+    /// durability asks for it.
+    pub rename: Duration,
+}
+
+impl Timings {
+    /// Timings with every step left at zero.
+    pub fn new(save: bool) -> Timings {
+        Timings {
+            save: save,
+            read: Duration::zero(),
+            decode: Duration::zero(),
+            encode: Duration::zero(),
+            write: Duration::zero(),
+            fsync: Duration::zero(),
+            rename: Duration::zero(),
+        }
+    }
+
+    /// The time taken by every step together.
+    pub fn total(&self) -> Duration {
+        self.read + self.decode + self.encode + self.write + self.fsync + self.rename
+    }
+}
+
+/// The time since `start`, a reading of `time::precise_time_ns`.
+pub fn since(start: u64) -> Duration {
+    Duration::nanoseconds((time::precise_time_ns() - start) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+    use super::Timings;
+    use super::super::FileBox;
+
+    static SLOW_SAVES: AtomicUint = INIT_ATOMIC_UINT;
+
+    fn count_slow(_: &Path, timings: &Timings) {
+        assert!(timings.save);
+        assert!(timings.encode > Duration::zero() && timings.rename > Duration::zero());
+        SLOW_SAVES.fetch_add(1, SeqCst);
+    }
+
+    #[test]
+    fn warn_about_slow_saves() {
+        let path = Path::new("target/warn_about_slow_saves");
+        let mut b = FileBox::open_new(&path, vec![0u8, ..1000]).unwrap();
+        b.set_slow_warning(Some(Duration::hours(1)), count_slow);
+        b.save().unwrap();
+        assert_eq!(SLOW_SAVES.load(SeqCst), 0);
+        // Every save takes longer than no time at all.
+        b.set_slow_warning(Some(Duration::zero()), count_slow);
+        b.mark_dirty();
+        b.save().unwrap();
+        assert_eq!(SLOW_SAVES.load(SeqCst), 1);
+    }
+}
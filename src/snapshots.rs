@@ -0,0 +1,171 @@
+//! Named copies of a box, taken before risky changes so that they can be rolled back.
+
+use std::io::{fs, File, IoError, IoResult, MemWriter, USER_RWX};
+use std::io::fs::PathExtensions;
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use encoding::CompactDecoder;
+use error;
+use names::{escape_name, unescape_name};
+use super::{FileBox, decode_payload, read_encoded};
+
+/// The directory the snapshots of the box at `p` are kept in, next to the box’s file.
+pub fn snapshot_dir(p: &Path) -> Path {
+    let mut name = p.filename().unwrap_or(b"filebox").to_vec();
+    name.push_all(b".snapshots");
+    p.with_filename(name)
+}
+
+impl<'a, T> FileBox<T> where T: Decodable<CompactDecoder, IoError>
+                              + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Takes a snapshot of the value of the box, including changes that haven’t been written,
+    /// under `name`, replacing any snapshot already taken under it. Snapshots are kept in
+    /// `snapshot_dir` as files of their own, so they survive the box being closed and reopened.
+    pub fn snapshot(&self, name: &str) -> IoResult<()> {
+        let p = try!(self.snapshot_path(name));
+        if !p.dir_path().exists() {
+            try!(fs::mkdir(&p.dir_path(), USER_RWX));
+        }
+        self.save_as(&p)
+    }
+
+    /// Replaces the value of the box with the one in the snapshot taken under `name`, and writes
+    /// it straight away. Changes to the box that haven’t been written are lost. The snapshot is
+    /// kept, so it can be restored again.
+    pub fn restore(&mut self, name: &str) -> IoResult<()> {
+        let val = try!(self.read_snapshot(name));
+        try!(self.replace(val));
+        Ok(())
+    }
+
+    /// Takes a snapshot of the box under `label` and hands its value to `f` to be changed. If `f`
+    /// succeeds, the box is written, and the snapshot removed once it has been. If `f` fails, the
+    /// value is put back as it was, the box is left unwritten, and the snapshot is removed.
+    /// This suits operations that make many changes, such as migrations, which should either
+    /// happen entirely or not at all.
+    ///
+    /// If `f` panics, the box is dropped without being written, and the snapshot is left, so the
+    /// value from before the operation can be recovered with `restore(label)`. If writing the box
+    /// fails, the box keeps the changed value and the snapshot is left as well.
+    pub fn guarded_operation<R>(&mut self, label: &str, f: |&mut T| -> IoResult<R>)
+                                -> IoResult<R> {
+        try!(self.snapshot(label));
+        // The value is read back before `f` changes it, so that putting it back can’t fail.
+        let original = try!(self.read_snapshot(label));
+        let dirty = self._dirty;
+        self._in_modify = true;
+        let res = f(self.val_mut());
+        self._in_modify = false;
+        match res {
+            Ok(res) => {
+                self.mark_dirty();
+                try!(self.save());
+                try!(self.delete_snapshot(label));
+                Ok(res)
+            }
+            Err(e) => {
+                self._val = Some(original);
+                self._dirty = dirty;
+                try!(self.delete_snapshot(label));
+                Err(e)
+            }
+        }
+    }
+
+    /// The names of the snapshots taken of the box, in order.
+    pub fn list_snapshots(&self) -> IoResult<Vec<String>> {
+        let dir = snapshot_dir(&self._path);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut names: Vec<String> = try!(fs::readdir(&dir)).iter().filter_map(|p| {
+            p.filename_str().and_then(unescape_name)
+        }).collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Removes the snapshot taken under `name`.
+    pub fn delete_snapshot(&self, name: &str) -> IoResult<()> {
+        fs::unlink(&try!(self.snapshot_path(name)))
+    }
+
+    fn read_snapshot(&self, name: &str) -> IoResult<T> {
+        let p = try!(self.snapshot_path(name));
+        match self._format {
+            Some(ref format) => format.decode(try!(File::open(&p).read_to_end())),
+            None => {
+                let (header, payload) = try!(read_encoded(&p, Some(&*self._compressor), None, None,
+                                                          false));
+                // Snapshots are written with the box’s own encoding, as `save_as` writes them.
+                match self._codec {
+                    Some(ref codec) => codec.decode(&header, payload).map_err(error::undecodable),
+                    None => decode_payload(&header, payload),
+                }
+            }
+        }
+    }
+
+    fn snapshot_path(&self, name: &str) -> IoResult<Path> {
+        Ok(snapshot_dir(&self._path).join(try!(escape_name(name))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::default::Default;
+    use std::io::{fs, IoError, OtherIoError};
+    use super::snapshot_dir;
+    use super::super::{FileBox, Encoding, peek};
+
+    #[test]
+    fn restore_named_snapshots() {
+        let path = Path::new("target/restore_named_snapshots");
+        let _ = fs::rmdir_recursive(&snapshot_dir(&path));
+        let mut b = FileBox::open_new(&path, vec![1u8]).unwrap();
+        b.snapshot("before import").unwrap();
+        b.push(2);
+        b.snapshot("after import").unwrap();
+        b.push(3);
+        b.save().unwrap();
+        assert_eq!(b.list_snapshots().unwrap(),
+                   vec!["after import".to_string(), "before import".to_string()]);
+
+        b.restore("before import").unwrap();
+        assert_eq!(*b, vec![1]);
+        assert_eq!(peek::<Vec<u8>>(&path).unwrap(), vec![1]);
+        b.delete_snapshot("before import").unwrap();
+        assert!(b.restore("before import").is_err());
+        assert_eq!(b.list_snapshots().unwrap(), vec!["after import".to_string()]);
+    }
+
+    #[test]
+    fn restore_encoded_snapshot() {
+        let path = Path::new("target/restore_encoded_snapshot");
+        let mut b = FileBox::open_new(&path, vec![1u32]).unwrap();
+        b.set_encoding(Encoding { varint: true, .. Default::default() });
+        b.snapshot("varint").unwrap();
+        b.push(2);
+        b.restore("varint").unwrap();
+        assert_eq!(*b, vec![1]);
+    }
+
+    #[test]
+    fn roll_back_failed_operation() {
+        let path = Path::new("target/roll_back_failed_operation");
+        let mut b = FileBox::open_new(&path, vec![1u8]).unwrap();
+        b.save().unwrap();
+        let res = b.guarded_operation("migrate", |v| {
+            v.push(2);
+            Err::<(), IoError>(IoError { kind: OtherIoError, desc: "half way", detail: None })
+        });
+        assert!(res.is_err());
+        assert_eq!(*b, vec![1]);
+        assert!(!b.has_changes());
+
+        assert_eq!(b.guarded_operation("migrate", |v| { v.push(3); Ok(v.len()) }).unwrap(), 2);
+        assert_eq!(peek::<Vec<u8>>(&path).unwrap(), vec![1, 3]);
+        assert!(b.list_snapshots().unwrap().is_empty());
+    }
+}
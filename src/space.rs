@@ -0,0 +1,127 @@
+//! Checking for free disk space before writing boxes.
+
+use std::io::{mod, IoError, IoResult};
+use libc;
+
+/// The description of errors caused by there not being enough free space to write a box.
+pub static INSUFFICIENT_SPACE: &'static str = "there isn’t enough free disk space to write the box";
+
+/// Why a box wasn’t written: the filesystem it is on didn’t have enough free space.
+///
+/// Writes that would run out of space fail with an `IoError` that this can be recovered from with
+/// `from_error`, before anything has been written, so no half-written temporary file is left
+/// behind.
+#[deriving(Clone, PartialEq, Show)]
+pub struct InsufficientSpace {
+    /// How many bytes the write asked to have free.
+    pub needed: u64,
+    /// How many bytes were free.
+    pub available: u64,
+}
+
+impl InsufficientSpace {
+    /// The error the write fails with.
+    pub fn to_error(&self) -> IoError {
+        IoError {
+            kind: io::OtherIoError,
+            desc: INSUFFICIENT_SPACE,
+            detail: Some(format!("{} bytes needed, {} available", self.needed, self.available)),
+        }
+    }
+
+    /// Returns why the write failed if `e` was caused by a lack of space.
+    pub fn from_error(e: &IoError) -> Option<InsufficientSpace> {
+        if e.desc != INSUFFICIENT_SPACE {
+            return None;
+        }
+        let detail = match e.detail {
+            Some(ref detail) => detail.as_slice(),
+            None => return None,
+        };
+        let mut counts = detail.split_str(" bytes needed, ");
+        let needed = counts.next().and_then(from_str);
+        let available = counts.next().and_then(|s| s.split(' ').next()).and_then(from_str);
+        match (needed, available) {
+            (Some(needed), Some(available)) => Some(InsufficientSpace {
+                needed: needed,
+                available: available,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Fails with an `InsufficientSpace` error if the filesystem holding `dir` has fewer than `needed`
+/// bytes free. Nothing is checked on systems where the free space can’t be found out.
+pub fn check(dir: &Path, needed: u64) -> IoResult<()> {
+    match try!(available(dir)) {
+        Some(available) if available < needed => Err(InsufficientSpace {
+            needed: needed,
+            available: available,
+        }.to_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(all(target_os = "linux", target_word_size = "64"))]
+#[repr(C)]
+struct StatVfs {
+    f_bsize: libc::c_ulong,
+    f_frsize: libc::c_ulong,
+    f_blocks: libc::c_ulong,
+    f_bfree: libc::c_ulong,
+    f_bavail: libc::c_ulong,
+    f_files: libc::c_ulong,
+    f_ffree: libc::c_ulong,
+    f_favail: libc::c_ulong,
+    f_fsid: libc::c_ulong,
+    f_flag: libc::c_ulong,
+    f_namemax: libc::c_ulong,
+    f_spare: [libc::c_int, ..6],
+}
+
+#[cfg(all(target_os = "linux", target_word_size = "64"))]
+extern {
+    fn statvfs(path: *const libc::c_char, buf: *mut StatVfs) -> libc::c_int;
+}
+
+/// The number of bytes free to unprivileged users on the filesystem holding `dir`.
+#[cfg(all(target_os = "linux", target_word_size = "64"))]
+fn available(dir: &Path) -> IoResult<Option<u64>> {
+    let mut buf: StatVfs = unsafe { ::std::mem::zeroed() };
+    if dir.with_c_str(|path| unsafe { statvfs(path, &mut buf) }) != 0 {
+        return Err(IoError::last_error());
+    }
+    Ok(Some(buf.f_bavail as u64 * buf.f_frsize as u64))
+}
+
+#[cfg(not(all(target_os = "linux", target_word_size = "64")))]
+fn available(_dir: &Path) -> IoResult<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InsufficientSpace;
+    use super::super::FileBox;
+
+    #[test]
+    fn round_trip_through_io_error() {
+        let err = InsufficientSpace { needed: 200, available: 100 };
+        assert_eq!(InsufficientSpace::from_error(&err.to_error()), Some(err));
+    }
+
+    #[cfg(all(target_os = "linux", target_word_size = "64"))]
+    #[test]
+    fn preflight() {
+        let path = Path::new("target/space_preflight");
+        let mut x = FileBox::open_new(&path, vec![1u8, 2, 3]).unwrap();
+        x.save().unwrap();
+        // No filesystem has this much room.
+        x.set_space_preflight(Some(1e18));
+        let err = x.save().unwrap_err();
+        assert!(InsufficientSpace::from_error(&err).is_some());
+        x.set_space_preflight(None);
+        x.close().unwrap();
+    }
+}
@@ -0,0 +1,164 @@
+//! Changing a box from many tasks at once without them waiting for each other.
+
+use std::comm::{channel, Sender, Receiver};
+use std::default::Default;
+use std::io::{IoError, IoResult, MemWriter};
+use std::mem;
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use encoding::CompactDecoder;
+use super::FileBox;
+
+/// Merges a change staged by a task into the value of a box.
+pub type MergeFn<T> = fn(&mut T, T);
+
+/// A box whose changes are staged by each task on its own, and merged into it by the task that
+/// owns the box.
+///
+/// Wrapping a box in a mutex has every task that changes it wait its turn, which is slow when
+/// many tasks change it all the time, as they do a box of counters or metrics. Instead, each task
+/// takes a `Stage` with `stage` and records its changes there, as a value of the box’s type that
+/// starts out as the default, such as counts that start at zero. A stage hands its changes over
+/// when it is flushed or dropped, and the owner of the box merges everything handed over, with
+/// the `MergeFn` the box was made with, and writes the box, each time it calls `merge`, such as
+/// once a second. Changes from different tasks are merged in the order they were handed over,
+/// which is not the order they were made in, so this only suits changes that can be merged in
+/// any order, such as adding to counts. Dropping the box merges what has been handed over before
+/// the box is written, as dropping a `FileBox` does; changes handed over afterwards are lost.
+pub struct StagedBox<T: Send> {
+    inner: FileBox<T>,
+    merge: MergeFn<T>,
+    tx: Sender<T>,
+    rx: Receiver<T>,
+}
+
+/// The changes one task has staged for a `StagedBox`.
+pub struct Stage<T: Send> {
+    local: T,
+    changed: bool,
+    tx: Sender<T>,
+}
+
+impl<'a, T> StagedBox<T> where T: Decodable<CompactDecoder, IoError>
+                               + Encodable<EncoderWriter<'a, MemWriter>, IoError>
+                               + Default + Send {
+    /// Stages changes to `b`, merging them into its value with `merge`.
+    pub fn new(b: FileBox<T>, merge: MergeFn<T>) -> StagedBox<T> {
+        let (tx, rx) = channel();
+        StagedBox {
+            inner: b,
+            merge: merge,
+            tx: tx,
+            rx: rx,
+        }
+    }
+
+    /// Returns a new stage, which can be sent to another task.
+    pub fn stage(&self) -> Stage<T> {
+        Stage {
+            local: Default::default(),
+            changed: false,
+            tx: self.tx.clone(),
+        }
+    }
+
+    /// Merges every change handed over by the stages into the value of the box, and writes it if
+    /// there were any, returning how many were merged. Changes handed over while this runs are
+    /// left for the next merge.
+    pub fn merge(&mut self) -> IoResult<uint> {
+        let merged = self.merge_staged();
+        if merged > 0 {
+            try!(self.inner.save());
+        }
+        Ok(merged)
+    }
+
+    /// The value of the box, with the changes merged so far.
+    pub fn get(&self) -> &T {
+        &*self.inner
+    }
+
+    fn merge_staged(&mut self) -> uint {
+        let merge = self.merge;
+        let mut merged = 0;
+        loop {
+            let staged = match self.rx.try_recv() {
+                Ok(staged) => staged,
+                Err(_) => return merged,
+            };
+            self.inner.modify(|val| merge(val, staged));
+            merged += 1;
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<'a, T> Drop for StagedBox<T> where T: Decodable<CompactDecoder, IoError>
+                                        + Encodable<EncoderWriter<'a, MemWriter>, IoError>
+                                        + Default + Send {
+    fn drop(&mut self) {
+        // The box itself is dropped after this, which writes what was merged.
+        self.merge_staged();
+    }
+}
+
+impl<T: Default + Send> Stage<T> {
+    /// Hands the staged value to `f` to record changes in.
+    pub fn modify<R>(&mut self, f: |&mut T| -> R) -> R {
+        self.changed = true;
+        f(&mut self.local)
+    }
+
+    /// Hands the changes staged so far over to be merged, starting again from the default value.
+    pub fn flush(&mut self) {
+        if self.changed {
+            let staged = mem::replace(&mut self.local, Default::default());
+            self.changed = false;
+            // If the box has gone, there is nothing left to merge the changes into.
+            let _ = self.tx.send_opt(staged);
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<T: Default + Send> Drop for Stage<T> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Future;
+    use super::StagedBox;
+    use super::super::{FileBox, peek};
+
+    fn add(total: &mut u64, n: u64) {
+        *total += n;
+    }
+
+    #[test]
+    fn merge_staged_counts() {
+        let path = Path::new("target/merge_staged_counts");
+        let mut b = StagedBox::new(FileBox::open_new(&path, 0u64).unwrap(), add);
+        let workers: Vec<Future<()>> = range(0u, 4).map(|_| {
+            let mut stage = b.stage();
+            Future::spawn(proc() {
+                for i in range(0u, 100) {
+                    stage.modify(|n| *n += 1);
+                    if i % 10 == 0 {
+                        stage.flush();
+                    }
+                }
+            })
+        }).collect();
+        for mut worker in workers.into_iter() {
+            worker.get();
+        }
+        assert!(b.merge().unwrap() > 4);
+        assert_eq!(*b.get(), 400);
+        assert_eq!(peek::<u64>(&path).unwrap(), 400);
+        assert_eq!(b.merge().unwrap(), 0);
+    }
+}
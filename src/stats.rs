@@ -0,0 +1,85 @@
+//! Counting how much reading and writing a box does.
+
+use std::io::fs;
+use std::time::Duration;
+
+/// Called with the path of a box and its statistics each time it is read or written, once
+/// `FileBox::set_stats_hook` has been given it.
+pub type StatsHook = fn(&Path, &Stats);
+
+/// How much a box has read from and written to the disk since it was opened, for services that
+/// want to know how much churn their boxes cause, such as to export it as metrics.
+///
+/// Sizes are those of the box’s file after it was read or written, or of its log entry for a
+/// save appended to a journal. They leave out metadata files and previous versions kept beside
+/// it.
+#[deriving(Clone, PartialEq, Show)]
+pub struct Stats {
+    /// How many times the box’s file has been read, counting when the box was opened.
+    pub loads: u64,
+    /// How many bytes have been read.
+    pub bytes_read: u64,
+    /// How many times the box has been saved.
+    pub saves: u64,
+    /// How many bytes have been written.
+    pub bytes_written: u64,
+    /// How long the last save took, or `None` if the box hasn’t been saved.
+    pub last_save_duration: Option<Duration>,
+    /// When the box was last saved, in milliseconds since the Unix epoch as told by the box’s
+    /// clock, or `None` if it hasn’t been saved.
+    pub last_save_at: Option<u64>,
+}
+
+impl Stats {
+    /// The statistics of a box that hasn’t read or written anything.
+    pub fn new() -> Stats {
+        Stats {
+            loads: 0,
+            bytes_read: 0,
+            saves: 0,
+            bytes_written: 0,
+            last_save_duration: None,
+            last_save_at: None,
+        }
+    }
+}
+
+/// The size of the file at `p`, or 0 if it can’t be found out, such as for a box that is kept
+/// inline in its store.
+pub fn file_size(p: &Path) -> u64 {
+    fs::stat(p).map(|stat| stat.size).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+    use super::Stats;
+    use super::super::{FileBox, ManualClock};
+
+    static EVENTS: AtomicUint = INIT_ATOMIC_UINT;
+
+    fn count_events(_: &Path, _: &Stats) {
+        EVENTS.fetch_add(1, SeqCst);
+    }
+
+    #[test]
+    fn count_reads_and_writes() {
+        let path = Path::new("target/count_reads_and_writes");
+        FileBox::open_new(&path, vec![0u8, ..100]).unwrap();
+        let mut b = FileBox::<Vec<u8>>::open(&path).unwrap();
+        assert_eq!(b.stats().loads, 1);
+        assert!(b.stats().bytes_read > 100);
+        assert_eq!(b.stats().last_save_at, None);
+        b.set_clock(box ManualClock::new(5000));
+        b.set_stats_hook(Some(count_events));
+        b.push(1);
+        b.save().unwrap();
+        b.reload().unwrap();
+        let stats = b.stats().clone();
+        assert_eq!((stats.loads, stats.saves), (2, 1));
+        assert!(stats.bytes_written > 100);
+        assert_eq!(stats.last_save_at, Some(5000));
+        assert!(stats.last_save_duration.is_some());
+        assert_eq!(EVENTS.load(SeqCst), 2);
+    }
+}
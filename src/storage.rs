@@ -0,0 +1,211 @@
+//! Boxes kept somewhere other than a file of their own, such as in memory.
+
+use std::io::{File, IoError, IoResult, MemWriter, SeekSet};
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use compress::NoCompression;
+use droppolicy;
+use encoding::{CompactDecoder, decode_bincode};
+use header::Header;
+use super::{encode_file, unpack};
+
+/// Where a `StorageBox` keeps the contents of its box file.
+///
+/// This is implemented for `File`, for boxes in files that are already open, and for
+/// `MemStorage`, for tests that shouldn’t touch the filesystem. Other streams, such as a region
+/// of a larger file or a record in a database, only need to implement the two methods.
+pub trait Storage {
+    /// Reads everything that is stored, which is empty if nothing has been stored yet.
+    fn load(&mut self) -> IoResult<Vec<u8>>;
+
+    /// Replaces everything that is stored with `bytes`.
+    fn store(&mut self, bytes: &[u8]) -> IoResult<()>;
+}
+
+impl Storage for File {
+    fn load(&mut self) -> IoResult<Vec<u8>> {
+        try!(self.seek(0, SeekSet));
+        self.read_to_end()
+    }
+
+    /// Writes `bytes` over the start of the file, and cuts off whatever is left after them. This
+    /// isn’t atomic, so boxes that must survive crashes should be kept in files of their own.
+    fn store(&mut self, bytes: &[u8]) -> IoResult<()> {
+        try!(self.seek(0, SeekSet));
+        try!(self.write(bytes));
+        try!(self.truncate(bytes.len() as i64));
+        self.datasync()
+    }
+}
+
+/// Storage in memory, which is lost when it is dropped.
+#[deriving(Clone, PartialEq, Show)]
+pub struct MemStorage {
+    bytes: Vec<u8>,
+}
+
+impl MemStorage {
+    /// Creates storage holding nothing.
+    pub fn new() -> MemStorage {
+        MemStorage::from_bytes(Vec::new())
+    }
+
+    /// Creates storage holding `bytes`, such as the contents of a box file.
+    pub fn from_bytes(bytes: Vec<u8>) -> MemStorage {
+        MemStorage {
+            bytes: bytes,
+        }
+    }
+
+    /// What is stored.
+    pub fn bytes(&self) -> &[u8] {
+        self.bytes.as_slice()
+    }
+}
+
+impl Storage for MemStorage {
+    fn load(&mut self) -> IoResult<Vec<u8>> {
+        Ok(self.bytes.clone())
+    }
+
+    fn store(&mut self, bytes: &[u8]) -> IoResult<()> {
+        self.bytes = bytes.to_vec();
+        Ok(())
+    }
+}
+
+/// A box kept in a `Storage` rather than at a path, in the same format as a box file.
+///
+/// This works like a `FileBox` without the features that need a path, such as locks, journals
+/// and backups: it is read when it is opened, written by `save`, and written again when it is
+/// dropped if its value has changed. Errors writing it when it is dropped are handled by the
+/// default `DropPolicy`. Payloads are stored uncompressed, and read back with any of the built-in
+/// compressors.
+pub struct StorageBox<T, S> {
+    val: T,
+    header: Header,
+    storage: Option<S>,
+    dirty: bool,
+}
+
+impl<'a, T, S> StorageBox<T, S> where T: Decodable<CompactDecoder, IoError>
+                                       + Encodable<EncoderWriter<'a, MemWriter>, IoError>,
+                                    S: Storage {
+    /// Opens the box kept in `storage`. Fails if nothing is stored there yet.
+    pub fn open(mut storage: S) -> IoResult<StorageBox<T, S>> {
+        let (header, payload) = try!(unpack(try!(storage.load()), None));
+        Ok(StorageBox {
+            val: try!(decode_bincode(payload)),
+            header: header,
+            storage: Some(storage),
+            dirty: false,
+        })
+    }
+
+    /// Creates a new box holding `val` in `storage`, replacing what is stored there.
+    pub fn open_new(storage: S, val: T) -> IoResult<StorageBox<T, S>> {
+        let mut b = StorageBox {
+            val: val,
+            header: Header::new(),
+            storage: Some(storage),
+            dirty: false,
+        };
+        try!(b.save());
+        Ok(b)
+    }
+
+    /// Writes the value of the box to its storage.
+    pub fn save(&mut self) -> IoResult<()> {
+        let (header, bytes) = try!(encode_file(&self.val, &NoCompression, &self.header));
+        try!(self.storage.as_mut().unwrap().store(bytes.as_slice()));
+        self.header = header;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Replaces the value of the box with the one in its storage, discarding any changes.
+    pub fn reload(&mut self) -> IoResult<()> {
+        let bytes = try!(self.storage.as_mut().unwrap().load());
+        let (header, payload) = try!(unpack(bytes, None));
+        self.val = try!(decode_bincode(payload));
+        self.header = header;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Writes the box if its value has changed, and returns its storage.
+    pub fn into_storage(mut self) -> IoResult<S> {
+        if self.dirty {
+            try!(self.save());
+        }
+        Ok(self.storage.take().unwrap())
+    }
+}
+
+impl<T, S> StorageBox<T, S> {
+    /// The header the box was last read or written with.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// The storage the box is kept in.
+    pub fn storage(&self) -> &S {
+        self.storage.as_ref().unwrap()
+    }
+}
+
+impl<T, S> Deref<T> for StorageBox<T, S> {
+    fn deref(&self) -> &T {
+        &self.val
+    }
+}
+
+impl<T, S> DerefMut<T> for StorageBox<T, S> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.dirty = true;
+        &mut self.val
+    }
+}
+
+#[unsafe_destructor]
+impl<'a, T, S> Drop for StorageBox<T, S> where T: Encodable<EncoderWriter<'a, MemWriter>, IoError>,
+                                                S: Storage {
+    fn drop(&mut self) {
+        if !self.dirty || self.storage.is_none() {
+            return;
+        }
+        let res = encode_file(&self.val, &NoCompression, &self.header).and_then(|(_, bytes)| {
+            self.storage.as_mut().unwrap().store(bytes.as_slice())
+        });
+        match res {
+            Ok(()) => {}
+            Err(e) => droppolicy::handle(None, &Path::new("storage"), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{File, Open, ReadWrite};
+    use super::{MemStorage, StorageBox};
+    use super::super::{peek, write_value};
+
+    #[test]
+    fn boxes_in_storage() {
+        let mut b = StorageBox::open_new(MemStorage::new(), vec![1u32, 2, 3]).unwrap();
+        b.push(4);
+        let storage = b.into_storage().unwrap();
+        let b: StorageBox<Vec<u32>, MemStorage> = StorageBox::open(storage).unwrap();
+        assert_eq!(*b, vec![1, 2, 3, 4]);
+        assert!(StorageBox::<u32, MemStorage>::open(MemStorage::new()).is_err());
+
+        let path = Path::new("target/boxes_in_storage");
+        write_value(&path, &"a long string".to_string()).unwrap();
+        let f = File::open_mode(&path, Open, ReadWrite).unwrap();
+        let mut b: StorageBox<String, File> = StorageBox::open(f).unwrap();
+        *b = "short".to_string();
+        drop(b);
+        assert_eq!(peek::<String>(&path).unwrap().as_slice(), "short");
+    }
+}
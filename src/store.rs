@@ -0,0 +1,870 @@
+//! Many boxes kept under one directory.
+
+use std::any::{Any, AnyRefExt};
+use std::cell::{RefCell, RefMut};
+use std::collections::HashMap;
+use std::collections::hash_map::{Occupied, Vacant};
+use std::default::Default;
+use std::io::{mod, fs, File, IoError, IoResult, MemWriter};
+use std::io::fs::PathExtensions;
+use std::rc::Rc;
+use std::task;
+use std::time::Duration;
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use archive;
+use atomic::write_atomic;
+use backup::{mod, BackupReport};
+use compress::{mod, NoCompression};
+use election::Election;
+use encoding::{CompactDecoder, decode_bincode};
+use filemap::FileMap;
+use header::Header;
+use lock::Lock;
+use maintenance::Maintenance;
+use names::{escape_name, unescape_name};
+use process;
+use progress::Progress;
+use quota;
+use readonly;
+use shared::{SharedBox, WeakBox};
+use super::{FileBox, unpack, unpack_file, check_bincode, read_encoded, encode_file, peek, now};
+
+/// The extension of the files boxes are stored in.
+pub static EXTENSION: &'static str = "box";
+
+/// The name of the file in each namespace that holds the values small enough to be inlined.
+static INLINE_FILE: &'static str = "inline.values";
+
+/// A directory of boxes that are opened by name.
+///
+/// The boxes of a store are organised into namespaces, for example one for each user of an
+/// application. Names of boxes and namespaces can be any string accepted by `escape_name`, which
+/// they are passed through before being used as file names, so they can never refer to files
+/// outside the store or collide with each other.
+///
+/// Clones of a store share the boxes opened with `Namespace::cached_open`.
+#[deriving(Clone)]
+pub struct Store {
+    root: Path,
+    quota: Option<u64>,
+    max_box_size: Option<u64>,
+    inline_limit: Option<uint>,
+    open: OpenBoxes,
+}
+
+/// Weak handles to the boxes of a store opened with `Namespace::cached_open`, by path. Each is a
+/// `WeakBox` of the type the box was opened with.
+type OpenBoxes = Rc<RefCell<HashMap<Path, Box<Any + 'static>>>>;
+
+/// The state of a store, as found by `Store::health_check`.
+#[deriving(Show)]
+pub struct HealthReport {
+    /// Whether new files can be created in the store’s directory.
+    pub writable: bool,
+    /// The total size of the files in the store, in bytes.
+    pub used: u64,
+    /// The quota set with `Store::set_quota`, if there is one.
+    pub quota: Option<u64>,
+    /// The owner files of boxes whose owners have gone without removing them.
+    pub stale_owners: Vec<Path>,
+    /// The temporary files left behind by processes that stopped while writing a box.
+    pub stale_temp_files: Vec<Path>,
+    /// The boxes whose checksums were checked.
+    pub checked: Vec<Path>,
+    /// The checked boxes that are damaged, along with what is wrong with them.
+    pub corrupt: Vec<(Path, IoError)>,
+}
+
+impl HealthReport {
+    /// Returns whether the store can be used: it can be written, is within its quota and none of
+    /// the checked boxes are damaged. Stale files don’t stop a store being used, since they are
+    /// cleaned up as boxes are claimed and written.
+    pub fn is_healthy(&self) -> bool {
+        self.writable && self.quota.map_or(true, |quota| self.used <= quota)
+            && self.corrupt.is_empty()
+    }
+}
+
+/// How much disk space the files kept for one box take up, in bytes, as found by
+/// `Store::disk_usage`.
+#[deriving(Clone, PartialEq, Show)]
+pub struct BoxUsage {
+    /// The path of the box’s file.
+    pub path: Path,
+    /// The box’s file, along with its header file if its layout is `Split`.
+    pub payload: u64,
+    /// The box’s earlier generations and autosaves.
+    pub snapshots: u64,
+    /// The box’s log, if it is journaled.
+    pub journal: u64,
+    /// The blobs stored next to the box.
+    pub blobs: u64,
+}
+
+impl BoxUsage {
+    /// The space taken up by all of the box’s files.
+    pub fn total(&self) -> u64 {
+        self.payload + self.snapshots + self.journal + self.blobs
+    }
+}
+
+/// How much disk space a store takes up, as found by `Store::disk_usage`.
+#[deriving(Clone, PartialEq, Show)]
+pub struct DiskUsage {
+    /// The space taken up by each box, in order of path.
+    pub boxes: Vec<BoxUsage>,
+    /// The space taken up by files that don’t belong to any one box, such as temporary files,
+    /// locks and schema documents, in bytes.
+    pub other: u64,
+}
+
+impl DiskUsage {
+    /// The space taken up by every file in the store, in bytes.
+    pub fn total(&self) -> u64 {
+        self.boxes.iter().fold(self.other, |total, usage| total + usage.total())
+    }
+}
+
+/// What a file kept for a box is for.
+enum Part {
+    PayloadPart,
+    SnapshotPart,
+    JournalPart,
+    BlobPart,
+}
+
+impl Store {
+    /// Opens the store in the given directory, creating the directory if it doesn’t exist.
+    pub fn new(root: &Path) -> IoResult<Store> {
+        try!(create_dir(root));
+        Ok(Store {
+            root: root.clone(),
+            quota: None,
+            max_box_size: None,
+            inline_limit: None,
+            open: Rc::new(RefCell::new(HashMap::new())),
+        })
+    }
+
+    /// The directory the store is kept in.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the namespace with the given name, creating it if it doesn’t exist.
+    pub fn namespace(&self, name: &str) -> IoResult<Namespace> {
+        Namespace::new(&self.root, name, self.inline_limit, self.max_box_size, self.open.clone())
+    }
+
+    /// Lists the names of the namespaces in the store.
+    pub fn namespaces(&self) -> IoResult<Vec<String>> {
+        list(&self.root, |p| p.is_dir() && p.extension().is_none())
+    }
+
+    /// Sets the largest size, in bytes, of the values written with `Namespace::put` that are kept
+    /// together in one file in their namespace instead of in box files of their own, for
+    /// namespaces opened from then on. Stores holding many values of a few bytes each then use
+    /// far fewer files, and read them with one `open` instead of one each. Values are never
+    /// inlined by default, and passing `None` stops them being inlined again.
+    pub fn set_inline_limit(&mut self, limit: Option<uint>) {
+        self.inline_limit = limit;
+    }
+
+    /// Sets the largest size, in bytes, of the encoded value of each box opened through the
+    /// namespaces opened from then on, as `FileBox::set_max_size` sets it, and of each value
+    /// written with `Namespace::put`. Passing `None` removes the limit, which is the default.
+    pub fn set_max_box_size(&mut self, limit: Option<u64>) {
+        self.max_box_size = limit;
+    }
+
+    /// Sets the number of bytes the files of the store should stay within, which `health_check`
+    /// reports on. Nothing stops boxes being written beyond it, since that would mean adding up
+    /// the size of the store on every write; `set_max_box_size` limits each box instead.
+    pub fn set_quota(&mut self, bytes: u64) {
+        self.quota = Some(bytes);
+    }
+
+    /// Checks whether the store is fit for use, as a service might before reporting that it is
+    /// ready. The checksums of up to `samples` boxes, spread evenly across the store, are checked
+    /// as well; checking every box of a large store could take a long time.
+    pub fn health_check(&self, samples: uint) -> IoResult<HealthReport> {
+        let mut report = HealthReport {
+            writable: try!(readonly::is_writable(&self.root.join("probe"))),
+            used: 0,
+            quota: self.quota,
+            stale_owners: Vec::new(),
+            stale_temp_files: Vec::new(),
+            checked: Vec::new(),
+            corrupt: Vec::new(),
+        };
+        let mut boxes = Vec::new();
+        for p in try!(fs::walk_dir(&self.root)) {
+            if !p.is_file() {
+                continue;
+            }
+            report.used += try!(fs::stat(&p)).size;
+            match p.extension_str() {
+                Some("owner") => {
+                    let election = Election::new(&p.with_extension(""), Duration::zero());
+                    if try!(election.owner()).is_none() {
+                        report.stale_owners.push(p);
+                    }
+                }
+                Some("tmp") => match temp_file_owner(&p) {
+                    Some(pid) if pid != process::id() && !process::is_alive(pid) => {
+                        report.stale_temp_files.push(p);
+                    }
+                    _ => {}
+                },
+                Some(EXTENSION) => boxes.push(p),
+                _ => {}
+            }
+        }
+        boxes.sort();
+        if samples > 0 && !boxes.is_empty() {
+            let step = (boxes.len() + samples - 1) / samples;
+            for (_, p) in boxes.into_iter().enumerate().filter(|&(i, _)| i % step == 0) {
+                // Reading the payload verifies it against the checksum in the header.
+                match read_encoded(&p, None, None, None, false) {
+                    Ok(_) => {}
+                    Err(e) => report.corrupt.push((p.clone(), e)),
+                }
+                report.checked.push(p);
+            }
+        }
+        report.stale_owners.sort();
+        report.stale_temp_files.sort();
+        Ok(report)
+    }
+
+    /// Finds how much disk space each box in the store takes up, broken down by what its files
+    /// are for, so programs can show where the space goes and decide what to compact or prune.
+    pub fn disk_usage(&self) -> IoResult<DiskUsage> {
+        let mut boxes: HashMap<Path, BoxUsage> = HashMap::new();
+        let mut other = 0;
+        for p in try!(fs::walk_dir(&self.root)) {
+            if !p.is_file() {
+                continue;
+            }
+            let size = try!(fs::stat(&p)).size;
+            let inner = p.with_extension("");
+            let owner = match p.extension_str() {
+                Some(EXTENSION) => Some((p.clone(), PayloadPart)),
+                _ if p.dir_path().extension_str() == Some("blobs") => {
+                    Some((p.dir_path().with_extension(""), BlobPart))
+                }
+                _ if inner.extension_str() != Some(EXTENSION) => None,
+                Some("meta") => Some((inner, PayloadPart)),
+                Some("wal") => Some((inner, JournalPart)),
+                Some(ext) if ext.starts_with("autosave-") => Some((inner, SnapshotPart)),
+                Some(ext) if from_str::<uint>(ext).is_some() => Some((inner, SnapshotPart)),
+                _ => None,
+            };
+            let (box_path, part) = match owner {
+                Some(owner) => owner,
+                None => {
+                    other += size;
+                    continue;
+                }
+            };
+            let usage = match boxes.entry(box_path.clone()) {
+                Occupied(entry) => entry.into_mut(),
+                Vacant(entry) => entry.set(BoxUsage {
+                    path: box_path,
+                    payload: 0,
+                    snapshots: 0,
+                    journal: 0,
+                    blobs: 0,
+                }),
+            };
+            match part {
+                PayloadPart => usage.payload += size,
+                SnapshotPart => usage.snapshots += size,
+                JournalPart => usage.journal += size,
+                BlobPart => usage.blobs += size,
+            }
+        }
+        let mut boxes: Vec<BoxUsage> = boxes.into_iter().map(|(_, usage)| usage).collect();
+        boxes.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(DiskUsage {
+            boxes: boxes,
+            other: other,
+        })
+    }
+
+    /// Returns the maintenance of the store, which does nothing until it is run or spawned.
+    pub fn maintenance(&self) -> Maintenance {
+        Maintenance::new(&self.root)
+    }
+
+    /// Packs every file in the store, including the headers, manifests and autosaves kept next
+    /// to its boxes, into a single archive file at `archive`. This suits features that export all
+    /// of a user’s data, or collect it for a bug report.
+    pub fn export_archive(&self, archive: &Path) -> IoResult<()> {
+        archive::export_archive(&self.root, archive)
+    }
+
+    /// Copies every box of the store, as they all were at one moment, to the directory `dir`,
+    /// along with a manifest, as `backup_store` does.
+    pub fn backup_to(&self, dir: &Path) -> IoResult<BackupReport> {
+        backup::backup_store(&self.root, dir)
+    }
+
+    /// Restores the files of an archive written by `export_archive` into the store, replacing any
+    /// boxes with the same names.
+    pub fn import_archive(&self, archive: &Path) -> IoResult<()> {
+        archive::import_archive(archive, &self.root)
+    }
+}
+
+/// A set of boxes in a `Store`, which can have namespaces of its own.
+///
+/// Values written with `put` that are small enough, as set by `Store::set_inline_limit`, are kept
+/// together in a file of the namespace’s own, which `get` reads them from, rather than in box
+/// files. Inlined values can’t be opened as boxes, but are listed, read and removed like them.
+pub struct Namespace {
+    dir: Path,
+    inline_limit: Option<uint>,
+    max_box_size: Option<u64>,
+    inline: RefCell<Option<FileMap<String, Vec<u8>>>>,
+    open: OpenBoxes,
+}
+
+impl Namespace {
+    fn new(parent: &Path, name: &str, inline_limit: Option<uint>, max_box_size: Option<u64>,
+           open: OpenBoxes) -> IoResult<Namespace> {
+        let dir = parent.join(try!(escape_name(name)));
+        try!(create_dir(&dir));
+        Ok(Namespace {
+            dir: dir,
+            inline_limit: inline_limit,
+            max_box_size: max_box_size,
+            inline: RefCell::new(None),
+            open: open,
+        })
+    }
+
+    /// Returns the namespace whose boxes are kept in `dir`, outside any store, creating the
+    /// directory if it doesn’t exist.
+    pub fn at(dir: &Path) -> IoResult<Namespace> {
+        try!(create_dir(dir));
+        Ok(Namespace {
+            dir: dir.clone(),
+            inline_limit: None,
+            max_box_size: None,
+            inline: RefCell::new(None),
+            open: Rc::new(RefCell::new(HashMap::new())),
+        })
+    }
+
+    /// The directory the boxes in this namespace are stored in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the namespace with the given name inside this one, creating it if it doesn’t exist.
+    pub fn namespace(&self, name: &str) -> IoResult<Namespace> {
+        Namespace::new(&self.dir, name, self.inline_limit, self.max_box_size, self.open.clone())
+    }
+
+    /// Returns the path of the file the box with the given name is stored in.
+    pub fn path(&self, name: &str) -> IoResult<Path> {
+        let mut file = try!(escape_name(name));
+        file.push('.');
+        file.push_str(EXTENSION);
+        Ok(self.dir.join(file))
+    }
+
+    /// Opens the box with the given name, as `FileBox::open` does.
+    pub fn open<'a, T>(&self, name: &str) -> IoResult<FileBox<T>>
+            where T: Decodable<CompactDecoder, IoError>
+                   + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+        Ok(self.limited(try!(FileBox::open(&try!(self.path(name))))))
+    }
+
+    /// Returns a handle to the box with the given name that is shared with the handles returned
+    /// for it by every namespace of the same store or its clones, opening the box only if no
+    /// handle to it is left. This lets parts of a program that don’t know about each other use the
+    /// same box, such as the program’s settings, without reading it twice or writing over each
+    /// other’s changes. The box is written when the last handle is dropped, as `SharedBox`
+    /// describes, and the handles can only be used in the task that opened the box.
+    ///
+    /// Opening a box that is already open with a different type fails.
+    pub fn cached_open<'a, T>(&self, name: &str) -> IoResult<SharedBox<T>>
+            where T: Decodable<CompactDecoder, IoError>
+                   + Encodable<EncoderWriter<'a, MemWriter>, IoError> + 'static {
+        let p = try!(self.path(name));
+        let mut open = self.open.borrow_mut();
+        match open.get(&p) {
+            Some(any) => {
+                let any: &Any = &**any;
+                return match any.downcast_ref::<WeakBox<T>>() {
+                    Some(weak) => weak.upgrade(),
+                    None => Err(IoError {
+                        kind: io::InvalidInput,
+                        desc: "the box is already open with a different type",
+                        detail: Some(p.display().to_string()),
+                    }),
+                };
+            }
+            None => {}
+        }
+        let shared = self.limited(try!(FileBox::open(&p))).into_shared();
+        open.insert(p, box shared.downgrade() as Box<Any + 'static>);
+        Ok(shared)
+    }
+
+    /// Creates a box with the given name and value, as `FileBox::open_new` does.
+    pub fn open_new<'a, T>(&self, name: &str, val: T) -> IoResult<FileBox<T>>
+            where T: Decodable<CompactDecoder, IoError>
+                   + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+        Ok(self.limited(try!(FileBox::open_new(&try!(self.path(name)), val))))
+    }
+
+    /// Opens the box with the given name, creating it with its default value if it doesn’t
+    /// exist, as `FileBox::open_or_new` does.
+    pub fn open_or_new<'a, T>(&self, name: &str) -> IoResult<FileBox<T>>
+            where T: Decodable<CompactDecoder, IoError>
+                   + Encodable<EncoderWriter<'a, MemWriter>, IoError>
+                   + Default {
+        Ok(self.limited(try!(FileBox::open_or_new(&try!(self.path(name))))))
+    }
+
+    /// Limits the size of `b` to the store’s largest box size, if it has one.
+    fn limited<T>(&self, mut b: FileBox<T>) -> FileBox<T> {
+        if self.max_box_size.is_some() {
+            b.set_max_size(self.max_box_size);
+        }
+        b
+    }
+
+    /// Reads the files of the boxes with the given names into memory, each in a task of its own,
+    /// so that they can be opened from the returned `Preloaded` without waiting for the disk. This
+    /// suits programs that know which boxes they will need soon after starting. If `progress` is
+    /// given, it is told how many of the boxes have been read after each one.
+    pub fn preload(&self, names: &[&str], mut progress: Option<&mut Progress>)
+                   -> IoResult<Preloaded> {
+        let (tx, rx) = channel();
+        for &name in names.iter() {
+            let tx = tx.clone();
+            let name = name.to_string();
+            let p = try!(self.path(name.as_slice()));
+            task::spawn(proc() {
+                let res = File::open(&p).read_to_end();
+                // The receiver only goes away if another box has already failed to load.
+                let _ = tx.send_opt((name, p, res));
+            });
+        }
+        let mut files = HashMap::new();
+        for done in range(0, names.len()) {
+            let (name, p, res) = rx.recv();
+            files.insert(name, (p, try!(res)));
+            match progress {
+                Some(ref mut progress) => progress.report(done as u64 + 1, names.len() as u64),
+                None => {}
+            }
+        }
+        Ok(Preloaded {
+            files: files,
+        })
+    }
+
+    /// Reads the values of the boxes with the given names as they all were at one moment. Each
+    /// box is locked while the files are read, as `FileBox::open_locked` locks it, so none of them
+    /// can be changed part of the way through by a program that locks boxes before writing them.
+    /// Programs that don’t can still be seen half way through changing several boxes.
+    pub fn read_snapshot(&self, names: &[&str]) -> IoResult<Snapshot> {
+        let mut paths = Vec::new();
+        for &name in names.iter() {
+            paths.push((name.to_string(), try!(self.path(name))));
+        }
+        // Locking in the same order everywhere keeps two snapshots from waiting on each other.
+        paths.sort();
+        paths.dedup();
+        let mut locks = Vec::new();
+        for &(_, ref p) in paths.iter() {
+            locks.push(try!(Lock::acquire(p, true)));
+        }
+        let mut files = HashMap::new();
+        for (name, p) in paths.into_iter() {
+            let bytes = try!(File::open(&p).read_to_end());
+            files.insert(name, (p, bytes));
+        }
+        drop(locks);
+        Ok(Snapshot {
+            files: files,
+        })
+    }
+
+    /// Writes `val` as the value of the box with the given name, inlining it if it is small
+    /// enough, and otherwise replacing the box’s file atomically.
+    pub fn put<'a, T>(&self, name: &str, val: &T) -> IoResult<()>
+            where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+        let p = try!(self.path(name));
+        let header = Header {
+            created_at: now(),
+            .. Header::new()
+        };
+        let (_, bytes) = try!(encode_file(val, &NoCompression, &header));
+        try!(quota::check(self.max_box_size, bytes.len()));
+        if self.inline_limit.map_or(false, |limit| bytes.len() <= limit) {
+            let mut inline = try!(self.inline_values(true));
+            try!(inline.as_mut().unwrap().insert(name.to_string(), bytes));
+            if p.exists() {
+                try!(fs::unlink(&p));
+            }
+        } else {
+            try!(write_atomic(&p, bytes.as_slice()));
+            // Otherwise the old value would still be read in place of the new one.
+            try!(self.remove_inline(name));
+        }
+        Ok(())
+    }
+
+    /// Reads the value of the box with the given name, whether it is inlined or has a file of its
+    /// own, as `peek` does.
+    pub fn get<T>(&self, name: &str) -> IoResult<T>
+            where T: Decodable<CompactDecoder, IoError> {
+        let inlined = match *try!(self.inline_values(false)) {
+            Some(ref inline) => inline.get(&name.to_string()).map(|bytes| bytes.clone()),
+            None => None,
+        };
+        match inlined {
+            Some(bytes) => {
+                let (_, payload) = try!(unpack(bytes, None));
+                decode_bincode(payload)
+            }
+            None => peek(&try!(self.path(name))),
+        }
+    }
+
+    /// Returns whether the value of the box with the given name is inlined.
+    pub fn is_inline(&self, name: &str) -> IoResult<bool> {
+        Ok(match *try!(self.inline_values(false)) {
+            Some(ref inline) => inline.contains_key(&name.to_string()),
+            None => false,
+        })
+    }
+
+    /// Deletes the box with the given name.
+    pub fn remove(&self, name: &str) -> IoResult<()> {
+        let p = try!(self.path(name));
+        if try!(self.remove_inline(name)) && !p.exists() {
+            return Ok(());
+        }
+        fs::unlink(&p)
+    }
+
+    /// Lists the names of the boxes in this namespace, including those whose values are inlined.
+    pub fn names(&self) -> IoResult<Vec<String>> {
+        let mut names = try!(list(&self.dir, |p| {
+            p.is_file() && p.extension_str() == Some(EXTENSION)
+        }));
+        match *try!(self.inline_values(false)) {
+            Some(ref inline) => {
+                names.extend(inline.iter().map(|(name, _)| name.clone()));
+                names.sort();
+                names.dedup();
+            }
+            None => {}
+        }
+        Ok(names)
+    }
+
+    /// The values inlined in this namespace, read from their file the first time they are needed.
+    /// If there is no such file, it is only created if `create` is true.
+    fn inline_values(&self, create: bool)
+                     -> IoResult<RefMut<Option<FileMap<String, Vec<u8>>>>> {
+        let mut inline = self.inline.borrow_mut();
+        if inline.is_none() {
+            let p = self.dir.join(INLINE_FILE);
+            if create || p.exists() {
+                *inline = Some(try!(FileMap::open(&p)));
+            }
+        }
+        Ok(inline)
+    }
+
+    /// Removes the inlined value of the box with the given name, returning whether it had one.
+    fn remove_inline(&self, name: &str) -> IoResult<bool> {
+        match *try!(self.inline_values(false)) {
+            Some(ref mut inline) => Ok(try!(inline.remove(&name.to_string())).is_some()),
+            None => Ok(false),
+        }
+    }
+
+    /// Lists the names of the namespaces inside this one.
+    pub fn namespaces(&self) -> IoResult<Vec<String>> {
+        list(&self.dir, |p| p.is_dir() && p.extension().is_none())
+    }
+}
+
+/// The files of boxes read into memory by `Namespace::preload`.
+pub struct Preloaded {
+    files: HashMap<String, (Path, Vec<u8>)>,
+}
+
+impl Preloaded {
+    /// Opens the preloaded box with the given name, as `Namespace::open` would. Each box can only
+    /// be opened once, since the box takes the contents of the file from here. Fails if the box
+    /// wasn’t preloaded, or has already been opened.
+    pub fn open<'a, T>(&mut self, name: &str) -> IoResult<FileBox<T>>
+            where T: Decodable<CompactDecoder, IoError>
+                   + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+        let (p, bytes) = match self.files.remove(&name.to_string()) {
+            Some(file) => file,
+            None => return Err(IoError {
+                kind: io::InvalidInput,
+                desc: "the box wasn’t preloaded",
+                detail: Some(name.to_string()),
+            }),
+        };
+        let (header, payload) = try!(unpack_file(&p, bytes, None));
+        try!(check_bincode(&header));
+        let c = compress::builtin(header.compressor.as_slice()).unwrap();
+        FileBox::from_payload(&p, header, payload, c)
+    }
+
+    /// Lists the names of the boxes that have been preloaded but not opened yet, in sorted order.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.files.keys().map(|name| name.clone()).collect();
+        names.sort();
+        names
+    }
+}
+
+/// The id of the process that created the temporary file at `p`, which is named as `TempFiles`
+/// names them by default.
+pub fn temp_file_owner(p: &Path) -> Option<u32> {
+    let name = match p.filestem_str() {
+        Some(name) => name,
+        None => return None,
+    };
+    name.rfind('.').and_then(|i| from_str(name.slice_from(i + 1)))
+}
+
+/// The values of several boxes at one moment, read by `Namespace::read_snapshot`.
+pub struct Snapshot {
+    files: HashMap<String, (Path, Vec<u8>)>,
+}
+
+impl Snapshot {
+    /// Decodes the value the box with the given name had. Fails if the box wasn’t part of the
+    /// snapshot.
+    pub fn get<T>(&self, name: &str) -> IoResult<T>
+            where T: Decodable<CompactDecoder, IoError> {
+        let (p, bytes) = match self.files.get(&name.to_string()) {
+            Some(&(ref p, ref bytes)) => (p, bytes.clone()),
+            None => return Err(IoError {
+                kind: io::InvalidInput,
+                desc: "the box isn’t part of the snapshot",
+                detail: Some(name.to_string()),
+            }),
+        };
+        let (header, payload) = try!(unpack_file(p, bytes, None));
+        try!(check_bincode(&header));
+        decode_bincode(payload)
+    }
+}
+
+fn create_dir(dir: &Path) -> IoResult<()> {
+    if dir.is_dir() {
+        Ok(())
+    } else {
+        fs::mkdir_recursive(dir, io::USER_RWX)
+    }
+}
+
+/// Lists the unescaped names of the entries of `dir` selected by `select`, in sorted order.
+/// Entries whose names weren’t produced by `escape_name` are ignored.
+fn list(dir: &Path, select: |&Path| -> bool) -> IoResult<Vec<String>> {
+    let mut found = Vec::new();
+    for p in try!(fs::readdir(dir)).iter() {
+        if !select(p) {
+            continue;
+        }
+        match p.filestem_str().and_then(unescape_name) {
+            Some(name) => found.push(name),
+            None => {}
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{fs, File};
+    use super::Store;
+    use super::super::Progress;
+
+    struct Log {
+        reports: Vec<(u64, u64)>,
+    }
+
+    impl Progress for Log {
+        fn report(&mut self, done: u64, total: u64) {
+            self.reports.push((done, total));
+        }
+    }
+
+    #[test]
+    fn namespaces() {
+        let root = Path::new("target/store_namespaces");
+        let _ = fs::rmdir_recursive(&root);
+        let store = Store::new(&root).unwrap();
+        {
+            let mut x = store.namespace("User 42").unwrap().open_or_new::<int>("prefs").unwrap();
+            *x = 5;
+            let mut y = store.namespace("user-7").unwrap().open_or_new::<int>("prefs").unwrap();
+            *y = 7;
+        }
+        let ns = store.namespace("User 42").unwrap();
+        assert_eq!(*ns.open::<int>("prefs").unwrap(), 5);
+        assert_eq!(ns.names().unwrap(), vec!["prefs".to_string()]);
+        assert_eq!(store.namespaces().unwrap(), vec!["User 42".to_string(), "user-7".to_string()]);
+
+        let evil = ns.path("../../escape").unwrap();
+        assert_eq!(evil.dir_path(), *ns.dir());
+    }
+
+    #[test]
+    fn disk_usage() {
+        let root = Path::new("target/store_disk_usage");
+        let _ = fs::rmdir_recursive(&root);
+        let store = Store::new(&root).unwrap();
+        let ns = store.namespace("app").unwrap();
+        {
+            let mut b = ns.open_new("log", vec![1u8, 2, 3]).unwrap();
+            b.set_generations(1);
+            b.save().unwrap();
+            b.push(4);
+            b.save().unwrap();
+            b.put_blob(&[0u8, ..100]).unwrap();
+        }
+        File::create(&root.join("stray.tmp")).write(&[0u8, ..10]).unwrap();
+
+        let usage = store.disk_usage().unwrap();
+        assert_eq!(usage.boxes.len(), 1);
+        let log = &usage.boxes[0];
+        assert_eq!(log.path, ns.path("log").unwrap());
+        assert_eq!(log.payload, fs::stat(&log.path).unwrap().size);
+        assert!(log.snapshots > 0);
+        assert_eq!(log.journal, 0);
+        assert_eq!(log.blobs, 100);
+        assert_eq!(usage.other, 10);
+        assert_eq!(usage.total(), log.total() + 10);
+    }
+
+    #[test]
+    fn preload() {
+        let root = Path::new("target/store_preload");
+        let _ = fs::rmdir_recursive(&root);
+        let ns = Store::new(&root).unwrap().namespace("app").unwrap();
+        for (i, name) in ["a", "b", "c"].iter().enumerate() {
+            ns.open_new(*name, i as uint).unwrap();
+        }
+        let mut log = Log { reports: Vec::new() };
+        let mut preloaded = ns.preload(&["a", "c"], Some(&mut log as &mut Progress)).unwrap();
+        assert_eq!(log.reports, vec![(1, 2), (2, 2)]);
+        assert_eq!(preloaded.names(), vec!["a".to_string(), "c".to_string()]);
+        assert_eq!(*preloaded.open::<uint>("c").unwrap(), 2);
+        assert!(preloaded.open::<uint>("c").is_err());
+        assert!(preloaded.open::<uint>("b").is_err());
+        assert!(ns.preload(&["a", "missing"], None).is_err());
+    }
+
+    #[test]
+    fn health_check() {
+        let root = Path::new("target/store_health_check");
+        let _ = fs::rmdir_recursive(&root);
+        let mut store = Store::new(&root).unwrap();
+        let ns = store.namespace("app").unwrap();
+        for name in ["a", "b", "c", "d"].iter() {
+            ns.open_new(*name, vec![0u8, ..100]).unwrap();
+        }
+        let report = store.health_check(4).unwrap();
+        assert!(report.is_healthy());
+        assert_eq!(report.checked.len(), 4);
+
+        let mut bytes = File::open(&ns.path("c").unwrap()).read_to_end().unwrap();
+        let last = bytes.len() - 1;
+        bytes.as_mut_slice()[last] = 1;
+        File::create(&ns.path("c").unwrap()).write(bytes.as_slice()).unwrap();
+        // No process can have an id this large.
+        File::create(&ns.dir().join("a.box.2147483646.tmp")).write(b"x").unwrap();
+        store.set_quota(100);
+        let report = store.health_check(2).unwrap();
+        assert!(!report.is_healthy());
+        assert!(report.used > 400);
+        assert_eq!(report.checked, vec![ns.path("a").unwrap(), ns.path("c").unwrap()]);
+        assert_eq!(report.corrupt.len(), 1);
+        assert_eq!(report.stale_temp_files, vec![ns.dir().join("a.box.2147483646.tmp")]);
+    }
+
+    #[test]
+    fn read_snapshot() {
+        let root = Path::new("target/store_read_snapshot");
+        let _ = fs::rmdir_recursive(&root);
+        let ns = Store::new(&root).unwrap().namespace("app").unwrap();
+        ns.open_new("index", vec![0u32, 1]).unwrap();
+        ns.open_new("data", vec!["a".to_string(), "b".to_string()]).unwrap();
+        let snapshot = ns.read_snapshot(&["index", "data"]).unwrap();
+        {
+            let mut data = ns.open::<Vec<String>>("data").unwrap();
+            data.push("c".to_string());
+        }
+        assert_eq!(snapshot.get::<Vec<u32>>("index").unwrap(), vec![0, 1]);
+        assert_eq!(snapshot.get::<Vec<String>>("data").unwrap().len(), 2);
+        assert!(snapshot.get::<u32>("other").is_err());
+    }
+
+    #[test]
+    fn cached_open() {
+        let root = Path::new("target/store_cached_open");
+        let _ = fs::rmdir_recursive(&root);
+        let store = Store::new(&root).unwrap();
+        store.namespace("app").unwrap().open_new("settings", 1u32).unwrap();
+
+        let other = store.clone();
+        let a = store.namespace("app").unwrap().cached_open::<u32>("settings").unwrap();
+        let b = other.namespace("app").unwrap().cached_open::<u32>("settings").unwrap();
+        assert_eq!(a.handles(), 2);
+        b.with_mut(|v| *v = 2);
+        assert_eq!(a.with(|v| *v), 2);
+        assert!(store.namespace("app").unwrap().cached_open::<String>("settings").is_err());
+        drop(a);
+        drop(b);
+        let ns = Store::new(&root).unwrap().namespace("app").unwrap();
+        assert_eq!(*ns.open::<u32>("settings").unwrap(), 2);
+    }
+
+    #[test]
+    fn inline_small_values() {
+        let root = Path::new("target/inline_small_values");
+        let _ = fs::rmdir_recursive(&root);
+        let mut store = Store::new(&root).unwrap();
+        store.set_inline_limit(Some(100));
+        let ns = store.namespace("settings").unwrap();
+        ns.put("small", &1u32).unwrap();
+        ns.put("large", &vec![0u8, ..1000]).unwrap();
+        assert!(ns.is_inline("small").unwrap());
+        assert!(!ns.is_inline("large").unwrap());
+        assert_eq!(fs::readdir(ns.dir()).unwrap().len(), 2);
+        assert_eq!(ns.names().unwrap(), vec!["large".to_string(), "small".to_string()]);
+
+        // Values move out of the inline file when they grow too large for it.
+        ns.put("small", &vec![1u8, ..1000]).unwrap();
+        assert_eq!(ns.get::<Vec<u8>>("small").unwrap().len(), 1000);
+        ns.put("large", &2u32).unwrap();
+        drop(ns);
+
+        let ns = store.namespace("settings").unwrap();
+        assert_eq!(ns.get::<u32>("large").unwrap(), 2);
+        ns.remove("large").unwrap();
+        assert!(ns.get::<u32>("large").is_err());
+        assert_eq!(ns.names().unwrap(), vec!["small".to_string()]);
+    }
+}
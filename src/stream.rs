@@ -0,0 +1,258 @@
+//! Writing and reading box files without holding their payloads in memory.
+//!
+//! `save` encodes the whole value into memory before writing it, so that it can be compressed and
+//! its checksum put in the header in front of it, which briefly doubles the memory a large value
+//! takes up. Uncompressed boxes can instead be encoded straight into a buffered file: the header
+//! is written first with room for a checksum, the checksum is worked out as the payload goes
+//! past, and the header is then written again in place, since its length doesn’t depend on the
+//! checksum or the payload’s length. Reading works the same way in reverse, checking the checksum
+//! once the value has been decoded.
+
+use std::io::{mod, fs, BufferedReader, BufferedWriter, File, IoError, IoResult};
+use std::io::{MemWriter, SeekSet};
+use std::io::fs::PathExtensions;
+use serialize::{Decodable, Encodable};
+use bincode::{mod, DecoderReader, EncoderWriter};
+use time;
+
+use atomic::{NoSync, Fsync, sync_dir};
+use compress::NoCompression;
+use encoding::CompactDecoder;
+use header::{mod, Header, BINCODE, WORD_BITS};
+use history;
+use journal;
+use layout::{Combined, layout_of};
+use progress;
+use quota;
+use slow;
+use space;
+use stats;
+use super::{FileBox, FNV_OFFSET, fnv1a_update};
+
+/// A writer that works out the FNV-1a hash of what is written through it, and fails once more
+/// than its limit has been written through it, if it has one.
+pub struct HashWriter<W> {
+    inner: W,
+    hash: u64,
+    len: u64,
+    limit: Option<u64>,
+}
+
+impl<W: Writer> Writer for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        self.len += buf.len() as u64;
+        try!(quota::check(self.limit, self.len as uint));
+        self.hash = fnv1a_update(self.hash, buf);
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.inner.flush()
+    }
+}
+
+/// A reader that works out the FNV-1a hash of what is read through it.
+pub struct HashReader<R> {
+    inner: R,
+    hash: u64,
+}
+
+impl<R: Reader> Reader for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let n = try!(self.inner.read(buf));
+        self.hash = fnv1a_update(self.hash, buf.slice_to(n));
+        Ok(n)
+    }
+}
+
+/// The writer `save_streaming` encodes values into.
+pub type StreamWriter = HashWriter<BufferedWriter<File>>;
+
+/// The reader `open_streaming` decodes values from.
+pub type StreamReader = HashReader<BufferedReader<File>>;
+
+impl<'a, T> FileBox<T> where T: Decodable<CompactDecoder, IoError>
+                              + Encodable<EncoderWriter<'a, MemWriter>, IoError>
+                              + Decodable<DecoderReader<'a, StreamReader>, IoError>
+                              + Encodable<EncoderWriter<'a, StreamWriter>, IoError> {
+    /// Like `open`, but the value is decoded as the file is read, rather than once all of it has
+    /// been, if the box is uncompressed and its header is in its file. Other boxes are opened as
+    /// by `open`, as are boxes written on a machine with wider integers than this one, since only
+    /// `open` can check that none of their `uint`s and `int`s are too large for this machine.
+    pub fn open_streaming(p: &Path) -> IoResult<FileBox<T>> {
+        try!(journal::recover(p));
+        if layout_of(p) != Combined {
+            return FileBox::open(p);
+        }
+        let mut r = BufferedReader::new(try!(File::open(p)));
+        let header = match try!(header::read_header(&mut r)) {
+            Some((ref header, _)) if header.compressor.as_slice() == "none"
+                                      && header.encoding.as_slice() == BINCODE
+                                      && header.word_bits <= WORD_BITS => header.clone(),
+            _ => return FileBox::open(p),
+        };
+        let mut r = HashReader {
+            inner: r,
+            hash: FNV_OFFSET,
+        };
+        let val = try!(bincode::decode_from(&mut r));
+        // Anything after the value would have counted towards the checksum too.
+        let rest = try!(r.read_to_end());
+        if !rest.is_empty() || header.checksum.map_or(false, |checksum| checksum != r.hash) {
+            return Err(IoError {
+                kind: io::InvalidInput,
+                desc: header::CORRUPT,
+                detail: Some("its payload doesn’t match its checksum".to_string()),
+            });
+        }
+        let mut b = try!(FileBox::with_value(p, val, box NoCompression));
+        b._header = header;
+        b._dirty = false;
+        let _ = b._temp.remove_stale(p);
+        Ok(b)
+    }
+
+    /// Like `save`, but the value is encoded straight into the box’s new file, rather than into
+    /// memory first, if the box is uncompressed and stored as `write_atomic` would store it, with
+    /// its header in its file. Other boxes, and boxes reporting their progress or with an encoding
+    /// of their own, are saved as by `save`.
+    ///
+    /// The box’s durability, cancel token, history and statistics apply as they do to `save`.
+    /// The size of the new file isn’t known until it has been written, so free space is checked
+    /// for as set with `set_space_preflight` against the size of the file being replaced, and a
+    /// value larger than `set_max_size` allows fails as soon as that much has been encoded, leaving
+    /// the box’s file as it was.
+    pub fn save_streaming(&mut self) -> IoResult<()> {
+        if self._compressor.name() != "none" || self._layout != Combined || self._format.is_some()
+           || self._journal.is_some() || self._direct || self._mapped || self._faults.is_some()
+           || self._progress.is_some() || self._codec.is_some() {
+            return self.save();
+        }
+        try!(self.prepare_write());
+        let start = time::precise_time_ns();
+        let tmp = self._temp.path_for(&self._path);
+        match self._space_factor {
+            Some(factor) => {
+                let needed = stats::file_size(&self._path) as f64 * factor;
+                try!(space::check(&tmp.dir_path(), needed as u64));
+            }
+            None => {}
+        }
+        let res = self.stream_to(&tmp).and_then(|header| {
+            try!(progress::check(self._cancel.as_ref()));
+            try!(fs::rename(&tmp, &self._path));
+            if self._durability == Fsync {
+                try!(sync_dir(&self._path));
+            }
+            Ok(header)
+        });
+        match res {
+            Ok(header) => {
+                try!(self.finish_write(header));
+                self.record_save(stats::file_size(&self._path), slow::since(start));
+                history::record(self)
+            }
+            Err(e) => {
+                if self._temp.remove_on_failure && tmp.exists() {
+                    let _ = fs::unlink(&tmp);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Writes the box’s file to `tmp`, returning its header.
+    fn stream_to(&self, tmp: &Path) -> IoResult<Header> {
+        let placeholder = self.stream_header(0, 0);
+        let start = try!(header::frame(&placeholder, &[]));
+        let mut w = HashWriter {
+            inner: BufferedWriter::new(try!(File::create(tmp))),
+            hash: FNV_OFFSET,
+            len: 0,
+            limit: self._max_size,
+        };
+        try!(w.inner.write(start.as_slice()));
+        try!(bincode::encode_into(self.val(), &mut w));
+        try!(w.flush());
+        let header = self.stream_header(w.hash, w.len);
+        let mut f = w.inner.unwrap();
+        try!(f.seek(0, SeekSet));
+        try!(f.write(try!(header::frame(&header, &[])).as_slice()));
+        if self._durability != NoSync {
+            try!(f.fsync());
+        }
+        Ok(header)
+    }
+
+    /// The header of a payload of `len` bytes with the given checksum.
+    fn stream_header(&self, checksum: u64, len: u64) -> Header {
+        Header {
+            compressor: "none".to_string(),
+            saves: self._header.saves + 1,
+            bytes_written: self._header.bytes_written + len,
+            checksum: Some(checksum),
+            encoding: BINCODE.to_string(),
+            field_offsets: Vec::new(),
+            word_bits: WORD_BITS,
+            .. self._header.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{File, Append, ReadWrite};
+    use super::super::{FileBox, CancelToken, Fsync, QuotaExceeded, is_cancelled, peek};
+
+    #[test]
+    fn stream_large_value() {
+        let path = Path::new("target/stream_large_value");
+        let val: Vec<u64> = range(0, 100000).collect();
+        let mut b = FileBox::open_new(&path, vec![]).unwrap();
+        *b = val.clone();
+        b.save_streaming().unwrap();
+        assert!(!b.has_changes());
+        drop(b);
+        assert!(peek::<Vec<u64>>(&path).unwrap() == val);
+        let b: FileBox<Vec<u64>> = FileBox::open_streaming(&path).unwrap();
+        assert!(*b == val);
+        drop(b);
+
+        File::open_mode(&path, Append, ReadWrite).unwrap().write(b"!").unwrap();
+        assert!(FileBox::<Vec<u64>>::open_streaming(&path).is_err());
+    }
+
+    #[test]
+    fn stream_with_box_settings() {
+        let path = Path::new("target/stream_with_box_settings");
+        let mut b = FileBox::open_new(&path, vec![1u64]).unwrap();
+        b.save_streaming().unwrap();
+        let token = CancelToken::new();
+        b.set_cancel_token(Some(token.clone()));
+        b.set_durability(Fsync);
+        b.push(2);
+        token.cancel();
+        assert!(is_cancelled(&b.save_streaming().err().unwrap()));
+        assert_eq!(peek::<Vec<u64>>(&path).unwrap(), vec![1]);
+        assert_eq!(b.stats().saves, 1);
+
+        b.set_cancel_token(None);
+        b.save_streaming().unwrap();
+        assert_eq!(peek::<Vec<u64>>(&path).unwrap(), vec![1, 2]);
+        assert_eq!(b.stats().saves, 2);
+        assert!(b.stats().bytes_written > 0);
+    }
+
+    #[test]
+    fn stream_past_max_size() {
+        let path = Path::new("target/stream_past_max_size");
+        let mut b = FileBox::open_new(&path, vec![0u64, ..10]).unwrap();
+        b.set_max_size(Some(1000));
+        b.save_streaming().unwrap();
+        b.push_all(&[0, ..1000]);
+        let e = b.save_streaming().err().unwrap();
+        assert_eq!(QuotaExceeded::from_error(&e).unwrap().limit, 1000);
+        assert_eq!(peek::<Vec<u64>>(&path).unwrap().len(), 10);
+        assert!(b.has_changes());
+    }
+}
@@ -0,0 +1,222 @@
+//! Running concurrent readers and writers against a box to check the way it is written.
+//!
+//! This is only built with the `stress-utils` feature.
+
+use std::comm::{channel, Receiver};
+use std::io::{mod, IoError, IoResult};
+use std::task;
+
+use layout::{Layout, Combined};
+use super::{FileBox, peek, write_value};
+
+/// Runs `f` while holding whatever lock an application takes around updates of the box at the
+/// given path.
+pub type WithLock = fn(&Path, || -> IoResult<()>) -> IoResult<()>;
+
+/// The lock used by workloads unless told otherwise, which is no lock at all.
+pub fn no_lock(_: &Path, f: || -> IoResult<()>) -> IoResult<()> {
+    f()
+}
+
+/// Describes the readers and writers to run against a box.
+///
+/// The box holds one counter per writer and a check value computed from them. Each writer opens
+/// the box, increments its own counter and writes the box, `updates` times over, inside
+/// `with_lock`. Each reader peeks at the box until the writers are done, checking that the value
+/// is intact and that no counter ever goes backwards.
+pub struct Workload {
+    /// The number of tasks reading the box.
+    pub readers: uint,
+    /// The number of tasks writing the box.
+    pub writers: uint,
+    /// The number of updates each writer makes.
+    pub updates: uint,
+    /// The layout the box is written in.
+    pub layout: Layout,
+    /// Whether writers write the box with direct I/O.
+    pub direct_io: bool,
+    /// The lock each update is made under.
+    pub with_lock: WithLock,
+}
+
+/// What went wrong while running a workload.
+#[deriving(Clone, PartialEq, Show)]
+pub struct StressReport {
+    /// The number of updates that were made but didn’t survive until the end.
+    pub lost_updates: u64,
+    /// The number of reads that returned a value that wasn’t written as it was, or that failed
+    /// to decode.
+    pub torn_reads: u64,
+    /// The number of times a reader saw a counter go backwards, meaning an update it had already
+    /// seen was lost.
+    pub reverted_reads: u64,
+    /// The errors returned by writers and readers, other than the ones counted above.
+    pub errors: Vec<IoError>,
+}
+
+impl StressReport {
+    /// Returns whether no invariant was broken and no error happened.
+    pub fn is_ok(&self) -> bool {
+        self.lost_updates == 0 && self.torn_reads == 0 && self.reverted_reads == 0
+            && self.errors.is_empty()
+    }
+
+    /// Panics with the report if any invariant was broken.
+    pub fn assert_ok(&self) {
+        assert!(self.is_ok(), "the stress workload broke its invariants: {}", self);
+    }
+}
+
+impl Workload {
+    /// A workload of `readers` readers and `writers` writers making `updates` updates each, to a
+    /// box with the default layout, without direct I/O or locking.
+    pub fn new(readers: uint, writers: uint, updates: uint) -> Workload {
+        Workload {
+            readers: readers,
+            writers: writers,
+            updates: updates,
+            layout: Combined,
+            direct_io: false,
+            with_lock: no_lock,
+        }
+    }
+
+    /// Runs the workload against the box at `p`, which is replaced, and reports what went wrong.
+    /// All the readers and writers have finished when this returns.
+    pub fn run(&self, p: &Path) -> IoResult<StressReport> {
+        try!(write_value(p, &record(Vec::from_elem(self.writers, 0))));
+        let mut report = StressReport {
+            lost_updates: 0,
+            torn_reads: 0,
+            reverted_reads: 0,
+            errors: Vec::new(),
+        };
+
+        let (done_tx, done_rx) = channel();
+        for w in range(0, self.writers) {
+            let (p, done_tx) = (p.clone(), done_tx.clone());
+            let (updates, layout, direct_io) = (self.updates, self.layout.clone(), self.direct_io);
+            let with_lock = self.with_lock;
+            task::spawn(proc() {
+                let res = range(0, updates).fold(Ok(()), |res, _| {
+                    res.and_then(|()| with_lock(&p, || update(&p, w, layout.clone(), direct_io)))
+                });
+                done_tx.send(res);
+            });
+        }
+        let (reads_tx, reads_rx) = channel();
+        let mut stops = Vec::new();
+        for _ in range(0, self.readers) {
+            let (stop_tx, stop_rx) = channel::<()>();
+            stops.push(stop_tx);
+            let (p, reads_tx) = (p.clone(), reads_tx.clone());
+            task::spawn(proc() {
+                reads_tx.send(read_until(&p, stop_rx));
+            });
+        }
+
+        for _ in range(0, self.writers) {
+            match done_rx.recv() {
+                Ok(()) => {}
+                Err(e) => report.errors.push(e),
+            }
+        }
+        for stop in stops.iter() {
+            let _ = stop.send_opt(());
+        }
+        for _ in range(0, self.readers) {
+            let (torn, reverted, errors) = reads_rx.recv();
+            report.torn_reads += torn;
+            report.reverted_reads += reverted;
+            report.errors.extend(errors.into_iter());
+        }
+
+        let expected = (self.writers * self.updates) as u64;
+        match peek::<(Vec<u64>, u64)>(p) {
+            Ok(ref val) if is_intact(val) => {
+                let (ref counts, _) = *val;
+                let total = counts.iter().fold(0, |n, &c| n + c);
+                report.lost_updates = expected - total;
+            }
+            Ok(_) => {
+                report.torn_reads += 1;
+                report.lost_updates = expected;
+            }
+            Err(e) => {
+                report.errors.push(e);
+                report.lost_updates = expected;
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Increments the counter of writer `w` in the box at `p`.
+fn update(p: &Path, w: uint, layout: Layout, direct_io: bool) -> IoResult<()> {
+    let mut b: FileBox<(Vec<u64>, u64)> = try!(FileBox::open(p));
+    if !is_intact(&*b) {
+        return Err(IoError {
+            kind: io::InvalidInput,
+            desc: "a writer read a torn value",
+            detail: Some(p.display().to_string()),
+        });
+    }
+    b.set_layout(layout);
+    b.set_direct_io(direct_io);
+    let (mut counts, _) = (*b).clone();
+    counts.as_mut_slice()[w] += 1;
+    *b = record(counts);
+    let res = b.write();
+    b._write_on_drop = false;
+    res
+}
+
+/// Peeks at the box at `p` until told to stop, returning the number of torn and reverted reads
+/// and any other errors.
+fn read_until(p: &Path, stop: Receiver<()>) -> (u64, u64, Vec<IoError>) {
+    let (mut torn, mut reverted, mut errors) = (0, 0, Vec::new());
+    let mut last: Vec<u64> = Vec::new();
+    while stop.try_recv().is_err() {
+        match peek::<(Vec<u64>, u64)>(p) {
+            Ok(ref val) if is_intact(val) => {
+                let (ref counts, _) = *val;
+                if counts.iter().zip(last.iter()).any(|(now, before)| now < before) {
+                    reverted += 1;
+                }
+                last = counts.clone();
+            }
+            Ok(_) => torn += 1,
+            Err(ref e) if e.kind == io::InvalidInput => torn += 1,
+            Err(e) => errors.push(e),
+        }
+    }
+    (torn, reverted, errors)
+}
+
+fn record(counts: Vec<u64>) -> (Vec<u64>, u64) {
+    let check = check_value(counts.as_slice());
+    (counts, check)
+}
+
+fn is_intact(val: &(Vec<u64>, u64)) -> bool {
+    let (ref counts, check) = *val;
+    check_value(counts.as_slice()) == check
+}
+
+fn check_value(counts: &[u64]) -> u64 {
+    counts.iter().enumerate().fold(counts.len() as u64, |check, (i, &c)| {
+        (check ^ c).rotate_left(i as uint + 1) * 0x100000001b3
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Workload;
+
+    #[test]
+    fn single_writer_keeps_invariants() {
+        let report = Workload::new(2, 1, 50).run(&Path::new("target/stress_single_writer"))
+                                            .unwrap();
+        report.assert_ok();
+    }
+}
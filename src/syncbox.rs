@@ -0,0 +1,236 @@
+//! Boxes that can be shared between tasks.
+
+use std::default::Default;
+use std::io::{IoError, IoResult, MemWriter};
+use std::sync::{Arc, Mutex, RWLock, RWLockReadGuard, RWLockWriteGuard};
+use std::sync::atomic::{AtomicBool, SeqCst};
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use atomic::{TempFiles, Durability, write_atomic_durable};
+use compress;
+use droppolicy;
+use encoding::CompactDecoder;
+use header::Header;
+use layout::{mod, Layout, Combined};
+use progress::is_cancelled;
+use super::{encode_payload, decode_payload, read_box, now};
+
+/// A box whose value can be read and changed by several tasks at once.
+///
+/// A `FileBox` can only be used by the task that opened it, and wrapping one in a `Mutex` makes
+/// readers wait for each other as well as for writers. A `SyncFileBox` keeps its value behind a
+/// reader-writer lock instead: any number of tasks can hold `read` guards at the same time, while
+/// a `write` guard waits for them and keeps them out until it is dropped.
+///
+/// Every handle made with `clone` shares the same value. Writes to the file are made one at a
+/// time by `save`, which only holds a read guard while the value is being encoded, so readers
+/// aren’t kept waiting while the file is written. The box is saved as well when its last handle is
+/// dropped, if it has changes, following the default `DropPolicy` if that fails.
+pub struct SyncFileBox<T> {
+    inner: Arc<Inner<T>>,
+}
+
+struct Inner<T> {
+    path: Path,
+    val: RWLock<T>,
+    dirty: AtomicBool,
+    /// The state of the box’s file, which is locked while it is being written.
+    file: Mutex<FileState>,
+}
+
+struct FileState {
+    header: Header,
+    layout: Layout,
+    temp: TempFiles,
+    durability: Durability,
+}
+
+impl<'a, T> SyncFileBox<T> where T: Decodable<CompactDecoder, IoError>
+                                 + Encodable<EncoderWriter<'a, MemWriter>, IoError>
+                                 + Send + Sync {
+    /// Opens the box at the given path, reading the value stored inside. Saves left in the log of
+    /// a journaled `FileBox` are brought into the file first, as `FileBox::open` would.
+    pub fn open(p: &Path) -> IoResult<SyncFileBox<T>> {
+        let (header, payload) = try!(read_box(p, None, None, None, false, false));
+        let val = try!(decode_payload(&header, payload));
+        Ok(SyncFileBox::with_state(p, val, false, FileState {
+            header: header,
+            layout: layout::layout_of(p),
+            temp: Default::default(),
+            durability: Default::default(),
+        }))
+    }
+
+    /// Creates a new box at the given path with the given value, writing it straight away.
+    pub fn open_new(p: &Path, val: T) -> IoResult<SyncFileBox<T>> {
+        let b = SyncFileBox::with_state(p, val, true, FileState {
+            header: Header {
+                created_at: now(),
+                .. Header::new()
+            },
+            layout: Combined,
+            temp: Default::default(),
+            durability: Default::default(),
+        });
+        try!(b.save());
+        Ok(b)
+    }
+
+    fn with_state(p: &Path, val: T, dirty: bool, file: FileState) -> SyncFileBox<T> {
+        SyncFileBox {
+            inner: Arc::new(Inner {
+                path: p.clone(),
+                val: RWLock::new(val),
+                dirty: AtomicBool::new(dirty),
+                file: Mutex::new(file),
+            }),
+        }
+    }
+
+    /// Waits until the value isn’t being changed, and returns a guard for reading it.
+    pub fn read(&self) -> RWLockReadGuard<T> {
+        self.inner.val.read()
+    }
+
+    /// Waits until the value isn’t being read or changed, and returns a guard for changing it.
+    /// The box counts as changed from then on, whether or not the value is.
+    pub fn write(&self) -> RWLockWriteGuard<T> {
+        let guard = self.inner.val.write();
+        self.inner.dirty.store(true, SeqCst);
+        guard
+    }
+
+    /// Writes the value to the box’s file if it has changes since it was last saved. Changes made
+    /// while the file is being written are left for the next save.
+    pub fn save(&self) -> IoResult<()> {
+        self.inner.save()
+    }
+
+    /// Returns whether the value may have changed since the box was last read or saved.
+    pub fn has_changes(&self) -> bool {
+        self.inner.dirty.load(SeqCst)
+    }
+
+    /// The path of the box’s file.
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    /// Sets how the temporary files used to replace the box’s file are named and where they are
+    /// created, for every handle. See `FileBox::set_temp_files`.
+    pub fn set_temp_files(&self, temp: TempFiles) {
+        self.inner.file.lock().temp = temp;
+    }
+
+    /// Sets how far each write of the box goes to make sure the new value survives the machine
+    /// losing power, for every handle. See `Durability`.
+    pub fn set_durability(&self, durability: Durability) {
+        self.inner.file.lock().durability = durability;
+    }
+}
+
+impl<'a, T> Inner<T> where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> + Send + Sync {
+    fn save(&self) -> IoResult<()> {
+        let mut file = self.file.lock();
+        let (header, payload) = {
+            let val = self.val.read();
+            if !self.dirty.swap(false, SeqCst) {
+                return Ok(());
+            }
+            let c = compress::builtin(file.header.compressor.as_slice()).unwrap();
+            match encode_payload(&*val, &*c, &file.header) {
+                Ok(encoded) => encoded,
+                Err(e) => {
+                    self.dirty.store(true, SeqCst);
+                    return Err(e);
+                }
+            }
+        };
+        let path = &self.path;
+        let temp = file.temp.clone();
+        let durability = file.durability.clone();
+        match layout::store(path, file.layout.clone(), &header, payload.as_slice(), |bytes| {
+            write_atomic_durable(path, bytes, &temp, None, None, None, false, durability.clone(),
+                                 None)
+        }) {
+            Ok(()) => {
+                file.header = header;
+                Ok(())
+            }
+            Err(e) => {
+                self.dirty.store(true, SeqCst);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<T: Send + Sync> Clone for SyncFileBox<T> {
+    fn clone(&self) -> SyncFileBox<T> {
+        SyncFileBox {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<'a, T> Drop for Inner<T> where T: Encodable<EncoderWriter<'a, MemWriter>, IoError>
+                                     + Send + Sync {
+    fn drop(&mut self) {
+        match self.save() {
+            Err(ref e) if is_cancelled(e) => {}
+            Err(e) => droppolicy::handle(None, &self.path, e),
+            Ok(()) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs::PathExtensions;
+    use std::sync::Future;
+    use journal::log_path;
+    use super::SyncFileBox;
+    use super::super::{FileBox, peek};
+
+    #[test]
+    fn share_between_tasks() {
+        let path = Path::new("target/share_between_tasks");
+        let b = SyncFileBox::open_new(&path, vec![0u32]).unwrap();
+        let writers: Vec<Future<()>> = range(1u32, 5).map(|i| {
+            let b = b.clone();
+            Future::spawn(proc() {
+                b.write().push(i);
+                assert!(b.read().len() > 1);
+            })
+        }).collect();
+        for mut writer in writers.into_iter() {
+            writer.get();
+        }
+        assert!(b.has_changes());
+        b.save().unwrap();
+        assert!(!b.has_changes());
+        let mut saved = peek::<Vec<u32>>(&path).unwrap();
+        saved.sort();
+        assert_eq!(saved, vec![0, 1, 2, 3, 4]);
+        drop(b);
+
+        let b: SyncFileBox<Vec<u32>> = SyncFileBox::open(&path).unwrap();
+        assert_eq!(b.read().len(), 5);
+    }
+    #[test]
+    fn open_sync_with_log() {
+        let path = Path::new("target/open_sync_with_log");
+        let mut x = FileBox::open_new(&path, 1u32).unwrap();
+        x.set_journaled(Some(10)).unwrap();
+        *x = 2;
+        x.save().unwrap();
+        x._write_on_drop = false;
+        drop(x);
+        let b: SyncFileBox<u32> = SyncFileBox::open(&path).unwrap();
+        assert_eq!(*b.read(), 2);
+        assert!(!log_path(&path).exists());
+        assert_eq!(peek::<u32>(&path).unwrap(), 2);
+    }
+}
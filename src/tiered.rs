@@ -0,0 +1,219 @@
+//! Maps that keep their rarely used entries in a separate file.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io::{fs, IoError, IoResult, MemReader, MemWriter};
+use std::io::fs::PathExtensions;
+use serialize::{Decodable, Encodable};
+use bincode::{DecoderReader, EncoderWriter};
+
+use atomic::write_atomic;
+use compress::Deflate;
+use encoding::CompactDecoder;
+use filemap::FileMap;
+use header::Header;
+use super::{peek_compressed, encode_file};
+
+/// A map whose entries are split between a hot tier, which is read when the map is opened, and
+/// a cold tier, which is only read once one of its entries is needed.
+///
+/// The hot tier is a `FileMap` at the map’s path, and every change is made there. Entries marked
+/// cold with `make_cold` are moved to a compressed box file next to it, named after it with
+/// `.cold` added, so the hot file stays small and quick to open however many entries the map has
+/// in all. Looking up an entry that isn’t hot reads the cold tier the first time it is needed,
+/// and keeps it in memory from then on. If a crash leaves an entry in both tiers, the hot one is
+/// the one that counts.
+pub struct TieredMap<K, V> {
+    hot: FileMap<K, V>,
+    cold_path: Path,
+    cold: Option<HashMap<K, V>>,
+}
+
+impl<'a, K, V> TieredMap<K, V> where K: Decodable<DecoderReader<'a, MemReader>, IoError>
+                                      + Decodable<CompactDecoder, IoError>
+                                      + Encodable<EncoderWriter<'a, MemWriter>, IoError>
+                                      + Hash + Eq + Clone,
+                                   V: Decodable<DecoderReader<'a, MemReader>, IoError>
+                                      + Decodable<CompactDecoder, IoError>
+                                      + Encodable<EncoderWriter<'a, MemWriter>, IoError>
+                                      + Clone {
+    /// Opens the map stored at `p`, creating an empty one if it doesn’t exist. Only the hot tier
+    /// is read.
+    pub fn open(p: &Path) -> IoResult<TieredMap<K, V>> {
+        let mut name = p.filename().unwrap_or(b"filemap").to_vec();
+        name.push_all(b".cold");
+        Ok(TieredMap {
+            hot: try!(FileMap::open(p)),
+            cold_path: p.with_filename(name),
+            cold: None,
+        })
+    }
+
+    /// Returns the value under `key`, if there is one, reading the cold tier if it isn’t hot.
+    pub fn get(&mut self, key: &K) -> IoResult<Option<&V>> {
+        if !self.hot.contains_key(key) {
+            try!(self.load_cold());
+        }
+        match self.hot.get(key) {
+            Some(val) => Ok(Some(val)),
+            None => Ok(self.cold.as_ref().and_then(|cold| cold.get(key))),
+        }
+    }
+
+    /// Inserts `val` under `key` in the hot tier, and returns the value that was there before, if
+    /// any, from either tier.
+    pub fn insert(&mut self, key: K, val: V) -> IoResult<Option<V>> {
+        let old = if self.hot.contains_key(&key) { None } else { try!(self.remove_cold(&key)) };
+        let hot = try!(self.hot.insert(key, val));
+        Ok(hot.or(old))
+    }
+
+    /// Removes the entry under `key` from both tiers, and returns its value, if there was one.
+    pub fn remove(&mut self, key: &K) -> IoResult<Option<V>> {
+        let hot = try!(self.hot.remove(key));
+        let cold = try!(self.remove_cold(key));
+        Ok(hot.or(cold))
+    }
+
+    /// Moves the entries under `keys` that are hot to the cold tier, returning how many were
+    /// moved, and then compacts the hot tier so that it no longer holds them at all.
+    pub fn make_cold(&mut self, keys: &[K]) -> IoResult<uint> {
+        try!(self.load_cold());
+        let mut moved = Vec::new();
+        for key in keys.iter() {
+            match self.hot.get(key) {
+                Some(val) => {
+                    self.cold.as_mut().unwrap().insert(key.clone(), val.clone());
+                    moved.push(key);
+                }
+                None => {}
+            }
+        }
+        if moved.is_empty() {
+            return Ok(0);
+        }
+        // The cold tier is written first, so the entries are in at least one tier throughout.
+        try!(self.write_cold());
+        for key in moved.iter() {
+            try!(self.hot.remove(*key));
+        }
+        try!(self.hot.compact());
+        Ok(moved.len())
+    }
+
+    /// Moves the entry under `key` back to the hot tier, returning whether it was cold.
+    pub fn make_hot(&mut self, key: &K) -> IoResult<bool> {
+        try!(self.load_cold());
+        match self.cold.as_mut().unwrap().remove(key) {
+            Some(val) => {
+                try!(self.hot.insert(key.clone(), val));
+                try!(self.write_cold());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Returns whether the entry under `key` is in the cold tier, reading it if needed.
+    pub fn is_cold(&mut self, key: &K) -> IoResult<bool> {
+        if self.hot.contains_key(key) {
+            return Ok(false);
+        }
+        try!(self.load_cold());
+        Ok(self.cold.as_ref().unwrap().contains_key(key))
+    }
+
+    /// The number of entries in both tiers, reading the cold tier if needed.
+    pub fn len(&mut self) -> IoResult<uint> {
+        try!(self.load_cold());
+        let hot = &self.hot;
+        let cold = self.cold.as_ref().unwrap().keys().filter(|key| !hot.contains_key(*key));
+        Ok(hot.len() + cold.count())
+    }
+
+    /// The number of entries in the hot tier.
+    pub fn hot_len(&self) -> uint {
+        self.hot.len()
+    }
+
+    /// The path of the file holding the cold tier.
+    pub fn cold_path(&self) -> &Path {
+        &self.cold_path
+    }
+
+    fn load_cold(&mut self) -> IoResult<()> {
+        if self.cold.is_none() {
+            self.cold = Some(if self.cold_path.exists() {
+                try!(peek_compressed(&self.cold_path, &Deflate))
+            } else {
+                HashMap::new()
+            });
+        }
+        Ok(())
+    }
+
+    fn remove_cold(&mut self, key: &K) -> IoResult<Option<V>> {
+        if !self.cold_path.exists() {
+            return Ok(None);
+        }
+        try!(self.load_cold());
+        match self.cold.as_mut().unwrap().remove(key) {
+            Some(val) => {
+                try!(self.write_cold());
+                Ok(Some(val))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn write_cold(&self) -> IoResult<()> {
+        let cold = self.cold.as_ref().unwrap();
+        if cold.is_empty() {
+            if self.cold_path.exists() {
+                try!(fs::unlink(&self.cold_path));
+            }
+            return Ok(());
+        }
+        let (_, bytes) = try!(encode_file(cold, &Deflate, &Header::new()));
+        write_atomic(&self.cold_path, bytes.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs;
+    use std::io::fs::PathExtensions;
+    use super::TieredMap;
+
+    #[test]
+    fn move_entries_between_tiers() {
+        let path = Path::new("target/move_entries_between_tiers");
+        let _ = fs::unlink(&path);
+        let _ = fs::unlink(&path.with_filename("move_entries_between_tiers.cold"));
+        let (a, b, c) = ("a".to_string(), "b".to_string(), "c".to_string());
+        {
+            let mut m = TieredMap::open(&path).unwrap();
+            m.insert(a.clone(), vec![1u8, ..1000]).unwrap();
+            m.insert(b.clone(), vec![2u8, ..1000]).unwrap();
+            m.insert(c.clone(), vec![3u8]).unwrap();
+            let before = fs::stat(&path).unwrap().size;
+            assert_eq!(m.make_cold(&[a.clone(), b.clone()]).unwrap(), 2);
+            assert!(fs::stat(&path).unwrap().size < before);
+            assert!(m.cold_path().exists());
+        }
+
+        let mut m: TieredMap<String, Vec<u8>> = TieredMap::open(&path).unwrap();
+        assert_eq!(m.hot_len(), 1);
+        assert_eq!(m.get(&a).unwrap().unwrap()[0], 1);
+        assert!(m.is_cold(&b).unwrap());
+        assert_eq!(m.remove(&b).unwrap().unwrap()[0], 2);
+        assert!(m.make_hot(&a).unwrap());
+        assert!(!m.cold_path().exists());
+        drop(m);
+
+        let mut m: TieredMap<String, Vec<u8>> = TieredMap::open(&path).unwrap();
+        assert_eq!(m.hot_len(), 2);
+        assert_eq!(m.len().unwrap(), 2);
+        assert!(m.get(&b).unwrap().is_none());
+    }
+}
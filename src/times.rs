@@ -0,0 +1,202 @@
+//! Storing times and durations in a chosen representation.
+
+use std::default::Default;
+use std::time::Duration;
+use serialize::{Decodable, Decoder, Encodable, Encoder};
+use time::{mod, Timespec};
+
+/// How a `Timestamp` is stored, as a number of milliseconds since the Unix epoch.
+pub trait TimeRepr {
+    /// Encodes the time `ms`.
+    fn encode_time<E, S: Encoder<E>>(&self, ms: i64, s: &mut S) -> Result<(), E>;
+    /// Decodes a time encoded by `encode_time`.
+    fn decode_time<E, D: Decoder<E>>(&self, d: &mut D) -> Result<i64, E>;
+}
+
+/// How an `Elapsed` is stored, as a number of milliseconds.
+pub trait DurationRepr {
+    /// Encodes the duration `ms`.
+    fn encode_duration<E, S: Encoder<E>>(&self, ms: i64, s: &mut S) -> Result<(), E>;
+    /// Decodes a duration encoded by `encode_duration`.
+    fn decode_duration<E, D: Decoder<E>>(&self, d: &mut D) -> Result<i64, E>;
+}
+
+/// Stores times as whole seconds since the Unix epoch, and durations as whole seconds, as most
+/// Unix tools do. Anything less than a second is lost.
+#[deriving(Clone, PartialEq, Show, Default)]
+pub struct Seconds;
+
+/// Stores times as milliseconds since the Unix epoch, as JavaScript does, and durations as
+/// milliseconds.
+#[deriving(Clone, PartialEq, Show, Default)]
+pub struct Millis;
+
+/// Stores times as RFC 3339 strings in UTC, such as `2014-11-05T09:30:00Z`, which suits text
+/// formats read by people and other programs. Anything less than a second is lost.
+#[deriving(Clone, PartialEq, Show, Default)]
+pub struct Rfc3339;
+
+impl TimeRepr for Seconds {
+    fn encode_time<E, S: Encoder<E>>(&self, ms: i64, s: &mut S) -> Result<(), E> {
+        s.emit_i64(ms / 1000)
+    }
+
+    fn decode_time<E, D: Decoder<E>>(&self, d: &mut D) -> Result<i64, E> {
+        Ok(try!(d.read_i64()) * 1000)
+    }
+}
+
+impl DurationRepr for Seconds {
+    fn encode_duration<E, S: Encoder<E>>(&self, ms: i64, s: &mut S) -> Result<(), E> {
+        self.encode_time(ms, s)
+    }
+
+    fn decode_duration<E, D: Decoder<E>>(&self, d: &mut D) -> Result<i64, E> {
+        self.decode_time(d)
+    }
+}
+
+impl TimeRepr for Millis {
+    fn encode_time<E, S: Encoder<E>>(&self, ms: i64, s: &mut S) -> Result<(), E> {
+        s.emit_i64(ms)
+    }
+
+    fn decode_time<E, D: Decoder<E>>(&self, d: &mut D) -> Result<i64, E> {
+        d.read_i64()
+    }
+}
+
+impl DurationRepr for Millis {
+    fn encode_duration<E, S: Encoder<E>>(&self, ms: i64, s: &mut S) -> Result<(), E> {
+        self.encode_time(ms, s)
+    }
+
+    fn decode_duration<E, D: Decoder<E>>(&self, d: &mut D) -> Result<i64, E> {
+        self.decode_time(d)
+    }
+}
+
+static RFC3339_FORMAT: &'static str = "%Y-%m-%dT%H:%M:%SZ";
+
+impl TimeRepr for Rfc3339 {
+    fn encode_time<E, S: Encoder<E>>(&self, ms: i64, s: &mut S) -> Result<(), E> {
+        let tm = time::at_utc(Timespec::new(ms / 1000, 0));
+        s.emit_str(format!("{}", tm.rfc3339()).as_slice())
+    }
+
+    fn decode_time<E, D: Decoder<E>>(&self, d: &mut D) -> Result<i64, E> {
+        let text = try!(d.read_str());
+        match time::strptime(text.as_slice(), RFC3339_FORMAT) {
+            Ok(tm) => Ok(tm.to_timespec().sec * 1000),
+            Err(e) => Err(d.error(format!("invalid RFC 3339 time {}: {}", text, e).as_slice())),
+        }
+    }
+}
+
+/// A point in time, stored as `R` says.
+#[deriving(Clone, PartialEq, Show)]
+pub struct Timestamp<R> {
+    /// The time itself.
+    pub time: Timespec,
+    repr: R,
+}
+
+impl<R: TimeRepr + Default> Timestamp<R> {
+    /// Wraps `time`, to be stored as `R` says.
+    pub fn new(time: Timespec) -> Timestamp<R> {
+        Timestamp {
+            time: time,
+            repr: Default::default(),
+        }
+    }
+
+    /// The current time.
+    pub fn now() -> Timestamp<R> {
+        Timestamp::new(time::get_time())
+    }
+}
+
+impl<E, S: Encoder<E>, R: TimeRepr> Encodable<S, E> for Timestamp<R> {
+    fn encode(&self, s: &mut S) -> Result<(), E> {
+        let ms = self.time.sec * 1000 + self.time.nsec as i64 / 1_000_000;
+        self.repr.encode_time(ms, s)
+    }
+}
+
+impl<E, D: Decoder<E>, R: TimeRepr + Default> Decodable<D, E> for Timestamp<R> {
+    fn decode(d: &mut D) -> Result<Timestamp<R>, E> {
+        let repr: R = Default::default();
+        let ms = try!(repr.decode_time(d));
+        Ok(Timestamp {
+            time: Timespec::new(ms / 1000, (ms % 1000) as i32 * 1_000_000),
+            repr: repr,
+        })
+    }
+}
+
+/// A length of time, stored as `R` says.
+#[deriving(Clone, PartialEq, Show)]
+pub struct Elapsed<R> {
+    /// The duration itself.
+    pub duration: Duration,
+    repr: R,
+}
+
+impl<R: DurationRepr + Default> Elapsed<R> {
+    /// Wraps `duration`, to be stored as `R` says.
+    pub fn new(duration: Duration) -> Elapsed<R> {
+        Elapsed {
+            duration: duration,
+            repr: Default::default(),
+        }
+    }
+}
+
+impl<E, S: Encoder<E>, R: DurationRepr> Encodable<S, E> for Elapsed<R> {
+    fn encode(&self, s: &mut S) -> Result<(), E> {
+        self.repr.encode_duration(self.duration.num_milliseconds(), s)
+    }
+}
+
+impl<E, D: Decoder<E>, R: DurationRepr + Default> Decodable<D, E> for Elapsed<R> {
+    fn decode(d: &mut D) -> Result<Elapsed<R>, E> {
+        let repr: R = Default::default();
+        let ms = try!(repr.decode_duration(d));
+        Ok(Elapsed {
+            duration: Duration::milliseconds(ms),
+            repr: repr,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::default::Default;
+    use std::time::Duration;
+    use bincode;
+    use time::Timespec;
+    use super::{Timestamp, Elapsed, Seconds, Millis, Rfc3339};
+    use super::super::{Format, Json};
+
+    #[deriving(Encodable, Decodable, PartialEq, Show)]
+    struct Event {
+        at: Timestamp<Rfc3339>,
+        took: Elapsed<Millis>,
+    }
+
+    #[test]
+    fn time_representations() {
+        let json: Json = Default::default();
+        let event = Event {
+            at: Timestamp::new(Timespec::new(1415179800, 0)),
+            took: Elapsed::new(Duration::milliseconds(1500)),
+        };
+        let bytes = json.encode(&event).unwrap();
+        assert_eq!(String::from_utf8(bytes.clone()).unwrap().as_slice(),
+                   r#"{"at":"2014-11-05T09:30:00Z","took":1500}"#);
+        assert_eq!(json.decode(bytes).unwrap(), event);
+
+        let secs: Timestamp<Seconds> = Timestamp::new(Timespec::new(1415179800, 250_000_000));
+        assert_eq!(bincode::encode(&secs).unwrap(), bincode::encode(&1415179800i64).unwrap());
+    }
+}
@@ -0,0 +1,112 @@
+//! Short descriptions of what happens to boxes, for logs gathered from the people using a program.
+
+use std::io::{fs, IoError};
+use std::sync::Arc;
+
+use conflicts::ConflictCopy;
+use header::Header;
+use observe::{BoxObserver, SharedObserver};
+
+/// Receives each line a `tracer` writes.
+pub type TraceHook = fn(&str);
+
+/// Returns a `BoxObserver` that describes every event in the life of the boxes it is attached to
+/// in a line of text, and hands the line to `hook`, such as to add it to the program’s log.
+///
+/// Lines start with the name of the box’s file, and say what happened along with the version,
+/// size and number of saves of the file, such as `prefs.box: loaded v3, 1.2 KB, gen 57`. They
+/// never include the value of a box, so they are safe to gather into support bundles sent in by
+/// the people using a program.
+pub fn tracer(hook: TraceHook) -> SharedObserver {
+    Arc::new(box Tracer { hook: hook } as Box<BoxObserver + Send + Sync + 'static>)
+}
+
+struct Tracer {
+    hook: TraceHook,
+}
+
+impl BoxObserver for Tracer {
+    fn on_load(&self, p: &Path, header: &Header) {
+        (self.hook)(file_line(p, "loaded", header).as_slice());
+    }
+
+    fn on_save(&self, p: &Path, header: &Header) {
+        (self.hook)(file_line(p, "saved", header).as_slice());
+    }
+
+    fn on_conflict(&self, p: &Path, copies: &[ConflictCopy]) {
+        let line = format!("{}: {} conflicting {} found", name(p), copies.len(),
+                           if copies.len() == 1 { "copy" } else { "copies" });
+        (self.hook)(line.as_slice());
+    }
+
+    fn on_corruption(&self, p: &Path, e: &IoError) {
+        let line = match e.detail {
+            Some(ref detail) => format!("{}: corrupt, {}", name(p), detail),
+            None => format!("{}: corrupt", name(p)),
+        };
+        (self.hook)(line.as_slice());
+    }
+
+    fn on_recovery(&self, p: &Path, slot: &Path) {
+        (self.hook)(format!("{}: recovered from {}", name(p), name(slot)).as_slice());
+    }
+}
+
+/// Describes the file at `p`, after `event` happened to it, leaving words out that can’t be found.
+fn file_line(p: &Path, event: &str, header: &Header) -> String {
+    let mut line = format!("{}: {} v{}", name(p), event, header.version);
+    match fs::stat(p) {
+        Ok(stat) => line.push_str(format!(", {}", size(stat.size)).as_slice()),
+        Err(_) => {}
+    }
+    line.push_str(format!(", gen {}", header.saves).as_slice());
+    line
+}
+
+fn name(p: &Path) -> String {
+    p.filename_display().to_string()
+}
+
+/// Writes `bytes` with one decimal place in the largest unit it has a whole one of.
+fn size(bytes: u64) -> String {
+    let units = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut n = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while n >= 1024.0 && unit + 1 < units.len() {
+        n /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", n, units[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+    use super::{tracer, size};
+    use super::super::FileBox;
+
+    static LINES: AtomicUint = INIT_ATOMIC_UINT;
+
+    fn count(line: &str) {
+        assert!(line.starts_with("trace_box_events: "));
+        LINES.fetch_add(1, SeqCst);
+    }
+
+    #[test]
+    fn trace_box_events() {
+        let path = Path::new("target/trace_box_events");
+        let mut b = FileBox::open_new(&path, vec![0u8, ..2000]).unwrap();
+        b.set_box_observer(Some(tracer(count)));
+        b.save().unwrap();
+        b.reload().unwrap();
+        assert_eq!(LINES.load(SeqCst), 2);
+
+        assert_eq!(size(900).as_slice(), "900 B");
+        assert_eq!(size(1229).as_slice(), "1.2 KB");
+        assert_eq!(size(5 * 1024 * 1024).as_slice(), "5.0 MB");
+    }
+}
@@ -0,0 +1,262 @@
+//! All-or-nothing writes to several boxes stored in one directory.
+
+use std::mem;
+use std::io::{mod, fs, IoError, IoResult, MemWriter};
+use std::io::fs::PathExtensions;
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use atomic::write_atomic;
+use encoding::{CompactDecoder, decode_bincode};
+use super::{Header, NoCompression, peek, write_value, encode_file, unpack};
+
+/// The name of the file listing which data file currently holds each box in the directory.
+static MANIFEST: &'static str = "MANIFEST";
+
+/// The contents of a manifest: the generation of the last commit and, for every box, its name and
+/// the name of the file holding its committed value.
+type Manifest = (u64, Vec<(String, String)>);
+
+/// Checks the values a transaction is about to commit, returning an error to stop the commit.
+///
+/// Hooks can enforce invariants that span several boxes, for example that an index only refers
+/// to records that exist, which no single box can check on its own.
+pub type CommitHook = fn(&Pending) -> IoResult<()>;
+
+/// A set of writes to named boxes in a directory that are committed together.
+///
+/// Each commit writes the staged values to fresh files and then atomically replaces the directory’s
+/// manifest, so readers going through `DirTransaction::read` see either all of the staged values or
+/// none of them.
+pub struct DirTransaction {
+    dir: Path,
+    staged: Vec<(String, Vec<u8>)>,
+    hooks: Vec<CommitHook>,
+}
+
+impl DirTransaction {
+    /// Starts a transaction over the given directory, creating it if it doesn’t exist.
+    pub fn new(dir: &Path) -> IoResult<DirTransaction> {
+        if !dir.is_dir() {
+            try!(fs::mkdir_recursive(dir, io::USER_RWX));
+        }
+        Ok(DirTransaction {
+            dir: dir.clone(),
+            staged: Vec::new(),
+            hooks: Vec::new(),
+        })
+    }
+
+    /// Stages a new value for the box with the given name. Nothing is written until `commit` is
+    /// called; staging the same name twice replaces the earlier value.
+    pub fn stage<'a, T>(&mut self, name: &str, val: &T) -> IoResult<()>
+            where T: Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+        try!(check_name(name));
+        let (_, bytes) = try!(encode_file(val, &NoCompression, &Header::new()));
+        self.staged.retain(|&(ref n, _)| n.as_slice() != name);
+        self.staged.push((name.to_string(), bytes));
+        Ok(())
+    }
+
+    /// Adds a hook that is run before the transaction is committed. Hooks run in the order they
+    /// were added, and the first to return an error stops the commit, which then returns the error
+    /// without having written anything.
+    pub fn add_hook(&mut self, hook: CommitHook) {
+        self.hooks.push(hook);
+    }
+
+    /// Commits every staged value. If this fails, the boxes keep their previously committed values.
+    pub fn commit(self) -> IoResult<()> {
+        let manifest_path = self.dir.join(MANIFEST);
+        let (generation, mut entries) = try!(read_manifest(&self.dir));
+        let generation = generation + 1;
+
+        {
+            let pending = Pending {
+                dir: &self.dir,
+                staged: self.staged.as_slice(),
+                committed: entries.as_slice(),
+            };
+            for hook in self.hooks.iter() {
+                try!((*hook)(&pending));
+            }
+        }
+
+        let mut replaced = Vec::new();
+        for &(ref name, ref bytes) in self.staged.iter() {
+            let file = format!("{}.{}", name, generation);
+            try!(write_atomic(&self.dir.join(file.as_slice()), bytes.as_slice()));
+            match entries.iter().position(|&(ref n, _)| n == name) {
+                Some(i) => {
+                    let (_, old) = mem::replace(&mut entries[i], (name.clone(), file));
+                    replaced.push(old);
+                }
+                None => entries.push((name.clone(), file)),
+            }
+        }
+
+        try!(write_value(&manifest_path, &(generation, entries)));
+
+        // The old files are no longer referenced, so failing to remove them loses nothing.
+        for file in replaced.iter() {
+            let _ = fs::unlink(&self.dir.join(file.as_slice()));
+        }
+        Ok(())
+    }
+
+    /// Returns the path of the file holding the last committed value of the named box in `dir`, or
+    /// `None` if no value has been committed for it.
+    pub fn committed_path(dir: &Path, name: &str) -> IoResult<Option<Path>> {
+        let (_, entries) = try!(read_manifest(dir));
+        Ok(entries.into_iter()
+                  .find(|&(ref n, _)| n.as_slice() == name)
+                  .map(|(_, file)| dir.join(file.as_slice())))
+    }
+
+    /// Reads the last committed value of the named box in `dir`.
+    pub fn read<T>(dir: &Path, name: &str) -> IoResult<T>
+            where T: Decodable<CompactDecoder, IoError> {
+        match try!(DirTransaction::committed_path(dir, name)) {
+            Some(p) => peek(&p),
+            None => Err(IoError {
+                kind: io::FileNotFound,
+                desc: "no value has been committed for this box",
+                detail: Some(name.to_string()),
+            }),
+        }
+    }
+}
+
+/// The values of the boxes in a directory as they will be once a transaction has been committed,
+/// as seen by its `CommitHook`s.
+pub struct Pending<'a> {
+    dir: &'a Path,
+    staged: &'a [(String, Vec<u8>)],
+    committed: &'a [(String, String)],
+}
+
+impl<'a> Pending<'a> {
+    /// The names of the boxes the transaction writes to, in the order they were first staged.
+    pub fn staged(&self) -> Vec<&str> {
+        self.staged.iter().map(|&(ref name, _)| name.as_slice()).collect()
+    }
+
+    /// The names of every box that will have a value after the commit, staged or not.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = self.staged();
+        for &(ref name, _) in self.committed.iter() {
+            if !names.contains(&name.as_slice()) {
+                names.push(name.as_slice());
+            }
+        }
+        names
+    }
+
+    /// The value the named box will have after the commit: the staged value if there is one, and
+    /// otherwise the value committed earlier. Returns `None` if the box will have no value.
+    pub fn value<T>(&self, name: &str) -> IoResult<Option<T>>
+            where T: Decodable<CompactDecoder, IoError> {
+        for &(ref n, ref bytes) in self.staged.iter() {
+            if n.as_slice() == name {
+                let (_, payload) = try!(unpack(bytes.clone(), None));
+                return decode_bincode(payload).map(Some);
+            }
+        }
+        for &(ref n, ref file) in self.committed.iter() {
+            if n.as_slice() == name {
+                return peek(&self.dir.join(file.as_slice())).map(Some);
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Reads the manifest of `dir`, treating a missing manifest as an empty one.
+fn read_manifest(dir: &Path) -> IoResult<Manifest> {
+    let p = dir.join(MANIFEST);
+    if p.exists() {
+        peek(&p)
+    } else {
+        Ok((0, Vec::new()))
+    }
+}
+
+/// Box names become file names, so they must not be able to escape the directory.
+fn check_name(name: &str) -> IoResult<()> {
+    if name.is_empty() || name.starts_with(".") || name == MANIFEST
+       || name.chars().any(|c| c == '/' || c == '\\') {
+        Err(IoError {
+            kind: io::InvalidInput,
+            desc: "invalid box name",
+            detail: Some(name.to_string()),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{mod, IoError, IoResult};
+    use std::io::fs::PathExtensions;
+    use super::{DirTransaction, Pending};
+
+    #[test]
+    fn commit_then_read() {
+        let dir = Path::new("target/commit_then_read");
+        let mut t = DirTransaction::new(&dir).unwrap();
+        t.stage("a", &1i).unwrap();
+        t.stage("b", &"two".to_string()).unwrap();
+        t.commit().unwrap();
+        assert_eq!(DirTransaction::read::<int>(&dir, "a").unwrap(), 1);
+        assert_eq!(DirTransaction::read::<String>(&dir, "b").unwrap(), "two".to_string());
+
+        let old = DirTransaction::committed_path(&dir, "a").unwrap().unwrap();
+        let mut t = DirTransaction::new(&dir).unwrap();
+        t.stage("a", &3i).unwrap();
+        t.commit().unwrap();
+        assert_eq!(DirTransaction::read::<int>(&dir, "a").unwrap(), 3);
+        assert_eq!(DirTransaction::read::<String>(&dir, "b").unwrap(), "two".to_string());
+        assert!(!old.exists());
+    }
+
+    #[test]
+    fn uncommitted_writes_are_invisible() {
+        let dir = Path::new("target/uncommitted_writes_are_invisible");
+        let mut t = DirTransaction::new(&dir).unwrap();
+        t.stage("a", &1i).unwrap();
+        drop(t);
+        assert!(DirTransaction::read::<int>(&dir, "a").is_err());
+    }
+
+    /// Every entry of the index must name a record that exists.
+    fn check_index(pending: &Pending) -> IoResult<()> {
+        let index: Vec<String> = try!(pending.value("index")).unwrap_or(Vec::new());
+        for name in index.iter() {
+            if try!(pending.value::<int>(name.as_slice())).is_none() {
+                return Err(IoError {
+                    kind: io::InvalidInput,
+                    desc: "the index refers to a missing record",
+                    detail: Some(name.clone()),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn hooks_veto_commits() {
+        let dir = Path::new("target/hooks_veto_commits");
+        let mut t = DirTransaction::new(&dir).unwrap();
+        t.add_hook(check_index);
+        t.stage("x", &1i).unwrap();
+        t.stage("index", &vec!["x".to_string()]).unwrap();
+        t.commit().unwrap();
+
+        let mut t = DirTransaction::new(&dir).unwrap();
+        t.add_hook(check_index);
+        t.stage("index", &vec!["x".to_string(), "y".to_string()]).unwrap();
+        assert_eq!(t.commit().unwrap_err().desc, "the index refers to a missing record");
+        assert_eq!(DirTransaction::read::<Vec<String>>(&dir, "index").unwrap().len(), 1);
+    }
+}
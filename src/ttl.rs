@@ -0,0 +1,88 @@
+//! Boxes whose values expire, for using boxes as entries of a cache on disk.
+
+use std::default::Default;
+use std::io::{fs, IoError, IoResult, MemWriter};
+use std::io::fs::PathExtensions;
+use std::time::Duration;
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use clock::{Clock, SystemClock};
+use encoding::CompactDecoder;
+use super::FileBox;
+
+impl<'a, T> FileBox<T> where T: Decodable<CompactDecoder, IoError>
+                              + Encodable<EncoderWriter<'a, MemWriter>, IoError>
+                              + Default {
+    /// Like `open_or_new`, but if the box’s file was last written more than `ttl` ago, its value
+    /// is treated as missing, and the box starts again from the default value, which is written
+    /// straight away. The age of the box is judged by when its file was last modified. The box
+    /// remembers `ttl`, so `is_stale` can tell when the value it holds has expired in turn.
+    pub fn open_with_ttl(p: &Path, ttl: Duration) -> IoResult<FileBox<T>> {
+        let expired = p.exists() && {
+            let modified = try!(fs::stat(p)).modified;
+            since(SystemClock.now_ms(), modified) > ttl
+        };
+        let mut b = if expired { try!(FileBox::new(p)) } else { try!(FileBox::open_or_new(p)) };
+        b._ttl = Some(ttl);
+        Ok(b)
+    }
+}
+
+impl<T> FileBox<T> {
+    /// How long ago the box’s file was last written, by this box or by whoever wrote the value it
+    /// last read, as told by the box’s clock. This is zero if the box has no file.
+    pub fn age(&self) -> Duration {
+        match self._seen {
+            Some(modified) => since(self._clock.now_ms(), modified),
+            None => Duration::zero(),
+        }
+    }
+
+    /// Returns whether the box was opened with `open_with_ttl`, and is older than the time to
+    /// live it was opened with. Writing the box makes it fresh again.
+    pub fn is_stale(&self) -> bool {
+        match self._ttl {
+            Some(ttl) => self.age() > ttl,
+            None => false,
+        }
+    }
+}
+
+/// How long before `now` the time `then` was, both in milliseconds since the Unix epoch, or zero
+/// if it is in the future, as a file written by a machine whose clock is ahead can be.
+fn since(now: u64, then: u64) -> Duration {
+    if now > then {
+        Duration::milliseconds((now - then) as i64)
+    } else {
+        Duration::zero()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs;
+    use std::time::Duration;
+    use super::super::{FileBox, ManualClock, SystemClock, Clock};
+
+    #[test]
+    fn expire_old_values() {
+        let path = Path::new("target/expire_old_values");
+        drop(FileBox::open_new(&path, 5u32).unwrap());
+        let hour_ago = SystemClock.now_ms() - 3_600_000;
+        fs::change_file_times(&path, hour_ago, hour_ago).unwrap();
+        let b = FileBox::<u32>::open_with_ttl(&path, Duration::hours(2)).unwrap();
+        assert_eq!(*b, 5);
+        assert!(!b.is_stale());
+        drop(b);
+
+        let mut b = FileBox::<u32>::open_with_ttl(&path, Duration::minutes(30)).unwrap();
+        assert_eq!(*b, 0);
+        let clock = ManualClock::new(SystemClock.now_ms());
+        b.set_clock(box clock.clone() as Box<Clock + 'static>);
+        assert!(!b.is_stale());
+        clock.advance(Duration::minutes(31));
+        assert!(b.age() > Duration::minutes(30));
+        assert!(b.is_stale());
+    }
+}
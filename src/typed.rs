@@ -0,0 +1,104 @@
+//! Maps with accessors named after what they hold.
+
+/// Defines a struct wrapping a `FileMap`, with methods named after the values it holds.
+///
+/// A program keeping several kinds of records in maps ends up calling `get` and `insert` with
+/// keys that say nothing about which map they belong to. This macro names the accessors instead,
+/// so that a map of users is read with `users.user(&id)` and changed with
+/// `users.insert_user(id, user)`. The accessors take and return what the map’s own methods do,
+/// and `map` and `map_mut` give the map itself for everything else, such as `iter` and `compact`.
+///
+/// ```rust,ignore
+/// typed_map! {
+///     /// The users of the site, by id.
+///     pub struct Users: u64 => User {
+///         get: user,
+///         insert: insert_user,
+///         remove: remove_user,
+///     }
+/// }
+///
+/// let mut users = try!(Users::open(&Path::new("users.map")));
+/// try!(users.insert_user(1, User { name: "ann".to_string() }));
+/// assert_eq!(users.user(&1).unwrap().name.as_slice(), "ann");
+/// ```
+#[macro_export]
+macro_rules! typed_map {
+    ($(#[$attr:meta])* pub struct $name:ident: $k:ty => $v:ty {
+        get: $get:ident,
+        insert: $insert:ident,
+        remove: $remove:ident,
+    }) => {
+        $(#[$attr])*
+        pub struct $name {
+            map: $crate::FileMap<$k, $v>,
+        }
+
+        impl $name {
+            /// Opens the map stored in the file at `p`, creating an empty one if the file
+            /// doesn’t exist.
+            pub fn open(p: &Path) -> ::std::io::IoResult<$name> {
+                Ok($name { map: try!($crate::FileMap::open(p)) })
+            }
+
+            /// Returns the value under `key`, if there is one.
+            pub fn $get(&self, key: &$k) -> Option<&$v> {
+                self.map.get(key)
+            }
+
+            /// Inserts `val` under `key`, returning the value it replaces, if any.
+            pub fn $insert(&mut self, key: $k, val: $v) -> ::std::io::IoResult<Option<$v>> {
+                self.map.insert(key, val)
+            }
+
+            /// Removes the value under `key`, returning it if there was one.
+            pub fn $remove(&mut self, key: &$k) -> ::std::io::IoResult<Option<$v>> {
+                self.map.remove(key)
+            }
+
+            /// The map itself.
+            pub fn map(&self) -> &$crate::FileMap<$k, $v> {
+                &self.map
+            }
+
+            /// The map itself, for changing it.
+            pub fn map_mut(&mut self) -> &mut $crate::FileMap<$k, $v> {
+                &mut self.map
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::fs;
+
+    #[deriving(Encodable, Decodable, PartialEq, Show)]
+    struct User {
+        name: String,
+    }
+
+    typed_map! {
+        pub struct Users: u64 => User {
+            get: user,
+            insert: insert_user,
+            remove: remove_user,
+        }
+    }
+
+    #[test]
+    fn named_accessors() {
+        let path = Path::new("target/named_accessors");
+        let _ = fs::unlink(&path);
+        let mut users = Users::open(&path).unwrap();
+        users.insert_user(1, User { name: "ann".to_string() }).unwrap();
+        users.insert_user(2, User { name: "bob".to_string() }).unwrap();
+        assert_eq!(users.remove_user(&2).unwrap().unwrap().name.as_slice(), "bob");
+        drop(users);
+
+        let users = Users::open(&path).unwrap();
+        assert_eq!(users.user(&1), Some(&User { name: "ann".to_string() }));
+        assert_eq!(users.user(&2), None);
+        assert_eq!(users.map().len(), 1);
+    }
+}
@@ -0,0 +1,85 @@
+//! Recording which type a box holds, to catch boxes opened as the wrong type.
+
+use std::io::{mod, IoError, IoResult, MemWriter};
+use serialize::{Decodable, Encodable};
+use bincode::EncoderWriter;
+
+use encoding::CompactDecoder;
+use header::Header;
+use super::FileBox;
+
+static TYPE_MISMATCH: &'static str = "the box holds a different type";
+
+/// Returns whether `e` means that a box was opened as a type other than the one its header
+/// records, which would otherwise decode to garbage whenever the encodings of the two types
+/// happen to line up.
+pub fn is_type_mismatch(e: &IoError) -> bool {
+    e.kind == io::InvalidInput && e.desc == TYPE_MISMATCH
+}
+
+/// Checks that the box with `header` can be opened as the type tagged `tag`. Boxes without a tag,
+/// such as those written before tags were used, are assumed to hold the right type.
+pub fn check(header: &Header, tag: &str) -> IoResult<()> {
+    if header.type_tag.is_empty() || header.type_tag.as_slice() == tag {
+        Ok(())
+    } else {
+        Err(IoError {
+            kind: io::InvalidInput,
+            desc: TYPE_MISMATCH,
+            detail: Some(format!("expected {}, found {}", tag, header.type_tag)),
+        })
+    }
+}
+
+impl<'a, T> FileBox<T> where T: Decodable<CompactDecoder, IoError>
+                              + Encodable<EncoderWriter<'a, MemWriter>, IoError> {
+    /// Opens the box at `p` as `open` does, checking that it holds the type tagged `tag`, and
+    /// failing with an error for which `is_type_mismatch` is true if its header records another.
+    /// Untagged boxes are tagged the next time they are written.
+    ///
+    /// Tags are chosen by the program, such as the name of the type, and each type must have its
+    /// own tag, which must stay the same for its boxes to keep opening.
+    pub fn open_tagged(p: &Path, tag: &str) -> IoResult<FileBox<T>> {
+        let mut b = try!(FileBox::open(p));
+        try!(b.set_type_tag(tag));
+        Ok(b)
+    }
+
+    /// Creates a new box at `p` holding `val`, as `open_new` does, whose header records that it
+    /// holds the type tagged `tag`. See `open_tagged`.
+    pub fn open_new_tagged(p: &Path, val: T, tag: &str) -> IoResult<FileBox<T>> {
+        let mut b = try!(FileBox::open_new(p, val));
+        b._header.type_tag = tag.to_string();
+        Ok(b)
+    }
+
+    /// Checks that the box holds the type tagged `tag`, as `open_tagged` does, and records the tag
+    /// in its header from the next write on, for boxes opened some other way.
+    pub fn set_type_tag(&mut self, tag: &str) -> IoResult<()> {
+        try!(check(&self._header, tag));
+        self._header.type_tag = tag.to_string();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_type_mismatch;
+    use super::super::{FileBox, write_value};
+
+    #[test]
+    fn open_as_wrong_type() {
+        let path = Path::new("target/open_as_wrong_type");
+        // Both types are encoded as eight bytes, so opening one as the other would succeed.
+        drop(FileBox::open_new_tagged(&path, 7u64, "count").unwrap());
+        let e = FileBox::<(u32, u32)>::open_tagged(&path, "point").err().unwrap();
+        assert!(is_type_mismatch(&e));
+        assert_eq!(*FileBox::<u64>::open_tagged(&path, "count").unwrap(), 7);
+
+        write_value(&path, &3u64).unwrap();
+        let mut b = FileBox::<u64>::open_tagged(&path, "count").unwrap();
+        *b = 4;
+        drop(b);
+        assert!(FileBox::<(u32, u32)>::open_tagged(&path, "point").is_err());
+    }
+}
@@ -0,0 +1,198 @@
+//! Checking a directory tree of box files that is kept as a backup.
+
+use std::collections::HashMap;
+use std::io::{mod, fs, IoError, IoResult};
+use std::io::fs::PathExtensions;
+use serialize::Decodable;
+
+use compress;
+use encoding::{Encoding, CompactDecoder, decode_bincode};
+use header::Header;
+use schema::SchemaTooNew;
+use super::{check_bincode, open_header, read_encoded};
+
+/// A directory tree of box files that is treated as an immutable backup.
+///
+/// Nothing is ever written to the files of a backup: values are only read, never opened as boxes.
+/// Every read checks the payload against the checksum in its header, and files whose headers don’t
+/// have one are refused, since they can’t be told apart from corrupt ones.
+pub struct Backup {
+    root: Path,
+    versions: HashMap<String, u64>,
+}
+
+/// The result of checking every file in a backup.
+#[deriving(Show)]
+pub struct VerifyReport {
+    /// The files whose payloads matched their checksums and could be read by this version.
+    pub ok: Vec<Path>,
+    /// The files that were written before boxes recorded checksums, so couldn’t be checked.
+    pub unchecked: Vec<Path>,
+    /// The files that are damaged, along with what is wrong with them.
+    pub corrupt: Vec<(Path, IoError)>,
+    /// The files that were written with a compressor, encoding or type version this version
+    /// doesn’t know, along with what it doesn’t know.
+    pub incompatible: Vec<(Path, IoError)>,
+}
+
+impl VerifyReport {
+    /// Returns whether every file in the backup was checked and found to be intact.
+    pub fn is_clean(&self) -> bool {
+        self.unchecked.is_empty() && self.corrupt.is_empty() && self.incompatible.is_empty()
+    }
+}
+
+impl Backup {
+    /// Opens the backup in the directory `root`. Nothing is read until the backup is used.
+    pub fn new(root: &Path) -> Backup {
+        Backup {
+            root: root.clone(),
+            versions: HashMap::new(),
+        }
+    }
+
+    /// Sets the newest version of the type with the given tag that can be read, so that files
+    /// holding newer versions are reported as incompatible.
+    pub fn set_max_version(&mut self, tag: &str, version: u64) {
+        self.versions.insert(tag.to_string(), version);
+    }
+
+    /// Reads the value of the box at `rel`, relative to the root of the backup.
+    pub fn read<T>(&self, rel: &Path) -> IoResult<T>
+            where T: Decodable<CompactDecoder, IoError> {
+        let p = self.root.join(rel);
+        let header = try!(open_header(&p)).header;
+        try!(self.check_compatible(&header));
+        try!(check_bincode(&header));
+        decode_bincode(try!(read_checked(&p)))
+    }
+
+    /// Checks every box file in the backup, without stopping at the first bad one.
+    pub fn verify(&self) -> IoResult<VerifyReport> {
+        let mut files = Vec::new();
+        for p in try!(fs::walk_dir(&self.root)) {
+            // Only box files themselves are checked, not the other files kept next to them.
+            match p.extension_str() {
+                Some("tmp") | Some("meta") | Some("lock") | Some("blob") | Some("json")
+                    | Some("info") => {}
+                _ if p.is_file() => files.push(p),
+                _ => {}
+            }
+        }
+        files.sort();
+
+        let mut report = VerifyReport {
+            ok: Vec::new(),
+            unchecked: Vec::new(),
+            corrupt: Vec::new(),
+            incompatible: Vec::new(),
+        };
+        for p in files.into_iter() {
+            let header = match open_header(&p) {
+                Ok(info) => info.header,
+                Err(e) if SchemaTooNew::from_error(&e).is_some() => {
+                    report.incompatible.push((p, e));
+                    continue;
+                }
+                Err(e) => {
+                    report.corrupt.push((p, e));
+                    continue;
+                }
+            };
+            match self.check_compatible(&header) {
+                Ok(()) => {}
+                Err(e) => {
+                    report.incompatible.push((p, e));
+                    continue;
+                }
+            }
+            match read_checked(&p) {
+                Ok(_) => report.ok.push(p),
+                Err(ref e) if e.desc == UNCHECKED => report.unchecked.push(p),
+                Err(e) => report.corrupt.push((p, e)),
+            }
+        }
+        Ok(report)
+    }
+
+    fn check_compatible(&self, header: &Header) -> IoResult<()> {
+        if compress::builtin(header.compressor.as_slice()).is_none() {
+            return Err(incompatible("the box was compressed with an unknown compressor",
+                                    header.compressor.clone()));
+        }
+        if Encoding::from_name(header.encoding.as_slice()).is_none() {
+            return Err(incompatible("the box was written with an unknown encoding",
+                                    header.encoding.clone()));
+        }
+        match self.versions.get(&header.type_tag) {
+            Some(&max) if header.version > max => Err(SchemaTooNew {
+                schema: header.type_tag.clone(),
+                found: header.version,
+                supported: max,
+            }.to_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+static UNCHECKED: &'static str = "the box has no checksum to verify it against";
+
+/// Reads the payload of the box at `p`, failing if its header has no checksum.
+fn read_checked(p: &Path) -> IoResult<Vec<u8>> {
+    // Reading the payload verifies the checksum when there is one.
+    let (header, payload) = try!(read_encoded(p, None, None, None, false));
+    if header.checksum.is_none() {
+        return Err(IoError {
+            kind: io::InvalidInput,
+            desc: UNCHECKED,
+            detail: Some(p.display().to_string()),
+        });
+    }
+    Ok(payload)
+}
+
+fn incompatible(desc: &'static str, detail: String) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: desc,
+        detail: Some(detail),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{fs, File};
+    use super::Backup;
+    use super::super::{write_value, header, Header};
+
+    #[test]
+    fn verify_backup() {
+        let root = Path::new("target/verify_backup");
+        let _ = fs::rmdir_recursive(&root);
+        fs::mkdir_recursive(&root.join("a"), ::std::io::USER_RWX).unwrap();
+        write_value(&root.join("good"), &1u32).unwrap();
+        write_value(&root.join("a/damaged"), &vec![1u8, 2, 3]).unwrap();
+        let mut bytes = File::open(&root.join("a/damaged")).read_to_end().unwrap();
+        let last = bytes.len() - 1;
+        bytes.as_mut_slice()[last] = 9;
+        File::create(&root.join("a/damaged")).write(bytes.as_slice()).unwrap();
+        let mut h = Header::new();
+        h.compressor = "zstd".to_string();
+        let framed = header::frame(&h, b"???").unwrap();
+        File::create(&root.join("unknown")).write(framed.as_slice()).unwrap();
+        // A bare payload, as boxes were stored before they had headers.
+        File::create(&root.join("old")).write(::bincode::encode(&2u32).unwrap().as_slice())
+                                       .unwrap();
+
+        let backup = Backup::new(&root);
+        assert_eq!(backup.read::<u32>(&Path::new("good")).unwrap(), 1);
+        assert!(backup.read::<Vec<u8>>(&Path::new("a/damaged")).is_err());
+        let report = backup.verify().unwrap();
+        assert!(!report.is_clean());
+        assert_eq!(report.ok, vec![root.join("good")]);
+        assert_eq!(report.unchecked, vec![root.join("old")]);
+        assert_eq!(report.corrupt.iter().map(|&(ref p, _)| p.clone()).collect::<Vec<_>>(),
+                   vec![root.join("a/damaged")]);
+        assert_eq!(report.incompatible.len(), 1);
+    }
+}